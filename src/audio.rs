@@ -0,0 +1,196 @@
+//! Host audio plumbing between the emulator and an audio device
+//!
+//! The emulator pushes the SPU's 44.1 kHz stereo samples through a [`Resampler`] into a
+//! [`SampleRing`], which the device's callback thread drains. The resampling ratio is
+//! nudged with the ring's fill level (dynamic rate control), so that small differences
+//! between the emulation speed and the device's clock neither underrun nor overflow it.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+/// Fill level the rate control aims for, in samples (about 46 ms)
+pub const TARGET_FILL: usize = 2048;
+
+/// Largest change of the resampling ratio made by the rate control
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+/// Lock-free ring of stereo samples, for a single producer and a single consumer
+#[derive(Debug)]
+pub struct SampleRing {
+    samples: Box<[AtomicU32]>,
+    /// Samples written and read so far. Only the producer stores `write`, and only the
+    /// consumer stores `read`.
+    write: AtomicUsize,
+    read: AtomicUsize,
+    /// Set by the producer to have the consumer drop everything and play silence
+    paused: AtomicBool,
+}
+
+impl SampleRing {
+    pub fn new(capacity: usize) -> Self {
+        SampleRing {
+            samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Samples waiting to be played
+    pub fn len(&self) -> usize {
+        self.write.load(Ordering::Acquire).wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Producer side: queue `sample`, returning false if the ring is full
+    pub fn push(&self, sample: [i16; 2]) -> bool {
+        let write = self.write.load(Ordering::Relaxed);
+        if write.wrapping_sub(self.read.load(Ordering::Acquire)) == self.capacity() {
+            return false;
+        }
+        let packed = sample[0] as u16 as u32 | (sample[1] as u16 as u32) << 16;
+        self.samples[write % self.capacity()].store(packed, Ordering::Relaxed);
+        self.write.store(write.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Consumer side: take the oldest sample
+    pub fn pop(&self) -> Option<[i16; 2]> {
+        let read = self.read.load(Ordering::Relaxed);
+        if read == self.write.load(Ordering::Acquire) {
+            return None;
+        }
+        let packed = self.samples[read % self.capacity()].load(Ordering::Relaxed);
+        self.read.store(read.wrapping_add(1), Ordering::Release);
+        Some([packed as i16, (packed >> 16) as i16])
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Consumer side: fill `out` with interleaved stereo samples. Plays silence while
+    /// paused, dropping whatever was queued, and fades out the last sample when the
+    /// ring runs dry, to avoid a click.
+    pub fn play(&self, out: &mut [i16], last: &mut [i16; 2]) {
+        if self.is_paused() {
+            while self.pop().is_some() {}
+            out.fill(0);
+            *last = [0; 2];
+            return;
+        }
+        for frame in out.chunks_exact_mut(2) {
+            *last = match self.pop() {
+                Some(sample) => sample,
+                None => last.map(|channel| (channel as i32 * 15 / 16) as i16),
+            };
+            frame.copy_from_slice(last);
+        }
+    }
+}
+
+/// Linear interpolation resampler with dynamic rate control
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    /// Input samples per output sample, before the rate control
+    step: f64,
+    /// Position between `previous` and the next input sample
+    pos: f64,
+    previous: [i16; 2],
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Resampler { step: input_rate as f64 / output_rate as f64, pos: 0.0, previous: [0; 2] }
+    }
+
+    /// Resample `input` into `ring`, speeding up or slowing down slightly to keep it
+    /// around [`TARGET_FILL`]. Samples that don't fit are dropped.
+    pub fn push(&mut self, input: &[[i16; 2]], ring: &SampleRing) {
+        let error = (TARGET_FILL as f64 - ring.len() as f64) / TARGET_FILL as f64;
+        // Below the target, make more output samples out of each input sample
+        let step = self.step / (1.0 + error.clamp(-1.0, 1.0) * MAX_RATE_ADJUST);
+        for &sample in input {
+            while self.pos < 1.0 {
+                let frac = self.pos;
+                let lerp = |channel: usize| {
+                    let (a, b) = (self.previous[channel] as f64, sample[channel] as f64);
+                    (a + (b - a) * frac).round() as i16
+                };
+                ring.push([lerp(0), lerp(1)]);
+                self.pos += step;
+            }
+            self.pos -= 1.0;
+            self.previous = sample;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps_and_pauses() {
+        let ring = SampleRing::new(4);
+        for idx in 0..4 {
+            assert!(ring.push([idx, -idx]));
+        }
+        assert!(!ring.push([9, 9]));
+        assert_eq!(ring.pop(), Some([0, 0]));
+        assert!(ring.push([4, -4]));
+        assert_eq!(ring.len(), 4);
+
+        let mut out = [0; 10];
+        let mut last = [0; 2];
+        ring.play(&mut out, &mut last);
+        assert_eq!(out, [1, -1, 2, -2, 3, -3, 4, -4, 3, -3]);
+        assert!(ring.is_empty());
+
+        ring.push([100, 100]);
+        ring.set_paused(true);
+        ring.play(&mut out, &mut last);
+        assert_eq!(out, [0; 10]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn resamples_with_rate_control() {
+        let input: Vec<[i16; 2]> = (0..4410).map(|idx| [idx as i16, 0]).collect();
+
+        // Halfway between the input samples at twice the rate, with the ring on target
+        let ring = SampleRing::new(1 << 16);
+        for _ in 0..TARGET_FILL {
+            ring.push([0; 2]);
+        }
+        let mut resampler = Resampler::new(22_050, 44_100);
+        resampler.push(&input[..3], &ring);
+        let out: Vec<i16> = std::iter::from_fn(|| ring.pop()).skip(TARGET_FILL).map(|[left, _]| left).collect();
+        assert_eq!(out, [0, 0, 0, 1, 1, 2]);
+
+        // An empty ring gets a little more than the nominal rate, a full one a little less
+        let mut resampler = Resampler::new(44_100, 48_000);
+        resampler.push(&input, &ring);
+        let low = ring.len();
+        assert!(low > 4800 && low <= 4800 + 4800 / 100, "{low}");
+        for _ in 0..low {
+            ring.pop();
+        }
+        for _ in 0..TARGET_FILL * 2 {
+            ring.push([0; 2]);
+        }
+        resampler.push(&input, &ring);
+        let high = ring.len() - TARGET_FILL * 2;
+        assert!((4800 - 4800 / 100..4800).contains(&high), "{high}");
+    }
+}
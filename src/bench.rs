@@ -0,0 +1,66 @@
+//! Benchmark mode: measures memory map dispatch and raw emulation throughput
+
+use std::{hint::black_box, time::Instant};
+
+use crate::emu::{map, Psx};
+
+/// Physical addresses hit by typical code: RAM, BIOS, scratchpad and a few I/O registers
+const LOOKUP_ADDRESSES: [u32; 8] = [
+    0x0000_1000, // RAM
+    0x001f_fff0, // RAM (end)
+    0x1fc0_0180, // BIOS
+    0x1f80_0010, // Scratchpad
+    0x1f80_1070, // IRQ_CTL
+    0x1f80_1100, // Timers
+    0x1f80_1814, // GPU
+    0x1f80_1d80, // SPU
+];
+
+/// Length of the pseudo-random address stream used for lookups
+const LOOKUP_STREAM_LEN: usize = 4096;
+const LOOKUP_ROUNDS: u32 = 2000;
+
+/// Mix `LOOKUP_ADDRESSES` into a pseudo-random stream, so that the branch predictor can't
+/// learn the access pattern
+fn lookup_stream() -> Vec<u32> {
+    let mut seed = 0x1234_5678u32;
+    (0..LOOKUP_STREAM_LEN)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            LOOKUP_ADDRESSES[(seed >> 16) as usize % LOOKUP_ADDRESSES.len()]
+        })
+        .collect()
+}
+
+/// Time `LOOKUP_ROUNDS` passes over `stream` and return nanoseconds per lookup
+fn time_lookups<T>(stream: &[u32], lookup: impl Fn(u32) -> T) -> f64 {
+    let start = Instant::now();
+    for _ in 0..LOOKUP_ROUNDS {
+        for &addr in stream {
+            black_box(lookup(black_box(addr)));
+        }
+    }
+    let lookups = LOOKUP_ROUNDS as f64 * stream.len() as f64;
+    start.elapsed().as_nanos() as f64 / lookups
+}
+
+/// Run the memory map benchmark, then emulate `instructions` instructions and report the
+/// throughput
+pub fn run(psx: &mut Psx, instructions: u64) {
+    let stream = lookup_stream();
+    let linear = time_lookups(&stream, map::get_region);
+    let table = map::PageTable::new();
+    let paged = time_lookups(&stream, |addr| table.get(addr));
+    let region = time_lookups(&stream, |addr| table.region(addr));
+    println!("memory map lookup: linear scan {linear:.2} ns, page table {paged:.2} ns ({:.1}x), \
+        region table {region:.2} ns ({:.1}x)", linear / paged, linear / region);
+
+    let start = Instant::now();
+    let target = psx.instructions_retired + instructions;
+    while psx.instructions_retired < target {
+        psx.step();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("emulated {instructions} instructions in {elapsed:.3} s ({:.2} MIPS)",
+        instructions as f64 / elapsed / 1e6);
+}
@@ -1,13 +1,178 @@
+pub mod input;
+pub mod file;
+
+use std::{ops::Range, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, bail, Result};
 use clap::{ValueEnum, Parser};
+use serde::Deserialize;
+
+use crate::{
+    config::{
+        file::{AudioConfig, ConfigFile, Enhancements, VideoConfig},
+        input::InputBindings,
+    },
+    emu::{bios::Region, trace::TraceFilter},
+    runner::{Artifacts, ExitConditions},
+};
+
+/// BIOS dump used when neither the command line nor the config file name one
+const DEFAULT_BIOS: &str = "scph1001.bin";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(value_enum, short, long, ignore_case=true, default_value_t=LogLevel::Error)]
-    pub log: LogLevel,
+    /// [default: error]
+    #[clap(value_enum, short, long, ignore_case=true)]
+    pub log: Option<LogLevel>,
+
+    /// TOML config file to use instead of $XDG_CONFIG_HOME/psx-rs/config.toml. The flags
+    /// override its settings.
+    #[clap(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// CPU execution engine [default: interpreter]
+    #[clap(value_enum, short, long, ignore_case=true)]
+    pub engine: Option<Engine>,
+
+    /// BIOS dump [default: scph1001.bin]
+    #[clap(long, value_name = "PATH")]
+    pub bios: Option<PathBuf>,
+
+    /// PS-X EXE or ELF executable to sideload once the BIOS reaches the shell
+    #[clap(long, value_name = "PATH")]
+    pub exe: Option<PathBuf>,
+
+    /// Skip the BIOS and jump straight into the executable (kernel calls won't work)
+    #[clap(long)]
+    pub fast_boot: bool,
+
+    /// Use the built-in high-level BIOS instead of a BIOS dump
+    #[clap(long)]
+    pub hle: bool,
+
+    /// Disc image (.iso or raw .bin) to insert. Only the high-level BIOS can boot it for now.
+    #[clap(long, value_name = "PATH")]
+    pub disc: Option<PathBuf>,
+
+    /// Console region, which decides the video standard [default: the BIOS's, or the
+    /// disc's with the high-level BIOS]
+    #[clap(value_enum, long, ignore_case=true)]
+    pub region: Option<ConsoleRegion>,
+
+    /// BIOS patches to apply, comma separated
+    #[clap(value_enum, long, ignore_case=true, value_delimiter=',')]
+    pub patch: Vec<BiosPatch>,
+
+    /// Expansion ROM image (cheat cartridge, Caetla, Xplorer, Unirom) to plug into the
+    /// parallel port
+    #[clap(long, value_name = "PATH")]
+    pub exp_rom: Option<PathBuf>,
+
+    /// Directory of the save state slots [default: states]
+    #[clap(long, value_name = "PATH")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Resume from this save state slot
+    #[clap(long, value_name = "SLOT", value_parser = clap::value_parser!(u8).range(0..crate::STATE_SLOTS as i64))]
+    pub load_slot: Option<u8>,
+
+    /// Keep this many snapshots to rewind to
+    #[clap(long, value_name = "SNAPSHOTS")]
+    pub rewind_depth: Option<usize>,
+
+    /// Video frames between rewind snapshots
+    #[clap(long, value_name = "FRAMES", default_value_t = 30, requires = "rewind_depth")]
+    pub rewind_interval: u64,
+
+    /// Memory used by the rewind snapshots at most, the oldest are dropped beyond it
+    #[clap(long, value_name = "MIB", default_value_t = 256, requires = "rewind_depth")]
+    pub rewind_memory: usize,
+
+    /// Write the TTY output of the expansion port DUART to this file instead of stdout
+    #[clap(long, value_name = "PATH")]
+    pub tty: Option<PathBuf>,
+
+    /// Run without a window, which is the only option when built without the `sdl` feature
+    #[clap(long)]
+    pub headless: bool,
+
+    /// Wait for GDB to connect on this localhost port before running
+    #[clap(long, value_name = "PORT", conflicts_with = "bench")]
+    pub gdb: Option<u16>,
+
+    /// Stop before the first instruction and read debugger commands from stdin
+    #[clap(long, conflicts_with_all = ["bench", "gdb"])]
+    pub debug: bool,
+
+    /// Run headless until this many frames were emulated
+    #[clap(long, value_name = "N", help_heading = "Headless runs")]
+    pub frames: Option<u64>,
+
+    /// Run headless until this many instructions were emulated
+    #[clap(long, value_name = "N", help_heading = "Headless runs")]
+    pub instructions: Option<u64>,
+
+    /// Run headless for this much emulated time
+    #[clap(long, value_name = "SECONDS", help_heading = "Headless runs")]
+    pub seconds: Option<f64>,
+
+    /// Run headless until the CPU reaches this address (hex), exiting with 2 if a limit
+    /// runs out first
+    #[clap(long, value_name = "ADDR", value_parser = parse_address, help_heading = "Headless runs")]
+    pub until_pc: Option<u32>,
+
+    /// Run headless until the kernel TTY output contains this text, exiting with 2 if a
+    /// limit runs out first
+    #[clap(long, value_name = "TEXT", value_parser = clap::builder::NonEmptyStringValueParser::new(), help_heading = "Headless runs")]
+    pub until_tty: Option<String>,
+
+    /// Write the last frame to this PPM file when a headless run stops
+    #[clap(long, value_name = "PATH", help_heading = "Headless runs")]
+    pub screenshot: Option<PathBuf>,
+
+    /// Write the VRAM (1024x512, 16-bit little endian) to this file when a headless run stops
+    #[clap(long, value_name = "PATH", help_heading = "Headless runs")]
+    pub vram_dump: Option<PathBuf>,
+
+    /// Write the kernel TTY output to this file when a headless run stops
+    #[clap(long, value_name = "PATH", help_heading = "Headless runs")]
+    pub tty_log: Option<PathBuf>,
+
+    /// Write a line per executed instruction to this file, for diffing against other
+    /// emulators
+    #[clap(long, value_name = "PATH", conflicts_with = "bench", help_heading = "Tracing")]
+    pub trace: Option<PathBuf>,
+
+    /// Only trace the instructions at these addresses (hex, END excluded)
+    #[clap(long, value_name = "START-END", value_parser = parse_address_range, requires = "trace", help_heading = "Tracing")]
+    pub trace_pc: Option<Range<u32>>,
+
+    /// Start tracing once this many instructions were emulated
+    #[clap(long, value_name = "N", requires = "trace", help_heading = "Tracing")]
+    pub trace_from: Option<u64>,
+
+    /// Stop tracing after this many instructions
+    #[clap(long, value_name = "N", requires = "trace", help_heading = "Tracing")]
+    pub trace_count: Option<u64>,
+
+    /// Run in lock-step with this trace of another emulator and stop at the first
+    /// difference, see the `golden` module for the format
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["bench", "gdb", "debug"], help_heading = "Tracing")]
+    pub golden: Option<PathBuf>,
+
+    /// Benchmark the memory map and emulate this many instructions, then exit
+    #[clap(long, value_name = "INSTRUCTIONS")]
+    pub bench: Option<u64>,
+
+    /// Check the recompiler against the interpreter after every block
+    #[cfg(feature = "recompiler")]
+    #[clap(long)]
+    pub lockstep: bool,
 }
 
-#[derive(ValueEnum, Clone, Debug)]
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub enum LogLevel {
     Trace,
     Debug,
@@ -16,19 +181,254 @@ pub enum LogLevel {
     Error,
 }
 
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Engine {
+    Interpreter,
+    Cached,
+    #[cfg(feature = "recompiler")]
+    Recompiler,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BiosPatch {
+    /// Enable the kernel's TTY output
+    Tty,
+    /// Skip the boot animation and logo checks
+    SkipIntro,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsoleRegion {
+    NtscJ,
+    NtscU,
+    Pal,
+}
+
+impl From<ConsoleRegion> for Region {
+    fn from(region: ConsoleRegion) -> Region {
+        match region {
+            ConsoleRegion::NtscJ => Region::Japan,
+            ConsoleRegion::NtscU => Region::NorthAmerica,
+            ConsoleRegion::Pal => Region::Europe,
+        }
+    }
+}
+
+/// Settings from the config file, with the command line flags on top
 pub struct Config {
-    pub log_level: LogLevel
+    pub log_level: LogLevel,
+    pub engine: Engine,
+    pub bios: PathBuf,
+    pub exe: Option<PathBuf>,
+    pub fast_boot: bool,
+    pub hle: bool,
+    pub disc: Option<PathBuf>,
+    /// `None` to go by the BIOS or disc
+    pub region: Option<Region>,
+    pub patches: Vec<BiosPatch>,
+    pub exp_rom: Option<PathBuf>,
+    pub memory_cards: [Option<PathBuf>; 2],
+    pub tty: Option<PathBuf>,
+    pub state_dir: PathBuf,
+    pub load_slot: Option<u8>,
+    pub rewind_depth: Option<usize>,
+    pub rewind_interval: u64,
+    pub rewind_memory: usize,
+    pub headless: bool,
+    pub gdb: Option<u16>,
+    pub debug: bool,
+    pub bench: Option<u64>,
+    pub trace: Option<PathBuf>,
+    pub trace_filter: TraceFilter,
+    pub golden: Option<PathBuf>,
+    /// Empty unless this is a headless run
+    pub exit: ExitConditions,
+    pub artifacts: Artifacts,
+    pub input: InputBindings,
+    pub audio: AudioConfig,
+    pub video: VideoConfig,
+    pub enhancements: Enhancements,
+    #[cfg(feature = "recompiler")]
+    pub lockstep: bool,
 }
 
 impl Config {
-    pub fn parse_args() -> Config {
+    pub fn parse_args() -> Result<Config> {
         let args = Args::parse();
-        Config::from_args(args)
+        let file = match &args.config {
+            Some(path) => ConfigFile::read(path)?,
+            None => match file::default_path() {
+                Some(path) if path.exists() => ConfigFile::read(&path)?,
+                _ => ConfigFile::default(),
+            },
+        };
+        Config::layer(args, file)
     }
-    fn from_args(args: Args) -> Config {
-        Config {
-            log_level: args.log,
+
+    /// `args` over `file`
+    fn layer(args: Args, file: ConfigFile) -> Result<Config> {
+        let exe = args.exe.or(file.exe);
+        let fast_boot = args.fast_boot || file.fast_boot;
+        if fast_boot && exe.is_none() {
+            bail!("fast boot needs an executable, from --exe or exe in the config file");
+        }
+        if let Some(seconds) = args.seconds && !(seconds.is_finite() && seconds > 0.0) {
+            bail!("--seconds needs a positive number of seconds, got {seconds}");
+        }
+        let exit = ExitConditions {
+            frames: args.frames,
+            instructions: args.instructions,
+            seconds: args.seconds,
+            pc: args.until_pc,
+            tty: args.until_tty,
+        };
+        let artifacts = Artifacts { screenshot: args.screenshot, vram: args.vram_dump, tty_log: args.tty_log };
+        if exit.is_empty() && (artifacts.screenshot.is_some() || artifacts.vram.is_some() || artifacts.tty_log.is_some()) {
+            bail!("artifacts are written when a headless run stops, which needs --frames, --instructions, --seconds, --until-pc or --until-tty");
+        }
+        if args.gdb.is_some() && !exit.is_empty() {
+            bail!("--gdb can't be combined with a headless run");
+        }
+        if args.debug && !exit.is_empty() {
+            bail!("--debug can't be combined with a headless run");
         }
+        if args.golden.is_some() && !exit.is_empty() {
+            bail!("--golden can't be combined with a headless run");
+        }
+        let trace_filter = TraceFilter {
+            pcs: args.trace_pc,
+            instructions: match (args.trace_from, args.trace_count) {
+                (None, None) => None,
+                (from, count) => {
+                    let from = from.unwrap_or(0);
+                    Some(from..count.map_or(u64::MAX, |count| from.saturating_add(count)))
+                },
+            },
+        };
+        Ok(Config {
+            log_level: args.log.or(file.log).unwrap_or(LogLevel::Error),
+            engine: args.engine.or(file.engine).unwrap_or(Engine::Interpreter),
+            bios: args.bios.or(file.bios).unwrap_or_else(|| PathBuf::from(DEFAULT_BIOS)),
+            exe,
+            fast_boot,
+            hle: args.hle || file.hle,
+            disc: args.disc.or(file.disc),
+            region: args.region.or(file.region).map(Region::from),
+            patches: if args.patch.is_empty() { file.patches } else { args.patch },
+            exp_rom: args.exp_rom.or(file.exp_rom),
+            memory_cards: [file.memory_cards.port1, file.memory_cards.port2],
+            tty: args.tty,
+            state_dir: args.state_dir.or(file.state_dir).unwrap_or_else(|| PathBuf::from("states")),
+            load_slot: args.load_slot,
+            rewind_depth: args.rewind_depth,
+            rewind_interval: args.rewind_interval,
+            rewind_memory: args.rewind_memory,
+            headless: args.headless,
+            gdb: args.gdb,
+            debug: args.debug,
+            bench: args.bench,
+            trace: args.trace,
+            trace_filter,
+            golden: args.golden,
+            exit,
+            artifacts,
+            // Checked when the file was read
+            input: file.input.resolve()?,
+            audio: file.audio,
+            video: file.video,
+            enhancements: file.enhancements,
+            #[cfg(feature = "recompiler")]
+            lockstep: args.lockstep,
+        })
     }
 }
 
+/// Address in hex, with or without 0x
+fn parse_address(text: &str) -> Result<u32, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).map_err(|e| format!("not a hex address: {e}"))
+}
+
+/// `START-END` in hex, END excluded
+fn parse_address_range(text: &str) -> Result<Range<u32>, String> {
+    let (start, end) = text.split_once('-').ok_or("expected START-END")?;
+    let (start, end) = (parse_address(start)?, parse_address(end)?);
+    if start >= end {
+        return Err(format!("empty range {start:08x}-{end:08x}"));
+    }
+    Ok(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(args: &[&str], file: &str) -> Result<Config> {
+        let args = Args::try_parse_from(std::iter::once("psx-rs").chain(args.iter().copied()))?;
+        Config::layer(args, ConfigFile::parse(file, Path::new("/config"))?)
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let file = r#"
+            bios = "bios.bin"
+            disc = "/games/game.bin"
+            engine = "cached"
+            region = "pal"
+            patches = ["tty", "skip-intro"]
+            memory_cards = { port2 = "card.mcd" }
+
+            [audio]
+            volume = 0.5
+
+            [video]
+            scale = 3
+        "#;
+        let config = layer(&[], file).unwrap();
+        assert_eq!(config.bios, Path::new("/config/bios.bin"));
+        assert_eq!(config.disc.as_deref(), Some(Path::new("/games/game.bin")));
+        assert!(matches!(config.engine, Engine::Cached));
+        assert_eq!(config.region, Some(Region::Europe));
+        assert_eq!(config.patches, [BiosPatch::Tty, BiosPatch::SkipIntro]);
+        assert_eq!(config.memory_cards, [None, Some(PathBuf::from("/config/card.mcd"))]);
+        assert_eq!(config.audio, AudioConfig { volume: 0.5, ..AudioConfig::default() });
+        assert_eq!(config.video.scale, 3);
+        assert_eq!(config.state_dir, Path::new("states"));
+
+        let config = layer(&["--bios", "other.bin", "--engine", "interpreter", "--patch", "tty", "--region", "ntsc-j"], file).unwrap();
+        assert_eq!(config.bios, Path::new("other.bin"));
+        assert!(matches!(config.engine, Engine::Interpreter));
+        assert_eq!(config.region, Some(Region::Japan));
+        assert_eq!(config.patches, [BiosPatch::Tty]);
+
+        let config = layer(&[], "").unwrap();
+        assert_eq!(config.bios, Path::new(DEFAULT_BIOS));
+        assert_eq!(config.video, VideoConfig::default());
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let error = |args: &[&str], file: &str| layer(args, file).err().unwrap().to_string();
+        assert!(error(&[], "region = \"usa\"").contains("unknown variant `usa`"));
+        assert!(error(&[], "[audio]\nvolume = 2.0").starts_with("audio.volume: 2 is out of range"));
+        assert!(error(&[], "[audio]\nbuffer = 1000").starts_with("audio.buffer: 1000 samples"));
+        assert!(error(&[], "[video]\nscale = 0").starts_with("video.scale: 0 is out of range"));
+        assert!(error(&[], "[input.port1]\nkind = \"guncon\"").starts_with("input.port1.kind"));
+        assert!(error(&["--fast-boot"], "").starts_with("fast boot needs an executable"));
+        assert_eq!(layer(&["--fast-boot"], "exe = \"game.exe\"").unwrap().exe, Some(PathBuf::from("/config/game.exe")));
+        assert!(error(&["--screenshot", "out.ppm"], "").starts_with("artifacts are written when a headless run stops"));
+        assert!(error(&["--seconds=-1"], "").starts_with("--seconds needs a positive number"));
+        assert!(error(&["--seconds", "NaN"], "").starts_with("--seconds needs a positive number"));
+        assert!(error(&["--seconds", "inf"], "").starts_with("--seconds needs a positive number"));
+        assert!(error(&["--until-pc", "zz"], "").contains("not a hex address"));
+        assert_eq!(layer(&["--until-pc", "0x80010000"], "").unwrap().exit.pc, Some(0x8001_0000));
+        assert!(error(&["--trace-pc", "80010000-80020000"], "").contains("--trace <PATH>"));
+        assert!(error(&["--trace", "t.log", "--trace-pc", "80020000-80010000"], "").contains("empty range"));
+        let config = layer(&["--trace", "t.log", "--trace-pc", "0x80010000-80020000", "--trace-from", "100", "--trace-count", "5"], "").unwrap();
+        assert_eq!(config.trace_filter, TraceFilter { pcs: Some(0x8001_0000..0x8002_0000), instructions: Some(100..105) });
+        assert!(error(&["--golden", "golden.txt", "--frames", "10"], "").starts_with("--golden can't be combined"));
+    }
+}
@@ -0,0 +1,176 @@
+//! The TOML config file
+//!
+//! Read from `$XDG_CONFIG_HOME/psx-rs/config.toml` (`~/.config/psx-rs/config.toml`) if it
+//! exists, or from the file given with `--config`. Every key is optional, and the
+//! command line flags win over it. Relative paths are relative to the file.
+//!
+//! ```toml
+//! bios = "bios/scph1001.bin"
+//! disc = "games/game.bin"
+//! region = "pal"
+//! patches = ["tty"]
+//! memory_cards = { port1 = "cards/1.mcd" }
+//!
+//! [audio]
+//! volume = 0.5
+//!
+//! [video]
+//! scale = 3
+//!
+//! [enhancements]
+//! linear_filtering = true
+//!
+//! [input.port1]
+//! kind = "dualshock"
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use crate::config::{input::InputConfig, BiosPatch, ConsoleRegion, Engine, LogLevel};
+
+/// Size of a memory card image
+const MEMORY_CARD_SIZE: u64 = 128 * 1024;
+
+/// Where the config file is looked for when there is no `--config`
+pub fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("psx-rs").join("config.toml"))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub log: Option<LogLevel>,
+    pub engine: Option<Engine>,
+    /// BIOS dump
+    pub bios: Option<PathBuf>,
+    /// Use the high-level BIOS instead of the dump
+    pub hle: bool,
+    pub disc: Option<PathBuf>,
+    pub exe: Option<PathBuf>,
+    pub fast_boot: bool,
+    pub region: Option<ConsoleRegion>,
+    pub patches: Vec<BiosPatch>,
+    pub exp_rom: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    pub memory_cards: MemoryCards,
+    pub input: InputConfig,
+    pub audio: AudioConfig,
+    pub video: VideoConfig,
+    pub enhancements: Enhancements,
+}
+
+/// Memory card images of ports 1 and 2
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryCards {
+    pub port1: Option<PathBuf>,
+    pub port2: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Open an audio device at all
+    pub enabled: bool,
+    /// From 0.0 (muted) to 1.0
+    pub volume: f32,
+    /// Device buffer size in samples, a power of two. Smaller means less latency, but
+    /// more risk of crackling.
+    pub buffer: u16,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig { enabled: true, volume: 1.0, buffer: 1024 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Window size, in multiples of 320x240
+    pub scale: u32,
+    pub fullscreen: bool,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig { scale: 2, fullscreen: false }
+    }
+}
+
+/// Presentation improvements over what the console outputs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Enhancements {
+    /// Smooth the picture when scaling it up, instead of keeping sharp pixels
+    pub linear_filtering: bool,
+    /// Letterbox the picture to 4:3 instead of stretching it over the window
+    pub keep_aspect_ratio: bool,
+}
+
+impl ConfigFile {
+    /// Read and check the file at `path`
+    pub fn read(path: &Path) -> Result<ConfigFile> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read config file {}: {e}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        ConfigFile::parse(&text, dir).map_err(|e| anyhow!("bad config file {}: {e}", path.display()))
+    }
+
+    /// Parse and check `text`, with relative paths taken from `dir`
+    pub fn parse(text: &str, dir: &Path) -> Result<ConfigFile> {
+        let mut file: ConfigFile = toml::from_str(text)?;
+        for path in [
+            &mut file.bios,
+            &mut file.disc,
+            &mut file.exe,
+            &mut file.exp_rom,
+            &mut file.state_dir,
+            &mut file.memory_cards.port1,
+            &mut file.memory_cards.port2,
+        ].into_iter().flatten() {
+            *path = dir.join(&*path);
+        }
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.input.resolve()?;
+        let audio = &self.audio;
+        if !(0.0..=1.0).contains(&audio.volume) {
+            bail!("audio.volume: {} is out of range, expected 0.0 to 1.0", audio.volume);
+        }
+        if !audio.buffer.is_power_of_two() || !(256..=8192).contains(&audio.buffer) {
+            bail!("audio.buffer: {} samples isn't a power of two from 256 to 8192", audio.buffer);
+        }
+        if !(1..=8).contains(&self.video.scale) {
+            bail!("video.scale: {} is out of range, expected 1 to 8", self.video.scale);
+        }
+        for (port, path) in [("port1", &self.memory_cards.port1), ("port2", &self.memory_cards.port2)] {
+            if let Some(path) = path {
+                check_memory_card(path).map_err(|e| anyhow!("memory_cards.{port}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A memory card image has to be 128 KiB. A missing one counts as an empty card.
+pub fn check_memory_card(path: &Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => bail!("{} is a directory", path.display()),
+        Ok(meta) if meta.len() != MEMORY_CARD_SIZE => {
+            bail!("{} is {} bytes, memory card images are {MEMORY_CARD_SIZE}", path.display(), meta.len())
+        },
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,317 @@
+//! Input mappings, the `[input]` table of the config file
+//!
+//! Keys and game controller buttons are named as SDL names them (`SDL_GetKeyFromName`,
+//! `SDL_GameControllerGetButtonFromString`). Key names are checked here against the keys
+//! of a standard keyboard, ignoring case, and a key can only be bound once. Game
+//! controller buttons are only checked when the window opens.
+//!
+//! ```toml
+//! [input.port1]
+//! kind = "dualshock"
+//! keys = { cross = "K", select = "" }  # merged over the defaults, "" unbinds
+//!
+//! [input.hotkeys]
+//! screenshot = "F9"
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::emu::pad::{Button, PadKind};
+
+/// Keyboard bindings of port 1. Port 2 has none.
+const DEFAULT_KEYS: [(Button, &str); 16] = [
+    (Button::Up, "Up"),
+    (Button::Down, "Down"),
+    (Button::Left, "Left"),
+    (Button::Right, "Right"),
+    (Button::Cross, "X"),
+    (Button::Circle, "C"),
+    (Button::Square, "Z"),
+    (Button::Triangle, "S"),
+    (Button::L1, "Q"),
+    (Button::R1, "E"),
+    (Button::L2, "1"),
+    (Button::R2, "3"),
+    (Button::L3, "A"),
+    (Button::R3, "D"),
+    (Button::Start, "Return"),
+    (Button::Select, "Backspace"),
+];
+
+/// Names of the keys that can be bound, besides the letters and digits, as SDL names them
+const KEY_NAMES: &[&str] = &[
+    "Return", "Escape", "Backspace", "Tab", "Space",
+    "-", "=", "[", "]", "\\", "#", ";", "'", "`", ",", ".", "/",
+    "CapsLock", "PrintScreen", "ScrollLock", "Pause", "Numlock", "Application", "Menu",
+    "Insert", "Home", "PageUp", "Delete", "End", "PageDown",
+    "Right", "Left", "Down", "Up",
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "F13", "F14", "F15", "F16", "F17", "F18", "F19", "F20", "F21", "F22", "F23", "F24",
+    "Keypad /", "Keypad *", "Keypad -", "Keypad +", "Keypad Enter", "Keypad .", "Keypad =",
+    "Keypad 0", "Keypad 1", "Keypad 2", "Keypad 3", "Keypad 4",
+    "Keypad 5", "Keypad 6", "Keypad 7", "Keypad 8", "Keypad 9",
+    "Left Ctrl", "Left Shift", "Left Alt", "Left GUI",
+    "Right Ctrl", "Right Shift", "Right Alt", "Right GUI",
+];
+
+/// Whether SDL knows the key called `name`
+fn is_key_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next())
+        && c.is_ascii_alphanumeric()
+    {
+        return true;
+    }
+    KEY_NAMES.iter().any(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// Game controller bindings of both ports. The triggers are axes in SDL, they count as
+/// pressed past half way.
+const DEFAULT_BUTTONS: [(Button, &str); 16] = [
+    (Button::Up, "dpup"),
+    (Button::Down, "dpdown"),
+    (Button::Left, "dpleft"),
+    (Button::Right, "dpright"),
+    (Button::Cross, "a"),
+    (Button::Circle, "b"),
+    (Button::Square, "x"),
+    (Button::Triangle, "y"),
+    (Button::L1, "leftshoulder"),
+    (Button::R1, "rightshoulder"),
+    (Button::L2, "lefttrigger"),
+    (Button::R2, "righttrigger"),
+    (Button::L3, "leftstick"),
+    (Button::R3, "rightstick"),
+    (Button::Start, "start"),
+    (Button::Select, "back"),
+];
+
+/// Frontend actions bound to keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Hotkey {
+    /// Save to the current slot
+    SaveState,
+    /// Load the current slot
+    LoadState,
+    /// Pause or resume
+    Pause,
+    /// Run unthrottled while held
+    FastForward,
+    /// Run one frame, then pause
+    FrameAdvance,
+    /// Write the current frame to the screenshot directory
+    Screenshot,
+    /// Go back about a second, when rewind is enabled
+    Rewind,
+}
+
+impl Hotkey {
+    pub const ALL: [Hotkey; 7] = [
+        Hotkey::SaveState, Hotkey::LoadState, Hotkey::Pause,
+        Hotkey::FastForward, Hotkey::FrameAdvance, Hotkey::Screenshot,
+        Hotkey::Rewind,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hotkey::SaveState => "save_state",
+            Hotkey::LoadState => "load_state",
+            Hotkey::Pause => "pause",
+            Hotkey::FastForward => "fast_forward",
+            Hotkey::FrameAdvance => "frame_advance",
+            Hotkey::Screenshot => "screenshot",
+            Hotkey::Rewind => "rewind",
+        }
+    }
+
+    fn default_key(self) -> &'static str {
+        match self {
+            Hotkey::SaveState => "F5",
+            Hotkey::LoadState => "F7",
+            Hotkey::Pause => "P",
+            Hotkey::FastForward => "Tab",
+            Hotkey::FrameAdvance => "F",
+            Hotkey::Screenshot => "F12",
+            Hotkey::Rewind => "R",
+        }
+    }
+}
+
+/// The `[input]` table as written
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub port1: PortConfig,
+    pub port2: PortConfig,
+    /// Hotkey name to key name, merged over the defaults
+    pub hotkeys: BTreeMap<String, String>,
+}
+
+/// `[input.port1]` and `[input.port2]`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortConfig {
+    /// `"digital"` (the default) or `"dualshock"`, whose analog sticks follow the
+    /// controller's
+    pub kind: Option<String>,
+    /// Game controller driving the port, in connection order. Port 1 gets the first by
+    /// default, port 2 the second.
+    pub controller: Option<usize>,
+    /// Button name to key name, merged over the defaults
+    pub keys: BTreeMap<String, String>,
+    /// Button name to game controller button (or trigger) name, merged over the defaults
+    pub buttons: BTreeMap<String, String>,
+}
+
+/// Checked mappings of a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortBindings {
+    pub kind: PadKind,
+    pub controller: usize,
+    pub keys: BTreeMap<Button, String>,
+    pub buttons: BTreeMap<Button, String>,
+}
+
+/// Checked mappings, with the defaults filled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputBindings {
+    pub ports: [PortBindings; 2],
+    pub hotkeys: BTreeMap<Hotkey, String>,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        InputConfig::default().resolve().unwrap()
+    }
+}
+
+impl InputConfig {
+    /// Check the names and fill in the defaults
+    pub fn resolve(&self) -> Result<InputBindings> {
+        let port1 = self.port1.resolve("port1", 0, &DEFAULT_KEYS)?;
+        let port2 = self.port2.resolve("port2", 1, &[])?;
+
+        let mut hotkeys: BTreeMap<Hotkey, String> = Hotkey::ALL.iter()
+            .map(|&hotkey| (hotkey, hotkey.default_key().to_string()))
+            .collect();
+        for (name, key) in &self.hotkeys {
+            let Some(hotkey) = Hotkey::ALL.into_iter().find(|hotkey| hotkey.name() == name) else {
+                bail!("input.hotkeys: unknown hotkey {name:?}, expected one of {}",
+                    Hotkey::ALL.map(Hotkey::name).join(", "));
+            };
+            hotkeys.insert(hotkey, key.clone());
+        }
+        hotkeys.retain(|_, key| !key.is_empty());
+
+        // Where each key is bound, to report the first binding a duplicate clashes with
+        let mut bound: HashMap<String, String> = HashMap::new();
+        let keys = [("port1", &port1), ("port2", &port2)].into_iter()
+            .flat_map(|(table, port)| port.keys.iter().map(move |(button, key)| (format!("input.{table}.keys.{button}"), key)))
+            .chain(hotkeys.iter().map(|(hotkey, key)| (format!("input.hotkeys.{}", hotkey.name()), key)));
+        for (path, key) in keys {
+            if !is_key_name(key) {
+                bail!("{path}: unknown key {key:?}");
+            }
+            if let Some(other) = bound.insert(key.to_ascii_lowercase(), path.clone()) {
+                bail!("{path}: key {key:?} is already bound to {other}");
+            }
+        }
+
+        Ok(InputBindings { ports: [port1, port2], hotkeys })
+    }
+}
+
+impl PortConfig {
+    fn resolve(&self, table: &str, port: usize, default_keys: &[(Button, &str)]) -> Result<PortBindings> {
+        let kind = match &self.kind {
+            None => PadKind::Digital,
+            Some(name) => match PadKind::from_name(name) {
+                Some(kind) => kind,
+                None => bail!("input.{table}.kind: unknown controller kind {name:?}, expected \"digital\" or \"dualshock\""),
+            },
+        };
+        Ok(PortBindings {
+            kind,
+            controller: self.controller.unwrap_or(port),
+            keys: merge(&format!("input.{table}.keys"), default_keys, &self.keys)?,
+            buttons: merge(&format!("input.{table}.buttons"), &DEFAULT_BUTTONS, &self.buttons)?,
+        })
+    }
+}
+
+/// `overrides` over `defaults`, keyed by pad button, without the unbound ones
+fn merge(table: &str, defaults: &[(Button, &str)], overrides: &BTreeMap<String, String>) -> Result<BTreeMap<Button, String>> {
+    let mut bindings: BTreeMap<Button, String> = defaults.iter()
+        .map(|&(button, name)| (button, name.to_string()))
+        .collect();
+    for (button, name) in overrides {
+        let Some(button) = Button::from_name(button) else {
+            bail!("{table}: unknown pad button {button:?}, expected one of {}",
+                Button::ALL.map(Button::name).join(", "));
+        };
+        bindings.insert(button, name.clone());
+    }
+    bindings.retain(|_, name| !name.is_empty());
+    Ok(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_over_defaults() {
+        let config: InputConfig = toml::from_str(r#"
+            [port1]
+            kind = "dualshock"
+            keys = { cross = "K", select = "" }
+
+            [port2]
+            controller = 0
+            keys = { start = "keypad enter" }
+
+            [hotkeys]
+            screenshot = "F9"
+            pause = ""
+        "#).unwrap();
+        let bindings = config.resolve().unwrap();
+
+        let [port1, port2] = &bindings.ports;
+        assert_eq!((port1.kind, port1.controller), (PadKind::DualShock, 0));
+        assert_eq!(port1.keys[&Button::Cross], "K");
+        assert_eq!(port1.keys[&Button::Up], "Up");
+        assert!(!port1.keys.contains_key(&Button::Select));
+        assert_eq!(port1.buttons[&Button::L2], "lefttrigger");
+        assert_eq!((port2.kind, port2.controller), (PadKind::Digital, 0));
+        assert_eq!(port2.keys.len(), 1);
+
+        assert_eq!(bindings.hotkeys[&Hotkey::Screenshot], "F9");
+        assert_eq!(bindings.hotkeys[&Hotkey::SaveState], "F5");
+        assert_eq!(bindings.hotkeys[&Hotkey::Rewind], "R");
+        assert!(!bindings.hotkeys.contains_key(&Hotkey::Pause));
+        assert_eq!(InputBindings::default().ports[1].controller, 1);
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        let error = |source: &str| {
+            let config: InputConfig = toml::from_str(source).unwrap();
+            config.resolve().unwrap_err().to_string()
+        };
+        assert!(error("port1.keys = { crss = \"X\" }").starts_with("input.port1.keys: unknown pad button \"crss\""));
+        assert!(error("port2.kind = \"negcon\"").starts_with("input.port2.kind: unknown controller kind"));
+        assert!(error("hotkeys.quit = \"Q\"").starts_with("input.hotkeys: unknown hotkey \"quit\""));
+        assert!(toml::from_str::<InputConfig>("port3 = {}").is_err());
+
+        assert_eq!(error("port1.keys = { cross = \"Kp5\" }"), "input.port1.keys.cross: unknown key \"Kp5\"");
+        assert_eq!(error("hotkeys.screenshot = \"F5\""),
+            "input.hotkeys.screenshot: key \"F5\" is already bound to input.hotkeys.save_state");
+        assert_eq!(error("hotkeys.pause = \"x\""),
+            "input.hotkeys.pause: key \"x\" is already bound to input.port1.keys.cross");
+        assert_eq!(error("port2.keys = { start = \"return\" }"),
+            "input.port2.keys.start: key \"return\" is already bound to input.port1.keys.start");
+    }
+}
@@ -0,0 +1,587 @@
+//! Built-in command line debugger
+//!
+//! `--debug` stops before the first instruction and reads commands from stdin, see
+//! `help` for the list. The CPU runs on the interpreter one instruction at a time, so that
+//! breakpoints, watchpoints and the call stack see every instruction. While running, the
+//! pause hotkey in the window breaks back into the prompt.
+
+mod command;
+
+use std::io::{BufRead, Write};
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{
+    debugger::command::{Command, Condition, Register, Value, WatchKind, HELP},
+    emu::{
+        cpu::{disasm::{Disasm, REGISTER_NAMES}, instruction::{Instruction, RegisterIndex}, Cpu, Engine},
+        watch::{Triggers, WatchAction, WatchHit, WatchId},
+    },
+    Context,
+};
+
+/// Steps between looks at the TTY output and the pause hotkey while running
+const POLL_STEPS: u32 = 0x1000;
+
+/// Calls tracked at most. Code that doesn't return the usual way (longjmp, exception
+/// handlers) would grow the stack forever.
+const MAX_CALLS: usize = 256;
+
+struct Breakpoint {
+    id: u32,
+    addr: u32,
+    condition: Option<Condition>,
+}
+
+struct Watchpoint {
+    id: u32,
+    kind: WatchKind,
+    addr: u32,
+    len: u32,
+    /// Watch set on the bus
+    watch: WatchId,
+}
+
+/// A call made by a `jal` or `jalr`
+#[derive(Debug, Clone, Copy)]
+struct Call {
+    site: u32,
+    target: u32,
+}
+
+/// Why the CPU stopped
+enum Stop {
+    /// The command ran its course
+    Done,
+    Breakpoint(u32),
+    Watchpoint(u32, WatchHit),
+    /// Cause code of an exception other than an interrupt or system call
+    Exception(u32),
+    /// The pause hotkey was pressed
+    Paused,
+    WindowClosed,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: u32,
+    /// Calls seen since the debugger started, innermost last
+    calls: Vec<Call>,
+    /// Log level to go back to after `trace off`
+    log_level: LevelFilter,
+    /// Step or next repeated by an empty line
+    repeat: Option<Command>,
+}
+
+impl Debugger {
+    pub fn new(log_level: LevelFilter) -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            calls: Vec::new(),
+            log_level,
+            repeat: None,
+        }
+    }
+
+    /// Read and run commands from `input` until `quit`, the end of the input, or the
+    /// window being closed
+    pub fn repl(&mut self, ctx: &mut Context, input: impl BufRead, out: &mut impl Write) -> Result<()> {
+        if ctx.psx.cpu().engine() != Engine::Interpreter {
+            writeln!(out, "switching to the interpreter while debugging")?;
+            ctx.psx.set_engine(Engine::Interpreter)?;
+        }
+        self.show_pc(ctx, out)?;
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(psx) ")?;
+            out.flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                return Ok(());
+            };
+            let command = match line.trim() {
+                "" => match &self.repeat {
+                    Some(command) => command.clone(),
+                    None => continue,
+                },
+                line => match Command::parse(line) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        writeln!(out, "error: {err}")?;
+                        continue;
+                    },
+                },
+            };
+            match self.execute(ctx, command, out) {
+                Ok(true) => {},
+                Ok(false) => return Ok(()),
+                Err(err) => writeln!(out, "error: {err}")?,
+            }
+        }
+    }
+
+    /// Run `command`. Returns false to leave the debugger.
+    fn execute(&mut self, ctx: &mut Context, command: Command, out: &mut impl Write) -> Result<bool> {
+        self.repeat = matches!(command, Command::Step(_) | Command::Next).then(|| command.clone());
+        let stop = match command {
+            Command::Step(count) => {
+                let mut left = count;
+                self.resume(ctx, |_, _| {
+                    left -= 1;
+                    left == 0
+                })?
+            },
+            Command::Next => {
+                let (pc, depth) = (ctx.psx.cpu().pc(), self.calls.len());
+                match call_target(ctx) {
+                    Some(_) => self.resume(ctx, |cpu, calls| cpu.pc() == pc.wrapping_add(8) && calls <= depth)?,
+                    None => self.resume(ctx, |_, _| true)?,
+                }
+            },
+            Command::Continue => self.resume(ctx, |_, _| false)?,
+            Command::Until(location) => match location {
+                Some(location) => {
+                    let addr = eval(ctx, &location)?;
+                    self.resume(ctx, |cpu, _| cpu.pc() == addr)?
+                },
+                None => {
+                    let (pc, depth) = (ctx.psx.cpu().pc(), self.calls.len());
+                    self.resume(ctx, |cpu, calls| cpu.pc() > pc && calls <= depth)?
+                },
+            },
+            Command::Break { location, condition } => {
+                let addr = eval(ctx, &location)?;
+                let id = self.new_id();
+                writeln!(out, "Breakpoint {id} at {}", ctx.psx.cpu().location(addr))?;
+                self.breakpoints.push(Breakpoint { id, addr, condition });
+                return Ok(true);
+            },
+            Command::Watch { kind, location, len } => {
+                let addr = eval(ctx, &location)?;
+                let id = self.new_id();
+                writeln!(out, "Watchpoint {id} on {len} bytes at {}", ctx.psx.cpu().location(addr))?;
+                let triggers = match kind {
+                    WatchKind::Read => Triggers::READ,
+                    WatchKind::Write => Triggers::WRITE,
+                    WatchKind::Access => Triggers::ACCESS,
+                };
+                let watch = ctx.psx.add_watch(addr, len, triggers, WatchAction::Break);
+                self.watchpoints.push(Watchpoint { id, kind, addr, len, watch });
+                return Ok(true);
+            },
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                for wp in self.watchpoints.drain(..) {
+                    ctx.psx.remove_watch(wp.watch);
+                }
+                return Ok(true);
+            },
+            Command::Delete(Some(id)) => {
+                let count = self.breakpoints.len() + self.watchpoints.len();
+                self.breakpoints.retain(|bp| bp.id != id);
+                if let Some(idx) = self.watchpoints.iter().position(|wp| wp.id == id) {
+                    ctx.psx.remove_watch(self.watchpoints.remove(idx).watch);
+                }
+                if count == self.breakpoints.len() + self.watchpoints.len() {
+                    return Err(anyhow!("no breakpoint or watchpoint {id}"));
+                }
+                return Ok(true);
+            },
+            Command::Info => {
+                self.info(ctx, out)?;
+                return Ok(true);
+            },
+            Command::Registers => {
+                show_registers(ctx.psx.cpu(), out)?;
+                return Ok(true);
+            },
+            Command::Cop0 => {
+                show_cop0(ctx.psx.cpu(), out)?;
+                return Ok(true);
+            },
+            Command::Examine { location, len } => {
+                let addr = eval(ctx, &location)?;
+                hexdump(ctx, addr, len, out)?;
+                return Ok(true);
+            },
+            Command::Edit { location, bytes } => {
+                let addr = eval(ctx, &location)?;
+                for (offset, &byte) in bytes.iter().enumerate() {
+                    ctx.psx.poke(addr.wrapping_add(offset as u32), byte)?;
+                }
+                return Ok(true);
+            },
+            Command::Set { register, value } => {
+                let value = eval(ctx, &value)?;
+                write_register(ctx.psx.cpu_mut(), register, value);
+                return Ok(true);
+            },
+            Command::Disassemble { location, count } => {
+                let start = match location {
+                    Some(location) => eval(ctx, &location)? & !3,
+                    None => ctx.psx.cpu().pc().wrapping_sub(4 * (count / 2)) & !3,
+                };
+                for idx in 0..count {
+                    show_instruction(ctx, start.wrapping_add(4 * idx), out)?;
+                }
+                return Ok(true);
+            },
+            Command::Backtrace => {
+                let cpu = ctx.psx.cpu();
+                writeln!(out, "#0  {}", cpu.location(cpu.pc()))?;
+                for (idx, call) in self.calls.iter().rev().enumerate() {
+                    writeln!(out, "#{:<2} {}  calling {}", idx + 1, cpu.location(call.site), cpu.location(call.target))?;
+                }
+                return Ok(true);
+            },
+            Command::Trace(on) => {
+                crate::set_log_level(if on { LevelFilter::TRACE } else { self.log_level });
+                return Ok(true);
+            },
+            Command::Help => {
+                writeln!(out, "{HELP}")?;
+                return Ok(true);
+            },
+            Command::Quit => return Ok(false),
+        };
+        ctx.flush_tty()?;
+        match stop {
+            Stop::Done => {},
+            Stop::Breakpoint(id) => writeln!(out, "Breakpoint {id}")?,
+            Stop::Watchpoint(id, hit) => {
+                writeln!(out, "Watchpoint {id}: {} of {} bytes at 0x{:08x}, value 0x{:x}", hit.kind, hit.len, hit.addr, hit.value)?;
+            },
+            Stop::Exception(code) => {
+                let cpu = ctx.psx.cpu();
+                writeln!(out, "{} exception at {}", exception_name(code), cpu.location(cpu.epc()))?;
+            },
+            Stop::Paused => writeln!(out, "Paused")?,
+            Stop::WindowClosed => return Ok(false),
+        }
+        self.show_pc(ctx, out)?;
+        Ok(true)
+    }
+
+    /// Execute instructions until `done` says so, or something else stops the CPU. `done`
+    /// is given the CPU and the depth of the call stack after each instruction. A
+    /// breakpoint on the first instruction doesn't count.
+    fn resume(&mut self, ctx: &mut Context, mut done: impl FnMut(&Cpu, usize) -> bool) -> Result<Stop> {
+        let mut steps = 0u32;
+        loop {
+            if steps != 0 && let Some(id) = self.breakpoint_hit(ctx) {
+                return Ok(Stop::Breakpoint(id));
+            }
+            let pc = ctx.psx.cpu().pc();
+            let call = call_target(ctx);
+            if !ctx.step()? {
+                return Ok(Stop::WindowClosed);
+            }
+            self.track_calls(pc, call, ctx.psx.cpu().pc());
+            let hits = ctx.psx.take_watch_breaks();
+            if let Some(hit) = hits.first() && let Some(wp) = self.watchpoints.iter().find(|wp| wp.watch == hit.id) {
+                return Ok(Stop::Watchpoint(wp.id, *hit));
+            }
+            let cpu = ctx.psx.cpu();
+            // Interrupts and system calls are business as usual
+            if let Some(code) = cpu.entered_exception(pc) && code != 0 && code != 8 {
+                return Ok(Stop::Exception(code));
+            }
+            if done(cpu, self.calls.len()) {
+                return Ok(Stop::Done);
+            }
+            steps = steps.wrapping_add(1);
+            if steps % POLL_STEPS == 0 {
+                ctx.flush_tty()?;
+                if ctx.paused {
+                    ctx.paused = false;
+                    return Ok(Stop::Paused);
+                }
+            }
+        }
+    }
+
+    /// Push the call made at `pc` to `call`, or pop the calls returning to `new_pc`
+    fn track_calls(&mut self, pc: u32, call: Option<u32>, new_pc: u32) {
+        if let Some(target) = call {
+            if self.calls.len() == MAX_CALLS {
+                self.calls.remove(0);
+            }
+            self.calls.push(Call { site: pc, target });
+        } else if let Some(idx) = self.calls.iter().rposition(|call| call.site.wrapping_add(8) == new_pc) {
+            self.calls.truncate(idx);
+        }
+    }
+
+    /// Breakpoint at the PC whose condition holds, if any. Conditions that can't be
+    /// evaluated count as holding.
+    fn breakpoint_hit(&self, ctx: &Context) -> Option<u32> {
+        let pc = ctx.psx.cpu().pc();
+        self.breakpoints.iter()
+            .filter(|bp| bp.addr == pc)
+            .find(|bp| match &bp.condition {
+                None => true,
+                Some(cond) => match (eval(ctx, &cond.lhs), eval(ctx, &cond.rhs)) {
+                    (Ok(lhs), Ok(rhs)) => cond.op.holds(lhs, rhs),
+                    _ => true,
+                },
+            })
+            .map(|bp| bp.id)
+    }
+
+    fn new_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn info(&self, ctx: &Context, out: &mut impl Write) -> Result<()> {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            writeln!(out, "No breakpoints or watchpoints")?;
+        }
+        let cpu = ctx.psx.cpu();
+        for bp in &self.breakpoints {
+            write!(out, "{:<3} break   {}", bp.id, cpu.location(bp.addr))?;
+            match &bp.condition {
+                Some(cond) => writeln!(out, " if {}", cond.text)?,
+                None => writeln!(out)?,
+            }
+        }
+        for wp in &self.watchpoints {
+            let kind = match wp.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            writeln!(out, "{:<3} {kind:<7} {}, {} bytes", wp.id, cpu.location(wp.addr), wp.len)?;
+        }
+        Ok(())
+    }
+
+    fn show_pc(&self, ctx: &Context, out: &mut impl Write) -> Result<()> {
+        show_instruction(ctx, ctx.psx.cpu().pc(), out)
+    }
+}
+
+/// Value of `value` in the current state
+fn eval(ctx: &Context, value: &Value) -> Result<u32> {
+    match value {
+        Value::Number(n) => Ok(*n),
+        Value::Register(reg) => Ok(read_register(ctx.psx.cpu(), *reg)),
+        Value::Symbol(name) => ctx.psx.cpu().symbols()
+            .and_then(|symbols| symbols.find(name))
+            .ok_or_else(|| anyhow!("no symbol {name:?}")),
+        Value::Word(addr) => {
+            let addr = eval(ctx, addr)?;
+            read_word(ctx, addr).ok_or_else(|| anyhow!("nothing is mapped at 0x{addr:08x}"))
+        },
+    }
+}
+
+/// Target of the call the instruction at the PC makes, if it's a `jal` or `jalr`
+fn call_target(ctx: &Context) -> Option<u32> {
+    let cpu = ctx.psx.cpu();
+    let inst = Instruction(read_word(ctx, cpu.pc())?);
+    match (inst.opcode(), inst.funct()) {
+        (0x03, _) => Some((cpu.pc().wrapping_add(4) & 0xf000_0000) | (inst.addr() << 2)),
+        (0x00, 0x09) => Some(cpu.reg(inst.rs())),
+        _ => None,
+    }
+}
+
+fn read_word(ctx: &Context, addr: u32) -> Option<u32> {
+    let bytes = [0, 1, 2, 3].map(|offset| ctx.psx.peek(addr.wrapping_add(offset)));
+    Some(u32::from_le_bytes([bytes[0]?, bytes[1]?, bytes[2]?, bytes[3]?]))
+}
+
+fn read_register(cpu: &Cpu, reg: Register) -> u32 {
+    match reg {
+        Register::Gpr(idx) => cpu.reg(RegisterIndex(idx)),
+        Register::Pc => cpu.pc(),
+        Register::Hi => cpu.hi(),
+        Register::Lo => cpu.lo(),
+        Register::Sr => cpu.status(),
+        Register::Cause => cpu.cause(),
+        Register::Epc => cpu.epc(),
+        Register::BadVaddr => cpu.bad_vaddr(),
+    }
+}
+
+fn write_register(cpu: &mut Cpu, reg: Register, value: u32) {
+    match reg {
+        Register::Gpr(idx) => cpu.write_reg(RegisterIndex(idx), value),
+        Register::Pc => cpu.set_pc(value),
+        Register::Hi => cpu.set_hi(value),
+        Register::Lo => cpu.set_lo(value),
+        Register::Sr => cpu.set_status(value),
+        Register::Cause => cpu.set_cause(value),
+        Register::Epc => cpu.set_epc(value),
+        Register::BadVaddr => cpu.set_bad_vaddr(value),
+    }
+}
+
+/// `=> 0x80010000 <main>: 3c088001  lui t0, 0x8001`, the arrow marking the PC
+fn show_instruction(ctx: &Context, addr: u32, out: &mut impl Write) -> Result<()> {
+    let cpu = ctx.psx.cpu();
+    let marker = if addr == cpu.pc() { "=>" } else { "  " };
+    match read_word(ctx, addr) {
+        Some(word) => {
+            let disasm = Disasm::new(Instruction(word), addr).with_symbols(cpu.symbols());
+            writeln!(out, "{marker} {}: {word:08x}  {disasm}", cpu.location(addr))?;
+        },
+        None => writeln!(out, "{marker} {}: ????????", cpu.location(addr))?,
+    }
+    Ok(())
+}
+
+fn show_registers(cpu: &Cpu, out: &mut impl Write) -> Result<()> {
+    for row in REGISTER_NAMES.chunks(4).enumerate() {
+        let (base, names) = row;
+        for (offset, name) in names.iter().enumerate() {
+            let value = cpu.reg(RegisterIndex((base * 4 + offset) as u32));
+            write!(out, "{name:>4} {value:08x}  ")?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "  pc {:08x}    hi {:08x}    lo {:08x}", cpu.pc(), cpu.hi(), cpu.lo())?;
+    Ok(())
+}
+
+fn show_cop0(cpu: &Cpu, out: &mut impl Write) -> Result<()> {
+    let cause = cpu.cause();
+    writeln!(out, "sr       {:08x}", cpu.status())?;
+    writeln!(out, "cause    {cause:08x}  ({})", exception_name((cause >> 2) & 0x1f))?;
+    writeln!(out, "epc      {}", cpu.location(cpu.epc()))?;
+    writeln!(out, "badvaddr {:08x}", cpu.bad_vaddr())?;
+    Ok(())
+}
+
+/// 16 bytes a line, `??` where nothing is mapped
+fn hexdump(ctx: &Context, addr: u32, len: u32, out: &mut impl Write) -> Result<()> {
+    for line in (0..len).step_by(16) {
+        let line_addr = addr.wrapping_add(line);
+        let bytes: Vec<Option<u8>> = (line..len.min(line + 16))
+            .map(|offset| ctx.psx.peek(addr.wrapping_add(offset)))
+            .collect();
+        write!(out, "{line_addr:08x}: ")?;
+        for byte in &bytes {
+            match byte {
+                Some(byte) => write!(out, "{byte:02x} ")?,
+                None => write!(out, "?? ")?,
+            }
+        }
+        let text: String = bytes.iter()
+            .map(|byte| match byte {
+                Some(byte @ 0x20..=0x7e) => *byte as char,
+                _ => '.',
+            })
+            .collect();
+        writeln!(out, "{:width$} {text}", "", width = 3 * (16 - bytes.len()))?;
+    }
+    Ok(())
+}
+
+fn exception_name(code: u32) -> &'static str {
+    match code {
+        0 => "Interrupt",
+        4 => "Load address error",
+        5 => "Store address error",
+        6 => "Instruction bus error",
+        7 => "Data bus error",
+        8 => "Syscall",
+        9 => "Break",
+        10 => "Reserved instruction",
+        11 => "Coprocessor unusable",
+        12 => "Overflow",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{symbols::{Symbol, SymbolTable}, Psx, TEST_ORIGIN as ORIGIN};
+
+    /// Run `script` against a program calling `twice` in a loop
+    fn debug(script: &str) -> String {
+        let mut ctx = Context::new_hle().unwrap();
+        ctx.set_tty_output(Box::new(std::io::sink()));
+        *ctx.psx = Psx::boot_test_program("
+                la    s0, counter
+                li    s1, 3
+            loop:
+                jal   twice
+                addiu s1, s1, -1
+                bnez  s1, loop
+                nop
+            spin:
+                b     spin
+                nop
+            twice:
+                lw    t0, 0(s0)
+                nop
+                addiu t0, t0, 2
+                jr    ra
+                sw    t0, 0(s0)
+            counter:
+                .word 0
+        ");
+        ctx.psx.set_symbols(SymbolTable::new(vec![
+            Symbol { addr: ORIGIN, size: 7 * 4, name: "main".into() },
+            Symbol { addr: ORIGIN + 7 * 4, size: 2 * 4, name: "spin".into() },
+            Symbol { addr: ORIGIN + 9 * 4, size: 5 * 4, name: "twice".into() },
+            Symbol { addr: ORIGIN + 14 * 4, size: 4, name: "counter".into() },
+        ]));
+        let mut out = Vec::new();
+        Debugger::new(LevelFilter::ERROR).repl(&mut ctx, script.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn breaks_and_steps() {
+        let out = debug("
+            b twice if s1 == 1
+            c
+            bt
+            x counter 4
+            n
+            watch counter
+            c
+            i
+            d 2
+            u 0x8001001c
+            r
+        ");
+        assert!(out.contains("Breakpoint 1 at 0x80010024 <twice>\n"), "{out}");
+        // Stopped on the second call
+        assert!(out.contains("Breakpoint 1\n=> 0x80010024 <twice>: 8e080000  lw      t0, 0x0(s0)"), "{out}");
+        assert!(out.contains("#1  0x8001000c <main+0xc>  calling 0x80010024 <twice>"), "{out}");
+        assert!(out.contains("80010038: 02 00 00 00"), "{out}");
+        // The store in the delay slot of the return
+        assert!(out.contains("Watchpoint 2: write of 4 bytes at 0x80010038"), "{out}");
+        assert!(out.contains("2   watch   0x80010038 <counter>, 4 bytes"), "{out}");
+        assert!(out.contains("=> 0x8001001c <spin>"), "{out}");
+        assert!(out.contains("  s0 80010038"), "{out}");
+    }
+
+    #[test]
+    fn edits_state() {
+        let out = debug("
+            u spin
+            set a0 0x1234
+            e counter 78563412
+            x counter 4
+            r
+            dis twice 2
+            s 2
+            cop0
+            nope
+        ");
+        assert!(out.contains("80010038: 78 56 34 12"), "{out}");
+        assert!(out.contains("  a0 00001234"), "{out}");
+        assert!(out.contains("   0x80010028 <twice+0x4>: 00000000  nop"), "{out}");
+        assert!(out.contains("cause    "), "{out}");
+        assert!(out.contains("error: unknown command \"nope\""), "{out}");
+    }
+}
@@ -0,0 +1,289 @@
+//! Parsing of the debugger commands
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::cpu::disasm::REGISTER_NAMES;
+
+/// Register that can be named in a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Gpr(u32),
+    Pc,
+    Hi,
+    Lo,
+    Sr,
+    Cause,
+    Epc,
+    BadVaddr,
+}
+
+impl Register {
+    /// Register called `name`, by its ABI name (`sp`), number (`r29`) or COP0 name.
+    /// A `$` in front is allowed.
+    pub fn from_name(name: &str) -> Option<Register> {
+        let name = name.strip_prefix('$').unwrap_or(name);
+        if let Some(idx) = REGISTER_NAMES.iter().position(|&reg| reg == name) {
+            return Some(Register::Gpr(idx as u32));
+        }
+        if let Some(idx) = name.strip_prefix('r').and_then(|idx| idx.parse().ok()) && idx < 32 {
+            return Some(Register::Gpr(idx));
+        }
+        Some(match name {
+            "pc" => Register::Pc,
+            "hi" => Register::Hi,
+            "lo" => Register::Lo,
+            "sr" => Register::Sr,
+            "cause" => Register::Cause,
+            "epc" => Register::Epc,
+            "badvaddr" => Register::BadVaddr,
+            _ => return None,
+        })
+    }
+}
+
+/// Operand of a command, evaluated when the command runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// Decimal, or hex with a `0x` prefix
+    Number(u32),
+    Register(Register),
+    Symbol(String),
+    /// Word at the address, written `*ADDR`
+    Word(Box<Value>),
+}
+
+impl Value {
+    pub fn parse(text: &str) -> Result<Value> {
+        if let Some(addr) = text.strip_prefix('*') {
+            return Ok(Value::Word(Box::new(Value::parse(addr)?)));
+        }
+        if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            return u32::from_str_radix(digits, 16).map(Value::Number).map_err(|e| anyhow!("bad number {text:?}: {e}"));
+        }
+        if text.starts_with(|c: char| c.is_ascii_digit()) {
+            return text.parse().map(Value::Number).map_err(|e| anyhow!("bad number {text:?}: {e}"));
+        }
+        if let Some(reg) = Register::from_name(text) {
+            return Ok(Value::Register(reg));
+        }
+        if text.is_empty() || text.starts_with('$') {
+            bail!("unknown register {text:?}");
+        }
+        Ok(Value::Symbol(text.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// Longest operators first, so that `<=` isn't taken for `<`
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    pub fn holds(self, lhs: u32, rhs: u32) -> bool {
+        match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Ge => lhs >= rhs,
+        }
+    }
+}
+
+/// Breakpoint condition, e.g. `a0 == 3` or `*0x80010000 != 0`. Values compare unsigned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub lhs: Value,
+    pub op: Comparison,
+    pub rhs: Value,
+    /// As typed, for listing the breakpoints
+    pub text: String,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition> {
+        let text = text.trim();
+        let (pos, op, symbol) = Comparison::OPERATORS.iter()
+            .find_map(|&(symbol, op)| text.find(symbol).map(|pos| (pos, op, symbol)))
+            .ok_or_else(|| anyhow!("condition {text:?} has no comparison (==, !=, <, <=, >, >=)"))?;
+        let lhs = Value::parse(text[..pos].trim())?;
+        let rhs = Value::parse(text[pos + symbol.len()..].trim())?;
+        Ok(Condition { lhs, op, rhs, text: text.to_string() })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Execute this many instructions
+    Step(u32),
+    /// Step over calls
+    Next,
+    Continue,
+    /// Run up to an address, or out of the current loop without one
+    Until(Option<Value>),
+    Break { location: Value, condition: Option<Condition> },
+    Watch { kind: WatchKind, location: Value, len: u32 },
+    /// Delete a breakpoint or watchpoint, or all of them
+    Delete(Option<u32>),
+    /// List the breakpoints and watchpoints
+    Info,
+    Registers,
+    Cop0,
+    Examine { location: Value, len: u32 },
+    Edit { location: Value, bytes: Vec<u8> },
+    Set { register: Register, value: Value },
+    Disassemble { location: Option<Value>, count: u32 },
+    Backtrace,
+    Trace(bool),
+    Help,
+    Quit,
+}
+
+pub const HELP: &str = "\
+s, step [N]             execute N instructions (1)
+n, next                 step over calls
+c, continue             run until a breakpoint, watchpoint or exception
+u, until [LOC]          run to LOC, or out of the current loop
+b, break LOC [if COND]  break at LOC when COND holds, e.g. `b main if a0 == 3`
+watch LOC [LEN]         break on writes to LEN bytes (4) at LOC
+rwatch LOC [LEN]        break on reads
+awatch LOC [LEN]        break on reads and writes
+d, delete [ID]          delete a breakpoint or watchpoint, or all of them
+i, info                 list the breakpoints and watchpoints
+r, regs                 show the general purpose registers
+cop0                    show the COP0 registers
+x LOC [LEN]             hexdump LEN bytes (64) at LOC
+e, edit LOC HEX         write bytes at LOC, e.g. `e 0x80010000 0000a0ac`
+set REG VALUE           write a register
+dis [LOC] [N]           disassemble N instructions (10) around LOC (the PC)
+bt, backtrace           show the calls seen since the debugger started
+trace on|off            log every executed instruction
+h, help                 show this
+q, quit                 exit the emulator
+
+LOC and VALUE are numbers (0x for hex), registers (sp, $a0, pc), symbols, or
+*ADDR for the word at ADDR. An empty line repeats the last step or next.";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command> {
+        let (line, condition) = match line.split_once(" if ") {
+            Some((line, condition)) => (line, Some(Condition::parse(condition)?)),
+            None => (line, None),
+        };
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            bail!("empty command");
+        };
+        let args: Vec<&str> = words.collect();
+        let arg = |idx: usize| args.get(idx).copied();
+        let value = |idx: usize| arg(idx).map(Value::parse).transpose();
+        let required = |idx: usize, what: &str| value(idx)?.ok_or_else(|| anyhow!("{name}: missing {what}"));
+        let number = |idx: usize, default: u32| match value(idx)? {
+            None => Ok(default),
+            Some(Value::Number(n)) => Ok(n),
+            Some(_) => Err(anyhow!("{name}: expected a number")),
+        };
+        if condition.is_some() && !matches!(name, "b" | "break") {
+            bail!("{name}: only breakpoints take a condition");
+        }
+        let command = match name {
+            "s" | "step" | "si" | "stepi" => Command::Step(number(0, 1)?.max(1)),
+            "n" | "next" | "ni" | "nexti" => Command::Next,
+            "c" | "continue" => Command::Continue,
+            "u" | "until" => Command::Until(value(0)?),
+            "b" | "break" => Command::Break { location: required(0, "location")?, condition },
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match name {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                Command::Watch { kind, location: required(0, "location")?, len: number(1, 4)?.max(1) }
+            },
+            "d" | "delete" => Command::Delete(arg(0).map(|id| id.parse()).transpose().map_err(|e| anyhow!("{name}: bad id: {e}"))?),
+            "i" | "info" => Command::Info,
+            "r" | "regs" => Command::Registers,
+            "cop0" => Command::Cop0,
+            "x" => Command::Examine { location: required(0, "location")?, len: number(1, 64)? },
+            "e" | "edit" => {
+                let hex = arg(1).ok_or_else(|| anyhow!("{name}: missing bytes"))?;
+                let bytes = (0..hex.len()).step_by(2)
+                    .map(|idx| hex.get(idx..idx + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| anyhow!("{name}: {hex:?} isn't hex bytes"))?;
+                Command::Edit { location: required(0, "location")?, bytes }
+            },
+            "set" => {
+                let register = arg(0).and_then(Register::from_name).ok_or_else(|| anyhow!("set: expected a register"))?;
+                Command::Set { register, value: required(1, "value")? }
+            },
+            "dis" | "disas" => Command::Disassemble { location: value(0)?, count: number(1, 10)? },
+            "bt" | "backtrace" => Command::Backtrace,
+            "trace" => match arg(0) {
+                Some("on") => Command::Trace(true),
+                Some("off") => Command::Trace(false),
+                _ => bail!("trace: expected on or off"),
+            },
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => bail!("unknown command {name:?}, try help"),
+        };
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("s 3").unwrap(), Command::Step(3));
+        assert_eq!(Command::parse("b main if $a0 >= 0x10").unwrap(), Command::Break {
+            location: Value::Symbol("main".into()),
+            condition: Some(Condition {
+                lhs: Value::Register(Register::Gpr(4)),
+                op: Comparison::Ge,
+                rhs: Value::Number(0x10),
+                text: "$a0 >= 0x10".into(),
+            }),
+        });
+        assert_eq!(Command::parse("rwatch *sp 2").unwrap(), Command::Watch {
+            kind: WatchKind::Read,
+            location: Value::Word(Box::new(Value::Register(Register::Gpr(29)))),
+            len: 2,
+        });
+        assert_eq!(Command::parse("e 0x80010000 00ff").unwrap(), Command::Edit {
+            location: Value::Number(0x8001_0000),
+            bytes: vec![0, 0xff],
+        });
+        assert_eq!(Command::parse("set r31 0").unwrap(), Command::Set { register: Register::Gpr(31), value: Value::Number(0) });
+        assert!(Command::parse("x").is_err());
+        assert!(Command::parse("s 1 if pc == 0").is_err());
+        assert!(Command::parse("set $nope 1").is_err());
+        assert!(Command::parse("e 0 abc").is_err());
+    }
+}
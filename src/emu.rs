@@ -6,21 +6,127 @@ pub mod map;
 pub mod ram;
 pub mod access;
 pub mod bus;
+pub mod exe;
+pub mod elf;
+pub mod symbols;
+pub mod disc;
+pub mod exp1;
+pub mod exp2;
+pub mod gpu;
+pub mod spu;
+pub mod pad;
+pub mod joy;
+pub mod mem_ctl;
+pub mod state;
+pub mod rewind;
+pub mod hle;
+pub mod ktrace;
+pub mod watch;
+pub mod trace;
 
 use crate::emu::{
-    bios::Bios, 
-    cpu::Cpu,
+    bios::{patch::PatchKind, Bios, Region, VideoStandard},
+    cpu::{Cpu, Engine, instruction::RegisterIndex},
     ram::Ram,
     bus::Bus,
+    exp1::Exp1,
+    exp2::Exp2,
+    gpu::{Frame, Gpu},
+    spu::Spu,
+    joy::Joy,
+    pad::PadState,
+    mem_ctl::MemCtl,
+    state::{Header, Snapshot, StateReader, StateWriter},
     access::{Access, AccessWidth},
+    exe::{ExeBoot, Image},
+    symbols::SymbolTable,
+    disc::Disc,
+    hle::Hle,
+    watch::{Triggers, WatchAction, WatchHit, WatchId},
+    ktrace::KernelTracer,
+    trace::{TraceFilter, Tracer},
 };
 
+#[cfg(feature = "recompiler")]
+use crate::emu::cpu::recompiler::LockStep;
+
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+/// Where [`Psx::boot_test_program`] loads its program
+#[cfg(test)]
+pub const TEST_ORIGIN: u32 = 0x8001_0000;
+
+/// Symbols, engine, lockstep checking and the TTY output are left out, see [`state`]
+impl Snapshot for Psx {
+    fn save(&self, w: &mut StateWriter) {
+        w.section(b"PSX ");
+        w.u64(self.instructions_retired);
+        w.bool(self.pending_exe.is_some());
+        if let Some(image) = &self.pending_exe {
+            image.save(w);
+        }
+        w.bool(self.hle.is_some());
+        self.cpu.save(w);
+        self.bus.save(w);
+        if let Some(hle) = &self.hle {
+            hle.save(w);
+        }
+        self.ktrace.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        r.section(b"PSX ")?;
+        self.instructions_retired = r.u64()?;
+        self.pending_exe = match r.bool()? {
+            true => {
+                let mut image = Image { entry: 0, gp: None, sp: None, segments: vec![], bss: vec![] };
+                Snapshot::load(&mut image, r)?;
+                Some(image)
+            },
+            false => None,
+        };
+        if r.bool()? != self.hle.is_some() {
+            bail!("save state is for the other BIOS kind (high-level or dump)");
+        }
+        Snapshot::load(&mut self.cpu, r)?;
+        Snapshot::load(&mut self.bus, r)?;
+        if let Some(hle) = &mut self.hle {
+            Snapshot::load(hle.as_mut(), r)?;
+        }
+        Snapshot::load(&mut self.ktrace, r)
+    }
+}
+
 /// Complete emulator core state. The contents of this struct comprise an accurate state
 /// of a virtual PSX system.
 pub struct Psx {
     cpu: Cpu,
     bus: Bus,
     pub instructions_retired: u64,
+
+    /// Executable waiting for the BIOS to reach the shell entry point
+    pending_exe: Option<Image>,
+
+    /// High-level BIOS, when running without a BIOS dump
+    hle: Option<Box<Hle>>,
+
+    /// Decodes the BIOS calls and captures their TTY output
+    ktrace: KernelTracer,
+
+    /// Writes a line per instruction while tracing
+    tracer: Option<Box<Tracer>>,
+
+    /// Controllers in ports 1 and 2. Host input, so not part of the save states.
+    pads: [Option<PadState>; 2],
+
+    /// Region set by the user, over the BIOS's or disc's
+    region_override: Option<Region>,
+
+    /// Shadow interpreter checking the recompiler after every block
+    #[cfg(feature = "recompiler")]
+    lockstep: Option<Box<LockStep>>,
 }
 
 impl Psx {
@@ -34,10 +140,383 @@ impl Psx {
             bus,
             cpu: Cpu::new(),
             instructions_retired: 0,
+            pending_exe: None,
+            hle: None,
+            ktrace: KernelTracer::new(),
+            tracer: None,
+            pads: [None; 2],
+            region_override: None,
+            #[cfg(feature = "recompiler")]
+            lockstep: None,
+        }
+    }
+
+    /// Run on the high-level BIOS instead of a BIOS dump, booting `disc` if there is one
+    pub fn new_hle(disc: Option<Disc>) -> Self {
+        let mut psx = Psx::new_from_bios(&hle::stub_rom());
+        psx.hle = Some(Box::new(Hle::new(disc)));
+        psx
+    }
+
+    /// Run `source`, assembled at [`TEST_ORIGIN`], on the high-level BIOS
+    #[cfg(test)]
+    pub fn boot_test_program(source: &str) -> Self {
+        let program = cpu::asm::assemble(source, TEST_ORIGIN).unwrap();
+        let text = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let image = Image { entry: TEST_ORIGIN, gp: None, sp: None, segments: vec![(TEST_ORIGIN, text)], bss: vec![] };
+        let mut psx = Psx::new_hle(None);
+        psx.sideload(image, ExeBoot::Intercept);
+        psx
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) -> Result<()> {
+        self.cpu.set_engine(engine)
+    }
+
+    /// Run a shadow interpreter in lock-step with the core, panicking on the first
+    /// divergence. Meant for validating the recompiler.
+    #[cfg(feature = "recompiler")]
+    pub fn enable_lockstep(&mut self) {
+        self.lockstep = Some(Box::new(LockStep::new(&self.cpu, &self.bus)));
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    /// For debuggers poking at registers
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// For debuggers poking at memory
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    /// Watch `len` bytes at `addr` for the accesses in `triggers`, see [`watch`]
+    pub fn add_watch(&mut self, addr: u32, len: u32, triggers: Triggers, action: WatchAction) -> WatchId {
+        self.bus.add_watch(addr, len, triggers, action)
+    }
+
+    /// Returns false if there was no such watch
+    pub fn remove_watch(&mut self, id: WatchId) -> bool {
+        self.bus.remove_watch(id)
+    }
+
+    /// Hits of the watches with a `Break` action since the last call. With the
+    /// recompiler, a step may have run past the access up to the end of its block.
+    pub fn take_watch_breaks(&mut self) -> Vec<WatchHit> {
+        self.bus.take_watch_breaks()
+    }
+
+    /// Write a line per retired instruction to `out`, see [`trace`]. Switches to the
+    /// interpreter.
+    pub fn start_trace(&mut self, out: Box<dyn Write>, filter: TraceFilter) -> Result<()> {
+        self.stop_trace()?;
+        if self.cpu.engine() != Engine::Interpreter {
+            tracing::info!("switching to the interpreter while tracing");
+            self.set_engine(Engine::Interpreter)?;
+        }
+        self.tracer = Some(Box::new(Tracer::new(&mut self.bus, out, filter)));
+        Ok(())
+    }
+
+    /// Stop tracing, if tracing, and flush the trace
+    pub fn stop_trace(&mut self) -> Result<()> {
+        if let Some(tracer) = self.tracer.take() {
+            tracer.finish(&mut self.bus)?;
         }
+        Ok(())
+    }
+
+    /// Byte at `addr` as the CPU sees it, `None` if nothing is mapped there
+    pub fn peek(&self, addr: u32) -> Option<u8> {
+        map::find_region(map::mask_region(addr))?;
+        Some(self.bus.peek(addr))
     }
+
+    /// Write a byte like the CPU would, refusing unmapped addresses and the BIOS
+    pub fn poke(&mut self, addr: u32, val: u8) -> Result<()> {
+        match map::find_region(map::mask_region(addr)) {
+            None => bail!("nothing is mapped at 0x{addr:08x}"),
+            Some(map::Region::Bios(_)) => bail!("0x{addr:08x} is in the BIOS ROM"),
+            Some(_) => self.bus.poke(addr, val),
+        }
+        Ok(())
+    }
+
+    pub fn hle(&self) -> Option<&Hle> {
+        self.hle.as_deref()
+    }
+
+    /// Number of video frames since power on, which goes up at every VBlank
+    pub fn frames(&self) -> u64 {
+        self.bus.gpu().frames()
+    }
+
+    /// The picture currently on screen
+    pub fn frame(&self) -> Frame {
+        self.bus.gpu().frame()
+    }
+
+    /// Plug in (or update) the controllers of ports 1 and 2, `None` for an empty port.
+    /// Programs polling the controller port see the change right away, and those using
+    /// the high-level BIOS's pad buffers at the next VBlank.
+    pub fn set_pads(&mut self, pads: [Option<PadState>; 2]) {
+        self.pads = pads;
+        self.bus.joy_mut().set_pads(pads);
+    }
+
+    pub fn pads(&self) -> &[Option<PadState>; 2] {
+        &self.pads
+    }
+
+    /// Take the 44.1 kHz stereo samples the SPU mixed since the last call
+    pub fn take_audio(&mut self) -> Vec<[i16; 2]> {
+        self.bus.spu_mut().take_output()
+    }
+
+    /// Take the characters sent to the expansion port DUART since the last call, which is
+    /// where the kernel's TTY output goes (see [`PatchKind::Tty`]), or written to the TTY
+    /// of the high-level BIOS
+    pub fn take_tty(&mut self) -> Vec<u8> {
+        let mut output = self.bus.exp2_mut().take_tty();
+        if let Some(hle) = &mut self.hle {
+            output.extend(hle.take_tty());
+        }
+        output
+    }
+
+    /// Value last written to the POST display on the expansion port
+    pub fn post(&self) -> u8 {
+        self.bus.exp2().post()
+    }
+
+    /// Take the TTY output (putchar, puts and printf) of the BIOS calls since the last call
+    pub fn take_kernel_tty(&mut self) -> Vec<u8> {
+        self.ktrace.take_tty()
+    }
+
+    /// Region of the console: the one set with [`Psx::set_region`], else the BIOS's, or
+    /// the inserted disc's with the high-level BIOS
+    pub fn region(&self) -> Option<Region> {
+        if let Some(region) = self.region_override {
+            return Some(region);
+        }
+        match &self.hle {
+            Some(hle) => hle.disc().and_then(Disc::region),
+            None => self.bus.bios().info().map(|info| info.region),
+        }
+    }
+
+    /// Treat the console as one from `region`. This only changes what [`Psx::region`] and
+    /// [`Psx::video_standard`] report: the GPU still runs in the video mode the software
+    /// sets, and a real BIOS keeps its own region checks.
+    pub fn set_region(&mut self, region: Region) {
+        self.region_override = Some(region);
+    }
+
+    /// Video standard of the console, NTSC unless the region says otherwise
+    pub fn video_standard(&self) -> VideoStandard {
+        self.region().map_or(VideoStandard::Ntsc, Region::video_standard)
+    }
+
+    /// Patch the BIOS, which must happen before it starts running since its code may
+    /// get cached
+    pub fn patch_bios(&mut self, kind: PatchKind) -> Result<()> {
+        if self.instructions_retired != 0 {
+            bail!("the BIOS can only be patched before starting emulation");
+        }
+        self.bus.bios_mut().apply_patch(kind)
+    }
+
+    /// Insert `disc`. A real BIOS refuses discs from other regions, which is reported
+    /// but not enforced.
+    pub fn insert_disc(&mut self, disc: Disc) {
+        let console = self.region_override.or(self.bus.bios().info().map(|info| info.region));
+        if let (Some(console), Some(region)) = (console, disc.region()) && console != region {
+            tracing::warn!("{region} disc in a {console} console, the BIOS would refuse to boot it");
+        }
+        match &mut self.hle {
+            Some(hle) => hle.insert_disc(disc),
+            None => tracing::warn!("no CD-ROM drive is emulated yet, discs only boot with the high-level BIOS"),
+        }
+    }
+
+    /// Plug a cartridge with the ROM image `rom` into the expansion port. The BIOS runs
+    /// its firmware during boot, so this has to happen before starting emulation.
+    pub fn insert_expansion_rom(&mut self, rom: Vec<u8>) -> Result<()> {
+        if self.hle.is_some() {
+            tracing::warn!("the high-level BIOS doesn't run expansion ROMs");
+        }
+        self.bus.exp1_mut().insert(rom)
+    }
+
+    /// ID of the inserted disc, see [`Disc::id`]
+    fn disc_id(&self) -> String {
+        self.hle.as_ref()
+            .and_then(|hle| hle.disc())
+            .and_then(Disc::id)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// Snapshot the whole system, in the format described in [`state`]
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        Header {
+            version: state::VERSION,
+            bios_md5: self.bus.bios().md5(),
+            disc_id: self.disc_id(),
+            instructions_retired: self.instructions_retired,
+        }.save(&mut w);
+        self.save(&mut w);
+        w.finish()
+    }
+
+    /// Restore a state from [`Psx::save_state`]. It must have been saved with the same BIOS
+    /// (and patches) and disc. Nothing is changed when the state is rejected.
+    pub fn load_state(&mut self, buf: &[u8]) -> Result<()> {
+        let (header, mut r) = Header::parse(buf)?;
+        if header.bios_md5 != self.bus.bios().md5() {
+            bail!("save state is for another BIOS, or another set of BIOS patches");
+        }
+        if header.disc_id != self.disc_id() {
+            bail!("save state is for disc {:?}, not {:?}", header.disc_id, self.disc_id());
+        }
+
+        // Validate the whole state on scratch devices first
+        let mut scratch = Psx {
+            cpu: Cpu::new(),
+            bus: self.bus.clone(),
+            instructions_retired: 0,
+            pending_exe: None,
+            hle: self.hle.as_ref().map(|_| Box::new(Hle::new(None))),
+            ktrace: KernelTracer::new(),
+            tracer: None,
+            pads: [None; 2],
+            region_override: None,
+            #[cfg(feature = "recompiler")]
+            lockstep: None,
+        };
+        let mut scratch_reader = r.clone();
+        Snapshot::load(&mut scratch, &mut scratch_reader)?;
+        scratch_reader.finish()?;
+
+        Snapshot::load(self, &mut r)?;
+        #[cfg(feature = "recompiler")]
+        if self.lockstep.is_some() {
+            self.enable_lockstep();
+        }
+        Ok(())
+    }
+
+    /// Use `symbols` to show addresses in traces and error messages
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.cpu.set_symbols(symbols);
+    }
+
+    /// Sideload an executable, either once the BIOS reaches the shell or right away
+    pub fn sideload(&mut self, image: Image, boot: ExeBoot) {
+        match boot {
+            ExeBoot::Intercept => self.pending_exe = Some(image),
+            ExeBoot::Fast => {
+                // Minimal init: a clean register file, the BIOS isn't run at all
+                for idx in 1..32 {
+                    self.cpu.write_reg(RegisterIndex(idx), 0);
+                }
+                self.inject(&image);
+            },
+        }
+    }
+
+    /// Inject the sideloaded executable once the BIOS reached the shell
+    fn inject_pending(&mut self) {
+        if self.pending_exe.is_some() && self.cpu.pc() == exe::SHELL_ENTRY {
+            let image = self.pending_exe.take().unwrap();
+            self.inject(&image);
+        }
+    }
+
+    /// Copy `image` into RAM, clear its BSS and point the CPU at its entry point
+    fn inject(&mut self, image: &Image) {
+        tracing::info!("loading executable: entry {}", self.cpu.location(image.entry));
+
+        image.load(&mut self.bus);
+        image.start(&mut self.cpu);
+
+        #[cfg(feature = "recompiler")]
+        if self.lockstep.is_some() {
+            self.enable_lockstep();
+        }
+    }
+
+    /// Execute the next instruction (or compiled block, when running the recompiler)
     pub fn step(&mut self) {
-        self.instructions_retired += 1;
-        self.cpu.handle_next_instruction(&mut self.bus);
+        self.inject_pending();
+
+        self.ktrace.observe(&self.cpu, &self.bus);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.begin(&self.cpu, &self.bus, self.instructions_retired);
+        }
+
+        self.execute();
+
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(err) = tracer.end(&self.cpu) {
+                tracing::error!("failed to write the trace, stopped tracing: {err}");
+                let _ = self.stop_trace();
+            } else if tracer.is_done(self.instructions_retired) && let Err(err) = self.stop_trace() {
+                tracing::error!("failed to write the trace: {err}");
+            }
+        }
+        // Right away, so that the entry point is seen between steps
+        self.inject_pending();
+    }
+
+    /// Run the next instruction (or block) on the CPU or the high-level BIOS
+    fn execute(&mut self) {
+        if let Some(hle) = &mut self.hle && hle::is_trap(self.cpu.pc()) {
+            hle.dispatch(&mut self.cpu, &mut self.bus);
+            self.instructions_retired += 1;
+            self.tick(1);
+            #[cfg(feature = "recompiler")]
+            if self.lockstep.is_some() {
+                self.enable_lockstep();
+            }
+            return;
+        }
+
+        let retired = self.cpu.run(&mut self.bus);
+        self.instructions_retired += retired as u64;
+
+        #[cfg(feature = "recompiler")]
+        if let Some(lockstep) = &mut self.lockstep
+            && let Err(divergence) = lockstep.check(&self.cpu, &self.bus, retired)
+        {
+            panic!("recompiler diverged from interpreter after {} instructions, before {}: {divergence}",
+                self.instructions_retired, self.cpu.location(self.cpu.pc()));
+        }
+        self.tick(retired as u64);
+    }
+
+    /// Advance the devices by `instructions`
+    fn tick(&mut self, instructions: u64) {
+        if self.bus.gpu_mut().tick(instructions)
+            && let Some(hle) = &self.hle
+        {
+            hle.update_pads(&mut self.bus, &self.pads);
+            // The shadow interpreter has no kernel writing its pad buffers
+            #[cfg(feature = "recompiler")]
+            if self.lockstep.is_some() {
+                self.enable_lockstep();
+            }
+        }
+        self.bus.spu_mut().tick(instructions);
     }
 }
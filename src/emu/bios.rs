@@ -1,13 +1,141 @@
 //! Module for handling bios access and database of bios versions
 
+pub mod patch;
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+
 use crate::emu::access::{AccessWidth, Access};
+use patch::{Edit, Patch, PatchKind};
 
 pub const BIOS_SIZE : usize = 512 * 1024;
 pub const BIOS_START: u32   = 0xbfc0_0000;
 pub const BIOS_END  : u32   = BIOS_START + BIOS_SIZE as u32;
 
+/// Console region, which decides the video standard and which discs boot
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Region {
+    /// NTSC-J
+    Japan,
+    /// NTSC-U/C
+    NorthAmerica,
+    /// PAL
+    Europe,
+}
+
+impl Region {
+    pub fn video_standard(self) -> VideoStandard {
+        match self {
+            Region::Japan | Region::NorthAmerica => VideoStandard::Ntsc,
+            Region::Europe => VideoStandard::Pal,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Region::Japan => "NTSC-J",
+            Region::NorthAmerica => "NTSC-U",
+            Region::Europe => "PAL",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VideoStandard {
+    Ntsc,
+    Pal,
+}
+
+/// A known BIOS dump
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BiosInfo {
+    /// Console models shipping this BIOS
+    pub models: &'static str,
+    pub version: &'static str,
+    /// Build date, as found in the version string
+    pub date: &'static str,
+    pub region: Region,
+    /// PSone console, rather than the original PlayStation
+    pub slim: bool,
+    /// MD5 of the whole 512 KiB image, in hex
+    pub md5: &'static str,
+}
+
+const fn bios(models: &'static str, version: &'static str, date: &'static str, region: Region, slim: bool, md5: &'static str) -> BiosInfo {
+    BiosInfo { models, version, date, region, slim, md5 }
+}
+
+use Region::{Europe, Japan, NorthAmerica};
+
+/// Known BIOS versions. The PlayStation kernels found in PS2 ROMs aren't listed: there is
+/// no set of verified hashes to take them from, and they show up as unknown kernels.
+pub const DATABASE: [BiosInfo; 24] = [
+    bios("SCPH-1000, DTL-H1000",                      "1.0", "1994-09-22", Japan,        false, "239665b1a3dade1b5a52c06338011044"),
+    bios("SCPH-3000, DTL-H1000H",                     "1.1", "1995-01-22", Japan,        false, "849515939161e62f6b866f6853006780"),
+    bios("SCPH-1001, DTL-H1001",                      "2.0", "1995-05-07", NorthAmerica, false, "dc2b9bf8da62ec93e868cfd29f0d067d"),
+    bios("SCPH-1002, DTL-H1002",                      "2.0", "1995-05-10", Europe,       false, "54847e693405ffeb0359c6287434cbef"),
+    bios("SCPH-3500",                                 "2.1", "1995-07-17", Japan,        false, "cba733ceeff5aef5c32254f1d617fa62"),
+    bios("SCPH-1001, DTL-H1101",                      "2.1", "1995-07-17", NorthAmerica, false, "da27e8b6dab242d8f91a9b25d80c63b8"),
+    bios("SCPH-1002, DTL-H1102",                      "2.1", "1995-07-17", Europe,       false, "417b34706319da7cf001e76e40136c23"),
+    bios("SCPH-5000, DTL-H1200, DTL-H3000",           "2.2", "1995-12-04", Japan,        false, "57a06303dfa9cf9351222dfcbb4a29d9"),
+    bios("SCPH-1001, SCPH-5003, DTL-H1201, DTL-H3001", "2.2", "1995-12-04", NorthAmerica, false, "924e392ed05558ffdb115408c263dccf"),
+    bios("SCPH-1002, DTL-H1202, DTL-H3002",           "2.2", "1995-12-04", Europe,       false, "e2110b8a2b97a8e0b857a45d32f7e187"),
+    bios("DTL-H1100",                                 "2.2", "1996-03-06", Japan,        false, "ca5cfc321f916756e3f0effbfaeba13b"),
+    bios("SCPH-5500",                                 "3.0", "1996-09-09", Japan,        false, "8dd7d5296a650fac7319bce665a6a53c"),
+    bios("SCPH-5501, SCPH-5503, SCPH-7003",           "3.0", "1996-11-18", NorthAmerica, false, "490f666e1afb15b7362b406ed1cea246"),
+    bios("SCPH-5502, SCPH-5552",                      "3.0", "1997-01-06", Europe,       false, "32736f17079d0b2b7024407c39bd3050"),
+    bios("SCPH-7000, SCPH-7500, SCPH-9000",           "4.0", "1997-08-18", Japan,        false, "8e4c14f567745eff2f0408c8129f72a6"),
+    bios("SCPH-7000W",                                "4.1", "1997-11-14", NorthAmerica, false, "b84be139db3ee6cbd075630aa20a6553"),
+    bios("SCPH-7001, SCPH-7501, SCPH-7503, SCPH-9001", "4.1", "1997-12-16", NorthAmerica, false, "1e68c231d0896b7eadcad1d7d8e76129"),
+    bios("SCPH-7002, SCPH-7502, SCPH-9002",           "4.1", "1997-12-16", Europe,       false, "b9d9a0286c33dc6b7237bb13cd46fdee"),
+    bios("SCPH-100",                                  "4.3", "2000-03-11", Japan,        true,  "8abc1b549a4a80954addc48ef02c4521"),
+    bios("SCPH-101",                                  "4.4", "2000-03-24", NorthAmerica, true,  "9a09ab7e49b422c007e6d54d7c49b965"),
+    bios("SCPH-102",                                  "4.4", "2000-03-24", Europe,       true,  "b10f5e0e3d9eb60e5159690680b1e774"),
+    bios("SCPH-101",                                  "4.5", "2000-05-25", NorthAmerica, true,  "6e3735ff4c7dc899ee98981385f6f3d0"),
+    bios("SCPH-102",                                  "4.5", "2000-05-25", Europe,       true,  "de93caec13d1a141a40a79f5c86168d6"),
+    bios("SCPH-1000R",                                "4.5", "2000-05-25", Japan,        true,  "c53ca5908936d412331790f4426c6c33"),
+];
+
+/// Offset of the kernel build date, as BCD `0xYYYYMMDD`
+const KERNEL_DATE: usize = 0x100;
+/// Offset of the maker string found in every retail kernel
+const KERNEL_MAKER: usize = 0x108;
+const MAKER: &[u8] = b"Sony Computer Entertainment Inc.";
+
+/// Result of looking up a BIOS image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Identification {
+    Known(&'static BiosInfo),
+    /// Looks like a PlayStation kernel (built on the given BCD date) but isn't in the
+    /// database, e.g. a modified or unusual dump
+    Unknown { date: u32 },
+    /// Not a PlayStation BIOS, or a damaged dump
+    Corrupt,
+}
+
+pub fn identify(buf: &[u8; BIOS_SIZE]) -> Identification {
+    let md5 = format!("{:x}", md5::compute(buf));
+    if let Some(info) = DATABASE.iter().find(|info| info.md5 == md5) {
+        return Identification::Known(info);
+    }
+    match &buf[KERNEL_MAKER..KERNEL_MAKER + MAKER.len()] == MAKER {
+        true => Identification::Unknown {
+            date: u32::from_le_bytes(buf[KERNEL_DATE..KERNEL_DATE + 4].try_into().unwrap()),
+        },
+        false => Identification::Corrupt,
+    }
+}
+
+#[derive(Clone)]
 pub struct Bios {
-    mem: Vec<u32>
+    mem: Vec<u32>,
+    /// Of the image as loaded, before any patch
+    identification: Identification,
+    /// Patches applied to `mem`, which keep the words they replaced
+    applied: Vec<&'static Patch>,
 }
 
 impl Bios {
@@ -16,13 +144,78 @@ impl Bios {
             .map(|chunk| u32::from_ne_bytes(*chunk))
             .collect::<Vec<u32>>();
 
-        Bios { mem }
+        Bios { mem, identification: identify(buf), applied: Vec::new() }
+    }
+
+    pub fn identification(&self) -> Identification {
+        self.identification
+    }
+
+    /// Database entry of this BIOS, if it is a known dump
+    pub fn info(&self) -> Option<&'static BiosInfo> {
+        match self.identification {
+            Identification::Known(info) => Some(info),
+            _ => None,
+        }
     }
 
     pub fn get_rom(&self) -> &[u32] {
         &self.mem
     }
 
+    /// MD5 of the ROM as it is now, including the applied patches
+    pub fn md5(&self) -> [u8; 16] {
+        let bytes: Vec<u8> = self.mem.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        md5::compute(bytes).0
+    }
+
+    /// Apply the patch of `kind` matching this BIOS. Fails, leaving the ROM untouched,
+    /// if there is none for this BIOS version.
+    pub fn apply_patch(&mut self, kind: PatchKind) -> Result<()> {
+        let Some(patch) = patch::patches(kind).find(|patch| {
+            !self.applied.contains(patch) && patch.edits.iter().all(|edit| self.words(edit) == edit.original)
+        }) else {
+            bail!("no {kind:?} patch for this BIOS version");
+        };
+        for edit in patch.edits {
+            tracing::debug!("patching BIOS @ 0x{:05x} ({kind:?})", edit.offset);
+            self.write_words(edit.offset, edit.replacement);
+        }
+        self.applied.push(patch);
+        Ok(())
+    }
+
+    /// Restore the original words of every applied patch
+    pub fn revert_patches(&mut self) {
+        while let Some(patch) = self.applied.pop() {
+            for edit in patch.edits {
+                self.write_words(edit.offset, edit.original);
+            }
+        }
+    }
+
+    pub fn applied_patches(&self) -> &[&'static Patch] {
+        &self.applied
+    }
+
+    /// Check that the applied patches are still in place
+    pub fn verify_patches(&self) -> bool {
+        self.applied.iter()
+            .flat_map(|patch| patch.edits)
+            .all(|edit| self.words(edit) == edit.replacement)
+    }
+
+    /// Words currently at the location of `edit`
+    fn words(&self, edit: &Edit) -> &[u32] {
+        let start = edit.offset as usize / 4;
+        self.mem.get(start..start + edit.original.len()).unwrap_or(&[])
+    }
+
+    fn write_words(&mut self, offset: u32, words: &[u32]) {
+        let start = offset as usize / 4;
+        self.mem[start..start + words.len()].copy_from_slice(words);
+    }
+
     pub fn load<T: Access>(&self, offset: u32) -> T {
         tracing::trace!("bios.load(0x{offset:08x}) ({:?})", T::width());
 
@@ -41,4 +234,45 @@ impl Bios {
         };
         Access::from_u32(sized_word)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifies_bios() {
+        let buf = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let buf: &[u8; BIOS_SIZE] = buf.as_slice().try_into().unwrap();
+        let Identification::Known(info) = identify(buf) else { panic!("scph1001.bin not identified") };
+        assert_eq!((info.version, info.region), ("2.2", Region::NorthAmerica));
+        assert_eq!(info.region.video_standard(), VideoStandard::Ntsc);
+        let mut bios = Bios::new(buf);
+        bios.apply_patch(PatchKind::Tty).unwrap();
+        assert_eq!(bios.info(), Some(info));
+
+        let mut patched = *buf;
+        patched[0x1000] ^= 1;
+        assert_eq!(identify(&patched), Identification::Unknown { date: 0x1995_1204 });
+        assert_eq!(identify(&[0; BIOS_SIZE]), Identification::Corrupt);
+    }
+
+    #[test]
+    fn patches_and_reverts() {
+        let buf = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let mut bios = Bios::new(buf.as_slice().try_into().unwrap());
+        let original = bios.get_rom().to_vec();
+
+        bios.apply_patch(PatchKind::Tty).unwrap();
+        bios.apply_patch(PatchKind::SkipIntro).unwrap();
+        assert_eq!(bios.applied_patches().len(), 2);
+        assert_eq!(bios.load::<u32>(0x6f0c), 0x2401_0001);
+        assert_eq!(bios.load::<u32>(0x1_800c), 0x03e0_0008);
+        assert!(bios.verify_patches());
+        assert!(bios.apply_patch(PatchKind::Tty).is_err());
+
+        bios.revert_patches();
+        assert_eq!(bios.get_rom(), original);
+        assert!(Bios::new(&[0; BIOS_SIZE]).apply_patch(PatchKind::Tty).is_err());
+    }
+}
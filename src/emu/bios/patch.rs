@@ -0,0 +1,70 @@
+//! In-memory patches for retail BIOS images
+//!
+//! Every patch carries the words it replaces. A patch is only applied when the image
+//! holds exactly those words, which is how it is matched to the BIOS versions sharing
+//! the patched code, and what allows reverting it.
+
+/// Patches available for known BIOS versions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PatchKind {
+    /// Enable the kernel's TTY output (printf/puts to the expansion port DUART)
+    Tty,
+    /// Replace the shell with a stub turning the display on, which skips the boot
+    /// animation and logo checks and goes straight to the disc or sideloaded EXE
+    SkipIntro,
+}
+
+/// A patch for one BIOS layout, only applied when every edit matches
+#[derive(Debug, PartialEq, Eq)]
+pub struct Patch {
+    pub kind: PatchKind,
+    pub edits: &'static [Edit],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Edit {
+    /// Offset in the ROM
+    pub offset: u32,
+    pub original: &'static [u32],
+    pub replacement: &'static [u32],
+}
+
+/// Known patches. A kind may have several entries for BIOS versions laid out differently.
+pub const PATCHES: [Patch; 2] = [
+    // The kernel clears its TTY flag (0xa000b9b0, also [gp - 0x5640]) during boot, store 1
+    Patch {
+        kind: PatchKind::Tty,
+        edits: &[
+            Edit {
+                offset: 0x6f0c,
+                original: &[0x3c01_a001], // lui at, 0xa001
+                replacement: &[0x2401_0001], // li at, 1
+            },
+            Edit {
+                offset: 0x6f14,
+                original: &[0xac20_b9b0], // sw zero, -0x4650(at)
+                replacement: &[0xaf81_a9c0], // sw at, -0x5640(gp)
+            },
+        ],
+    },
+    // Shell entry, copied to 0x80030000
+    Patch {
+        kind: PatchKind::SkipIntro,
+        edits: &[Edit {
+            offset: 0x1_8000,
+            original: &[0x27bd_ffe8, 0xafbf_0014, 0x0c01_6a34, 0x0000_0000, 0x3c01_8008],
+            replacement: &[
+                0x3c01_1f80, // lui at, 0x1f80
+                0x3c0a_0300, // lui t2, 0x0300
+                0xac2a_1814, // sw  t2, 0x1814(at)    # GP1(03h): display on
+                0x03e0_0008, // jr  ra
+                0x0000_0000, // nop
+            ],
+        }],
+    },
+];
+
+/// Every patch of `kind`
+pub fn patches(kind: PatchKind) -> impl Iterator<Item = &'static Patch> {
+    PATCHES.iter().filter(move |patch| patch.kind == kind)
+}
@@ -4,13 +4,68 @@ use crate::{emu::{
     map,
     Ram,
     Bios,
-    Access, AccessWidth
+    Exp1, Exp2,
+    Gpu,
+    Spu,
+    Joy,
+    MemCtl,
+    Access, AccessWidth,
+    ram::{RAM_SIZE, SCRATCHPAD_SIZE},
+    cpu::block::PAGE_SIZE,
+    state::{Snapshot, StateReader, StateWriter},
+    watch::{AccessKind, Triggers, WatchAction, WatchHit, WatchId, Watches},
 }, set_log_level};
 
+#[derive(Clone)]
 pub struct Bus {
     ram: Ram,
     bios: Bios,
+    scratchpad: Ram,
+    mem_ctl: MemCtl,
+    exp1: Exp1,
+    exp2: Exp2,
+    gpu: Gpu,
+    spu: Spu,
+    joy: Joy,
+    /// Dispatch table for memory accesses
+    pages: map::PageTable,
+
+    /// RAM pages that the block cache has decoded instructions from
+    code_pages: Vec<bool>,
+    /// Code pages written to since the block cache last checked
+    dirty_code_pages: Vec<u32>,
+
+    /// Set by tools, not part of the state
+    watches: Watches,
 }
+/// The BIOS and the expansion ROM are configuration, they aren't part of the state
+impl Snapshot for Bus {
+    fn save(&self, w: &mut StateWriter) {
+        w.section(b"BUS ");
+        self.ram.save(w);
+        self.scratchpad.save(w);
+        self.mem_ctl.save(w);
+        self.exp2.save(w);
+        self.gpu.save(w);
+        self.spu.save(w);
+        self.joy.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.section(b"BUS ")?;
+        Snapshot::load(&mut self.ram, r)?;
+        Snapshot::load(&mut self.scratchpad, r)?;
+        Snapshot::load(&mut self.mem_ctl, r)?;
+        Snapshot::load(&mut self.exp2, r)?;
+        Snapshot::load(&mut self.gpu, r)?;
+        Snapshot::load(&mut self.spu, r)?;
+        Snapshot::load(&mut self.joy, r)?;
+        // Whatever code was cached from RAM may have changed
+        self.invalidate_code_pages();
+        Ok(())
+    }
+}
+
 impl Bus {
     pub fn new(
         ram: Ram,
@@ -19,9 +74,157 @@ impl Bus {
         Bus {
             ram,
             bios,
+            scratchpad: Ram::with_size(SCRATCHPAD_SIZE),
+            mem_ctl: MemCtl::new(),
+            exp1: Exp1::new(),
+            exp2: Exp2::new(),
+            gpu: Gpu::new(),
+            spu: Spu::new(),
+            joy: Joy::new(),
+            pages: map::PageTable::new(),
+            code_pages: vec![false; RAM_SIZE / PAGE_SIZE as usize],
+            dirty_code_pages: Vec::new(),
+            watches: Watches::default(),
+        }
+    }
+
+    pub fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    pub fn bios(&self) -> &Bios {
+        &self.bios
+    }
+
+    pub fn bios_mut(&mut self) -> &mut Bios {
+        &mut self.bios
+    }
+
+    pub fn exp1_mut(&mut self) -> &mut Exp1 {
+        &mut self.exp1
+    }
+
+    pub fn exp2(&self) -> &Exp2 {
+        &self.exp2
+    }
+
+    pub fn exp2_mut(&mut self) -> &mut Exp2 {
+        &mut self.exp2
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    pub fn spu(&self) -> &Spu {
+        &self.spu
+    }
+
+    pub fn spu_mut(&mut self) -> &mut Spu {
+        &mut self.spu
+    }
+
+    pub fn joy_mut(&mut self) -> &mut Joy {
+        &mut self.joy
+    }
+
+    /// Return the page table entry for physical address `paddr`
+    pub fn page(&self, paddr: u32) -> map::Page {
+        self.pages.get(paddr)
+    }
+
+    /// Flag a RAM page as containing cached code, so that writes to it get reported
+    pub fn mark_code_page(&mut self, page: u32) {
+        self.code_pages[page as usize] = true;
+    }
+
+    /// Return the code pages written to since the last call
+    pub fn take_dirty_code_pages(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.dirty_code_pages)
+    }
+
+    pub fn has_dirty_code_pages(&self) -> bool {
+        !self.dirty_code_pages.is_empty()
+    }
+
+    /// Report every code page as dirty, e.g. after the instruction cache has been flushed
+    pub fn invalidate_code_pages(&mut self) {
+        for (page, is_code) in self.code_pages.iter_mut().enumerate() {
+            if *is_code {
+                *is_code = false;
+                self.dirty_code_pages.push(page as u32);
+            }
+        }
+    }
+
+    /// Watch `len` bytes at `addr`, see [`crate::emu::watch`]. Code in the watched pages
+    /// stops being cached, so that fetches from it can be seen.
+    pub fn add_watch(&mut self, addr: u32, len: u32, triggers: Triggers, action: WatchAction) -> WatchId {
+        let (id, pages) = self.watches.add(addr, len, triggers, action);
+        for page in pages {
+            self.pages.set_watched(page, true);
+            self.dirty_code_pages.push(page);
+        }
+        id
+    }
+
+    /// Returns false if there was no such watch
+    pub fn remove_watch(&mut self, id: WatchId) -> bool {
+        let Some(pages) = self.watches.remove(id) else {
+            return false;
+        };
+        for page in pages {
+            self.pages.set_watched(page, false);
+        }
+        true
+    }
+
+    /// Hits of the watches with a `Break` action since the last call
+    pub fn take_watch_breaks(&mut self) -> Vec<WatchHit> {
+        self.watches.take_breaks()
+    }
+
+    /// Load like the CPU would, but without triggering any watch. For tools inspecting
+    /// memory.
+    pub fn peek<T: Access>(&self, addr: u32) -> T {
+        let paddr = map::mask_region(addr);
+        match self.pages.get(paddr) {
+            map::Page::Ram(base) => self.ram.load::<T>(paddr - base),
+            map::Page::Bios(base) => self.bios.load::<T>(paddr - base),
+            map::Page::Scratchpad(base) if paddr - base < SCRATCHPAD_SIZE as u32 => {
+                self.scratchpad.load::<T>(paddr - base)
+            },
+            _ => self.load_device(addr, paddr),
+        }
+    }
+
+    /// Store like the CPU would, but without triggering any watch. For tools editing
+    /// memory.
+    pub fn poke<T: Access>(&mut self, addr: u32, val: T) {
+        let paddr = map::mask_region(addr);
+        match self.pages.get(paddr) {
+            map::Page::Watched => self.store_device(addr, paddr, val),
+            _ => self.store(addr, val),
         }
     }
 
+    /// Fetch an instruction. Triggers the execute watches instead of the read ones.
+    pub fn fetch(&self, addr: u32) -> u32 {
+        let paddr = map::mask_region(addr);
+        if !self.pages.is_watched(paddr) {
+            return self.load(addr);
+        }
+        let inst = self.peek(addr);
+        if self.watches.has_execute() {
+            self.watches.access(AccessKind::Execute, addr, 4, inst);
+        }
+        inst
+    }
+
     /// Routes load request @ addr to proper device
     pub fn load<T: Access>(&self, addr: u32) -> T {
         tracing::trace!("psx.load(0x{addr:08x}) ({:?})", T::width());
@@ -38,60 +241,101 @@ impl Bus {
         */
 
         let paddr = map::mask_region(addr);
-        match map::get_region(paddr) {
+
+        // Memory backed pages are accessed directly through the page table
+        match self.pages.get(paddr) {
+            map::Page::Ram(base) => self.ram.load::<T>(paddr - base),
+            map::Page::Bios(base) => self.bios.load::<T>(paddr - base),
+            map::Page::Scratchpad(base) if paddr - base < SCRATCHPAD_SIZE as u32 => {
+                self.scratchpad.load::<T>(paddr - base)
+            },
+            _ => self.load_io(addr, paddr),
+        }
+    }
+
+    /// Slow path of [`Bus::load`], for devices sharing a page or with side effects, and
+    /// watched pages
+    #[inline(never)]
+    fn load_io<T: Access>(&self, addr: u32, paddr: u32) -> T {
+        let val: T = self.load_device(addr, paddr);
+        if self.pages.is_watched(paddr) {
+            self.watches.access(AccessKind::Read, addr, std::mem::size_of::<T>() as u32, val.as_u32());
+        }
+        val
+    }
+
+    fn load_device<T: Access>(&self, addr: u32, paddr: u32) -> T {
+        match self.pages.region(paddr) {
             map::Region::Bios(mapping) => {
                 let offset = paddr - mapping.base;
-                return self.bios.load::<T>(offset);
+                self.bios.load::<T>(offset)
             },
             map::Region::Ram(mapping) => {
                 let offset = paddr - mapping.base;
-                return self.ram.load::<T>(offset);
+                self.ram.load::<T>(offset)
             },
-            map::Region::MemCtl(_mapping) => {
-                tracing::warn!("read from memctrl region (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+            map::Region::Scratchpad(mapping) => {
+                let offset = paddr - mapping.base;
+                self.scratchpad.load::<T>(offset)
+            },
+            map::Region::MemCtl(mapping) => {
+                let offset = paddr - mapping.base;
+                T::from_u32(self.mem_ctl.load(offset))
+            },
+            map::Region::Joy(mapping) => {
+                let offset = paddr - mapping.base;
+                T::from_u32(self.joy.load(offset))
             },
             map::Region::RamCtl(_mapping) => {
                 tracing::warn!("read from ramctrl region (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+                T::from_u32(0)
             },
             map::Region::IrqCtl(_mapping) => {
                 tracing::warn!("read from irqctrl region (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+                T::from_u32(0)
             },
             map::Region::Timer(_mapping) => {
                 tracing::warn!("read from timer region (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+                T::from_u32(0)
             },
             map::Region::CacheCtl(_mapping) => {
                 tracing::warn!("read from cachectrl region (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+                T::from_u32(0)
             },
-            map::Region::Spu(_mapping) => {
-                tracing::warn!("read from SPU region (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+            map::Region::Spu(mapping) => {
+                // 16 bit registers, word accesses read two of them
+                let offset = paddr - mapping.base;
+                let val = match T::width() {
+                    AccessWidth::Word => self.spu.load(offset) as u32 | (self.spu.load(offset + 2) as u32) << 16,
+                    _ => self.spu.load(offset & !1) as u32 >> ((offset & 1) * 8),
+                };
+                T::from_u32(val)
             },
-            map::Region::Exp1(_mapping) => {
-                tracing::warn!("read from expansion region 1 (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+            map::Region::Exp1(mapping) => {
+                // 8 bit bus, wider accesses are split into little-endian byte reads
+                let offset = paddr - mapping.base;
+                let window = self.mem_ctl.exp1_size();
+                let val = (0..std::mem::size_of::<T>() as u32).fold(0, |val, byte| {
+                    val | (self.exp1.load(offset + byte, window) as u32) << (byte * 8)
+                });
+                T::from_u32(val)
             },
-            map::Region::Exp2(_mapping) => {
-                tracing::warn!("read from expansion region 2 (0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+            map::Region::Exp2(mapping) => {
+                // 8 bit registers, wider accesses only see the addressed one
+                let offset = paddr - mapping.base;
+                T::from_u32(self.exp2.load(offset) as u32)
             },
             map::Region::Dma(_mapping) => {
                 tracing::warn!("read from dma register 0x{addr:08x}), but this is unimplemented");
-                return T::from_u32(0);
+                T::from_u32(0)
             }
             map::Region::Gpu(mapping) => {
-                tracing::warn!("read from gpu register 0x{addr:08x}), but this is unimplemented");
                 let offset = paddr - mapping.base;
-                // Simulate GPUSTAT::Ready to recieve DMA blocks
-                let response = match offset {
-                    4 => 0x1000_0000,
-                    _ => 0,
+                let val = match offset {
+                    0..=3 => self.gpu.read(),
+                    _ => self.gpu.status(),
                 };
-                T::from_u32(response)
+                T::from_u32(val)
             }
         }
     }
@@ -111,16 +355,48 @@ impl Bus {
         */
 
         let paddr = map::mask_region(addr);
-        match map::get_region(paddr) {
+
+        // Memory backed pages are accessed directly through the page table
+        match self.pages.get(paddr) {
+            map::Page::Ram(base) => self.store_ram(paddr - base, val),
+            map::Page::Scratchpad(base) if paddr - base < SCRATCHPAD_SIZE as u32 => {
+                self.scratchpad.store::<T>(paddr - base, val)
+            },
+            _ => self.store_io(addr, paddr, val),
+        }
+    }
+
+    /// Slow path of [`Bus::store`], for devices sharing a page or with side effects, and
+    /// watched pages
+    #[inline(never)]
+    fn store_io<T: Access>(&mut self, addr: u32, paddr: u32, val: T) {
+        let value = val.as_u32();
+        self.store_device(addr, paddr, val);
+        if self.pages.is_watched(paddr) {
+            self.watches.access(AccessKind::Write, addr, std::mem::size_of::<T>() as u32, value);
+        }
+    }
+
+    fn store_device<T: Access>(&mut self, addr: u32, paddr: u32, val: T) {
+        match self.pages.region(paddr) {
             map::Region::Bios(_mapping) => {
                 panic!("attempt to write to bios region which is read only");
             },
             map::Region::Ram(mapping) => {
                 let offset = paddr - mapping.base;
-                self.ram.store::<T>(offset, val);
+                self.store_ram(offset, val);
             },
-            map::Region::MemCtl(_mapping) => {
-                tracing::warn!("wrote to memctrl region (0x{addr:08x}), but this is unimplemented");
+            map::Region::Scratchpad(mapping) => {
+                let offset = paddr - mapping.base;
+                self.scratchpad.store::<T>(offset, val);
+            },
+            map::Region::MemCtl(mapping) => {
+                let offset = paddr - mapping.base;
+                self.mem_ctl.store(offset, val.as_u32());
+            },
+            map::Region::Joy(mapping) => {
+                let offset = paddr - mapping.base;
+                self.joy.store(offset, val.as_u32());
             },
             map::Region::RamCtl(_mapping) => {
                 tracing::warn!("wrote to ramctrl region (0x{addr:08x}), but this is unimplemented");
@@ -134,21 +410,42 @@ impl Bus {
             map::Region::CacheCtl(_mapping) => {
                 tracing::warn!("wrote to cachectrl region (0x{addr:08x}), but this is unsupported");
             },
-            map::Region::Spu(_mapping) => {
-                tracing::warn!("wrote to SPU region (0x{addr:08x}), but this is unsupported");
+            map::Region::Spu(mapping) => {
+                let offset = paddr - mapping.base;
+                self.spu.store(offset, val.as_u32() as u16);
+                if T::width() == AccessWidth::Word {
+                    self.spu.store(offset + 2, (val.as_u32() >> 16) as u16);
+                }
             },
-            map::Region::Exp1(_mapping) => {
-                tracing::warn!("wrote to expansion region 1 (0x{addr:08x}), but this is unsupported");
+            map::Region::Exp1(mapping) => {
+                let offset = paddr - mapping.base;
+                self.exp1.store(offset, val.as_u8());
             },
-            map::Region::Exp2(_mapping) => {
-                tracing::warn!("wrote to expansion region 2 (0x{addr:08x}), but this is unsupported");
+            map::Region::Exp2(mapping) => {
+                let offset = paddr - mapping.base;
+                self.exp2.store(offset, val.as_u32() as u8);
             },
             map::Region::Dma(_mapping) => {
                 tracing::warn!("wrote to dma register (0x{addr:08x}), but this is unsupported");
             },
-            map::Region::Gpu(_mapping) => {
-                tracing::warn!("wrote to gpu register (0x{addr:08x}), but this is unsupported");
+            map::Region::Gpu(mapping) => {
+                let offset = paddr - mapping.base;
+                match offset {
+                    0..=3 => self.gpu.gp0(val.as_u32()),
+                    _ => self.gpu.gp1(val.as_u32()),
+                }
             },
         }
     }
+
+    /// Store to RAM, reporting writes to pages holding cached code
+    fn store_ram<T: Access>(&mut self, offset: u32, val: T) {
+        self.ram.store::<T>(offset, val);
+
+        let page = (offset / PAGE_SIZE) as usize;
+        if self.code_pages[page] {
+            self.code_pages[page] = false;
+            self.dirty_code_pages.push(page as u32);
+        }
+    }
 }
\ No newline at end of file
@@ -1,20 +1,47 @@
 //! CPU module for handling all CPU instructions including the dispatch of other modules 
 //! (e.g. coprocessor or GPU)
 
+use std::sync::Arc;
+
 use crate::{emu::{
     bios::BIOS_START,
+    symbols::{Location, SymbolTable},
     cpu::instruction::{Instruction, RegisterIndex},
     cpu::exception::{Exception, ExceptionClass},
     bus::Bus,
+    state::{self, StateReader, StateWriter},
 }, set_log_level};
 
 use anyhow::{anyhow,Result};
 
 use super::Psx;
 
-mod instruction;
+pub mod instruction;
+pub mod disasm;
+pub mod asm;
 mod cop;
 mod exception;
+pub mod block;
+#[cfg(feature = "recompiler")]
+pub mod recompiler;
+#[cfg(test)]
+mod tests;
+
+use block::BlockCache;
+#[cfg(feature = "recompiler")]
+use recompiler::Recompiler;
+
+/// Strategy used by the CPU to fetch and decode instructions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Engine {
+    /// Fetch and decode every instruction through the bus
+    Interpreter,
+    /// Decode basic blocks once and keep them cached by PC
+    CachedInterpreter,
+    /// Translate basic blocks into native x86-64 code
+    #[cfg(feature = "recompiler")]
+    Recompiler,
+}
 
 #[derive(Debug, Default)]
 pub struct Cpu {
@@ -48,6 +75,15 @@ pub struct Cpu {
     pending_branch: bool,
     /// Is the CPU in the branch delay slot?
     branch_delay_slot: bool,
+
+    /// Decoded basic blocks, present when running the cached interpreter
+    block_cache: Option<BlockCache>,
+    /// Native code translation state, present when running the recompiler
+    #[cfg(feature = "recompiler")]
+    recompiler: Option<Box<Recompiler>>,
+
+    /// Symbols of the running program, for traces and error messages
+    symbols: Option<Arc<SymbolTable>>,
 }
 
 #[derive(Debug, Default)]
@@ -72,11 +108,173 @@ impl Cpu {
         }
     }
 
-    fn reg(&self, idx: RegisterIndex) -> u32 {
+    pub fn engine(&self) -> Engine {
+        #[cfg(feature = "recompiler")]
+        if self.recompiler.is_some() {
+            return Engine::Recompiler;
+        }
+        match self.block_cache {
+            Some(_) => Engine::CachedInterpreter,
+            None => Engine::Interpreter,
+        }
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) -> Result<()> {
+        if engine == self.engine() {
+            return Ok(());
+        }
+        self.block_cache = None;
+        #[cfg(feature = "recompiler")]
+        {
+            self.recompiler = None;
+        }
+
+        match engine {
+            Engine::Interpreter => {},
+            Engine::CachedInterpreter => self.block_cache = Some(BlockCache::new()),
+            #[cfg(feature = "recompiler")]
+            Engine::Recompiler => self.recompiler = Some(Box::new(Recompiler::new()?)),
+        }
+        Ok(())
+    }
+
+    /// Copy of the architectural state, running on the plain interpreter
+    pub fn interpreter_copy(&self) -> Cpu {
+        Cpu {
+            pc: self.pc,
+            next_pc: self.next_pc,
+            current_pc: self.current_pc,
+            regs: self.regs,
+            out_regs: self.out_regs,
+            lo: self.lo,
+            hi: self.hi,
+            cop: self.cop.clone(),
+            pending_load: self.pending_load,
+            pending_branch: self.pending_branch,
+            branch_delay_slot: self.branch_delay_slot,
+            symbols: self.symbols.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Address of the next instruction to be executed
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    /// Continue execution at `pc`, dropping any pending branch
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.pending_branch = false;
+        self.branch_delay_slot = false;
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(Arc::new(symbols));
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_deref()
+    }
+
+    /// `addr` annotated with the symbol it belongs to, if any
+    pub fn location(&self, addr: u32) -> Location<'_> {
+        Location::new(addr, self.symbols())
+    }
+
+    /// COP0 status register
+    pub fn status(&self) -> u32 {
+        self.cop.sr()
+    }
+
+    pub fn set_status(&mut self, sr: u32) {
+        self.cop.set_sr(sr);
+    }
+
+    /// COP0 cause register
+    pub fn cause(&self) -> u32 {
+        self.cop.cause()
+    }
+
+    pub fn set_cause(&mut self, cause: u32) {
+        self.cop.set_cause_raw(cause);
+    }
+
+    /// COP0 exception program counter
+    pub fn epc(&self) -> u32 {
+        self.cop.epc
+    }
+
+    pub fn set_epc(&mut self, epc: u32) {
+        self.cop.epc = epc;
+    }
+
+    /// COP0 address of the last address error
+    pub fn bad_vaddr(&self) -> u32 {
+        self.cop.bad_vaddr
+    }
+
+    pub fn set_bad_vaddr(&mut self, addr: u32) {
+        self.cop.bad_vaddr = addr;
+    }
+
+    /// Leave an exception handler: restore the interrupt/kernel mode stack (as RFE does)
+    /// and continue at `pc`
+    pub fn return_from_exception(&mut self, pc: u32) {
+        self.cop.pop_mode();
+        self.set_pc(pc);
+    }
+
+    /// Cause code of the exception the CPU entered by executing the instruction at
+    /// `prev_pc`, if it's now at the start of the general exception handler
+    pub fn entered_exception(&self, prev_pc: u32) -> Option<u32> {
+        let vectors = [
+            exception::handler(exception::ExceptionVector::Normal, ExceptionClass::General),
+            exception::handler(exception::ExceptionVector::Boot, ExceptionClass::General),
+        ];
+        (vectors.contains(&self.pc) && !vectors.contains(&prev_pc)).then(|| (self.cause() >> 2) & 0x1f)
+    }
+
+    /// Retire any pending load and commit the register file, so that the architectural
+    /// state can be inspected or modified from outside of the pipeline
+    pub fn settle(&mut self) {
+        self.handle_pending_load();
+        self.pending_load = None;
+        self.commit_registers();
+    }
+
+    pub fn hi(&self) -> u32 {
+        self.hi
+    }
+
+    pub fn lo(&self) -> u32 {
+        self.lo
+    }
+
+    pub fn set_hi(&mut self, val: u32) {
+        self.hi = val;
+    }
+
+    pub fn set_lo(&mut self, val: u32) {
+        self.lo = val;
+    }
+
+    pub fn reg(&self, idx: RegisterIndex) -> u32 {
         let RegisterIndex(i) = idx;
         self.regs[i as usize]
     }
 
+    /// Overwrite a register from outside of the pipeline, e.g. to set up a test or
+    /// debugger state. Bypasses the load delay.
+    pub fn write_reg(&mut self, idx: RegisterIndex, val: u32) {
+        let RegisterIndex(i) = idx;
+        if i != 0 {
+            self.regs[i as usize] = val;
+            self.out_regs[i as usize] = val;
+        }
+    }
+
     fn set_reg(&mut self, idx: RegisterIndex, val: u32) {
         let RegisterIndex(i) = idx;
         self.out_regs[i as usize] = val;
@@ -125,8 +323,8 @@ impl Cpu {
             ExceptionClass::General,
         );
 
-        tracing::debug!("Exception handler: 0x{handler:08x} (from BEV = {})", 
-            self.cop.status().exception_vector() as u32);
+        tracing::debug!("Exception handler: 0x{handler:08x} (from BEV = {}), raised by {:?} @ {}",
+            self.cop.status().exception_vector() as u32, cause, self.location(self.current_pc));
 
         // From emulation guide, pg. 66:
         //
@@ -143,6 +341,12 @@ impl Cpu {
         self.next_pc = handler.wrapping_add(4);
     }
 
+    /// Raise an alignment exception for an access to `addr`
+    fn address_error(&mut self, cause: Exception, addr: u32) {
+        self.cop.bad_vaddr = addr;
+        self.exception(cause);
+    }
+
     fn increment_pc(&mut self) {
         self.pc = self.next_pc;
         self.next_pc = self.next_pc.wrapping_add(4);
@@ -153,7 +357,7 @@ impl Cpu {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct LoadDelay {
     pub target_reg: RegisterIndex,
     pub val: u32,
@@ -166,110 +370,183 @@ impl LoadDelay {
     }
 }
 
+/// Architectural state only: decoded blocks and native code are dropped as the bus
+/// reports the restored RAM as rewritten
+impl state::Snapshot for Cpu {
+    fn save(&self, w: &mut StateWriter) {
+        w.section(b"CPU ");
+        w.u32(self.pc);
+        w.u32(self.next_pc);
+        w.u32(self.current_pc);
+        w.words(&self.regs);
+        w.words(&self.out_regs);
+        w.u32(self.lo);
+        w.u32(self.hi);
+        state::Snapshot::save(&self.cop, w);
+        w.bool(self.pending_load.is_some());
+        let load = self.pending_load.unwrap_or(LoadDelay::new(RegisterIndex(0), 0));
+        w.u32(load.target_reg.0);
+        w.u32(load.val);
+        w.bool(self.pending_branch);
+        w.bool(self.branch_delay_slot);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        r.section(b"CPU ")?;
+        self.pc = r.u32()?;
+        self.next_pc = r.u32()?;
+        self.current_pc = r.u32()?;
+        r.words_into(&mut self.regs)?;
+        r.words_into(&mut self.out_regs)?;
+        self.lo = r.u32()?;
+        self.hi = r.u32()?;
+        state::Snapshot::load(&mut self.cop, r)?;
+        let has_load = r.bool()?;
+        let load = LoadDelay::new(RegisterIndex(r.u32()? & 0x1f), r.u32()?);
+        self.pending_load = has_load.then_some(load);
+        self.pending_branch = r.bool()?;
+        self.branch_delay_slot = r.bool()?;
+        Ok(())
+    }
+}
+
+/// Signature shared by every opcode handler, as produced by [`decode`]
+pub type Handler = fn(&mut Cpu, &mut Bus, Instruction);
+
+/// Resolve the opcode handler for `inst` without executing it
+pub fn decode(inst: Instruction) -> Handler {
+    // Primary opcode
+    match inst.opcode() {
+        0x01 => |cpu, _bus, inst| cpu.op_bcondz(inst),
+        0x02 => |cpu, _bus, inst| cpu.op_j(inst),
+        0x03 => |cpu, _bus, inst| cpu.op_jal(inst),
+        0x04 => |cpu, _bus, inst| cpu.op_beq(inst),
+        0x05 => |cpu, _bus, inst| cpu.op_bne(inst),
+        0x06 => |cpu, _bus, inst| cpu.op_blez(inst),
+        0x07 => |cpu, _bus, inst| cpu.op_bgtz(inst),
+        0x08 => |cpu, _bus, inst| cpu.op_addi(inst),
+        0x09 => |cpu, _bus, inst| cpu.op_addiu(inst),
+        0x0a => |cpu, _bus, inst| cpu.op_slti(inst),
+        0x0b => |cpu, _bus, inst| cpu.op_sltiu(inst),
+        0x0c => |cpu, _bus, inst| cpu.op_andi(inst),
+        0x0d => |cpu, _bus, inst| cpu.op_ori(inst),
+        0x0e => |cpu, _bus, inst| cpu.op_xori(inst),
+        0x0f => |cpu, _bus, inst| cpu.op_lui(inst),
+        0x10 => |cpu, bus, inst| cpu.op_cop0(bus, inst),
+        0x11 => |cpu, _bus, inst| cpu.op_cop1(inst),
+        0x12 => |cpu, bus, inst| cpu.op_cop2(bus, inst),
+        0x13 => |cpu, _bus, inst| cpu.op_cop3(inst),
+        0x20 => |cpu, bus, inst| cpu.op_lb(bus, inst),
+        0x21 => |cpu, bus, inst| cpu.op_lh(bus, inst),
+        0x22 => |cpu, bus, inst| cpu.op_lwl(bus, inst),
+        0x23 => |cpu, bus, inst| cpu.op_lw(bus, inst),
+        0x24 => |cpu, bus, inst| cpu.op_lbu(bus, inst),
+        0x25 => |cpu, bus, inst| cpu.op_lhu(bus, inst),
+        0x26 => |cpu, bus, inst| cpu.op_lwr(bus, inst),
+        0x28 => |cpu, bus, inst| cpu.op_sb(bus, inst),
+        0x29 => |cpu, bus, inst| cpu.op_sh(bus, inst),
+        0x2a => |cpu, bus, inst| cpu.op_swl(bus, inst),
+        0x2b => |cpu, bus, inst| cpu.op_sw(bus, inst),
+        0x2e => |cpu, bus, inst| cpu.op_swr(bus, inst),
+        0x30 => |cpu, _bus, inst| cpu.op_lwc0(inst),
+        0x31 => |cpu, _bus, inst| cpu.op_lwc1(inst),
+        0x32 => |cpu, bus, inst| cpu.op_lwc2(bus, inst),
+        0x33 => |cpu, _bus, inst| cpu.op_lwc3(inst),
+        0x38 => |cpu, _bus, inst| cpu.op_swc0(inst),
+        0x39 => |cpu, _bus, inst| cpu.op_swc1(inst),
+        0x3a => |cpu, bus, inst| cpu.op_swc2(bus, inst),
+        0x3b => |cpu, _bus, inst| cpu.op_swc3(inst),
+
+        // Secondary Opcodes
+        0x00 => match inst.funct() { 
+            0x00 => |cpu, _bus, inst| cpu.op_sll(inst),
+            0x02 => |cpu, _bus, inst| cpu.op_srl(inst),
+            0x03 => |cpu, _bus, inst| cpu.op_sra(inst),
+            0x04 => |cpu, _bus, inst| cpu.op_sllv(inst),
+            0x06 => |cpu, _bus, inst| cpu.op_srlv(inst),
+            0x07 => |cpu, _bus, inst| cpu.op_srav(inst),
+            0x08 => |cpu, _bus, inst| cpu.op_jr(inst),
+            0x09 => |cpu, _bus, inst| cpu.op_jalr(inst),
+            0x0c => |cpu, _bus, inst| cpu.op_syscall(inst),
+            0x0d => |cpu, _bus, inst| cpu.op_break(inst),
+            0x10 => |cpu, _bus, inst| cpu.op_mfhi(inst),
+            0x11 => |cpu, _bus, inst| cpu.op_mthi(inst),
+            0x12 => |cpu, _bus, inst| cpu.op_mflo(inst),
+            0x13 => |cpu, _bus, inst| cpu.op_mtlo(inst),
+            0x18 => |cpu, _bus, inst| cpu.op_mult(inst),
+            0x19 => |cpu, _bus, inst| cpu.op_multu(inst),
+            0x1a => |cpu, _bus, inst| cpu.op_div(inst),
+            0x1b => |cpu, _bus, inst| cpu.op_divu(inst),
+            0x20 => |cpu, _bus, inst| cpu.op_add(inst),
+            0x21 => |cpu, _bus, inst| cpu.op_addu(inst),
+            0x22 => |cpu, _bus, inst| cpu.op_sub(inst),
+            0x23 => |cpu, _bus, inst| cpu.op_subu(inst),
+            0x24 => |cpu, _bus, inst| cpu.op_and(inst),
+            0x25 => |cpu, _bus, inst| cpu.op_or(inst),
+            0x26 => |cpu, _bus, inst| cpu.op_xor(inst),
+            0x27 => |cpu, _bus, inst| cpu.op_nor(inst),
+            0x2a => |cpu, _bus, inst| cpu.op_slt(inst),
+            0x2b => |cpu, _bus, inst| cpu.op_sltu(inst),
+            _    => |cpu, _bus, inst| cpu.op_illegal(inst),
+        },
+        _    => |cpu, _bus, inst| cpu.op_illegal(inst),
+    }
+}
+
 impl Cpu {
+    /// Execute the next instruction, or the next compiled block when running the
+    /// recompiler. Returns the number of instructions retired.
+    pub fn run(&mut self, bus: &mut Bus) -> u32 {
+        #[cfg(feature = "recompiler")]
+        if self.recompiler.is_some() {
+            return self.run_recompiled(bus);
+        }
+        self.handle_next_instruction(bus);
+        1
+    }
+
     pub fn handle_next_instruction(&mut self, bus: &mut Bus) {
 
         // Track instruction address in case of exception
         self.current_pc = self.pc;
 
         if self.current_pc % 4 != 0 {
-            self.exception(Exception::LoadAlignmentError);
+            self.address_error(Exception::LoadAlignmentError, self.current_pc);
             return;
         }
 
         let inst_addr = self.pc;
-        let inst = Instruction(bus.load(inst_addr));
+        let cached = match &mut self.block_cache {
+            Some(cache) => cache.fetch(bus, inst_addr),
+            None => None,
+        };
+        let (inst, handler) = cached.unwrap_or_else(|| {
+            let inst = Instruction(bus.fetch(inst_addr));
+            (inst, decode(inst))
+        });
 
-        tracing::trace!("fetched instruction: 0x{inst:08x} @ 0x{inst_addr:08x}"); 
+        tracing::trace!("{}: {inst:08x}  {}", self.location(inst_addr),
+            disasm::Disasm::new(inst, inst_addr).with_symbols(self.symbols()));
 
+        self.execute(bus, inst, handler);
+    }
+
+    /// Execute an already fetched and decoded instruction located at `self.pc`
+    fn execute(&mut self, bus: &mut Bus, inst: Instruction, handler: Handler) {
         self.increment_pc();
         self.handle_pending_load();
 
         self.branch_delay_slot = self.pending_branch;
         self.pending_branch = false;
 
-        self.dispatch_instruction(bus, inst);
+        handler(self, bus, inst);
 
         self.commit_registers();
     }
 
     pub fn dispatch_instruction(&mut self, bus: &mut Bus, inst: Instruction) {
-        // Primary opcode
-        match inst.opcode() {
-            0x01 => self.op_bcondz(inst),
-            0x02 => self.op_j(inst),
-            0x03 => self.op_jal(inst),
-            0x04 => self.op_beq(inst),
-            0x05 => self.op_bne(inst),
-            0x06 => self.op_blez(inst),
-            0x07 => self.op_bgtz(inst),
-            0x08 => self.op_addi(inst),
-            0x09 => self.op_addiu(inst),
-            0x0a => self.op_slti(inst),
-            0x0b => self.op_sltiu(inst),
-            0x0c => self.op_andi(inst),
-            0x0d => self.op_ori(inst),
-            0x0e => self.op_xori(inst),
-            0x0f => self.op_lui(inst),
-            0x10 => self.op_cop0(bus, inst),
-            0x11 => self.op_cop1(inst),
-            0x12 => self.op_cop2(bus, inst),
-            0x13 => self.op_cop3(inst),
-            0x20 => self.op_lb(bus, inst),
-            0x21 => self.op_lh(bus, inst),
-            0x22 => self.op_lwl(bus, inst),
-            0x23 => self.op_lw(bus, inst),
-            0x24 => self.op_lbu(bus, inst),
-            0x25 => self.op_lhu(bus, inst),
-            0x26 => self.op_lwr(bus, inst),
-            0x28 => self.op_sb(bus, inst),
-            0x29 => self.op_sh(bus, inst),
-            0x2a => self.op_swl(bus, inst),
-            0x2b => self.op_sw(bus, inst),
-            0x2e => self.op_swr(bus, inst),
-            0x30 => self.op_lwc0(inst),
-            0x31 => self.op_lwc1(inst),
-            0x32 => self.op_lwc2(bus, inst),
-            0x33 => self.op_lwc3(inst),
-            0x38 => self.op_swc0(inst),
-            0x39 => self.op_swc1(inst),
-            0x3a => self.op_swc2(bus, inst),
-            0x3b => self.op_swc3(inst),
-
-            // Secondary Opcodes
-            0x00 => match inst.funct() { 
-                0x00 => self.op_sll(inst),
-                0x02 => self.op_srl(inst),
-                0x03 => self.op_sra(inst),
-                0x04 => self.op_sllv(inst),
-                0x06 => self.op_srlv(inst),
-                0x07 => self.op_srav(inst),
-                0x08 => self.op_jr(inst),
-                0x09 => self.op_jalr(inst),
-                0x0c => self.op_syscall(inst),
-                0x0d => self.op_break(inst),
-                0x10 => self.op_mfhi(inst),
-                0x11 => self.op_mthi(inst),
-                0x12 => self.op_mflo(inst),
-                0x13 => self.op_mtlo(inst),
-                0x18 => self.op_mult(inst),
-                0x19 => self.op_multu(inst),
-                0x1a => self.op_div(inst),
-                0x1b => self.op_divu(inst),
-                0x20 => self.op_add(inst),
-                0x21 => self.op_addu(inst),
-                0x22 => self.op_sub(inst),
-                0x23 => self.op_subu(inst),
-                0x24 => self.op_and(inst),
-                0x25 => self.op_or(inst),
-                0x26 => self.op_xor(inst),
-                0x27 => self.op_nor(inst),
-                0x2a => self.op_slt(inst),
-                0x2b => self.op_sltu(inst),
-                _    => self.op_illegal(inst),
-            },
-            _    => self.op_illegal(inst),
-        };
+        let handler = decode(inst);
+        handler(self, bus, inst);
     }
 
     /* ========= Opcodes ========= */
@@ -309,7 +586,7 @@ impl Cpu {
         let addr = base.wrapping_add(offset);
 
         if addr % 4 != 0 {
-            self.exception(Exception::LoadAlignmentError)
+            self.address_error(Exception::LoadAlignmentError, addr)
         } else {
             let val = bus.load(addr);
             let load = LoadDelay::new(rt, val);
@@ -337,7 +614,7 @@ impl Cpu {
         let addr = base.wrapping_add(offset);
 
         if addr % 2 != 0 {
-            self.exception(Exception::LoadAlignmentError)
+            self.address_error(Exception::LoadAlignmentError, addr)
         } else {
             // Cast as i16 to force sign extension
             let val = bus.load::<u16>(addr) as i16;
@@ -417,7 +694,7 @@ impl Cpu {
         let addr = base.wrapping_add(offset);
 
         if addr % 2 != 0 {
-            self.exception(Exception::LoadAlignmentError)
+            self.address_error(Exception::LoadAlignmentError, addr)
         } else {
             let val = bus.load::<u16>(addr);
             let load = LoadDelay::new(rt, val as u32);
@@ -511,6 +788,7 @@ impl Cpu {
 
         if self.cop.status().is_isolate_cache() {
             tracing::warn!("ignoring store while cache is isolated");
+            bus.invalidate_code_pages();
             return;
         }
 
@@ -522,7 +800,7 @@ impl Cpu {
         let addr = offset.wrapping_add(i);
 
         if addr % 4 != 0 {
-            self.exception(Exception::StoreAlignmentError)
+            self.address_error(Exception::StoreAlignmentError, addr)
         } else {
             let val = self.reg(rt);
             bus.store(addr, val);
@@ -537,6 +815,7 @@ impl Cpu {
 
         if self.cop.status().is_isolate_cache() {
             tracing::warn!("ignoring store while cache is isolated");
+            bus.invalidate_code_pages();
             return;
         }
 
@@ -548,7 +827,7 @@ impl Cpu {
         let addr = offset.wrapping_add(i);
 
         if addr % 2 != 0 {
-            self.exception(Exception::StoreAlignmentError)
+            self.address_error(Exception::StoreAlignmentError, addr)
         } else {
             let val = self.reg(rt) as u16;
             bus.store(addr, val);
@@ -563,6 +842,7 @@ impl Cpu {
 
         if self.cop.status().is_isolate_cache() {
             tracing::warn!("ignoring store while cache is isolated");
+            bus.invalidate_code_pages();
             return;
         }
 
@@ -585,7 +865,8 @@ impl Cpu {
         tracing::trace!("exec SWL");
 
         if self.cop.status().is_isolate_cache() {
-            tracing::warn!("ignoring load while cache is isolated");
+            tracing::warn!("ignoring store while cache is isolated");
+            bus.invalidate_code_pages();
             return;
         }
 
@@ -618,7 +899,8 @@ impl Cpu {
         tracing::trace!("exec SWR");
 
         if self.cop.status().is_isolate_cache() {
-            tracing::warn!("ignoring load while cache is isolated");
+            tracing::warn!("ignoring store while cache is isolated");
+            bus.invalidate_code_pages();
             return;
         }
 
@@ -862,12 +1144,12 @@ impl Cpu {
         let rs = inst.rs();
         let rt = inst.rt();
 
-        let multiplicand = self.reg(rs) as i64;
-        let multiplier = self.reg(rt) as i64;
+        let multiplicand = self.reg(rs) as i32 as i64;
+        let multiplier = self.reg(rt) as i32 as i64;
         let product = multiplicand * multiplier;
 
-        self.lo = (product >> 32) as u32;
-        self.hi = product as u32;
+        self.hi = (product >> 32) as u32;
+        self.lo = product as u32;
     }
 
     /// Multiply Unsigned
@@ -883,8 +1165,8 @@ impl Cpu {
 
         let product = multiplicand * multiplier;
 
-        self.lo = (product >> 32) as u32;
-        self.hi = product as u32;
+        self.hi = (product >> 32) as u32;
+        self.lo = product as u32;
     }
 
     /// Divide
@@ -904,7 +1186,7 @@ impl Cpu {
                 self.hi = n as u32;
             },
             (n, 0) if n < 0 => { // Special case: Divide by zero (negative)
-                self.lo = 1 as u32;
+                self.lo = 1;
                 self.hi = n as u32;
             },
             (i32::MIN, -1) => { // Special case: i32::MIN / -1 cannot fit in i32. 
@@ -1128,10 +1410,10 @@ impl Cpu {
         let s = self.reg(rs) as i32;
 
         let discriminant = (inst.inner() >> 16) & 0x1f;
-        let should_link = discriminant & 0x1e == 0x80;
+        let should_link = discriminant & 0x1e == 0x10;
 
         if should_link {
-            let return_addr = self.pc;
+            let return_addr = self.next_pc;
             self.set_reg(RegisterIndex::RETURN, return_addr);
         }
 
@@ -1387,7 +1669,8 @@ impl Cpu {
             0x00 => self.op_mfc0(bus, inst),
             0x04 => self.op_mtc0(bus, inst),
             0x10 => self.op_rfe(bus, inst),
-            _else => panic!("unknown cop0 delegation: {_else:05x} (op: 0x{inst:08x})"),
+            _else => panic!("unknown cop0 delegation: {_else:05x} (op: 0x{inst:08x}) @ {}",
+                self.location(self.current_pc)),
         }
     }
 
@@ -1403,7 +1686,7 @@ impl Cpu {
     // exec cop2 command 0x0..0x1ff_ffff
     fn op_cop2(&mut self, _bus: &mut Bus, inst: Instruction) {
         tracing::trace!("exec COP2");
-        panic!("unhandled GTE instruction: (instruction: {})", inst.inner());
+        panic!("unhandled GTE instruction: 0x{inst:08x} @ {}", self.location(self.current_pc));
     }
 
     /// Invoke coprocessor 3 (Unused)
@@ -1434,7 +1717,7 @@ impl Cpu {
     // mem[offset] = cop2[data_reg]
     fn op_lwc2(&mut self, _bus: &mut Bus, inst: Instruction) {
         tracing::trace!("exec LWC2");
-        panic!("Unhandled GTE LWC: {}", inst.inner());
+        panic!("Unhandled GTE LWC: 0x{inst:08x} @ {}", self.location(self.current_pc));
     }
 
     /// Load word from coprocessor 3 (Unused)
@@ -1466,7 +1749,7 @@ impl Cpu {
     // cop2[data_reg] = mem[offset]
     fn op_swc2(&mut self, _bus: &mut Bus, inst: Instruction) {
         tracing::trace!("exec SWC2");
-        panic!("Unhandled GTE SWC: {}", inst.inner());
+        panic!("Unhandled GTE SWC: 0x{inst:08x} @ {}", self.location(self.current_pc));
     }
 
     /// Store word to coprocessor 3 (Unused)
@@ -1505,15 +1788,15 @@ impl Cpu {
         self.cop.mtc0(bus, cop_r, val);
     }
 
-    fn op_rfe(&mut self, bus: &mut Bus, inst: Instruction) {
+    fn op_rfe(&mut self, _bus: &mut Bus, inst: Instruction) {
         tracing::trace!("delegate RFE");
         if inst.inner() & 0x3f != 0b01_0000 {
-            panic!("Unsupported cop0 instruction: {inst:08x}");
+            panic!("Unsupported cop0 instruction: {inst:08x} @ {}", self.location(self.current_pc));
         }
         self.cop.pop_mode();
     }
 
-    fn op_illegal(&mut self, inst: Instruction) {
+    fn op_illegal(&mut self, _inst: Instruction) {
         tracing::warn!("exec ILLEGAL");
         self.exception(Exception::IllegalInstruction)
     }
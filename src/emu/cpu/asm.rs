@@ -0,0 +1,538 @@
+//! Minimal MIPS R3000A assembler, mostly meant for building small test programs
+//!
+//! Accepts one statement per line, in the syntax produced by the disassembler: ABI or
+//! numbered (`$n`) register names, decimal or `0x` immediates, `offset(base)` memory
+//! operands and labels (`name:`) wherever an immediate, branch or jump target is expected.
+//! Comments start with `#` or `;`. Besides the plain instruction set the following are
+//! understood:
+//!
+//! - `.word v[, v...]` emits raw words
+//! - `nop`, `move`, `negu`, `not`, `b`, `bal`, `beqz`, `bnez`, `jalr rs`
+//! - `li rt, imm`: one instruction when `imm` fits in 16 bits, `lui`+`ori` otherwise
+//! - `la rt, addr`: always `lui`+`addiu`
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::cpu::disasm::{
+    COP0_REGISTER_NAMES,
+    GTE_COMMANDS,
+    GTE_CONTROL_NAMES,
+    GTE_DATA_NAMES,
+    REGISTER_NAMES,
+};
+
+/// Assemble `source` into machine words, the first of which is located at `origin`
+pub fn assemble(source: &str, origin: u32) -> Result<Vec<u32>> {
+    let statements = parse(source)?;
+
+    // First pass: assign addresses to labels
+    let mut labels = HashMap::new();
+    let mut pc = origin;
+    for stmt in &statements {
+        match &stmt.kind {
+            StatementKind::Label(name) => {
+                if labels.insert(name.clone(), pc).is_some() {
+                    bail!("line {}: duplicate label `{name}`", stmt.line);
+                }
+            },
+            StatementKind::Op(mnemonic, operands) => {
+                pc = pc.wrapping_add(4 * op_len(mnemonic, operands) as u32);
+            },
+        }
+    }
+
+    // Second pass: encode
+    let mut words = Vec::new();
+    let mut asm = Assembler { labels: &labels, pc: origin };
+    for stmt in &statements {
+        if let StatementKind::Op(mnemonic, operands) = &stmt.kind {
+            let encoded = asm.encode(mnemonic, operands)
+                .map_err(|e| anyhow!("line {}: {e}", stmt.line))?;
+            asm.pc = asm.pc.wrapping_add(4 * encoded.len() as u32);
+            words.extend(encoded);
+        }
+    }
+    Ok(words)
+}
+
+#[derive(Debug)]
+enum StatementKind {
+    Label(String),
+    /// Mnemonic or directive, and its comma separated operands
+    Op(String, Vec<String>),
+}
+
+#[derive(Debug)]
+struct Statement {
+    /// 1-based source line, for error messages
+    line: usize,
+    kind: StatementKind,
+}
+
+fn parse(source: &str) -> Result<Vec<Statement>> {
+    let mut statements = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let mut text = line.split(['#', ';']).next().unwrap_or("").trim();
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                bail!("line {line_no}: invalid label `{label}`");
+            }
+            statements.push(Statement { line: line_no, kind: StatementKind::Label(label.to_string()) });
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands = rest.split(',')
+            .map(|operand| operand.trim().to_string())
+            .filter(|operand| !operand.is_empty())
+            .collect();
+        statements.push(Statement {
+            line: line_no,
+            kind: StatementKind::Op(mnemonic.to_lowercase(), operands),
+        });
+    }
+    Ok(statements)
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Parse a decimal or hexadecimal literal, optionally negative
+fn parse_number(s: &str) -> Option<u32> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let val = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u32>().ok()?,
+    };
+    Some(if negative { val.wrapping_neg() } else { val })
+}
+
+/// Does `val` fit in a 16 bit immediate, either sign or zero extended?
+fn fits_imm16(val: u32) -> bool {
+    (-0x8000..=0xffff).contains(&(val as i32))
+}
+
+/// Can `li` load this operand with a single instruction?
+fn li_is_short(operand: Option<&String>) -> bool {
+    operand.and_then(|operand| parse_number(operand)).is_some_and(fits_imm16)
+}
+
+/// Number of words emitted by a statement. Must agree with `Assembler::encode`.
+fn op_len(mnemonic: &str, operands: &[String]) -> usize {
+    match mnemonic {
+        ".word" => operands.len(),
+        "la" => 2,
+        "li" if !li_is_short(operands.get(1)) => 2,
+        _ => 1,
+    }
+}
+
+fn r_type(funct: u32, rs: u32, rt: u32, rd: u32, shamt: u32) -> u32 {
+    (rs << 21) | (rt << 16) | (rd << 11) | (shamt << 6) | funct
+}
+
+fn i_type(opcode: u32, rs: u32, rt: u32, imm: u32) -> u32 {
+    (opcode << 26) | (rs << 21) | (rt << 16) | (imm & 0xffff)
+}
+
+struct Assembler<'a> {
+    labels: &'a HashMap<String, u32>,
+    /// Address of the statement being encoded
+    pc: u32,
+}
+
+impl Assembler<'_> {
+    fn reg(&self, operand: &str) -> Result<u32> {
+        let name = operand.strip_prefix('$').unwrap_or(operand);
+        if let Some(idx) = REGISTER_NAMES.iter().position(|reg| *reg == name) {
+            return Ok(idx as u32);
+        }
+        match name {
+            "s8" => Ok(30),
+            _ => match name.parse::<u32>() {
+                Ok(idx) if idx < 32 && operand.starts_with('$') => Ok(idx),
+                _ => Err(anyhow!("invalid register `{operand}`")),
+            },
+        }
+    }
+
+    /// Coprocessor register, by name (from `names`) or as `$n`
+    fn cop_reg(&self, operand: &str, names: &[(u32, &str)]) -> Result<u32> {
+        if let Some((idx, _)) = names.iter().find(|(_, name)| *name == operand) {
+            return Ok(*idx);
+        }
+        let idx = operand.strip_prefix('$')
+            .or_else(|| operand.strip_prefix("cop0r"))
+            .and_then(|idx| idx.parse::<u32>().ok());
+        match idx {
+            Some(idx) if idx < 32 => Ok(idx),
+            _ => Err(anyhow!("invalid coprocessor register `{operand}`")),
+        }
+    }
+
+    fn value(&self, operand: &str) -> Result<u32> {
+        // Symbolized disassembly annotates addresses as `0x80010010 <main+0x10>`
+        let operand = match operand.split_once(" <") {
+            Some((addr, symbol)) if symbol.ends_with('>') => addr,
+            _ => operand,
+        };
+        parse_number(operand)
+            .or_else(|| self.labels.get(operand).copied())
+            .ok_or_else(|| anyhow!("invalid value or unknown label `{operand}`"))
+    }
+
+    /// 16 bit immediate, accepting both the signed and unsigned range
+    fn imm16(&self, operand: &str) -> Result<u32> {
+        let val = self.value(operand)?;
+        if !fits_imm16(val) {
+            bail!("immediate `{operand}` doesn't fit in 16 bits");
+        }
+        Ok(val & 0xffff)
+    }
+
+    fn shamt(&self, operand: &str) -> Result<u32> {
+        match self.value(operand)? {
+            val @ 0..=31 => Ok(val),
+            _ => Err(anyhow!("shift amount `{operand}` out of range")),
+        }
+    }
+
+    /// `offset(base)` memory operand
+    fn mem(&self, operand: &str) -> Result<(u32, u32)> {
+        let (offset, base) = operand.strip_suffix(')')
+            .and_then(|operand| operand.split_once('('))
+            .ok_or_else(|| anyhow!("invalid memory operand `{operand}`"))?;
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => self.imm16(offset)?,
+        };
+        Ok((offset, self.reg(base.trim())?))
+    }
+
+    /// Word offset from the delay slot to a branch target
+    fn branch_offset(&self, operand: &str) -> Result<u32> {
+        let target = self.value(operand)?;
+        let offset = target.wrapping_sub(self.pc.wrapping_add(4)) as i32;
+        if offset % 4 != 0 || !(-0x20000..0x20000).contains(&offset) {
+            bail!("branch target `{operand}` out of range");
+        }
+        Ok((offset >> 2) as u32 & 0xffff)
+    }
+
+    fn jump_target(&self, operand: &str) -> Result<u32> {
+        let target = self.value(operand)?;
+        if target % 4 != 0 || (target ^ self.pc.wrapping_add(4)) & 0xf000_0000 != 0 {
+            bail!("jump target `{operand}` out of range");
+        }
+        Ok((target >> 2) & 0x3ff_ffff)
+    }
+
+    fn encode(&self, mnemonic: &str, ops: &[String]) -> Result<Vec<u32>> {
+        let arity = |n: usize| -> Result<()> {
+            match ops.len() == n {
+                true => Ok(()),
+                false => Err(anyhow!("`{mnemonic}` expects {n} operands, got {}", ops.len())),
+            }
+        };
+        let op = |i: usize| ops[i].as_str();
+
+        let word = match mnemonic {
+            ".word" => return ops.iter().map(|operand| self.value(operand)).collect(),
+            "li" => {
+                arity(2)?;
+                let rt = self.reg(op(0))?;
+                if !li_is_short(ops.get(1)) {
+                    let val = self.value(op(1))?;
+                    return Ok(vec![
+                        i_type(0x0f, 0, rt, val >> 16),
+                        i_type(0x0d, rt, rt, val),
+                    ]);
+                }
+                match self.value(op(1))? {
+                    val if val <= 0xffff => i_type(0x0d, 0, rt, val),
+                    val => i_type(0x09, 0, rt, val),
+                }
+            },
+            "la" => {
+                arity(2)?;
+                let rt = self.reg(op(0))?;
+                let val = self.value(op(1))?;
+                return Ok(vec![
+                    i_type(0x0f, 0, rt, val.wrapping_add(0x8000) >> 16),
+                    i_type(0x09, rt, rt, val),
+                ]);
+            },
+
+            // Special
+            "nop" => { arity(0)?; 0 },
+            "sll" | "srl" | "sra" => {
+                arity(3)?;
+                let funct = match mnemonic { "sll" => 0x00, "srl" => 0x02, _ => 0x03 };
+                r_type(funct, 0, self.reg(op(1))?, self.reg(op(0))?, self.shamt(op(2))?)
+            },
+            "sllv" | "srlv" | "srav" => {
+                arity(3)?;
+                let funct = match mnemonic { "sllv" => 0x04, "srlv" => 0x06, _ => 0x07 };
+                r_type(funct, self.reg(op(2))?, self.reg(op(1))?, self.reg(op(0))?, 0)
+            },
+            "jr" => { arity(1)?; r_type(0x08, self.reg(op(0))?, 0, 0, 0) },
+            "jalr" => match ops.len() {
+                1 => r_type(0x09, self.reg(op(0))?, 0, 31, 0),
+                _ => { arity(2)?; r_type(0x09, self.reg(op(1))?, 0, self.reg(op(0))?, 0) },
+            },
+            "syscall" | "break" => {
+                let code = match ops.len() {
+                    0 => 0,
+                    _ => { arity(1)?; self.value(op(0))? & 0xf_ffff },
+                };
+                (code << 6) | if mnemonic == "syscall" { 0x0c } else { 0x0d }
+            },
+            "mfhi" | "mflo" => {
+                arity(1)?;
+                r_type(if mnemonic == "mfhi" { 0x10 } else { 0x12 }, 0, 0, self.reg(op(0))?, 0)
+            },
+            "mthi" | "mtlo" => {
+                arity(1)?;
+                r_type(if mnemonic == "mthi" { 0x11 } else { 0x13 }, self.reg(op(0))?, 0, 0, 0)
+            },
+            "mult" | "multu" | "div" | "divu" => {
+                arity(2)?;
+                let funct = match mnemonic { "mult" => 0x18, "multu" => 0x19, "div" => 0x1a, _ => 0x1b };
+                r_type(funct, self.reg(op(0))?, self.reg(op(1))?, 0, 0)
+            },
+            "add" | "addu" | "sub" | "subu" | "and" | "or" | "xor" | "nor" | "slt" | "sltu" => {
+                arity(3)?;
+                let funct = match mnemonic {
+                    "add" => 0x20, "addu" => 0x21, "sub" => 0x22, "subu" => 0x23,
+                    "and" => 0x24, "or" => 0x25, "xor" => 0x26, "nor" => 0x27,
+                    "slt" => 0x2a, _ => 0x2b,
+                };
+                r_type(funct, self.reg(op(1))?, self.reg(op(2))?, self.reg(op(0))?, 0)
+            },
+            "move" => { arity(2)?; r_type(0x21, self.reg(op(1))?, 0, self.reg(op(0))?, 0) },
+            "negu" => { arity(2)?; r_type(0x23, 0, self.reg(op(1))?, self.reg(op(0))?, 0) },
+            "not" => { arity(2)?; r_type(0x27, self.reg(op(1))?, 0, self.reg(op(0))?, 0) },
+
+            // Branches and jumps
+            "bltz" | "bgez" | "bltzal" | "bgezal" => {
+                arity(2)?;
+                let cond = match mnemonic { "bltz" => 0x00, "bgez" => 0x01, "bltzal" => 0x10, _ => 0x11 };
+                i_type(0x01, self.reg(op(0))?, cond, self.branch_offset(op(1))?)
+            },
+            "b" => { arity(1)?; i_type(0x04, 0, 0, self.branch_offset(op(0))?) },
+            "bal" => { arity(1)?; i_type(0x01, 0, 0x11, self.branch_offset(op(0))?) },
+            "j" | "jal" => {
+                arity(1)?;
+                let opcode = if mnemonic == "j" { 0x02 } else { 0x03 };
+                (opcode << 26) | self.jump_target(op(0))?
+            },
+            "beq" | "bne" => {
+                arity(3)?;
+                let opcode = if mnemonic == "beq" { 0x04 } else { 0x05 };
+                i_type(opcode, self.reg(op(0))?, self.reg(op(1))?, self.branch_offset(op(2))?)
+            },
+            "beqz" | "bnez" => {
+                arity(2)?;
+                let opcode = if mnemonic == "beqz" { 0x04 } else { 0x05 };
+                i_type(opcode, self.reg(op(0))?, 0, self.branch_offset(op(1))?)
+            },
+            "blez" | "bgtz" => {
+                arity(2)?;
+                let opcode = if mnemonic == "blez" { 0x06 } else { 0x07 };
+                i_type(opcode, self.reg(op(0))?, 0, self.branch_offset(op(1))?)
+            },
+
+            // Immediate arithmetic
+            "addi" | "addiu" | "slti" | "sltiu" | "andi" | "ori" | "xori" => {
+                arity(3)?;
+                let opcode = match mnemonic {
+                    "addi" => 0x08, "addiu" => 0x09, "slti" => 0x0a, "sltiu" => 0x0b,
+                    "andi" => 0x0c, "ori" => 0x0d, _ => 0x0e,
+                };
+                i_type(opcode, self.reg(op(1))?, self.reg(op(0))?, self.imm16(op(2))?)
+            },
+            "lui" => { arity(2)?; i_type(0x0f, 0, self.reg(op(0))?, self.imm16(op(1))?) },
+
+            // Loads and stores
+            "lb" | "lh" | "lwl" | "lw" | "lbu" | "lhu" | "lwr" | "sb" | "sh" | "swl" | "sw" | "swr" => {
+                arity(2)?;
+                let opcode = match mnemonic {
+                    "lb" => 0x20, "lh" => 0x21, "lwl" => 0x22, "lw" => 0x23, "lbu" => 0x24,
+                    "lhu" => 0x25, "lwr" => 0x26, "sb" => 0x28, "sh" => 0x29, "swl" => 0x2a,
+                    "sw" => 0x2b, _ => 0x2e,
+                };
+                let (offset, base) = self.mem(op(1))?;
+                i_type(opcode, base, self.reg(op(0))?, offset)
+            },
+
+            // Coprocessors
+            "mfc0" | "mtc0" => {
+                arity(2)?;
+                let cop_op = if mnemonic == "mfc0" { 0x00 } else { 0x04 };
+                let rd = self.cop_reg(op(1), &COP0_REGISTER_NAMES)?;
+                (0x10 << 26) | (cop_op << 21) | (self.reg(op(0))? << 16) | (rd << 11)
+            },
+            "rfe" => { arity(0)?; 0x4200_0010 },
+            "mfc2" | "cfc2" | "mtc2" | "ctc2" => {
+                arity(2)?;
+                let (cop_op, names) = match mnemonic {
+                    "mfc2" => (0x00, &GTE_DATA_NAMES),
+                    "cfc2" => (0x02, &GTE_CONTROL_NAMES),
+                    "mtc2" => (0x04, &GTE_DATA_NAMES),
+                    _ => (0x06, &GTE_CONTROL_NAMES),
+                };
+                let rd = self.cop_reg(op(1), &indexed(names))?;
+                (0x12 << 26) | (cop_op << 21) | (self.reg(op(0))? << 16) | (rd << 11)
+            },
+            "cop0" | "cop1" | "cop2" | "cop3" => {
+                arity(1)?;
+                let cop = mnemonic.as_bytes()[3] as u32 - b'0' as u32;
+                ((0x10 | cop) << 26) | (self.value(op(0))? & 0x3ff_ffff)
+            },
+            "lwc0" | "lwc1" | "lwc2" | "lwc3" | "swc0" | "swc1" | "swc2" | "swc3" => {
+                arity(2)?;
+                let cop = mnemonic.as_bytes()[3] as u32 - b'0' as u32;
+                let opcode = (if mnemonic.starts_with('l') { 0x30 } else { 0x38 }) | cop;
+                let rt = match cop {
+                    2 => self.cop_reg(op(0), &indexed(&GTE_DATA_NAMES))?,
+                    _ => self.cop_reg(op(0), &[])?,
+                };
+                let (offset, base) = self.mem(op(1))?;
+                i_type(opcode, base, rt, offset)
+            },
+            _ => match GTE_COMMANDS.iter().find(|(_, name)| *name == mnemonic) {
+                Some(&(funct, _)) => self.gte_command(funct, ops)?,
+                None => bail!("unknown instruction `{mnemonic}`"),
+            },
+        };
+        Ok(vec![word])
+    }
+
+    /// GTE command, with optional `sf`/`lm` flags. `mvmva` also takes `key=value`
+    /// selectors as printed by the disassembler.
+    fn gte_command(&self, funct: u32, ops: &[String]) -> Result<u32> {
+        let mut command = 0x4a00_0000 | funct;
+        for flag in ops.iter().flat_map(|operand| operand.split_whitespace()) {
+            let (key, val) = flag.split_once('=').unwrap_or((flag, "1"));
+            let field = |names: &[&str]| {
+                names.iter()
+                    .position(|name| *name == val)
+                    .map(|idx| idx as u32)
+                    .ok_or_else(|| anyhow!("invalid `{key}` selector `{val}`"))
+            };
+            command |= match key {
+                "sf" => self.value(val)?.min(1) << 19,
+                "lm" => self.value(val)?.min(1) << 10,
+                "mx" => field(&["rt", "llm", "lcm", "garbage"])? << 17,
+                "v" => field(&["v0", "v1", "v2", "ir"])? << 15,
+                "cv" => field(&["tr", "bk", "fc", "none"])? << 13,
+                _ => bail!("invalid GTE command flag `{flag}`"),
+            };
+        }
+        Ok(command)
+    }
+}
+
+/// Pair register names with their index, for `Assembler::cop_reg`
+fn indexed<'a>(names: &[&'a str; 32]) -> Vec<(u32, &'a str)> {
+    names.iter().enumerate().map(|(idx, name)| (idx as u32, *name)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::cpu::{disasm::disassemble, instruction::Instruction};
+
+    #[test]
+    fn encodes_basic_instructions() {
+        let words = assemble("
+            lui   t0, 0x13
+            ori   t0, t0, 0x243f
+            sw    t0, 0x1010(at)
+            addiu sp, sp, -24
+            jr    ra
+            nop
+        ", 0).unwrap();
+        assert_eq!(words, [0x3c08_0013, 0x3508_243f, 0xac28_1010, 0x27bd_ffe8, 0x03e0_0008, 0]);
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let words = assemble("
+            start:
+                b     end
+                nop
+            loop: bnez  a0, loop
+                addiu a0, a0, -1
+                jal   start
+            end:  .word loop, 0xdeadbeef
+        ", 0x8001_0000).unwrap();
+        assert_eq!(words, [
+            0x1000_0004,
+            0x0000_0000,
+            0x1480_ffff,
+            0x2484_ffff,
+            0x0c00_4000,
+            0x8001_0008,
+            0xdead_beef,
+        ]);
+    }
+
+    #[test]
+    fn expands_pseudo_instructions() {
+        let words = assemble("
+            li   t0, 0x1234
+            li   t0, -1
+            li   t0, 0x12345678
+            la   t1, 0x80018000
+            move a0, s0
+        ", 0).unwrap();
+        assert_eq!(words, [
+            0x3408_1234,
+            0x2408_ffff,
+            0x3c08_1234, 0x3508_5678,
+            0x3c09_8002, 0x2529_8000,
+            0x0200_2021,
+        ]);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let err = assemble("nop\naddiu t0, t9, 0x10000", 0).unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"), "{err}");
+        assert!(assemble("frobnicate t0", 0).is_err());
+        assert!(assemble("b nowhere", 0).is_err());
+        assert!(assemble("x: nop\nx: nop", 0).is_err());
+    }
+
+    /// Every instruction of the BIOS must disassemble to text that assembles back to an
+    /// instruction with the same disassembly
+    #[test]
+    fn disassembly_round_trips() {
+        let bios = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let base = 0xbfc0_0000;
+        for (idx, chunk) in bios.chunks_exact(4).enumerate() {
+            let pc = base + idx as u32 * 4;
+            let inst = Instruction(u32::from_le_bytes(chunk.try_into().unwrap()));
+            let text = disassemble(inst, pc);
+            let words = assemble(&text, pc)
+                .unwrap_or_else(|e| panic!("0x{pc:08x}: `{text}`: {e}"));
+            assert_eq!(words.len(), 1, "0x{pc:08x}: `{text}`");
+            assert_eq!(disassemble(Instruction(words[0]), pc), text, "0x{pc:08x}");
+        }
+    }
+}
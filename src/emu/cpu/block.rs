@@ -0,0 +1,284 @@
+//! Basic block cache for the cached interpreter
+//!
+//! Instructions are fetched and decoded once per basic block and kept keyed by the PC of
+//! their first instruction. A block ends after the delay slot of its first branch or jump,
+//! or at the end of its 4 KiB page, so that every block lives entirely inside one page and
+//! can be dropped when that page is written to.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+    rc::Rc,
+};
+
+use crate::emu::{
+    bus::Bus,
+    map,
+    cpu::{
+        decode,
+        Handler,
+        instruction::Instruction,
+    },
+};
+
+/// Size of the pages used for code invalidation
+pub const PAGE_SIZE: u32 = 4096;
+
+/// Upper bound on the number of instructions in a single block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// Multiplicative hasher for PC keys, much cheaper than the default SipHash
+#[derive(Default)]
+pub struct PcHasher(u64);
+
+impl Hasher for PcHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0.rotate_left(8) ^ b as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (n as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// Map keyed by PC or page number
+pub type PcMap<V> = HashMap<u32, V, BuildHasherDefault<PcHasher>>;
+
+#[derive(Debug)]
+pub struct Block {
+    /// Virtual address of the first instruction
+    start: u32,
+    /// Decoded instructions, in program order
+    ops: Vec<(Instruction, Handler)>,
+}
+
+impl Block {
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[(Instruction, Handler)] {
+        &self.ops
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    /// Compiled blocks keyed by the PC of their first instruction
+    blocks: PcMap<Rc<Block>>,
+    /// Start PCs of the blocks living in each physical page
+    pages: PcMap<Vec<u32>>,
+    /// Block currently being executed and the index of its next instruction
+    cursor: Option<(Rc<Block>, usize)>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of blocks currently held in the cache
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Return the decoded instruction at `pc`, compiling a new block if it isn't cached.
+    /// Returns `None` if `pc` doesn't point into cacheable memory (RAM or BIOS).
+    pub fn fetch(&mut self, bus: &mut Bus, pc: u32) -> Option<(Instruction, Handler)> {
+        if bus.has_dirty_code_pages() {
+            for page in bus.take_dirty_code_pages() {
+                self.invalidate_page(page);
+            }
+        }
+
+        if let Some((block, idx)) = &mut self.cursor
+            && *idx < block.ops.len()
+            && block.start.wrapping_add(*idx as u32 * 4) == pc
+        {
+            let op = block.ops[*idx];
+            *idx += 1;
+            return Some(op);
+        }
+
+        let block = match self.blocks.get(&pc) {
+            Some(block) => block.clone(),
+            None => self.compile(bus, pc)?,
+        };
+        let op = block.ops[0];
+        self.cursor = Some((block, 1));
+        Some(op)
+    }
+
+    /// Decode a new block starting at `pc` and insert it into the cache
+    fn compile(&mut self, bus: &mut Bus, pc: u32) -> Option<Rc<Block>> {
+        let block = Rc::new(decode_block(bus, pc)?);
+        let page = map::mask_region(pc) / PAGE_SIZE;
+        self.blocks.insert(pc, block.clone());
+        self.pages.entry(page).or_default().push(pc);
+        Some(block)
+    }
+
+    /// Drop every block decoded from physical page `page`
+    pub fn invalidate_page(&mut self, page: u32) {
+        let Some(starts) = self.pages.remove(&page) else {
+            return;
+        };
+        tracing::trace!("invalidating {} blocks in page 0x{:08x}", starts.len(), page * PAGE_SIZE);
+
+        for start in starts {
+            self.blocks.remove(&start);
+        }
+        if let Some((block, _)) = &self.cursor
+            && map::mask_region(block.start) / PAGE_SIZE == page
+        {
+            self.cursor = None;
+        }
+    }
+
+    /// Drop every cached block, e.g. after the instruction cache has been flushed
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.cursor = None;
+    }
+}
+
+/// Fetch and decode the basic block starting at `pc`. Returns `None` if `pc` doesn't
+/// point into cacheable memory (RAM or BIOS). RAM pages are flagged as code pages on the
+/// bus so that later writes to them are reported.
+pub fn decode_block(bus: &mut Bus, pc: u32) -> Option<Block> {
+    let paddr = map::mask_region(pc);
+    let is_ram = match bus.page(paddr) {
+        map::Page::Ram(_) => true,
+        map::Page::Bios(_) => false,
+        _ => return None,
+    };
+
+    let page = paddr / PAGE_SIZE;
+    let page_end = (page + 1) * PAGE_SIZE;
+
+    let mut ops = Vec::new();
+    let mut addr = pc;
+    let mut in_delay_slot = false;
+    while ops.len() < MAX_BLOCK_LEN && map::mask_region(addr) < page_end {
+        let inst = Instruction(bus.load(addr));
+        ops.push((inst, decode(inst)));
+        addr = addr.wrapping_add(4);
+
+        if in_delay_slot {
+            break;
+        }
+        in_delay_slot = is_branch(inst);
+    }
+
+    tracing::trace!("decoded block @ 0x{pc:08x} ({} instructions)", ops.len());
+
+    if is_ram {
+        bus.mark_code_page(page);
+    }
+
+    Some(Block { start: pc, ops })
+}
+
+/// Does `inst` transfer control (and therefore own a delay slot)?
+pub fn is_branch(inst: Instruction) -> bool {
+    match inst.opcode() {
+        0x01..=0x07 => true,
+        0x00 => matches!(inst.funct(), 0x08 | 0x09),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{bios::{Bios, BIOS_SIZE}, cpu::asm::assemble, ram::Ram};
+
+    /// Bus with `source` assembled at `origin`
+    fn bus_with(source: &str, origin: u32) -> Bus {
+        let mut bus = Bus::new(Ram::new(), Bios::new(&[0; BIOS_SIZE]));
+        for (idx, word) in assemble(source, origin).unwrap().into_iter().enumerate() {
+            bus.store::<u32>(origin + 4 * idx as u32, word);
+        }
+        bus
+    }
+
+    #[test]
+    fn drops_blocks_written_to() {
+        const ORIGIN: u32 = 0x8001_0000;
+        let mut bus = bus_with("
+            li    v0, 1
+            li    v0, 2
+            jr    ra
+            nop
+        ", ORIGIN);
+        let mut cache = BlockCache::new();
+        let (first, _) = cache.fetch(&mut bus, ORIGIN).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // Through KSEG1, still the same physical page
+        let patch = assemble("li v0, 3", 0).unwrap()[0];
+        bus.store::<u32>(0xa001_0004, patch);
+        assert_eq!(cache.fetch(&mut bus, ORIGIN + 4).unwrap().0, Instruction(patch));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.fetch(&mut bus, ORIGIN).unwrap().0, first);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn blocks_end_after_delay_slots() {
+        const ORIGIN: u32 = 0x8001_0000;
+        let mut bus = bus_with("
+            addiu a0, a0, -1
+            bnez  a0, 0x80010000
+            addiu v0, v0, 1
+            li    v1, 1
+        ", ORIGIN);
+        let block = decode_block(&mut bus, ORIGIN).unwrap();
+        assert_eq!(block.len(), 3);
+        assert_eq!(block.ops()[2].0, Instruction(bus.load(ORIGIN + 8)));
+    }
+
+    #[test]
+    fn delay_slots_can_start_the_next_page() {
+        // The branch is the last instruction of its page
+        const ORIGIN: u32 = 0x8001_0ff8;
+        let mut bus = bus_with("
+            li    v0, 1
+            b     0x80010ff8
+            li    v0, 2
+        ", ORIGIN);
+        assert_eq!(decode_block(&mut bus, ORIGIN).unwrap().len(), 2);
+
+        let mut cache = BlockCache::new();
+        let fetched: Vec<_> = (0..3)
+            .map(|idx| cache.fetch(&mut bus, ORIGIN + 4 * idx).unwrap().0)
+            .collect();
+        let expected: Vec<_> = (0..3).map(|idx| Instruction(bus.load(ORIGIN + 4 * idx))).collect();
+        assert_eq!(fetched, expected);
+        assert_eq!(cache.len(), 2);
+
+        // Writing the second page leaves the branch's block alone
+        bus.store::<u32>(ORIGIN + 8, 0);
+        assert_eq!(cache.fetch(&mut bus, ORIGIN + 8).unwrap().0, Instruction(0));
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -7,9 +7,10 @@ use crate::emu::{
         exception::{Exception, ExceptionVector},
     },
     Bus,
+    state::{Snapshot, StateReader, StateWriter},
 };
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Cop0 {
     /// Status register
     sr: u32,
@@ -17,6 +18,25 @@ pub struct Cop0 {
     cause: u32,
     /// Exception program counter
     pub epc: u32,
+    /// Address that caused the last address error
+    pub bad_vaddr: u32,
+}
+
+impl Snapshot for Cop0 {
+    fn save(&self, w: &mut StateWriter) {
+        w.u32(self.sr);
+        w.u32(self.cause);
+        w.u32(self.epc);
+        w.u32(self.bad_vaddr);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.sr = r.u32()?;
+        self.cause = r.u32()?;
+        self.epc = r.u32()?;
+        self.bad_vaddr = r.u32()?;
+        Ok(())
+    }
 }
 
 impl Cop0 {
@@ -28,6 +48,24 @@ impl Cop0 {
         ProcessorStatus(self.sr)
     }
 
+    /// Raw value of the status register
+    pub fn sr(&self) -> u32 {
+        self.sr
+    }
+
+    pub fn set_sr(&mut self, sr: u32) {
+        self.sr = sr;
+    }
+
+    pub fn cause(&self) -> u32 {
+        self.cause
+    }
+
+    /// Overwrite the cause register, e.g. from a debugger
+    pub fn set_cause_raw(&mut self, cause: u32) {
+        self.cause = cause;
+    }
+
     /// Disables interrupts and sets cpu to kernel mode
     //
     // Stores previous processor mode in mode stack.
@@ -57,7 +95,7 @@ impl Cop0 {
     }
 
     /// COP0 internal implementation of MTC0: Move to coprocessor 0
-    pub fn mtc0(&mut self, bus: &mut Bus, cop_r: RegisterIndex, val: u32) {
+    pub fn mtc0(&mut self, _bus: &mut Bus, cop_r: RegisterIndex, val: u32) {
         tracing::trace!("cop0 exec MTC0");
         match cop_r.into() {
             3 | 5 | 6 | 7 | 9 | 11 => {
@@ -83,9 +121,10 @@ impl Cop0 {
     }
 
     /// COP0 internal implementation of MFC0: Move from coprocessor 0
-    pub fn mfc0(&mut self, bus: &mut Bus, cop_r: RegisterIndex) -> u32 {
+    pub fn mfc0(&mut self, _bus: &mut Bus, cop_r: RegisterIndex) -> u32 {
         tracing::trace!("cop0 exec MFC0");
         match cop_r.into() {
+            8 => self.bad_vaddr,
             12 => self.sr,
             13 => self.cause,
             14 => self.epc,
@@ -0,0 +1,359 @@
+//! MIPS R3000A disassembler
+//!
+//! Renders instructions in the usual assembler syntax: ABI register names, sign-extended
+//! immediates, resolved branch and jump targets, and the common pseudo-ops (`nop`, `move`,
+//! `li`, `b`, ...). Encodings that don't decode to any instruction are shown as `.word`.
+
+use std::fmt;
+
+use crate::emu::{
+    cpu::instruction::{Instruction, RegisterIndex},
+    symbols::{Location, SymbolTable},
+};
+
+/// ABI names of the general purpose registers
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0",   "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0",   "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8",   "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// Names of the COP0 registers that exist on the PlayStation, by index
+pub const COP0_REGISTER_NAMES: [(u32, &str); 11] = [
+    (3,  "bpc"),
+    (5,  "bda"),
+    (6,  "jumpdest"),
+    (7,  "dcic"),
+    (8,  "badvaddr"),
+    (9,  "bdam"),
+    (11, "bpcm"),
+    (12, "sr"),
+    (13, "cause"),
+    (14, "epc"),
+    (15, "prid"),
+];
+
+/// Names of the GTE data registers
+pub const GTE_DATA_NAMES: [&str; 32] = [
+    "vxy0", "vz0",  "vxy1", "vz1",  "vxy2", "vz2",  "rgbc", "otz",
+    "ir0",  "ir1",  "ir2",  "ir3",  "sxy0", "sxy1", "sxy2", "sxyp",
+    "sz0",  "sz1",  "sz2",  "sz3",  "rgb0", "rgb1", "rgb2", "res1",
+    "mac0", "mac1", "mac2", "mac3", "irgb", "orgb", "lzcs", "lzcr",
+];
+
+/// Names of the GTE control registers
+pub const GTE_CONTROL_NAMES: [&str; 32] = [
+    "rt11rt12", "rt13rt21", "rt22rt23", "rt31rt32", "rt33", "trx",  "try",  "trz",
+    "l11l12",   "l13l21",   "l22l23",   "l31l32",   "l33",  "rbk",  "gbk",  "bbk",
+    "lr1lr2",   "lr3lg1",   "lg2lg3",   "lb1lb2",   "lb3",  "rfc",  "gfc",  "bfc",
+    "ofx",      "ofy",      "h",        "dqa",      "dqb",  "zsf3", "zsf4", "flag",
+];
+
+/// GTE commands, by their function field
+pub const GTE_COMMANDS: [(u32, &str); 22] = [
+    (0x01, "rtps"),
+    (0x06, "nclip"),
+    (0x0c, "op"),
+    (0x10, "dpcs"),
+    (0x11, "intpl"),
+    (0x12, "mvmva"),
+    (0x13, "ncds"),
+    (0x14, "cdp"),
+    (0x16, "ncdt"),
+    (0x1b, "nccs"),
+    (0x1c, "cc"),
+    (0x1e, "ncs"),
+    (0x20, "nct"),
+    (0x28, "sqr"),
+    (0x29, "dcpl"),
+    (0x2a, "dpct"),
+    (0x2d, "avsz3"),
+    (0x2e, "avsz4"),
+    (0x30, "rtpt"),
+    (0x3d, "gpf"),
+    (0x3e, "gpl"),
+    (0x3f, "ncct"),
+];
+
+/// ABI name of a general purpose register
+pub fn register_name(reg: RegisterIndex) -> &'static str {
+    REGISTER_NAMES[reg.0 as usize & 0x1f]
+}
+
+/// Name of a COP0 register, or `cop0r<n>` for the ones without a known function
+pub fn cop0_register_name(reg: RegisterIndex) -> String {
+    COP0_REGISTER_NAMES.iter()
+        .find(|(idx, _)| *idx == reg.0)
+        .map_or_else(|| format!("cop0r{}", reg.0), |(_, name)| name.to_string())
+}
+
+/// Disassemble `inst`, located at address `pc`
+pub fn disassemble(inst: Instruction, pc: u32) -> String {
+    Disasm::new(inst, pc).to_string()
+}
+
+/// Lazily formatted disassembly of an instruction, cheap to pass to log macros
+#[derive(Debug, Copy, Clone)]
+pub struct Disasm<'a> {
+    inst: Instruction,
+    pc: u32,
+    /// Used to annotate branch and jump targets
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> Disasm<'a> {
+    pub fn new(inst: Instruction, pc: u32) -> Self {
+        Disasm { inst, pc, symbols: None }
+    }
+
+    pub fn with_symbols(self, symbols: Option<&'a SymbolTable>) -> Self {
+        Disasm { symbols, ..self }
+    }
+
+    /// Destination of a PC-relative branch
+    fn branch_target(&self) -> Location<'a> {
+        let target = self.pc.wrapping_add(4).wrapping_add(self.inst.imm_se() << 2);
+        Location::new(target, self.symbols)
+    }
+
+    /// Destination of a `j`/`jal`
+    fn jump_target(&self) -> Location<'a> {
+        let target = (self.pc.wrapping_add(4) & 0xf000_0000) | (self.inst.addr() << 2);
+        Location::new(target, self.symbols)
+    }
+}
+
+/// Format `val` as a signed hexadecimal number
+struct SignedHex(u32);
+
+impl fmt::Display for SignedHex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = self.0 as i32;
+        if val < 0 {
+            write!(f, "-0x{:x}", val.unsigned_abs())
+        } else {
+            write!(f, "0x{val:x}")
+        }
+    }
+}
+
+impl fmt::Display for Disasm<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inst = self.inst;
+        let rs = register_name(inst.rs());
+        let rt = register_name(inst.rt());
+        let rd = register_name(inst.rd());
+        let zero = |r: RegisterIndex| r.0 == 0;
+        let simm = SignedHex(inst.imm_se());
+        let imm = inst.imm();
+
+        macro_rules! op {
+            ($mnemonic:expr) => { write!(f, "{}", $mnemonic) };
+            ($mnemonic:expr, $($arg:tt)*) => {{
+                write!(f, "{:<8}", $mnemonic)?;
+                write!(f, $($arg)*)
+            }};
+        }
+
+        match inst.opcode() {
+            0x00 => match inst.funct() {
+                0x00 if inst.inner() & 0x1f_ffc0 == 0 => op!("nop"),
+                0x00 => op!("sll", "{rd}, {rt}, {}", inst.shamt()),
+                0x02 => op!("srl", "{rd}, {rt}, {}", inst.shamt()),
+                0x03 => op!("sra", "{rd}, {rt}, {}", inst.shamt()),
+                0x04 => op!("sllv", "{rd}, {rt}, {rs}"),
+                0x06 => op!("srlv", "{rd}, {rt}, {rs}"),
+                0x07 => op!("srav", "{rd}, {rt}, {rs}"),
+                0x08 => op!("jr", "{rs}"),
+                0x09 if inst.rd() == RegisterIndex::RETURN => op!("jalr", "{rs}"),
+                0x09 => op!("jalr", "{rd}, {rs}"),
+                0x0c | 0x0d => {
+                    let mnemonic = if inst.funct() == 0x0c { "syscall" } else { "break" };
+                    match (inst.inner() >> 6) & 0xf_ffff {
+                        0 => op!(mnemonic),
+                        code => op!(mnemonic, "0x{code:x}"),
+                    }
+                },
+                0x10 => op!("mfhi", "{rd}"),
+                0x11 => op!("mthi", "{rs}"),
+                0x12 => op!("mflo", "{rd}"),
+                0x13 => op!("mtlo", "{rs}"),
+                0x18 => op!("mult", "{rs}, {rt}"),
+                0x19 => op!("multu", "{rs}, {rt}"),
+                0x1a => op!("div", "{rs}, {rt}"),
+                0x1b => op!("divu", "{rs}, {rt}"),
+                0x20 => op!("add", "{rd}, {rs}, {rt}"),
+                0x21 | 0x25 if zero(inst.rt()) => op!("move", "{rd}, {rs}"),
+                0x21 | 0x25 if zero(inst.rs()) => op!("move", "{rd}, {rt}"),
+                0x21 => op!("addu", "{rd}, {rs}, {rt}"),
+                0x22 => op!("sub", "{rd}, {rs}, {rt}"),
+                0x23 if zero(inst.rs()) => op!("negu", "{rd}, {rt}"),
+                0x23 => op!("subu", "{rd}, {rs}, {rt}"),
+                0x24 => op!("and", "{rd}, {rs}, {rt}"),
+                0x25 => op!("or", "{rd}, {rs}, {rt}"),
+                0x26 => op!("xor", "{rd}, {rs}, {rt}"),
+                0x27 if zero(inst.rt()) => op!("not", "{rd}, {rs}"),
+                0x27 => op!("nor", "{rd}, {rs}, {rt}"),
+                0x2a => op!("slt", "{rd}, {rs}, {rt}"),
+                0x2b => op!("sltu", "{rd}, {rs}, {rt}"),
+                _ => op!(".word", "0x{inst:08x}"),
+            },
+            0x01 => {
+                // Only bit 0 (condition) and bits [4:1] == 0b1000 (link) are decoded
+                let discriminant = inst.rt().0;
+                let link = discriminant & 0x1e == 0x10;
+                let target = self.branch_target();
+                match (discriminant & 1 != 0, link) {
+                    (true, false) if zero(inst.rs()) => op!("b", "{target}"),
+                    (true, true) if zero(inst.rs()) => op!("bal", "{target}"),
+                    (false, false) => op!("bltz", "{rs}, {target}"),
+                    (true, false) => op!("bgez", "{rs}, {target}"),
+                    (false, true) => op!("bltzal", "{rs}, {target}"),
+                    (true, true) => op!("bgezal", "{rs}, {target}"),
+                }
+            },
+            0x02 => op!("j", "{}", self.jump_target()),
+            0x03 => op!("jal", "{}", self.jump_target()),
+            0x04 | 0x05 => {
+                let target = self.branch_target();
+                let (mnemonic, mnemonic_z) = match inst.opcode() {
+                    0x04 => ("beq", "beqz"),
+                    _ => ("bne", "bnez"),
+                };
+                match (zero(inst.rs()), zero(inst.rt())) {
+                    (true, true) if inst.opcode() == 0x04 => op!("b", "{target}"),
+                    (_, true) => op!(mnemonic_z, "{rs}, {target}"),
+                    (true, false) => op!(mnemonic_z, "{rt}, {target}"),
+                    (false, false) => op!(mnemonic, "{rs}, {rt}, {target}"),
+                }
+            },
+            0x06 => op!("blez", "{rs}, {}", self.branch_target()),
+            0x07 => op!("bgtz", "{rs}, {}", self.branch_target()),
+            0x08 => op!("addi", "{rt}, {rs}, {simm}"),
+            0x09 if zero(inst.rs()) => op!("li", "{rt}, {simm}"),
+            0x09 => op!("addiu", "{rt}, {rs}, {simm}"),
+            0x0a => op!("slti", "{rt}, {rs}, {simm}"),
+            0x0b => op!("sltiu", "{rt}, {rs}, {simm}"),
+            0x0c => op!("andi", "{rt}, {rs}, 0x{imm:x}"),
+            0x0d if zero(inst.rs()) => op!("li", "{rt}, 0x{imm:x}"),
+            0x0d => op!("ori", "{rt}, {rs}, 0x{imm:x}"),
+            0x0e => op!("xori", "{rt}, {rs}, 0x{imm:x}"),
+            0x0f => op!("lui", "{rt}, 0x{imm:x}"),
+            0x10 => match inst.cop_op() {
+                0x00 => op!("mfc0", "{rt}, {}", cop0_register_name(inst.rd())),
+                0x04 => op!("mtc0", "{rt}, {}", cop0_register_name(inst.rd())),
+                0x10 if inst.funct() == 0x10 => op!("rfe"),
+                _ => op!("cop0", "0x{:x}", inst.inner() & 0x3ff_ffff),
+            },
+            0x12 => {
+                let data = GTE_DATA_NAMES[inst.rd().0 as usize];
+                let control = GTE_CONTROL_NAMES[inst.rd().0 as usize];
+                match inst.cop_op() {
+                    0x00 => op!("mfc2", "{rt}, {data}"),
+                    0x02 => op!("cfc2", "{rt}, {control}"),
+                    0x04 => op!("mtc2", "{rt}, {data}"),
+                    0x06 => op!("ctc2", "{rt}, {control}"),
+                    0x10..=0x1f => fmt_gte_command(f, inst),
+                    _ => op!("cop2", "0x{:x}", inst.inner() & 0x3ff_ffff),
+                }
+            },
+            0x11 | 0x13 => op!(format!("cop{}", inst.opcode() & 3), "0x{:x}", inst.inner() & 0x3ff_ffff),
+            0x20 => op!("lb", "{rt}, {simm}({rs})"),
+            0x21 => op!("lh", "{rt}, {simm}({rs})"),
+            0x22 => op!("lwl", "{rt}, {simm}({rs})"),
+            0x23 => op!("lw", "{rt}, {simm}({rs})"),
+            0x24 => op!("lbu", "{rt}, {simm}({rs})"),
+            0x25 => op!("lhu", "{rt}, {simm}({rs})"),
+            0x26 => op!("lwr", "{rt}, {simm}({rs})"),
+            0x28 => op!("sb", "{rt}, {simm}({rs})"),
+            0x29 => op!("sh", "{rt}, {simm}({rs})"),
+            0x2a => op!("swl", "{rt}, {simm}({rs})"),
+            0x2b => op!("sw", "{rt}, {simm}({rs})"),
+            0x2e => op!("swr", "{rt}, {simm}({rs})"),
+            0x32 => op!("lwc2", "{}, {simm}({rs})", GTE_DATA_NAMES[inst.rt().0 as usize]),
+            0x3a => op!("swc2", "{}, {simm}({rs})", GTE_DATA_NAMES[inst.rt().0 as usize]),
+            op @ (0x30 | 0x31 | 0x33) => op!(format!("lwc{}", op & 3), "${}, {simm}({rs})", inst.rt().0),
+            op @ (0x38 | 0x39 | 0x3b) => op!(format!("swc{}", op & 3), "${}, {simm}({rs})", inst.rt().0),
+            _ => op!(".word", "0x{inst:08x}"),
+        }
+    }
+}
+
+/// Format a GTE command with its `sf`/`lm` flags and, for `mvmva`, its operand selectors
+fn fmt_gte_command(f: &mut fmt::Formatter<'_>, inst: Instruction) -> fmt::Result {
+    let command = inst.inner() & 0x3ff_ffff;
+    let Some((_, mnemonic)) = GTE_COMMANDS.iter().find(|(funct, _)| *funct == inst.funct()) else {
+        return write!(f, "{:<8}0x{command:x}", "cop2");
+    };
+
+    let sf = (command >> 19) & 1;
+    let lm = (command >> 10) & 1;
+    if inst.funct() == 0x12 {
+        const MATRICES: [&str; 4] = ["rt", "llm", "lcm", "garbage"];
+        const VECTORS: [&str; 4] = ["v0", "v1", "v2", "ir"];
+        const TRANSLATIONS: [&str; 4] = ["tr", "bk", "fc", "none"];
+        let mx = MATRICES[((command >> 17) & 3) as usize];
+        let v = VECTORS[((command >> 15) & 3) as usize];
+        let cv = TRANSLATIONS[((command >> 13) & 3) as usize];
+        return write!(f, "{mnemonic:<8}sf={sf}, mx={mx}, v={v}, cv={cv}, lm={lm}");
+    }
+
+    write!(f, "{mnemonic}")?;
+    if sf != 0 {
+        write!(f, " sf")?;
+    }
+    if lm != 0 {
+        write!(f, " lm")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Words and their disassembly at `PC`
+    const GOLDEN: &[(u32, &str)] = &[
+        (0x0000_0000, "nop"),
+        (0x0000_0040, "sll     zero, zero, 1"),
+        (0x0008_4080, "sll     t0, t0, 2"),
+        (0x0109_5021, "addu    t2, t0, t1"),
+        (0x0080_5021, "move    t2, a0"),
+        (0x2402_0005, "li      v0, 0x5"),
+        (0x2402_fffb, "li      v0, -0x5"),
+        (0x3c08_1f80, "lui     t0, 0x1f80"),
+        (0x3421_1234, "ori     at, at, 0x1234"),
+        (0x8fbf_0010, "lw      ra, 0x10(sp)"),
+        (0xafa4_fff8, "sw      a0, -0x8(sp)"),
+        (0x1000_ffff, "b       0x80010018"),
+        (0x1440_fffd, "bnez    v0, 0x80010010"),
+        (0x0411_0002, "bal     0x80010024"),
+        (0x0c00_4000, "jal     0x80010000"),
+        (0x03e0_0008, "jr      ra"),
+        (0x0100_f809, "jalr    t0"),
+        (0x0100_1009, "jalr    v0, t0"),
+        (0x0000_000c, "syscall"),
+        (0x0000_000d, "break"),
+        (0x4002_6000, "mfc0    v0, sr"),
+        (0x4084_6000, "mtc0    a0, sr"),
+        (0x4200_0010, "rfe"),
+        (0x4200_0001, "cop0    0x2000001"),
+        (0x4802_4800, "mfc2    v0, ir1"),
+        (0x48c2_f800, "ctc2    v0, flag"),
+        (0x4a18_0001, "rtps sf"),
+        (0x4a48_6012, "mvmva   sf=1, mx=rt, v=v0, cv=none, lm=0"),
+        (0x4c00_0001, "cop3    0x1"),
+        (0xc801_0000, "lwc2    vz0, 0x0(zero)"),
+        (0xfc00_0000, ".word   0xfc000000"),
+    ];
+
+    const PC: u32 = 0x8001_0018;
+
+    #[test]
+    fn matches_golden() {
+        for &(word, expected) in GOLDEN {
+            assert_eq!(disassemble(Instruction(word), PC), expected, "{word:08x}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Exception {
     Interrupt           = 0,
     LoadAlignmentError  = 4,
@@ -27,7 +27,7 @@ impl From<RegisterIndex> for u32 {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Instruction(pub u32);
 
 impl Instruction {
@@ -46,8 +46,7 @@ impl Instruction {
     /// Return coprocessor opcode value in bits [25:21]
     pub fn cop_op(self) -> u32 {
         let Instruction(op) = self;
-        let i = (op >> 21) & 0x1f;
-        i
+        (op >> 21) & 0x1f
     }
 
     /// Return register index in bits [25:21]
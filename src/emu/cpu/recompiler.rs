@@ -0,0 +1,327 @@
+//! Dynamic recompiler translating MIPS basic blocks into x86-64 machine code
+//!
+//! Each guest block is compiled into a native function which runs the whole block and
+//! returns the number of instructions it retired. Non-trapping ALU instructions are
+//! translated directly whenever the load delay and branch delay state is known at compile
+//! time, i.e. when the previous instruction in the block neither loads nor branches. Every
+//! other instruction calls back into its interpreter handler, so load delays, branch delay
+//! slots, exceptions and coprocessor semantics stay identical to the interpreter.
+
+mod x64;
+
+use anyhow::Result;
+
+use crate::emu::{
+    bus::Bus,
+    map,
+    cpu::{
+        Cpu,
+        Handler,
+        block::{self, PcMap, PAGE_SIZE},
+        instruction::Instruction,
+    },
+};
+
+use x64::{AluOp, CodeArena, Cond, Emitter, Reg, ShiftOp};
+
+/// Size of the executable memory reserved for compiled blocks
+const ARENA_SIZE: usize = 32 * 1024 * 1024;
+
+/// Native entry point of a compiled block
+type BlockFn = unsafe extern "sysv64" fn(*mut Cpu, *mut Bus) -> u32;
+
+#[derive(Debug)]
+pub struct Recompiler {
+    arena: CodeArena,
+    /// Compiled blocks keyed by the PC of their first instruction
+    blocks: PcMap<BlockFn>,
+    /// Start PCs of the blocks living in each physical page
+    pages: PcMap<Vec<u32>>,
+}
+
+impl Recompiler {
+    pub fn new() -> Result<Self> {
+        Ok(Recompiler {
+            arena: CodeArena::new(ARENA_SIZE)?,
+            blocks: PcMap::default(),
+            pages: PcMap::default(),
+        })
+    }
+
+    /// Return the compiled block starting at `pc`, compiling it if needed.
+    /// Returns `None` if `pc` doesn't point into cacheable memory.
+    fn lookup(&mut self, cpu: &Cpu, bus: &mut Bus, pc: u32) -> Option<BlockFn> {
+        for page in bus.take_dirty_code_pages() {
+            self.invalidate_page(page);
+        }
+
+        if let Some(block) = self.blocks.get(&pc) {
+            return Some(*block);
+        }
+
+        let block = block::decode_block(bus, pc)?;
+        let code = compile(&Offsets::of(cpu), &block);
+
+        let entry = match self.arena.push(&code) {
+            Some(entry) => entry,
+            None => {
+                tracing::debug!("recompiler code arena full, flushing all blocks");
+                self.flush();
+                self.arena.push(&code)?
+            }
+        };
+
+        // SAFETY: `entry` points at a complete function emitted by `compile`
+        let entry: BlockFn = unsafe { std::mem::transmute(entry) };
+
+        self.blocks.insert(pc, entry);
+        self.pages.entry(map::mask_region(pc) / PAGE_SIZE).or_default().push(pc);
+        Some(entry)
+    }
+
+    /// Drop every block compiled from physical page `page`
+    fn invalidate_page(&mut self, page: u32) {
+        if let Some(starts) = self.pages.remove(&page) {
+            for start in starts {
+                self.blocks.remove(&start);
+            }
+        }
+    }
+
+    /// Drop every compiled block and reclaim the code arena
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.arena.reset();
+    }
+}
+
+impl Cpu {
+    /// Run one compiled block and return the number of instructions retired
+    pub(super) fn run_recompiled(&mut self, bus: &mut Bus) -> u32 {
+        let pc = self.pc;
+        let mut recompiler = match self.recompiler.take() {
+            Some(recompiler) if pc % 4 == 0 => recompiler,
+            recompiler => {
+                self.recompiler = recompiler;
+                self.handle_next_instruction(bus);
+                return 1;
+            }
+        };
+
+        let retired = match recompiler.lookup(self, bus, pc) {
+            // SAFETY: compiled code only touches `Cpu` fields through offsets computed from
+            // this very struct layout, and calls back into Rust with the same pointers
+            Some(entry) => unsafe { entry(self, bus) },
+            None => {
+                self.handle_next_instruction(bus);
+                1
+            }
+        };
+
+        self.recompiler = Some(recompiler);
+        retired
+    }
+}
+
+/// Byte offsets of the `Cpu` fields accessed by compiled code
+struct Offsets {
+    pc: u32,
+    next_pc: u32,
+    current_pc: u32,
+    regs: u32,
+    out_regs: u32,
+    branch_delay_slot: u32,
+}
+
+impl Offsets {
+    fn of(cpu: &Cpu) -> Self {
+        let base = cpu as *const Cpu as usize;
+        let offset = |field: usize| (field - base) as u32;
+        Offsets {
+            pc:                offset(&cpu.pc as *const _ as usize),
+            next_pc:           offset(&cpu.next_pc as *const _ as usize),
+            current_pc:        offset(&cpu.current_pc as *const _ as usize),
+            regs:              offset(&cpu.regs as *const _ as usize),
+            out_regs:          offset(&cpu.out_regs as *const _ as usize),
+            branch_delay_slot: offset(&cpu.branch_delay_slot as *const _ as usize),
+        }
+    }
+}
+
+/// Execute one instruction through its interpreter handler. Returns whether the block
+/// may carry on, i.e. control fell through to `next_pc` and no code page was written.
+unsafe extern "sysv64" fn interpret(
+    cpu: *mut Cpu,
+    bus: *mut Bus,
+    inst: u32,
+    handler: u64,
+    next_pc: u32,
+) -> bool {
+    let cpu = &mut *cpu;
+    let bus = &mut *bus;
+    let handler: Handler = std::mem::transmute(handler as usize);
+
+    cpu.current_pc = cpu.pc;
+    cpu.execute(bus, Instruction(inst), handler);
+
+    cpu.pc == next_pc && !bus.has_dirty_code_pages()
+}
+
+/// Translate a decoded block into a native function
+fn compile(offsets: &Offsets, block: &block::Block) -> Vec<u8> {
+    let mut e = Emitter::new();
+    let mut exits = Vec::new();
+
+    e.prologue();
+
+    let mut prev: Option<Instruction> = None;
+    for (i, &(inst, handler)) in block.ops().iter().enumerate() {
+        let addr = block.start().wrapping_add(i as u32 * 4);
+
+        // Delay state is only known statically if the previous instruction of this block
+        // left no pending load and no pending branch behind.
+        let delay_state_known = prev.is_some_and(|p| !sets_load_delay(p) && !block::is_branch(p));
+
+        if delay_state_known && emit_native(&mut e, offsets, inst, addr) {
+            e.count_retired();
+        } else {
+            e.call_with_state(interpret as usize, inst.inner(), handler as usize as u64, addr.wrapping_add(4));
+            e.count_retired();
+            exits.push(e.jump_if_false());
+        }
+        prev = Some(inst);
+    }
+
+    for exit in exits {
+        e.bind(exit);
+    }
+    e.epilogue();
+
+    e.code().to_vec()
+}
+
+/// Does `inst` queue a value in the load delay slot?
+fn sets_load_delay(inst: Instruction) -> bool {
+    matches!(inst.opcode(), 0x10 | 0x12 | 0x20..=0x26 | 0x32)
+}
+
+/// Emit native code for `inst` if it is a non-trapping ALU instruction. Must only be used
+/// when no load and no branch are pending. Returns false if `inst` isn't supported.
+fn emit_native(e: &mut Emitter, offsets: &Offsets, inst: Instruction, addr: u32) -> bool {
+    let reg = |r: u32| offsets.regs + r * 4;
+    let rs = inst.rs().0;
+    let rt = inst.rt().0;
+
+    let dest = match inst.opcode() {
+        0x00 => {
+            let rd = inst.rd().0;
+            match inst.funct() {
+                0x00 => { e.load(Reg::Eax, reg(rt)); e.shift_imm(ShiftOp::Shl, inst.shamt() as u8) },
+                0x02 => { e.load(Reg::Eax, reg(rt)); e.shift_imm(ShiftOp::Shr, inst.shamt() as u8) },
+                0x03 => { e.load(Reg::Eax, reg(rt)); e.shift_imm(ShiftOp::Sar, inst.shamt() as u8) },
+                0x04 => { e.load(Reg::Eax, reg(rt)); e.load(Reg::Ecx, reg(rs)); e.shift_cl(ShiftOp::Shl) },
+                0x06 => { e.load(Reg::Eax, reg(rt)); e.load(Reg::Ecx, reg(rs)); e.shift_cl(ShiftOp::Shr) },
+                0x07 => { e.load(Reg::Eax, reg(rt)); e.load(Reg::Ecx, reg(rs)); e.shift_cl(ShiftOp::Sar) },
+                0x21 => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Add) },
+                0x23 => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Sub) },
+                0x24 => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::And) },
+                0x25 => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Or) },
+                0x26 => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Xor) },
+                0x27 => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Or); e.not() },
+                0x2a => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Cmp); e.set_cond(Cond::Less) },
+                0x2b => { e.load(Reg::Eax, reg(rs)); e.load(Reg::Ecx, reg(rt)); e.alu(AluOp::Cmp); e.set_cond(Cond::Below) },
+                _ => return false,
+            }
+            rd
+        },
+        0x09 => { e.load(Reg::Eax, reg(rs)); e.alu_imm(AluOp::Add, inst.imm_se()); rt },
+        0x0a => { e.load(Reg::Eax, reg(rs)); e.alu_imm(AluOp::Cmp, inst.imm_se()); e.set_cond(Cond::Less); rt },
+        0x0b => { e.load(Reg::Eax, reg(rs)); e.alu_imm(AluOp::Cmp, inst.imm_se()); e.set_cond(Cond::Below); rt },
+        0x0c => { e.load(Reg::Eax, reg(rs)); e.alu_imm(AluOp::And, inst.imm()); rt },
+        0x0d => { e.load(Reg::Eax, reg(rs)); e.alu_imm(AluOp::Or, inst.imm()); rt },
+        0x0e => { e.load(Reg::Eax, reg(rs)); e.alu_imm(AluOp::Xor, inst.imm()); rt },
+        0x0f => { e.mov_imm(Reg::Eax, inst.imm() << 16); rt },
+        _ => return false,
+    };
+
+    // Writes to $zero are dropped, like `Cpu::set_reg` does
+    if dest != 0 {
+        e.store(reg(dest), Reg::Eax);
+        e.store(offsets.out_regs + dest * 4, Reg::Eax);
+    }
+
+    // Sequential flow: the PC bookkeeping is known at compile time
+    e.store_imm32(offsets.current_pc, addr);
+    e.store_imm32(offsets.pc, addr.wrapping_add(4));
+    e.store_imm32(offsets.next_pc, addr.wrapping_add(8));
+    e.store_imm8(offsets.branch_delay_slot, 0);
+    true
+}
+
+/// Number of blocks between two comparisons of the whole RAM in lock-step mode
+const RAM_CHECK_INTERVAL: u64 = 1024;
+
+/// Shadow interpreter running alongside the recompiler to catch divergence
+pub struct LockStep {
+    cpu: Cpu,
+    bus: Bus,
+    blocks_checked: u64,
+}
+
+impl LockStep {
+    pub fn new(cpu: &Cpu, bus: &Bus) -> Self {
+        LockStep {
+            cpu: cpu.interpreter_copy(),
+            bus: bus.clone(),
+            blocks_checked: 0,
+        }
+    }
+
+    /// Advance the shadow interpreter by `retired` instructions and compare its state with
+    /// the recompiled core. Returns a description of the differences found. Registers are
+    /// compared after every block, RAM only every `RAM_CHECK_INTERVAL` blocks.
+    pub fn check(&mut self, cpu: &Cpu, bus: &Bus, retired: u32) -> Result<(), String> {
+        let start_pc = self.cpu.pc;
+        for _ in 0..retired {
+            self.cpu.handle_next_instruction(&mut self.bus);
+        }
+        // Ticked like the core, so that the GPU and SPU reads match
+        self.bus.gpu_mut().tick(retired as u64);
+        self.bus.spu_mut().tick(retired as u64);
+
+        let mut diffs = Vec::new();
+        let mut compare = |name: &str, expected: u32, actual: u32| {
+            if expected != actual {
+                diffs.push(format!("{name}: interpreter 0x{expected:08x}, recompiler 0x{actual:08x}"));
+            }
+        };
+        compare("pc", self.cpu.pc, cpu.pc);
+        compare("next_pc", self.cpu.next_pc, cpu.next_pc);
+        compare("hi", self.cpu.hi, cpu.hi);
+        compare("lo", self.cpu.lo, cpu.lo);
+        for (i, (&expected, &actual)) in self.cpu.regs.iter().zip(cpu.regs.iter()).enumerate() {
+            compare(&format!("r{i}"), expected, actual);
+        }
+        if self.cpu.cop != cpu.cop {
+            diffs.push(format!("cop0: interpreter {:?}, recompiler {:?}", self.cpu.cop, cpu.cop));
+        }
+        if self.cpu.pending_load != cpu.pending_load {
+            diffs.push(format!("pending load: interpreter {:?}, recompiler {:?}",
+                self.cpu.pending_load, cpu.pending_load));
+        }
+        if self.cpu.pending_branch != cpu.pending_branch
+            || self.cpu.branch_delay_slot != cpu.branch_delay_slot {
+            diffs.push("branch delay state differs".to_string());
+        }
+        self.blocks_checked += 1;
+        if self.blocks_checked % RAM_CHECK_INTERVAL == 0 && self.bus.ram() != bus.ram() {
+            diffs.push("RAM contents differ".to_string());
+        }
+
+        match diffs.is_empty() {
+            true => Ok(()),
+            false => Err(format!("block @ 0x{start_pc:08x} ({retired} instructions): {}", diffs.join(", "))),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Minimal x86-64 machine code emitter and executable code arena
+//!
+//! Only the handful of encodings used by the recompiler are provided. Guest state is
+//! always addressed relative to `rbx`, which holds the `Cpu` pointer in compiled code.
+
+use anyhow::{anyhow, Result};
+
+/// Scratch registers available to the recompiler
+#[derive(Debug, Copy, Clone)]
+pub enum Reg {
+    Eax = 0,
+    Ecx = 1,
+}
+
+/// Condition codes for `setcc`
+#[derive(Debug, Copy, Clone)]
+pub enum Cond {
+    /// Unsigned less than
+    Below = 0x2,
+    /// Zero / equal
+    Equal = 0x4,
+    /// Signed less than
+    Less = 0xc,
+}
+
+/// Two-operand ALU operations, encoded with their `op r/m32, r32` opcode
+#[derive(Debug, Copy, Clone)]
+pub enum AluOp {
+    Add = 0x01,
+    Or  = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+impl AluOp {
+    /// Opcode of the `op eax, imm32` short form
+    fn eax_imm_opcode(self) -> u8 {
+        self as u8 + 4
+    }
+}
+
+/// Shift operations, encoded with their ModRM `reg` extension
+#[derive(Debug, Copy, Clone)]
+pub enum ShiftOp {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// A forward jump whose 32 bit displacement still has to be resolved
+#[derive(Debug, Copy, Clone)]
+pub struct Fixup(usize);
+
+#[derive(Debug, Default)]
+pub struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.code.push(b);
+    }
+
+    fn bytes(&mut self, bs: &[u8]) {
+        self.code.extend_from_slice(bs);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn imm64(&mut self, imm: u64) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// ModRM byte addressing `[rbx + disp32]`
+    fn rbx_disp(&mut self, reg: u8, disp: u32) {
+        self.byte(0x80 | (reg << 3) | 0x3);
+        self.imm32(disp);
+    }
+
+    /// Save callee-saved registers and move `cpu` (rdi) to rbx and `bus` (rsi) to r12.
+    /// r13d is cleared and used as the retired instruction counter.
+    pub fn prologue(&mut self) {
+        self.bytes(&[0x53]);             // push rbx
+        self.bytes(&[0x41, 0x54]);       // push r12
+        self.bytes(&[0x41, 0x55]);       // push r13
+        self.bytes(&[0x48, 0x89, 0xfb]); // mov rbx, rdi
+        self.bytes(&[0x49, 0x89, 0xf4]); // mov r12, rsi
+        self.bytes(&[0x45, 0x31, 0xed]); // xor r13d, r13d
+    }
+
+    /// Return the retired instruction counter and restore callee-saved registers
+    pub fn epilogue(&mut self) {
+        self.bytes(&[0x44, 0x89, 0xe8]); // mov eax, r13d
+        self.bytes(&[0x41, 0x5d]);       // pop r13
+        self.bytes(&[0x41, 0x5c]);       // pop r12
+        self.bytes(&[0x5b]);             // pop rbx
+        self.byte(0xc3);                 // ret
+    }
+
+    /// inc r13d
+    pub fn count_retired(&mut self) {
+        self.bytes(&[0x41, 0xff, 0xc5]);
+    }
+
+    /// mov reg, [rbx + disp]
+    pub fn load(&mut self, reg: Reg, disp: u32) {
+        self.byte(0x8b);
+        self.rbx_disp(reg as u8, disp);
+    }
+
+    /// mov [rbx + disp], reg
+    pub fn store(&mut self, disp: u32, reg: Reg) {
+        self.byte(0x89);
+        self.rbx_disp(reg as u8, disp);
+    }
+
+    /// mov dword [rbx + disp], imm
+    pub fn store_imm32(&mut self, disp: u32, imm: u32) {
+        self.byte(0xc7);
+        self.rbx_disp(0, disp);
+        self.imm32(imm);
+    }
+
+    /// mov byte [rbx + disp], imm
+    pub fn store_imm8(&mut self, disp: u32, imm: u8) {
+        self.byte(0xc6);
+        self.rbx_disp(0, disp);
+        self.byte(imm);
+    }
+
+    /// mov reg, imm
+    pub fn mov_imm(&mut self, reg: Reg, imm: u32) {
+        self.byte(0xb8 + reg as u8);
+        self.imm32(imm);
+    }
+
+    /// op eax, ecx
+    pub fn alu(&mut self, op: AluOp) {
+        self.bytes(&[op as u8, 0xc8]);
+    }
+
+    /// op eax, imm
+    pub fn alu_imm(&mut self, op: AluOp, imm: u32) {
+        self.byte(op.eax_imm_opcode());
+        self.imm32(imm);
+    }
+
+    /// not eax
+    pub fn not(&mut self) {
+        self.bytes(&[0xf7, 0xd0]);
+    }
+
+    /// shift eax, imm
+    pub fn shift_imm(&mut self, op: ShiftOp, amount: u8) {
+        self.bytes(&[0xc1, 0xc0 | ((op as u8) << 3), amount]);
+    }
+
+    /// shift eax, cl (the hardware masks the amount to 5 bits, as MIPS does)
+    pub fn shift_cl(&mut self, op: ShiftOp) {
+        self.bytes(&[0xd3, 0xc0 | ((op as u8) << 3)]);
+    }
+
+    /// setcc al; movzx eax, al
+    pub fn set_cond(&mut self, cond: Cond) {
+        self.bytes(&[0x0f, 0x90 | cond as u8, 0xc0]);
+        self.bytes(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    /// Call `func(cpu, bus, arg0, arg1, arg2)` following the System V calling convention
+    pub fn call_with_state(&mut self, func: usize, arg0: u32, arg1: u64, arg2: u32) {
+        self.bytes(&[0x48, 0x89, 0xdf]); // mov rdi, rbx
+        self.bytes(&[0x4c, 0x89, 0xe6]); // mov rsi, r12
+        self.byte(0xba);                 // mov edx, arg0
+        self.imm32(arg0);
+        self.bytes(&[0x48, 0xb9]);       // mov rcx, arg1
+        self.imm64(arg1);
+        self.bytes(&[0x41, 0xb8]);       // mov r8d, arg2
+        self.imm32(arg2);
+        self.bytes(&[0x48, 0xb8]);       // mov rax, func
+        self.imm64(func as u64);
+        self.bytes(&[0xff, 0xd0]);       // call rax
+    }
+
+    /// test al, al; jz <unresolved>
+    pub fn jump_if_false(&mut self) -> Fixup {
+        self.bytes(&[0x84, 0xc0]);
+        self.bytes(&[0x0f, 0x84]);
+        let fixup = Fixup(self.code.len());
+        self.imm32(0);
+        fixup
+    }
+
+    /// Point a forward jump at the current position
+    pub fn bind(&mut self, fixup: Fixup) {
+        let Fixup(at) = fixup;
+        let rel = (self.code.len() - (at + 4)) as u32;
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+/// A region of executable memory that compiled blocks are appended to
+pub struct CodeArena {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl CodeArena {
+    pub fn new(size: usize) -> Result<Self> {
+        // SAFETY: anonymous private mapping with no address hint, checked for failure below
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(anyhow!("failed to map {size} bytes of executable memory"));
+        }
+        Ok(CodeArena { base: base as *mut u8, size, used: 0 })
+    }
+
+    /// Copy `code` into the arena and return its address, or `None` if the arena is full
+    pub fn push(&mut self, code: &[u8]) -> Option<*const u8> {
+        if self.used + code.len() > self.size {
+            return None;
+        }
+        // SAFETY: the destination range was checked to lie inside the mapping
+        let dst = unsafe { self.base.add(self.used) };
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len()) };
+
+        // Keep blocks 16 byte aligned
+        self.used = (self.used + code.len() + 15) & !15;
+        Some(dst)
+    }
+
+    /// Forget every block in the arena. Previously returned pointers become dangling.
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeArena {
+    fn drop(&mut self) {
+        // SAFETY: `base` and `size` describe the mapping created in `new`
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
+
+impl std::fmt::Debug for CodeArena {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CodeArena {{ used: {}/{} }}", self.used, self.size)
+    }
+}
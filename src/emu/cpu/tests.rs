@@ -0,0 +1,300 @@
+//! CPU tests, running small assembled programs from RAM
+
+use crate::emu::{
+    bios::{Bios, BIOS_SIZE},
+    bus::Bus,
+    ram::Ram,
+    cpu::{
+        asm::assemble,
+        disasm::REGISTER_NAMES,
+        instruction::RegisterIndex,
+        Cpu,
+        Engine,
+    },
+    trace::Registers,
+};
+
+/// Load address of the test programs
+const ORIGIN: u32 = 0x8001_0000;
+
+/// Assemble `source` at `ORIGIN`, ready to run from there
+fn load(engine: Engine, source: &str) -> (Cpu, Bus) {
+    let mut bus = Bus::new(Ram::new(), Bios::new(&[0; BIOS_SIZE]));
+    let program = assemble(source, ORIGIN).unwrap();
+    for (idx, word) in program.into_iter().enumerate() {
+        bus.store::<u32>(ORIGIN + 4 * idx as u32, word);
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.set_engine(engine).unwrap();
+    cpu.set_pc(ORIGIN);
+    (cpu, bus)
+}
+
+/// Assemble `source` at `ORIGIN` and run it for at least `steps` instructions
+fn run_with(engine: Engine, source: &str, steps: u32) -> (Cpu, Bus) {
+    let (mut cpu, mut bus) = load(engine, source);
+    let mut retired = 0;
+    while retired < steps {
+        retired += cpu.run(&mut bus);
+    }
+    (cpu, bus)
+}
+
+/// Run `source` on the interpreter, checking that the recompiler agrees with it
+fn run(source: &str, steps: u32) -> (Cpu, Bus) {
+    #[cfg(feature = "recompiler")]
+    check_recompiler(source, steps);
+    run_with(Engine::Interpreter, source, steps)
+}
+
+/// Run `source` on the recompiler, then on the interpreter for as many instructions as
+/// the recompiled blocks retired, and compare the CPUs and RAM
+#[cfg(feature = "recompiler")]
+fn check_recompiler(source: &str, steps: u32) {
+    let (mut cpu, mut bus) = load(Engine::Recompiler, source);
+    let mut retired = 0;
+    while retired < steps {
+        retired += cpu.run(&mut bus);
+    }
+    let (expected, expected_bus) = run_with(Engine::Interpreter, source, retired);
+    assert_eq!(Registers::of(&cpu), Registers::of(&expected), "{source}");
+    assert_eq!(cpu.pc(), expected.pc(), "{source}");
+    assert!(bus.ram() == expected_bus.ram(), "RAM differs after {source}");
+}
+
+/// Every engine available in this build
+fn engines() -> Vec<Engine> {
+    vec![
+        Engine::Interpreter,
+        Engine::CachedInterpreter,
+        #[cfg(feature = "recompiler")]
+        Engine::Recompiler,
+    ]
+}
+
+fn reg(cpu: &Cpu, name: &str) -> u32 {
+    let idx = REGISTER_NAMES.iter().position(|reg| *reg == name).unwrap();
+    cpu.reg(RegisterIndex(idx as u32))
+}
+
+#[test]
+fn arithmetic() {
+    let (cpu, _) = run("
+        li   t0, 7
+        li   t1, -3
+        addu t2, t0, t1
+        subu t3, t1, t0
+        slt  t4, t1, t0
+        sltu t5, t1, t0
+        sll  t6, t0, 4
+        sra  t7, t1, 1
+        li   s0, 0x12345678
+    ", 10);
+    assert_eq!(reg(&cpu, "t2"), 4);
+    assert_eq!(reg(&cpu, "t3"), -10i32 as u32);
+    assert_eq!(reg(&cpu, "t4"), 1);
+    assert_eq!(reg(&cpu, "t5"), 0);
+    assert_eq!(reg(&cpu, "t6"), 0x70);
+    assert_eq!(reg(&cpu, "t7"), -2i32 as u32);
+    assert_eq!(reg(&cpu, "s0"), 0x1234_5678);
+}
+
+#[test]
+fn load_delay_slot() {
+    let (cpu, _) = run("
+        la   t0, data
+        li   t1, 1
+        lw   t1, 0(t0)
+        move t2, t1     # still sees the old value
+        move t3, t1
+    end:
+        b    end
+        nop
+    data:
+        .word 0xcafef00d
+    ", 6);
+    assert_eq!(reg(&cpu, "t2"), 1);
+    assert_eq!(reg(&cpu, "t3"), 0xcafe_f00d);
+}
+
+#[test]
+fn branch_delay_slot() {
+    let (cpu, _) = run("
+        li    a0, 5
+        li    v0, 0
+        li    v1, 0
+    loop:
+        addiu v0, v0, 2
+        addiu a0, a0, -1
+        bnez  a0, loop
+        addiu v1, v1, 1     # delay slot, runs on every iteration
+    ", 3 + 5 * 4);
+    assert_eq!(reg(&cpu, "a0"), 0);
+    assert_eq!(reg(&cpu, "v0"), 10);
+    assert_eq!(reg(&cpu, "v1"), 5);
+    assert_eq!(cpu.pc(), ORIGIN + 7 * 4);
+}
+
+#[test]
+fn call_and_return() {
+    let (cpu, _) = run("
+        jal  func
+        li   s0, 1
+        li   s1, 2
+    func:
+        jr   ra
+        li   s2, 3
+    ", 5);
+    assert_eq!(reg(&cpu, "ra"), ORIGIN + 8);
+    assert_eq!((reg(&cpu, "s0"), reg(&cpu, "s1"), reg(&cpu, "s2")), (1, 2, 3));
+    assert_eq!(cpu.pc(), ORIGIN + 12);
+}
+
+#[test]
+fn branch_and_link_on_sign() {
+    let source = "
+        li     t0, 1
+        bltzal t0, skip     # not taken, links anyway
+        nop
+        move   s0, ra
+        li     ra, 0
+        .word  0x04120000   # bltz zero with rt = 0x12, which doesn't link
+        nop
+        move   s1, ra
+        bgezal t0, target
+        nop
+    skip:
+        li     s3, 1
+    target:
+        move   s2, ra
+    ";
+    for engine in engines() {
+        let (cpu, _) = run_with(engine, source, 11);
+        assert_eq!(reg(&cpu, "s0"), ORIGIN + 12, "{engine:?}");
+        assert_eq!(reg(&cpu, "s1"), 0, "{engine:?}");
+        assert_eq!(reg(&cpu, "s2"), ORIGIN + 40, "{engine:?}");
+        assert_eq!(reg(&cpu, "s3"), 0, "{engine:?}");
+    }
+}
+
+#[test]
+fn loads_and_stores() {
+    let (cpu, bus) = run("
+        la   t0, buf
+        li   t1, 0x12348081
+        sw   t1, 0(t0)
+        lb   t2, 0(t0)
+        lbu  t3, 0(t0)
+        lh   t4, 0(t0)
+        lhu  t5, 2(t0)
+        sb   zero, 3(t0)
+        lw   t6, 0(t0)
+        nop
+    buf:
+        .word 0
+    ", 12);
+    assert_eq!(reg(&cpu, "t2"), 0xffff_ff81);
+    assert_eq!(reg(&cpu, "t3"), 0x81);
+    assert_eq!(reg(&cpu, "t4"), 0xffff_8081);
+    assert_eq!(reg(&cpu, "t5"), 0x1234);
+    assert_eq!(reg(&cpu, "t6"), 0x0034_8081);
+    assert_eq!(bus.load::<u32>(ORIGIN + 12 * 4), 0x0034_8081);
+}
+
+#[test]
+fn unaligned_loads() {
+    let (cpu, _) = run("
+        la   t0, data
+        lwr  t1, 1(t0)
+        lwl  t1, 4(t0)
+        nop
+    data:
+        .word 0x44332211, 0x88776655
+    ", 5);
+    assert_eq!(reg(&cpu, "t1"), 0x5544_3322);
+}
+
+#[test]
+fn multiply_and_divide() {
+    let (cpu, _) = run("
+        li    t0, -7
+        li    t1, 2
+        div   t0, t1
+        mflo  s0
+        mfhi  s1
+        mult  t0, t1
+        mflo  s2
+        mfhi  s3
+        multu t0, t1
+        mfhi  s4
+        divu  t1, zero
+    ", 11);
+    assert_eq!(reg(&cpu, "s0"), -3i32 as u32);
+    assert_eq!(reg(&cpu, "s1"), -1i32 as u32);
+    assert_eq!(reg(&cpu, "s2"), -14i32 as u32);
+    assert_eq!(reg(&cpu, "s3"), 0xffff_ffff);
+    assert_eq!(reg(&cpu, "s4"), 1);
+    assert_eq!((cpu.lo(), cpu.hi()), (0xffff_ffff, 2));
+}
+
+#[test]
+fn overflow_raises_exception() {
+    let (mut cpu, mut bus) = run("
+        li   t0, 0x7fffffff
+        li   t1, 5
+        addi t1, t0, 1
+    ", 4);
+    assert_eq!(cpu.pc(), 0x8000_0080);
+    assert_eq!(reg(&cpu, "t1"), 5);
+    assert_eq!(cpu.cop.epc, ORIGIN + 12);
+    let cause = cpu.cop.mfc0(&mut bus, RegisterIndex(13));
+    assert_eq!((cause >> 2) & 0x1f, 0xc);
+    assert_eq!(cpu.entered_exception(ORIGIN + 12), Some(0xc));
+    assert_eq!(cpu.entered_exception(0x8000_0080), None);
+}
+
+#[test]
+fn self_modifying_code() {
+    let source = "
+        la    t0, patch
+        li    t1, 0x24020002    # li v0, 2
+        li    a0, 2
+    patch:
+        li    v0, 1
+        addiu a0, a0, -1
+        sw    t1, 0(t0)
+        bnez  a0, patch
+        nop
+    ";
+    for engine in engines() {
+        let (cpu, _) = run_with(engine, source, 5 + 2 * 5);
+        assert_eq!(reg(&cpu, "v0"), 2, "{engine:?}");
+        assert_eq!(reg(&cpu, "a0"), 0, "{engine:?}");
+    }
+}
+
+#[cfg(feature = "recompiler")]
+#[test]
+fn lockstep_reports_divergence() {
+    use crate::emu::cpu::recompiler::LockStep;
+
+    let (mut cpu, mut bus) = load(Engine::Recompiler, "
+    loop:
+        addiu t0, t0, 1
+        b     loop
+        nop
+    ");
+    cpu.set_reg(RegisterIndex(8), 0);
+    cpu.commit_registers();
+    let mut lockstep = LockStep::new(&cpu, &bus);
+    let retired = cpu.run(&mut bus);
+    assert_eq!(lockstep.check(&cpu, &bus, retired), Ok(()));
+
+    // Behind the shadow interpreter's back
+    cpu.set_reg(RegisterIndex(8), 0x100);
+    cpu.commit_registers();
+    let retired = cpu.run(&mut bus);
+    let report = lockstep.check(&cpu, &bus, retired).unwrap_err();
+    assert!(report.contains("r8: interpreter 0x00000002, recompiler 0x00000101"), "{report}");
+}
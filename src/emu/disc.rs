@@ -0,0 +1,338 @@
+//! CD-ROM disc images and their ISO 9660 filesystem
+//!
+//! Both plain 2048 byte per sector images (.iso) and raw 2352 byte per sector dumps
+//! (.bin) are supported. Only the first data track is used.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::bios::Region;
+
+/// User data bytes in a Mode 1 / Mode 2 Form 1 sector
+pub const SECTOR_SIZE: usize = 2048;
+
+/// Bytes per sector of a raw dump, including sync pattern, header and error correction
+pub const RAW_SECTOR_SIZE: usize = 2352;
+
+/// LBA of the ISO 9660 primary volume descriptor
+const PVD_LBA: u32 = 16;
+
+/// LBA of the license string checked by the BIOS
+const LICENSE_LBA: u32 = 4;
+
+/// Size of a directory record without its file identifier
+const MIN_RECORD_LEN: usize = 34;
+
+/// Most bytes reserved up front when reading a file, as its size comes from the disc
+const MAX_READ_RESERVE: usize = 4 * 1024 * 1024;
+
+const SYNC: [u8; 12] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// Anything a disc image can be read from
+trait Source: Read + Seek {}
+impl<T: Read + Seek> Source for T {}
+
+/// A file found in the ISO 9660 filesystem
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub lba: u32,
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+pub struct Disc {
+    source: Box<dyn Source>,
+    /// Bytes per sector in the image
+    sector_size: u64,
+    /// Offset of the user data inside of a sector
+    data_offset: u64,
+    region: Option<Region>,
+    /// Name of the boot executable, e.g. `SLUS_006.74`
+    id: Option<String>,
+}
+
+impl fmt::Debug for Disc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Disc")
+            .field("sector_size", &self.sector_size)
+            .field("data_offset", &self.data_offset)
+            .field("region", &self.region)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl Disc {
+    pub fn open(path: &Path) -> Result<Disc> {
+        let file = File::open(path)
+            .map_err(|e| anyhow!("failed to open disc image {}: {e}", path.display()))?;
+        let disc = Disc::from_source(Box::new(file))?;
+        tracing::info!("opened disc image {} ({} byte sectors)", path.display(), disc.sector_size);
+        Ok(disc)
+    }
+
+    /// Disc image held in memory
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Disc> {
+        Disc::from_source(Box::new(Cursor::new(buf)))
+    }
+
+    fn from_source(mut source: Box<dyn Source>) -> Result<Disc> {
+        // A raw dump starts every sector with the sync pattern, followed by the mode byte
+        let mut raw_header = [0u8; 16];
+        source.seek(SeekFrom::Start(PVD_LBA as u64 * RAW_SECTOR_SIZE as u64))?;
+        let is_raw = source.read_exact(&mut raw_header).is_ok() && raw_header[..12] == SYNC;
+        let (sector_size, data_offset) = match (is_raw, raw_header[15]) {
+            (false, _) => (SECTOR_SIZE as u64, 0),
+            (true, 1) => (RAW_SECTOR_SIZE as u64, 16),
+            (true, 2) => (RAW_SECTOR_SIZE as u64, 24),
+            (true, mode) => bail!("unsupported sector mode {mode}"),
+        };
+
+        let mut disc = Disc { source, sector_size, data_offset, region: None, id: None };
+        let pvd = disc.read_sector(PVD_LBA)?;
+        if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+            bail!("not an ISO 9660 disc (no primary volume descriptor)");
+        }
+        disc.region = disc.read_sector(LICENSE_LBA).ok().and_then(|sector| license_region(&sector));
+        disc.id = disc.boot_file_name();
+        Ok(disc)
+    }
+
+    /// File name of the executable booted through SYSTEM.CNF
+    fn boot_file_name(&mut self) -> Option<String> {
+        let cnf = self.find("SYSTEM.CNF").ok()??;
+        let cnf = self.read_file(cnf).ok()?;
+        let boot = String::from_utf8_lossy(&cnf).lines().find_map(|line| {
+            let (key, val) = line.split_once('=')?;
+            key.trim().eq_ignore_ascii_case("BOOT").then(|| val.trim().to_string())
+        })?;
+        let path = boot.split_whitespace().next()?;
+        let name = path.rsplit(['\\', '/', ':']).next()?;
+        Some(strip_version(name).to_string())
+    }
+
+    /// ID of the disc: the name of the executable booted through SYSTEM.CNF, which is the
+    /// serial number on licensed discs. `None` if it doesn't have a SYSTEM.CNF.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Region from the license string, `None` for unlicensed (e.g. homebrew) discs
+    pub fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    /// User data of sector `lba`
+    pub fn read_sector(&mut self, lba: u32) -> Result<[u8; SECTOR_SIZE]> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.source.seek(SeekFrom::Start(lba as u64 * self.sector_size + self.data_offset))?;
+        self.source.read_exact(&mut buf)
+            .map_err(|e| anyhow!("failed to read sector {lba}: {e}"))?;
+        Ok(buf)
+    }
+
+    fn root(&mut self) -> Result<DirEntry> {
+        let pvd = self.read_sector(PVD_LBA)?;
+        Ok(parse_record(&pvd[156..190])?.0)
+    }
+
+    /// Look up `path` (e.g. `\SYSTEM.CNF;1` or `MUSIC/TRACK01.XA`). Names are matched
+    /// case-insensitively and the `;1` version suffix is optional.
+    pub fn find(&mut self, path: &str) -> Result<Option<DirEntry>> {
+        let mut entry = self.root()?;
+        for name in path.split(['\\', '/']).filter(|name| !name.is_empty()) {
+            if !entry.is_dir {
+                return Ok(None);
+            }
+            match self.find_in_dir(entry, strip_version(name))? {
+                Some(found) => entry = found,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    fn find_in_dir(&mut self, dir: DirEntry, name: &str) -> Result<Option<DirEntry>> {
+        let sectors = ((dir.size as usize + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32;
+        let end = dir.lba.checked_add(sectors)
+            .ok_or_else(|| anyhow!("directory of {} bytes @ sector {} is out of bounds", dir.size, dir.lba))?;
+        for lba in dir.lba..end {
+            let sector = self.read_sector(lba)?;
+            let mut offset = 0;
+            // Records never cross sector boundaries, a zero length pads to the next sector
+            while offset < SECTOR_SIZE && sector[offset] != 0 {
+                let len = sector[offset] as usize;
+                let Some(record) = sector.get(offset..offset + len) else { break };
+                let (entry, record_name) = parse_record(record)?;
+                if strip_version(record_name).eq_ignore_ascii_case(name) {
+                    return Ok(Some(entry));
+                }
+                offset += len;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whole contents of a file
+    pub fn read_file(&mut self, entry: DirEntry) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity((entry.size as usize).min(MAX_READ_RESERVE));
+        let mut lba = entry.lba;
+        while data.len() < entry.size as usize {
+            let sector = self.read_sector(lba)?;
+            let len = SECTOR_SIZE.min(entry.size as usize - data.len());
+            data.extend_from_slice(&sector[..len]);
+            lba += 1;
+        }
+        Ok(data)
+    }
+}
+
+/// Parse a directory record, returning the entry and its file identifier
+fn parse_record(record: &[u8]) -> Result<(DirEntry, &str)> {
+    if record.len() < MIN_RECORD_LEN {
+        bail!("directory record of {} bytes is too short", record.len());
+    }
+    let word = |offset: usize| u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap());
+    let name_len = record.get(32).copied().unwrap_or(0) as usize;
+    let name = record.get(33..33 + name_len)
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .unwrap_or("");
+    let entry = DirEntry {
+        lba: word(2),
+        size: word(10),
+        is_dir: record[25] & 2 != 0,
+    };
+    Ok((entry, name))
+}
+
+/// "Licensed by Sony Computer Entertainment Inc." for Japan, "...Amer ica" and
+/// "...Euro pe" for the other regions, with the wide spacing of the original
+fn license_region(sector: &[u8]) -> Option<Region> {
+    let text: String = String::from_utf8_lossy(sector).split_whitespace().collect();
+    let licensee = text.split_once("LicensedbySonyComputerEntertainment")?.1;
+    match licensee {
+        _ if licensee.starts_with("America") => Some(Region::NorthAmerica),
+        _ if licensee.starts_with("Europe") => Some(Region::Europe),
+        _ if licensee.starts_with("Inc") => Some(Region::Japan),
+        _ => None,
+    }
+}
+
+fn strip_version(name: &str) -> &str {
+    name.split_once(';').map_or(name, |(name, _)| name)
+}
+
+/// Build a minimal ISO 9660 image with `files` in the root directory, for tests
+#[cfg(test)]
+pub fn build_iso(files: &[(&str, &[u8])]) -> Vec<u8> {
+    const ROOT_LBA: u32 = 18;
+    let record = |lba: u32, size: u32, flags: u8, name: &[u8]| {
+        let len = (33 + name.len() + 1) & !1;
+        let mut rec = vec![0u8; len];
+        rec[0] = len as u8;
+        rec[2..6].copy_from_slice(&lba.to_le_bytes());
+        rec[10..14].copy_from_slice(&size.to_le_bytes());
+        rec[25] = flags;
+        rec[32] = name.len() as u8;
+        rec[33..33 + name.len()].copy_from_slice(name);
+        rec
+    };
+
+    let mut lba = ROOT_LBA + 1;
+    let mut root = [record(ROOT_LBA, SECTOR_SIZE as u32, 2, &[0]), record(ROOT_LBA, SECTOR_SIZE as u32, 2, &[1])].concat();
+    let mut contents = Vec::new();
+    for (name, data) in files {
+        root.extend(record(lba, data.len() as u32, 0, format!("{name};1").as_bytes()));
+        let mut data = data.to_vec();
+        data.resize(((data.len() + SECTOR_SIZE - 1) / SECTOR_SIZE).max(1) * SECTOR_SIZE, 0);
+        lba += (data.len() / SECTOR_SIZE) as u32;
+        contents.extend(data);
+    }
+
+    let mut iso = vec![0u8; ROOT_LBA as usize * SECTOR_SIZE];
+    let pvd = &mut iso[PVD_LBA as usize * SECTOR_SIZE..];
+    pvd[0] = 1;
+    pvd[1..6].copy_from_slice(b"CD001");
+    let root_record = record(ROOT_LBA, SECTOR_SIZE as u32, 2, &[0]);
+    pvd[156..156 + root_record.len()].copy_from_slice(&root_record);
+    root.resize(SECTOR_SIZE, 0);
+    iso.extend(root);
+    iso.extend(contents);
+    iso
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Convert a 2048 byte per sector image into a raw Mode 2 dump
+    fn to_raw(iso: &[u8]) -> Vec<u8> {
+        iso.chunks(SECTOR_SIZE)
+            .flat_map(|data| {
+                let mut sector = vec![0u8; RAW_SECTOR_SIZE];
+                sector[..12].copy_from_slice(&SYNC);
+                sector[15] = 2;
+                sector[24..24 + SECTOR_SIZE].copy_from_slice(data);
+                sector
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_files() {
+        let iso = build_iso(&[("SYSTEM.CNF", b"BOOT = cdrom:\\MAIN.EXE;1\r\n"), ("MAIN.EXE", &[0xaa; 3000])]);
+        for image in [iso.clone(), to_raw(&iso)] {
+            let mut disc = Disc::from_bytes(image).unwrap();
+            let cnf = disc.find("\\system.cnf;1").unwrap().unwrap();
+            assert_eq!(disc.read_file(cnf).unwrap(), b"BOOT = cdrom:\\MAIN.EXE;1\r\n");
+            let exe = disc.find("MAIN.EXE").unwrap().unwrap();
+            assert_eq!((exe.size, exe.is_dir), (3000, false));
+            assert_eq!(disc.read_file(exe).unwrap(), [0xaa; 3000]);
+            assert!(disc.find("MISSING.DAT").unwrap().is_none());
+            assert!(disc.find("MAIN.EXE\\NESTED").unwrap().is_none());
+            assert_eq!(disc.id(), Some("MAIN.EXE"));
+        }
+    }
+
+    #[test]
+    fn reads_license_region() {
+        let mut iso = build_iso(&[]);
+        let license = b"          Licensed  by          Sony Computer Entertainment Euro pe   ";
+        iso[LICENSE_LBA as usize * SECTOR_SIZE..][..license.len()].copy_from_slice(license);
+        assert_eq!(Disc::from_bytes(iso).unwrap().region(), Some(Region::Europe));
+        assert_eq!(Disc::from_bytes(build_iso(&[])).unwrap().region(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_directories() {
+        const ROOT: usize = 18 * SECTOR_SIZE;
+        let iso = build_iso(&[("MAIN.EXE", &[0xaa; 16])]);
+
+        // The record of MAIN.EXE, after "." and "..", cut short
+        let mut short = iso.clone();
+        short[ROOT + 2 * MIN_RECORD_LEN] = 20;
+        assert!(Disc::from_bytes(short).unwrap().find("MAIN.EXE").is_err());
+
+        let mut far = iso.clone();
+        far[PVD_LBA as usize * SECTOR_SIZE + 156 + 2..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Disc::from_bytes(far).unwrap().find("MAIN.EXE").is_err());
+
+        let mut huge = iso;
+        huge[ROOT + 2 * MIN_RECORD_LEN + 10..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut disc = Disc::from_bytes(huge).unwrap();
+        let exe = disc.find("MAIN.EXE").unwrap().unwrap();
+        assert!(disc.read_file(exe).is_err());
+    }
+
+    #[test]
+    fn rejects_other_images() {
+        assert!(Disc::from_bytes(vec![0; 20 * SECTOR_SIZE]).is_err());
+        assert!(Disc::from_bytes(vec![]).is_err());
+    }
+}
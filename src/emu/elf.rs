@@ -0,0 +1,294 @@
+//! ELF32 little-endian MIPS executables, as produced by homebrew toolchains
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::{
+    exe::{check_in_ram, Image},
+    symbols::{Symbol, SymbolTable},
+};
+
+pub const MAGIC: &[u8; 4] = b"\x7fELF";
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+
+const PT_LOAD: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_MIPS_REGINFO: u32 = 0x7000_0006;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// A `PT_LOAD` segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u32,
+    /// Contents from the file. The remaining `mem_size - data.len()` bytes are zeroed.
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: u32,
+    /// Global pointer, from the `_gp` symbol or the `.reginfo` section
+    pub gp: Option<u32>,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+/// Little-endian field reader with bounds checking
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        offset.checked_add(len)
+            .and_then(|end| self.0.get(offset..end))
+            .ok_or_else(|| anyhow!("truncated ELF: {len} bytes @ 0x{offset:x} out of bounds"))
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+
+    /// NUL terminated string starting at `offset`
+    fn str(&self, offset: usize) -> Result<String> {
+        let tail = self.0.get(offset..).ok_or_else(|| anyhow!("string @ 0x{offset:x} out of bounds"))?;
+        let len = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+}
+
+/// Section header fields used by the loader
+struct Section {
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+impl Elf {
+    pub fn is_elf(buf: &[u8]) -> bool {
+        buf.starts_with(MAGIC)
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Elf> {
+        let r = Reader(buf);
+        if !Elf::is_elf(buf) {
+            bail!("not an ELF file");
+        }
+        if r.bytes(4, 2)? != [ELFCLASS32, ELFDATA2LSB] {
+            bail!("unsupported ELF: only 32 bit little-endian files can be loaded");
+        }
+        if r.u16(16)? != ET_EXEC || r.u16(18)? != EM_MIPS {
+            bail!("unsupported ELF: not a MIPS executable");
+        }
+
+        let entry = r.u32(24)?;
+        let ph_offset = r.u32(28)? as usize;
+        let sh_offset = r.u32(32)? as usize;
+        let ph_size = r.u16(42)? as usize;
+        let ph_count = r.u16(44)? as usize;
+        let sh_size = r.u16(46)? as usize;
+        let sh_count = r.u16(48)? as usize;
+
+        let mut segments = Vec::new();
+        for idx in 0..ph_count {
+            let ph = ph_offset + idx * ph_size;
+            if r.u32(ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = r.u32(ph + 4)? as usize;
+            let addr = r.u32(ph + 8)?;
+            let file_size = r.u32(ph + 16)?;
+            let mem_size = r.u32(ph + 20)?;
+            check_in_ram(&format!("ELF segment {idx}"), addr, file_size.max(mem_size))?;
+            segments.push(Segment {
+                addr,
+                data: r.bytes(offset, file_size as usize)?.to_vec(),
+                mem_size,
+            });
+        }
+
+        let sections = (0..sh_count)
+            .map(|idx| {
+                let sh = sh_offset + idx * sh_size;
+                Ok(Section {
+                    kind: r.u32(sh + 4)?,
+                    offset: r.u32(sh + 16)? as usize,
+                    size: r.u32(sh + 20)? as usize,
+                    link: r.u32(sh + 24)? as usize,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut symbols = Vec::new();
+        for symtab in sections.iter().filter(|section| section.kind == SHT_SYMTAB) {
+            let strtab = sections.get(symtab.link)
+                .ok_or_else(|| anyhow!("symbol table links to missing section {}", symtab.link))?;
+            // Entry 0 is always the null symbol
+            for entry in (symtab.offset..symtab.offset + symtab.size).step_by(16).skip(1) {
+                let kind = r.bytes(entry + 12, 1)?[0] & 0xf;
+                let section_idx = r.u16(entry + 14)?;
+                if !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) || section_idx == 0 {
+                    continue;
+                }
+                let name = r.str(strtab.offset + r.u32(entry)? as usize)?;
+                // Skip compiler generated local labels
+                if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                    continue;
+                }
+                symbols.push(Symbol { addr: r.u32(entry + 4)?, size: r.u32(entry + 8)?, name });
+            }
+        }
+        let symbols = SymbolTable::new(symbols);
+
+        // `_gp` is what the linker uses, `.reginfo` is the fallback for stripped files
+        let reginfo_gp = sections.iter()
+            .find(|section| section.kind == SHT_MIPS_REGINFO)
+            .map(|section| r.u32(section.offset + 20))
+            .transpose()?;
+        let gp = symbols.find("_gp").or(reginfo_gp);
+
+        Ok(Elf { entry, gp, segments, symbols })
+    }
+
+    pub fn image(&self) -> Image {
+        Image {
+            entry: self.entry,
+            gp: self.gp,
+            sp: None,
+            segments: self.segments.iter()
+                .map(|segment| (segment.addr, segment.data.clone()))
+                .collect(),
+            bss: self.segments.iter()
+                .filter(|segment| segment.mem_size as usize > segment.data.len())
+                .map(|segment| {
+                    // Parsing checked that the segment fits in RAM
+                    let file_size = segment.data.len() as u32;
+                    (segment.addr.wrapping_add(file_size), segment.mem_size - file_size)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::symbols::Location;
+
+    /// Build an executable with one segment and a symbol table
+    fn build_elf(code: &[u8], symbols: &[(&str, u32, u32, u8)]) -> Vec<u8> {
+        const EHDR: usize = 52;
+        const PHDR: usize = 32;
+        const SHDR: usize = 40;
+        let put16 = |buf: &mut Vec<u8>, at: usize, val: u16| buf[at..at + 2].copy_from_slice(&val.to_le_bytes());
+        let put32 = |buf: &mut Vec<u8>, at: usize, val: u32| buf[at..at + 4].copy_from_slice(&val.to_le_bytes());
+
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 16];
+        for &(name, addr, size, kind) in symbols {
+            let mut sym = vec![0u8; 16];
+            put32(&mut sym, 0, strtab.len() as u32);
+            put32(&mut sym, 4, addr);
+            put32(&mut sym, 8, size);
+            sym[12] = kind;
+            put16(&mut sym, 14, 1);
+            symtab.extend(sym);
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let code_offset = EHDR + PHDR;
+        let symtab_offset = code_offset + code.len();
+        let strtab_offset = symtab_offset + symtab.len();
+        let sh_offset = strtab_offset + strtab.len();
+
+        let mut buf = vec![0u8; sh_offset + 3 * SHDR];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4] = ELFCLASS32;
+        buf[5] = ELFDATA2LSB;
+        put16(&mut buf, 16, ET_EXEC);
+        put16(&mut buf, 18, EM_MIPS);
+        put32(&mut buf, 24, 0x8001_0000);
+        put32(&mut buf, 28, EHDR as u32);
+        put32(&mut buf, 32, sh_offset as u32);
+        put16(&mut buf, 42, PHDR as u16);
+        put16(&mut buf, 44, 1);
+        put16(&mut buf, 46, SHDR as u16);
+        put16(&mut buf, 48, 3);
+
+        put32(&mut buf, EHDR, PT_LOAD);
+        put32(&mut buf, EHDR + 4, code_offset as u32);
+        put32(&mut buf, EHDR + 8, 0x8001_0000);
+        put32(&mut buf, EHDR + 16, code.len() as u32);
+        put32(&mut buf, EHDR + 20, code.len() as u32 + 0x100);
+        buf[code_offset..symtab_offset].copy_from_slice(code);
+        buf[symtab_offset..strtab_offset].copy_from_slice(&symtab);
+        buf[strtab_offset..sh_offset].copy_from_slice(&strtab);
+
+        // Section 0 is null, 1 is .symtab linked to 2, .strtab
+        let sh = sh_offset + SHDR;
+        put32(&mut buf, sh + 4, SHT_SYMTAB);
+        put32(&mut buf, sh + 16, symtab_offset as u32);
+        put32(&mut buf, sh + 20, symtab.len() as u32);
+        put32(&mut buf, sh + 24, 2);
+        let sh = sh_offset + 2 * SHDR;
+        put32(&mut buf, sh + 4, 3);
+        put32(&mut buf, sh + 16, strtab_offset as u32);
+        put32(&mut buf, sh + 20, strtab.len() as u32);
+        buf
+    }
+
+    #[test]
+    fn parses_segments_and_symbols() {
+        let buf = build_elf(&[0; 16], &[
+            ("main", 0x8001_0000, 8, STT_FUNC),
+            ("helper", 0x8001_0008, 8, STT_FUNC),
+            ("_gp", 0x8001_8000, 0, STT_NOTYPE),
+            ("$L1", 0x8001_0004, 0, STT_NOTYPE),
+        ]);
+        let elf = Elf::parse(&buf).unwrap();
+        assert_eq!(elf.entry, 0x8001_0000);
+        assert_eq!(elf.gp, Some(0x8001_8000));
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].addr, 0x8001_0000);
+        assert_eq!(elf.image().bss, [(0x8001_0010, 0x100)]);
+
+        assert_eq!(elf.symbols.len(), 3);
+        let (sym, offset) = elf.symbols.lookup(0x8001_000c).unwrap();
+        assert_eq!((sym.name.as_str(), offset), ("helper", 4));
+        assert!(elf.symbols.lookup(0x8000_fffc).is_none());
+        assert_eq!(Location::new(0x8001_000c, Some(&elf.symbols)).to_string(), "0x8001000c <helper+0x4>");
+        assert_eq!(Location::new(0x8001_0000, Some(&elf.symbols)).to_string(), "0x80010000 <main>");
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Elf::parse(b"PS-X EXE").is_err());
+        let mut buf = build_elf(&[], &[]);
+        buf[18] = 3; // x86
+        assert!(Elf::parse(&buf).is_err());
+        assert!(Elf::parse(&buf[..40]).is_err());
+
+        // Segments outside of RAM
+        let error = |addr: u32, mem_size: u32| {
+            let mut buf = build_elf(&[0; 16], &[]);
+            buf[52 + 8..52 + 12].copy_from_slice(&addr.to_le_bytes());
+            buf[52 + 20..52 + 24].copy_from_slice(&mem_size.to_le_bytes());
+            Elf::parse(&buf).unwrap_err().to_string()
+        };
+        assert_eq!(error(0xbfc0_0000, 16), "ELF segment 0 at 0xbfc00000..0xbfc00010 isn't in RAM");
+        assert_eq!(error(0x801f_fff0, 0x100), "ELF segment 0 at 0x801ffff0..0x802000f0 isn't in RAM");
+        assert_eq!(error(0xffff_fff8, 16), "ELF segment 0 at 0xfffffff8 (16 bytes) wraps around the address space");
+    }
+}
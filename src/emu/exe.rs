@@ -0,0 +1,336 @@
+//! PS-X EXE executables, as found on discs or produced by homebrew toolchains
+//!
+//! The file starts with a 2 KiB header, followed by the text section which is copied as
+//! is to its load address in RAM.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::{
+    bus::Bus,
+    cpu::{instruction::RegisterIndex, Cpu},
+    elf::Elf,
+    ram::RAM_SIZE,
+    state::{self, StateReader, StateWriter},
+    symbols::SymbolTable,
+};
+
+/// Size of the header preceding the text section
+pub const HEADER_SIZE: usize = 0x800;
+
+const MAGIC: &[u8; 8] = b"PS-X EXE";
+
+/// Where main RAM starts in KUSEG, KSEG0 and KSEG1
+const RAM_BASES: [u32; 3] = [0x0000_0000, 0x8000_0000, 0xa000_0000];
+
+/// Check that the `len` bytes at `addr`, which the executable calls `what`, are in main RAM
+pub fn check_in_ram(what: &str, addr: u32, len: u32) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let Some(end) = addr.checked_add(len) else {
+        bail!("{what} at 0x{addr:08x} ({len} bytes) wraps around the address space");
+    };
+    let in_ram = RAM_BASES.iter().any(|&base| addr >= base && end - base <= RAM_SIZE as u32);
+    if !in_ram {
+        bail!("{what} at 0x{addr:08x}..0x{end:08x} isn't in RAM");
+    }
+    Ok(())
+}
+
+/// Entry point of the BIOS shell, where the kernel is fully initialized and an EXE can
+/// be injected in place of the shell
+pub const SHELL_ENTRY: u32 = 0x8003_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exe {
+    /// Initial program counter
+    pub pc: u32,
+    /// Initial global pointer (r28)
+    pub gp: u32,
+    /// Load address of the text section
+    pub text_addr: u32,
+    /// Text section, padded to a multiple of 2 KiB
+    pub text: Vec<u8>,
+    /// Start of the region to clear before running
+    pub bss_addr: u32,
+    pub bss_size: u32,
+    /// Initial stack pointer (r29 and r30), if not left to the BIOS
+    pub sp: Option<u32>,
+    /// Region marker, e.g. "Sony Computer Entertainment Inc. for North America area"
+    pub marker: String,
+}
+
+impl Exe {
+    pub fn parse(buf: &[u8]) -> Result<Exe> {
+        if buf.len() < HEADER_SIZE || &buf[..MAGIC.len()] != MAGIC {
+            bail!("not a PS-X EXE (missing header)");
+        }
+        let word = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+
+        let text_size = word(0x1c) as usize;
+        let Some(text) = buf.get(HEADER_SIZE..HEADER_SIZE + text_size) else {
+            bail!("truncated PS-X EXE: header declares {text_size} bytes of text, file has {}",
+                buf.len() - HEADER_SIZE);
+        };
+
+        let text_addr = word(0x18);
+        let (bss_addr, bss_size) = (word(0x28), word(0x2c));
+        check_in_ram("PS-X EXE text", text_addr, text.len() as u32)?;
+        check_in_ram("PS-X EXE BSS", bss_addr, bss_size)?;
+
+        let stack_base = word(0x30);
+        let sp = match stack_base {
+            0 => None,
+            base => Some(base.wrapping_add(word(0x34))),
+        };
+
+        let marker = &buf[0x4c..HEADER_SIZE];
+        let marker_len = marker.iter().position(|&b| b == 0).unwrap_or(marker.len());
+
+        Ok(Exe {
+            pc: word(0x10),
+            gp: word(0x14),
+            text_addr,
+            text: text.to_vec(),
+            bss_addr,
+            bss_size,
+            sp,
+            marker: String::from_utf8_lossy(&marker[..marker_len]).into_owned(),
+        })
+    }
+
+    pub fn image(&self) -> Image {
+        Image {
+            entry: self.pc,
+            gp: Some(self.gp),
+            sp: self.sp,
+            segments: vec![(self.text_addr, self.text.clone())],
+            bss: match self.bss_size {
+                0 => vec![],
+                size => vec![(self.bss_addr, size)],
+            },
+        }
+    }
+}
+
+/// An executable (PS-X EXE or ELF) ready to be copied into memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub entry: u32,
+    /// Initial global pointer (r28), if the executable defines one
+    pub gp: Option<u32>,
+    /// Initial stack pointer (r29 and r30), if not left to the BIOS
+    pub sp: Option<u32>,
+    /// Contents to copy, by load address
+    pub segments: Vec<(u32, Vec<u8>)>,
+    /// Regions to clear, as (address, size)
+    pub bss: Vec<(u32, u32)>,
+}
+
+impl state::Snapshot for Image {
+    fn save(&self, w: &mut StateWriter) {
+        w.u32(self.entry);
+        w.option_u32(self.gp);
+        w.option_u32(self.sp);
+        w.u32(self.segments.len() as u32);
+        for (addr, data) in &self.segments {
+            w.u32(*addr);
+            w.bytes(data);
+        }
+        w.u32(self.bss.len() as u32);
+        for &(addr, size) in &self.bss {
+            w.u32(addr);
+            w.u32(size);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        self.entry = r.u32()?;
+        self.gp = r.option_u32()?;
+        self.sp = r.option_u32()?;
+        let len = r.u32()?;
+        self.segments = (0..len).map(|_| Ok((r.u32()?, r.bytes()?))).collect::<Result<_>>()?;
+        let len = r.u32()?;
+        self.bss = (0..len).map(|_| Ok((r.u32()?, r.u32()?))).collect::<Result<_>>()?;
+        Ok(())
+    }
+}
+
+impl Image {
+    /// Copy the segments into memory and clear the BSS
+    pub fn load(&self, bus: &mut Bus) {
+        for (addr, data) in &self.segments {
+            tracing::debug!("loading {} bytes @ 0x{addr:08x}", data.len());
+            let mut addr = *addr;
+            for chunk in data.chunks(4) {
+                match chunk.try_into() {
+                    Ok(word) => bus.store::<u32>(addr, u32::from_le_bytes(word)),
+                    Err(_) => for (i, &byte) in chunk.iter().enumerate() {
+                        bus.store::<u8>(addr + i as u32, byte);
+                    },
+                }
+                addr = addr.wrapping_add(4);
+            }
+        }
+        for &(addr, size) in &self.bss {
+            for offset in 0..size {
+                bus.store::<u8>(addr.wrapping_add(offset), 0);
+            }
+        }
+    }
+
+    /// Point the CPU at the entry point and set up gp/sp
+    pub fn start(&self, cpu: &mut Cpu) {
+        cpu.set_pc(self.entry);
+        if let Some(gp) = self.gp {
+            cpu.write_reg(RegisterIndex(28), gp);
+        }
+        if let Some(sp) = self.sp {
+            cpu.write_reg(RegisterIndex(29), sp);
+            cpu.write_reg(RegisterIndex(30), sp);
+        }
+    }
+}
+
+/// Load a PS-X EXE or ELF executable, along with its symbols (ELF only)
+pub fn load_executable(path: &Path) -> Result<(Image, SymbolTable)> {
+    let buf = fs::read(path)
+        .map_err(|e| anyhow!("failed to read executable {}: {e}", path.display()))?;
+    if Elf::is_elf(&buf) {
+        let elf = Elf::parse(&buf)?;
+        tracing::info!("loaded ELF {} ({} symbols)", path.display(), elf.symbols.len());
+        Ok((elf.image(), elf.symbols))
+    } else {
+        let exe = Exe::parse(&buf)?;
+        tracing::info!("loaded PS-X EXE {} ({})", path.display(), exe.marker);
+        Ok((exe.image(), SymbolTable::default()))
+    }
+}
+
+/// How a sideloaded executable gets started
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExeBoot {
+    /// Boot the BIOS normally and replace the shell with the executable when it is entered
+    Intercept,
+    /// Skip the BIOS and jump to the executable right away. Kernel calls won't work.
+    Fast,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{
+        bios::BIOS_SIZE,
+        cpu::asm::assemble,
+        Psx,
+    };
+
+    const ORIGIN: u32 = 0x8001_0000;
+
+    fn header(text_size: u32) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_SIZE];
+        buf[..8].copy_from_slice(MAGIC);
+        for (offset, val) in [
+            (0x10, ORIGIN),
+            (0x14, 0x8002_0000),
+            (0x18, ORIGIN),
+            (0x1c, text_size),
+            (0x28, 0x8001_1000),
+            (0x2c, 0x100),
+            (0x30, 0x801f_ff00),
+            (0x34, 0xf0),
+        ] {
+            buf[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(val));
+        }
+        buf[0x4c..0x4f].copy_from_slice(b"SCE");
+        buf
+    }
+
+    /// EXE storing 0x1234 right after its code, then spinning
+    fn test_exe() -> Exe {
+        let program = assemble("
+                li   t0, 0x1234
+                la   t1, result
+                sw   t0, 0(t1)
+            spin:
+                b    spin
+                nop
+            result:
+                .word 0
+        ", ORIGIN).unwrap();
+        let mut buf = header(0x800);
+        buf.extend(program.iter().flat_map(|word| word.to_le_bytes()));
+        buf.resize(HEADER_SIZE + 0x800, 0);
+        Exe::parse(&buf).unwrap()
+    }
+
+    fn run_until_spinning(psx: &mut Psx, max_instructions: u64) {
+        let spin = ORIGIN + 4 * 4;
+        while psx.cpu().pc() != spin {
+            assert!(psx.instructions_retired < max_instructions, "EXE never ran");
+            psx.step();
+        }
+    }
+
+    #[test]
+    fn parses_header() {
+        let mut buf = header(0x800);
+        buf.extend(std::iter::repeat(0xaa).take(0x800));
+        let exe = Exe::parse(&buf).unwrap();
+        assert_eq!((exe.pc, exe.gp, exe.text_addr), (ORIGIN, 0x8002_0000, ORIGIN));
+        assert_eq!((exe.bss_addr, exe.bss_size), (0x8001_1000, 0x100));
+        assert_eq!(exe.sp, Some(0x801f_fff0));
+        assert_eq!(exe.text.len(), 0x800);
+        assert_eq!(exe.marker, "SCE");
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(Exe::parse(b"PS-X EXE").is_err());
+        assert!(Exe::parse(&header(0x800)).is_err());
+        let mut buf = header(0);
+        buf[0] = b'X';
+        assert!(Exe::parse(&buf).is_err());
+
+        // Text and BSS outside of RAM
+        let error = |patch: &[(usize, u32)]| {
+            let mut buf = header(0x800);
+            buf.resize(HEADER_SIZE + 0x800, 0);
+            for &(offset, val) in patch {
+                buf[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+            }
+            Exe::parse(&buf).unwrap_err().to_string()
+        };
+        assert_eq!(error(&[(0x18, 0xbfc0_0000)]), "PS-X EXE text at 0xbfc00000..0xbfc00800 isn't in RAM");
+        assert_eq!(error(&[(0x18, 0x801f_fc00)]), "PS-X EXE text at 0x801ffc00..0x80200400 isn't in RAM");
+        assert_eq!(error(&[(0x28, 0x1f80_0000)]), "PS-X EXE BSS at 0x1f800000..0x1f800100 isn't in RAM");
+        assert_eq!(error(&[(0x28, 0xffff_ff00), (0x2c, 0x200)]),
+            "PS-X EXE BSS at 0xffffff00 (512 bytes) wraps around the address space");
+        assert!(check_in_ram("text", 0xa01f_f800, 0x800).is_ok());
+        assert!(check_in_ram("text", 0x7fff_f000, 0x2000).is_err());
+    }
+
+    #[test]
+    fn fast_boot() {
+        let mut psx = Psx::new_from_bios(&[0; BIOS_SIZE]);
+        psx.sideload(test_exe().image(), ExeBoot::Fast);
+        run_until_spinning(&mut psx, 100);
+
+        assert_eq!(psx.bus().load::<u32>(ORIGIN + 6 * 4), 0x1234);
+        assert_eq!(psx.cpu().reg(RegisterIndex(28)), 0x8002_0000);
+        assert_eq!(psx.cpu().reg(RegisterIndex(29)), 0x801f_fff0);
+        assert_eq!(psx.bus().load::<u32>(0x8001_1000), 0);
+    }
+
+    #[test]
+    fn intercepts_bios_shell() {
+        let bios = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let mut psx = Psx::new_from_bios(bios.as_slice().try_into().unwrap());
+        psx.sideload(test_exe().image(), ExeBoot::Intercept);
+        run_until_spinning(&mut psx, 10_000_000);
+
+        assert_eq!(psx.bus().load::<u32>(ORIGIN + 6 * 4), 0x1234);
+    }
+}
@@ -0,0 +1,115 @@
+//! Expansion region 1: the parallel port cartridge (cheat carts, Caetla, Xplorer, Unirom)
+//!
+//! The BIOS looks for "Licensed by Sony Computer Entertainment Inc." at 0x1f000084 and
+//! 0x1f000004 and, when found, calls the entry point stored in the word in front of it.
+//! Writes are ignored, so flashing a cartridge isn't supported.
+
+use anyhow::{bail, Result};
+
+/// Largest cartridge ROM, which is the whole region
+pub const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+/// Cartridge ID checked by the BIOS
+const LICENSE: &[u8] = b"Licensed by Sony Computer Entertainment Inc.";
+
+/// Offsets of the IDs the BIOS checks, each after an entry point
+const ID_OFFSETS: [usize; 2] = [0x84, 0x04];
+
+#[derive(Debug, Clone, Default)]
+pub struct Exp1 {
+    /// Cartridge ROM, padded to a power of two with erased (0xff) bytes
+    rom: Option<Vec<u8>>,
+}
+
+impl Exp1 {
+    pub fn new() -> Self {
+        Exp1::default()
+    }
+
+    /// Plug in a cartridge with `rom`
+    pub fn insert(&mut self, mut rom: Vec<u8>) -> Result<()> {
+        if rom.is_empty() || rom.len() > MAX_ROM_SIZE {
+            bail!("expansion ROM must be between 1 byte and 8 MiB, not {} bytes", rom.len());
+        }
+        if !ID_OFFSETS.iter().any(|&offset| rom.get(offset..offset + LICENSE.len()) == Some(LICENSE)) {
+            tracing::warn!("expansion ROM has no license header, the BIOS won't run it");
+        }
+        rom.resize(rom.len().next_power_of_two(), 0xff);
+        self.rom = Some(rom);
+        Ok(())
+    }
+
+    /// Unplug the cartridge
+    pub fn remove(&mut self) {
+        self.rom = None;
+    }
+
+    pub fn is_inserted(&self) -> bool {
+        self.rom.is_some()
+    }
+
+    /// Read the byte at `offset` in the region, which is only decoded within the
+    /// `window` bytes set up in MEM_CTL. The ROM is mirrored across the window.
+    pub fn load(&self, offset: u32, window: u32) -> u8 {
+        match &self.rom {
+            Some(rom) if offset < window => rom[offset as usize & (rom.len() - 1)],
+            Some(_) => 0xff,
+            None => 0,
+        }
+    }
+
+    pub fn store(&mut self, offset: u32, val: u8) {
+        tracing::warn!("wrote 0x{val:02x} to expansion region 1 (offset 0x{offset:x}), but cartridge writes are unsupported");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{cpu::asm::assemble, Psx};
+
+    #[test]
+    fn mirrors_rom() {
+        let mut rom = vec![0u8; 0x1_8000];
+        rom[0x84..0x84 + LICENSE.len()].copy_from_slice(LICENSE);
+        rom[0] = 0x12;
+
+        let mut exp1 = Exp1::new();
+        assert_eq!(exp1.load(0x84, 0x8_0000), 0);
+        exp1.insert(rom).unwrap();
+        assert_eq!(exp1.load(0x84, 0x8_0000), b'L');
+        // Padded to 128 KiB, then mirrored across the 512 KiB window
+        assert_eq!(exp1.load(0x1_8000, 0x8_0000), 0xff);
+        assert_eq!(exp1.load(0x2_0000, 0x8_0000), 0x12);
+        assert_eq!(exp1.load(0x8_0000, 0x8_0000), 0xff);
+        assert!(exp1.insert(vec![]).is_err());
+    }
+
+    #[test]
+    fn bios_runs_cartridge() {
+        let mut rom = vec![0u8; 0x200];
+        rom[0x84..0x84 + LICENSE.len()].copy_from_slice(LICENSE);
+        let code = assemble("
+            lui t0, 0x8001
+            li t1, 0xcafe
+            sw t1, 0(t0)
+            jr ra
+            nop
+        ", 0x1f00_0100).unwrap();
+        rom[0x80..0x84].copy_from_slice(&0x1f00_0100u32.to_le_bytes());
+        for (idx, word) in code.iter().enumerate() {
+            rom[0x100 + idx * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        let bios = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let mut psx = Psx::new_from_bios(bios.as_slice().try_into().unwrap());
+        psx.insert_expansion_rom(rom).unwrap();
+        for _ in 0..5_000_000 {
+            psx.step();
+            if psx.bus().load::<u32>(0x8001_0000) == 0xcafe {
+                return;
+            }
+        }
+        panic!("the BIOS didn't run the cartridge");
+    }
+}
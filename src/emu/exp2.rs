@@ -0,0 +1,116 @@
+//! Expansion region 2: the dev-kit DUART used as a TTY console, and the POST register
+//!
+//! Only the transmit side of the DUART (an SCN2681) is emulated: both channels are
+//! always ready, and the characters sent on channel A make up the TTY output.
+
+use std::collections::VecDeque;
+
+use crate::emu::state::{Snapshot, StateReader, StateWriter};
+
+/// Channel A status register (read), clock select (write)
+const DUART_SRA: u32 = 0x21;
+/// Channel A command register
+const DUART_CRA: u32 = 0x22;
+/// Channel A receive holding register (read), transmit holding register (write)
+const DUART_THRA: u32 = 0x23;
+const DUART_SRB: u32 = 0x29;
+const DUART_THRB: u32 = 0x2b;
+/// 7-segment display showing the boot progress
+const POST: u32 = 0x41;
+
+/// Status register bits: transmitter ready and empty
+const SR_TX_READY: u8 = 1 << 2;
+const SR_TX_EMPTY: u8 = 1 << 3;
+
+/// Output is kept up to this size when nobody reads it, dropping the oldest bytes
+const TTY_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct Exp2 {
+    /// The DUART registers that aren't emulated, as last written
+    duart: [u8; 16],
+    tty: VecDeque<u8>,
+    post: u8,
+}
+
+/// The TTY output is the host's, it isn't part of the state
+impl Snapshot for Exp2 {
+    fn save(&self, w: &mut StateWriter) {
+        w.raw(&self.duart);
+        w.u8(self.post);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        let duart = r.raw(self.duart.len())?;
+        self.duart.copy_from_slice(duart);
+        self.post = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Exp2 {
+    pub fn new() -> Self {
+        Exp2::default()
+    }
+
+    /// Read the byte register at `offset` in the region
+    pub fn load(&self, offset: u32) -> u8 {
+        match offset {
+            DUART_SRA | DUART_SRB => SR_TX_READY | SR_TX_EMPTY,
+            DUART_THRA | DUART_THRB => 0, // Nothing is ever received
+            0x20..=0x2f => self.duart[offset as usize - 0x20],
+            POST => self.post,
+            _ => {
+                tracing::warn!("read from expansion region 2 (offset 0x{offset:x}), but this is unimplemented");
+                0
+            },
+        }
+    }
+
+    /// Write the byte register at `offset` in the region
+    pub fn store(&mut self, offset: u32, val: u8) {
+        match offset {
+            DUART_THRA => {
+                self.tty.push_back(val);
+                let excess = self.tty.len().saturating_sub(TTY_CAPACITY);
+                self.tty.drain(..excess);
+            },
+            DUART_THRB => tracing::debug!("DUART channel B: {:?}", val as char),
+            DUART_CRA if val & 0x0c == 0x04 => tracing::debug!("DUART channel A transmitter enabled"),
+            0x20..=0x2f => self.duart[offset as usize - 0x20] = val,
+            POST => {
+                tracing::info!("POST: {val:02x}");
+                self.post = val;
+            },
+            _ => tracing::warn!("wrote to expansion region 2 (offset 0x{offset:x}), but this is unimplemented"),
+        }
+    }
+
+    /// Take the characters sent to the DUART since the last call
+    pub fn take_tty(&mut self) -> Vec<u8> {
+        self.tty.drain(..).collect()
+    }
+
+    /// Value last written to the POST display
+    pub fn post(&self) -> u8 {
+        self.post
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duart_tty() {
+        let mut exp2 = Exp2::new();
+        assert_eq!(exp2.load(DUART_SRA) & SR_TX_READY, SR_TX_READY);
+        for c in b"hi\n" {
+            exp2.store(DUART_THRA, *c);
+        }
+        exp2.store(POST, 0x07);
+        assert_eq!(exp2.take_tty(), b"hi\n");
+        assert!(exp2.take_tty().is_empty());
+        assert_eq!(exp2.post(), 0x07);
+    }
+}
@@ -0,0 +1,796 @@
+//! GPU: VRAM, the GP0 drawing commands, the GP1 display control and the video timing
+//!
+//! Drawing covers fills, VRAM transfers, and untextured polygons and rectangles.
+//! Textured primitives and lines are parsed, so that the command stream stays in sync,
+//! but not drawn. Video timing is counted in CPU instructions, and since DMA isn't
+//! emulated yet, only the commands written to the GP0 port are seen.
+
+use std::cell::Cell;
+
+use anyhow::{bail, Result};
+
+use crate::emu::{
+    bios::VideoStandard,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+pub const VRAM_WIDTH: usize = 1024;
+pub const VRAM_HEIGHT: usize = 512;
+
+/// CPU clock in Hz
+const CPU_CLOCK: u64 = 33_868_800;
+
+/// Average CPU cycles an instruction takes, assumed everywhere instead of real timings
+pub const CYCLES_PER_INSTRUCTION: u64 = 2;
+
+/// Approximate instructions per second of emulated time
+pub const INSTRUCTIONS_PER_SECOND: u64 = CPU_CLOCK / CYCLES_PER_INSTRUCTION;
+
+/// Version reported by GP1(10h) (the original 160-pin GPU)
+const GPU_VERSION: u32 = 2;
+
+/// Frames per second
+pub fn frame_rate(standard: VideoStandard) -> u32 {
+    match standard {
+        VideoStandard::Ntsc => 60,
+        VideoStandard::Pal => 50,
+    }
+}
+
+/// Approximate instructions per video frame
+pub fn frame_instructions(standard: VideoStandard) -> u64 {
+    INSTRUCTIONS_PER_SECOND / frame_rate(standard) as u64
+}
+
+fn scanlines(standard: VideoStandard) -> u64 {
+    match standard {
+        VideoStandard::Ntsc => 263,
+        VideoStandard::Pal => 314,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    Bits15,
+    Bits24,
+}
+
+/// Part of VRAM shown on screen. `x` and `width` are in pixels of `depth`, except that
+/// `x` is always a 16-bit VRAM column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayArea {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub depth: ColorDepth,
+}
+
+/// The display area converted to RGB888, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Rectangle of VRAM copied one halfword at a time, wrapping around the edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transfer {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    /// Halfwords copied so far
+    pos: u32,
+}
+
+impl Transfer {
+    /// Transfer from the position and size words of a copy command
+    fn new(xy: u32, size: u32) -> Self {
+        Transfer {
+            x: (xy & 0x3ff) as u16,
+            y: (xy >> 16 & 0x1ff) as u16,
+            width: ((size & 0xffff).wrapping_sub(1) & 0x3ff) as u16 + 1,
+            height: ((size >> 16).wrapping_sub(1) & 0x1ff) as u16 + 1,
+            pos: 0,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.width as u32 * self.height as u32
+    }
+
+    /// VRAM index of the next halfword
+    fn next(&mut self) -> usize {
+        let x = (self.x as usize + (self.pos % self.width as u32) as usize) % VRAM_WIDTH;
+        let y = (self.y as usize + (self.pos / self.width as u32) as usize) % VRAM_HEIGHT;
+        self.pos += 1;
+        y * VRAM_WIDTH + x
+    }
+
+    fn save(&self, w: &mut StateWriter) {
+        w.words(&[self.x as u32, self.y as u32, self.width as u32, self.height as u32, self.pos]);
+    }
+
+    fn load(r: &mut StateReader) -> Result<Self> {
+        let mut words = [0; 5];
+        r.words_into(&mut words)?;
+        let [x, y, width, height, pos] = words;
+        if x as usize >= VRAM_WIDTH || y as usize >= VRAM_HEIGHT || width == 0 || height == 0
+            || width as usize > VRAM_WIDTH || height as usize > VRAM_HEIGHT
+        {
+            bail!("corrupt save state: invalid GPU transfer");
+        }
+        Ok(Transfer { x: x as u16, y: y as u16, width: width as u16, height: height as u16, pos })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Gp0Mode {
+    /// Collecting the words of a command
+    Command,
+    /// Receiving the pixels of a CPU to VRAM copy
+    ImageLoad(Transfer),
+}
+
+#[derive(Clone)]
+pub struct Gpu {
+    vram: Vec<u16>,
+
+    /// GP0(E1h): texture page, semi-transparency, dithering, drawing to the display area
+    draw_mode: u32,
+    /// GP0(E2h)
+    texture_window: u32,
+    /// GP0(E3h) and GP0(E4h): inclusive corners of the drawing area
+    draw_area: [u32; 2],
+    /// GP0(E5h)
+    draw_offset: u32,
+    /// GP0(E6h): set the mask bit when drawing, skip masked pixels
+    mask: u32,
+
+    gp0_words: Vec<u32>,
+    gp0_mode: Gp0Mode,
+    /// VRAM to CPU copy in progress, read through GPUREAD
+    read: Cell<Option<Transfer>>,
+    gpuread: Cell<u32>,
+    irq: bool,
+
+    display_enabled: bool,
+    dma_direction: u32,
+    /// GP1(05h): top-left corner of the display area in VRAM
+    display_start: u32,
+    /// GP1(06h) and GP1(07h): display range in clock ticks and scanlines
+    horizontal_range: u32,
+    vertical_range: u32,
+    /// GP1(08h): resolution, video standard, color depth, interlacing
+    display_mode: u32,
+
+    /// Instructions into the current frame
+    frame_clock: u64,
+    frames: u64,
+}
+
+impl Snapshot for Gpu {
+    fn save(&self, w: &mut StateWriter) {
+        w.section(b"GPU ");
+        let vram: Vec<u32> = self.vram.chunks(2)
+            .map(|pair| pair[0] as u32 | (pair[1] as u32) << 16)
+            .collect();
+        w.words(&vram);
+        w.words(&[self.draw_mode, self.texture_window, self.draw_area[0], self.draw_area[1],
+            self.draw_offset, self.mask]);
+        w.words(&self.gp0_words);
+        match self.gp0_mode {
+            Gp0Mode::Command => w.bool(false),
+            Gp0Mode::ImageLoad(transfer) => {
+                w.bool(true);
+                transfer.save(w);
+            },
+        }
+        w.bool(self.read.get().is_some());
+        if let Some(transfer) = self.read.get() {
+            transfer.save(w);
+        }
+        w.u32(self.gpuread.get());
+        w.bool(self.irq);
+        w.bool(self.display_enabled);
+        w.words(&[self.dma_direction, self.display_start, self.horizontal_range,
+            self.vertical_range, self.display_mode]);
+        w.u64(self.frame_clock);
+        w.u64(self.frames);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        r.section(b"GPU ")?;
+        let mut vram = vec![0; VRAM_WIDTH * VRAM_HEIGHT / 2];
+        r.words_into(&mut vram)?;
+        for (idx, word) in vram.into_iter().enumerate() {
+            self.vram[idx * 2] = word as u16;
+            self.vram[idx * 2 + 1] = (word >> 16) as u16;
+        }
+        let mut regs = [0; 6];
+        r.words_into(&mut regs)?;
+        [self.draw_mode, self.texture_window, self.draw_area[0], self.draw_area[1],
+            self.draw_offset, self.mask] = regs;
+        self.gp0_words = r.words()?;
+        self.gp0_mode = match r.bool()? {
+            true => Gp0Mode::ImageLoad(Transfer::load(r)?),
+            false => Gp0Mode::Command,
+        };
+        self.read.set(match r.bool()? {
+            true => Some(Transfer::load(r)?),
+            false => None,
+        });
+        self.gpuread.set(r.u32()?);
+        self.irq = r.bool()?;
+        self.display_enabled = r.bool()?;
+        let mut regs = [0; 5];
+        r.words_into(&mut regs)?;
+        [self.dma_direction, self.display_start, self.horizontal_range,
+            self.vertical_range, self.display_mode] = regs;
+        self.dma_direction &= 3;
+        self.frame_clock = r.u64()?;
+        self.frames = r.u64()?;
+        Ok(())
+    }
+}
+
+impl Default for Gpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gpu {
+    pub fn new() -> Self {
+        let mut gpu = Gpu {
+            vram: vec![0; VRAM_WIDTH * VRAM_HEIGHT],
+            draw_mode: 0,
+            texture_window: 0,
+            draw_area: [0; 2],
+            draw_offset: 0,
+            mask: 0,
+            gp0_words: Vec::new(),
+            gp0_mode: Gp0Mode::Command,
+            read: Cell::new(None),
+            gpuread: Cell::new(0),
+            irq: false,
+            display_enabled: false,
+            dma_direction: 0,
+            display_start: 0,
+            horizontal_range: 0,
+            vertical_range: 0,
+            display_mode: 0,
+            frame_clock: 0,
+            frames: 0,
+        };
+        gpu.reset();
+        gpu
+    }
+
+    /// GP1(00h): reset everything but VRAM and the video timing
+    fn reset(&mut self) {
+        self.draw_mode = 0;
+        self.texture_window = 0;
+        self.draw_area = [0; 2];
+        self.draw_offset = 0;
+        self.mask = 0;
+        self.reset_fifo();
+        self.irq = false;
+        self.display_enabled = false;
+        self.dma_direction = 0;
+        self.display_start = 0;
+        self.horizontal_range = 0x200 | 0xc00 << 12;
+        self.vertical_range = 0x10 | 0x100 << 10;
+        self.display_mode = 0;
+    }
+
+    /// GP1(01h): drop the command being received and any transfer
+    fn reset_fifo(&mut self) {
+        self.gp0_words.clear();
+        self.gp0_mode = Gp0Mode::Command;
+        self.read.set(None);
+    }
+
+    pub fn vram(&self) -> &[u16] {
+        &self.vram
+    }
+
+    /// Number of frames since power on
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Video standard set up in GP1(08h)
+    pub fn video_standard(&self) -> VideoStandard {
+        match self.display_mode & 0x08 != 0 {
+            true => VideoStandard::Pal,
+            false => VideoStandard::Ntsc,
+        }
+    }
+
+    fn is_interlaced(&self) -> bool {
+        self.display_mode & 0x20 != 0
+    }
+
+    /// Whether the 480-line interlaced mode is set up
+    fn is_480_lines(&self) -> bool {
+        self.display_mode & 0x24 == 0x24
+    }
+
+    /// Advance the video timing by `instructions`. Returns whether a frame ended, which
+    /// is when the VBlank is signalled.
+    pub fn tick(&mut self, instructions: u64) -> bool {
+        self.frame_clock += instructions;
+        let frame = frame_instructions(self.video_standard());
+        if self.frame_clock < frame {
+            return false;
+        }
+        self.frame_clock %= frame;
+        self.frames += 1;
+        true
+    }
+
+    fn scanline(&self) -> u64 {
+        let standard = self.video_standard();
+        self.frame_clock * scanlines(standard) / frame_instructions(standard)
+    }
+
+    /// GPUSTAT
+    pub fn status(&self) -> u32 {
+        let mut stat = self.draw_mode & 0x7ff;
+        stat |= (self.draw_mode >> 11 & 1) << 15;
+        stat |= self.mask << 11;
+        let odd_field = self.frames & 1 == 1;
+        stat |= ((!self.is_interlaced() || odd_field) as u32) << 13;
+        stat |= (self.display_mode >> 7 & 1) << 14;
+        stat |= (self.display_mode >> 6 & 1) << 16;
+        stat |= (self.display_mode & 0x3f) << 17;
+        stat |= (!self.display_enabled as u32) << 23;
+        stat |= (self.irq as u32) << 24;
+
+        // Commands are executed as they arrive, so the GPU is always ready for more
+        let ready_vram_read = self.read.get().is_some() as u32;
+        stat |= 1 << 26 | ready_vram_read << 27 | 1 << 28;
+        stat |= match self.dma_direction {
+            0 => 0,
+            1 | 2 => 1,
+            _ => ready_vram_read,
+        } << 25;
+        stat |= self.dma_direction << 29;
+
+        let line = self.scanline() as u32;
+        let (first, last) = (self.vertical_range & 0x3ff, self.vertical_range >> 10 & 0x3ff);
+        let odd_line = match self.is_480_lines() {
+            true => odd_field,
+            false => line & 1 == 1,
+        };
+        if (first..last).contains(&line) && odd_line {
+            stat |= 1 << 31;
+        }
+        stat
+    }
+
+    /// GPUREAD: the next pixels of a VRAM to CPU copy, or the reply to GP1(10h)
+    pub fn read(&self) -> u32 {
+        if let Some(mut transfer) = self.read.get() {
+            let mut val = self.vram[transfer.next()] as u32;
+            if !transfer.is_done() {
+                val |= (self.vram[transfer.next()] as u32) << 16;
+            }
+            self.read.set((!transfer.is_done()).then_some(transfer));
+            self.gpuread.set(val);
+        }
+        self.gpuread.get()
+    }
+
+    pub fn gp0(&mut self, val: u32) {
+        if let Gp0Mode::ImageLoad(mut transfer) = self.gp0_mode {
+            for pixel in [val as u16, (val >> 16) as u16] {
+                if !transfer.is_done() {
+                    let idx = transfer.next();
+                    self.vram[idx] = pixel | self.mask_bit();
+                }
+            }
+            self.gp0_mode = match transfer.is_done() {
+                true => Gp0Mode::Command,
+                false => Gp0Mode::ImageLoad(transfer),
+            };
+            return;
+        }
+
+        self.gp0_words.push(val);
+        let complete = match command_len(self.gp0_words[0]) {
+            Some(len) => self.gp0_words.len() >= len,
+            // Polylines end with a terminator in place of a vertex or a color
+            None => self.gp0_words.len() > 3 && val & 0xf000_f000 == 0x5000_5000,
+        };
+        if complete {
+            let words = std::mem::take(&mut self.gp0_words);
+            self.gp0_command(&words);
+            self.gp0_words = words;
+            self.gp0_words.clear();
+        }
+    }
+
+    fn gp0_command(&mut self, words: &[u32]) {
+        let op = words[0] >> 24;
+        match op {
+            0x02 => self.fill(words),
+            0x1f => self.irq = true,
+            0x20..=0x3f => self.polygon(words),
+            0x40..=0x5f => tracing::trace!("GP0({op:02x}h): lines aren't drawn"),
+            0x60..=0x7f => self.rectangle(words),
+            0x80..=0x9f => {
+                let mut src = Transfer::new(words[1], words[3]);
+                let mut dst = Transfer::new(words[2], words[3]);
+                while !src.is_done() {
+                    let pixel = self.vram[src.next()];
+                    self.vram[dst.next()] = pixel | self.mask_bit();
+                }
+            },
+            0xa0..=0xbf => self.gp0_mode = Gp0Mode::ImageLoad(Transfer::new(words[1], words[2])),
+            0xc0..=0xdf => self.read.set(Some(Transfer::new(words[1], words[2]))),
+            0xe1 => self.draw_mode = words[0] & 0x3fff,
+            0xe2 => self.texture_window = words[0] & 0xf_ffff,
+            0xe3 => self.draw_area[0] = words[0] & 0xf_ffff,
+            0xe4 => self.draw_area[1] = words[0] & 0xf_ffff,
+            0xe5 => self.draw_offset = words[0] & 0x3f_ffff,
+            0xe6 => self.mask = words[0] & 3,
+            _ => {}, // NOP and cache clear
+        }
+    }
+
+    pub fn gp1(&mut self, val: u32) {
+        match val >> 24 {
+            0x00 => self.reset(),
+            0x01 => self.reset_fifo(),
+            0x02 => self.irq = false,
+            0x03 => self.display_enabled = val & 1 == 0,
+            0x04 => self.dma_direction = val & 3,
+            0x05 => self.display_start = val & 0x7_ffff,
+            0x06 => self.horizontal_range = val & 0xff_ffff,
+            0x07 => self.vertical_range = val & 0xf_ffff,
+            0x08 => self.display_mode = val & 0xff,
+            0x10..=0x1f => {
+                let info = match val & 7 {
+                    2 => self.texture_window,
+                    3 => self.draw_area[0],
+                    4 => self.draw_area[1],
+                    5 => self.draw_offset,
+                    7 => GPU_VERSION,
+                    _ => self.gpuread.get(),
+                };
+                self.gpuread.set(info);
+            },
+            op => tracing::warn!("GP1({op:02x}h) is unimplemented"),
+        }
+    }
+
+    /// Part of VRAM shown on screen, as set up in GP1(05h) to GP1(08h)
+    pub fn display_area(&self) -> DisplayArea {
+        let width = match (self.display_mode >> 6 & 1, self.display_mode & 3) {
+            (1, _) => 368,
+            (_, 0) => 256,
+            (_, 1) => 320,
+            (_, 2) => 512,
+            _ => 640,
+        };
+        let (first, last) = (self.vertical_range & 0x3ff, self.vertical_range >> 10 & 0x3ff);
+        let mut height = last.saturating_sub(first).min(scanlines(self.video_standard()) as u32);
+        if self.is_480_lines() {
+            height *= 2;
+        }
+        DisplayArea {
+            x: (self.display_start & 0x3fe) as u16,
+            y: (self.display_start >> 10 & 0x1ff) as u16,
+            width,
+            height: height.min(VRAM_HEIGHT as u32) as u16,
+            depth: match self.display_mode & 0x10 != 0 {
+                true => ColorDepth::Bits24,
+                false => ColorDepth::Bits15,
+            },
+        }
+    }
+
+    /// The display area as RGB888, black while the display is disabled
+    pub fn frame(&self) -> Frame {
+        let area = self.display_area();
+        let (width, height) = (area.width as usize, area.height as usize);
+        let mut pixels = vec![0; width * height * 3];
+        if !self.display_enabled {
+            return Frame { width: width as u32, height: height as u32, pixels };
+        }
+
+        for (row, out) in pixels.chunks_exact_mut(width * 3).enumerate() {
+            let y = (area.y as usize + row) % VRAM_HEIGHT;
+            let line = &self.vram[y * VRAM_WIDTH..][..VRAM_WIDTH];
+            match area.depth {
+                ColorDepth::Bits15 => {
+                    for (col, rgb) in out.chunks_exact_mut(3).enumerate() {
+                        let pixel = line[(area.x as usize + col) % VRAM_WIDTH];
+                        rgb.copy_from_slice(&rgb888(pixel));
+                    }
+                },
+                ColorDepth::Bits24 => {
+                    // Pixels are packed in 3 bytes across the halfwords
+                    for (byte, out) in out.iter_mut().enumerate() {
+                        let idx = area.x as usize * 2 + byte;
+                        let halfword = line[idx / 2 % VRAM_WIDTH];
+                        *out = (halfword >> (idx % 2 * 8)) as u8;
+                    }
+                },
+            }
+        }
+        Frame { width: width as u32, height: height as u32, pixels }
+    }
+
+    fn mask_bit(&self) -> u16 {
+        ((self.mask & 1) as u16) << 15
+    }
+
+    /// Write a drawn pixel, unless it's outside the drawing area or masked
+    fn plot(&mut self, x: i32, y: i32, color: u16) {
+        let (left, top) = (self.draw_area[0] & 0x3ff, self.draw_area[0] >> 10 & 0x1ff);
+        let (right, bottom) = (self.draw_area[1] & 0x3ff, self.draw_area[1] >> 10 & 0x1ff);
+        if x < left as i32 || x > right as i32 || y < top as i32 || y > bottom as i32 {
+            return;
+        }
+        let idx = y as usize * VRAM_WIDTH + x as usize;
+        if self.mask & 2 != 0 && self.vram[idx] & 0x8000 != 0 {
+            return;
+        }
+        self.vram[idx] = color | self.mask_bit();
+    }
+
+    /// Vertex of a drawing command, with the drawing offset applied
+    fn vertex(&self, word: u32) -> (i32, i32) {
+        let (dx, dy) = (sign_extend_11(self.draw_offset), sign_extend_11(self.draw_offset >> 11));
+        (sign_extend_11(word) + dx, sign_extend_11(word >> 16) + dy)
+    }
+
+    /// GP0(02h): fill a rectangle, ignoring the drawing area and the mask
+    fn fill(&mut self, words: &[u32]) {
+        let color = rgb555(words[0]);
+        let (x, y) = ((words[1] & 0x3f0) as usize, (words[1] >> 16 & 0x1ff) as usize);
+        let width = (((words[2] & 0x3ff) + 0xf) & !0xf) as usize;
+        let height = (words[2] >> 16 & 0x1ff) as usize;
+        for row in 0..height {
+            for col in 0..width {
+                let idx = (y + row) % VRAM_HEIGHT * VRAM_WIDTH + (x + col) % VRAM_WIDTH;
+                self.vram[idx] = color;
+            }
+        }
+    }
+
+    /// GP0(20h-3Fh)
+    fn polygon(&mut self, words: &[u32]) {
+        let op = words[0] >> 24;
+        if op & 0x04 != 0 {
+            tracing::trace!("GP0({op:02x}h): textured polygons aren't drawn");
+            return;
+        }
+        let shaded = op & 0x10 != 0;
+        let count = if op & 0x08 != 0 { 4 } else { 3 };
+        let mut vertices = [((0, 0), 0); 4];
+        let mut words = words.iter().copied();
+        let mut color = words.next().unwrap();
+        for (idx, vertex) in vertices[..count].iter_mut().enumerate() {
+            if shaded && idx > 0 {
+                color = words.next().unwrap();
+            }
+            *vertex = (self.vertex(words.next().unwrap()), color);
+        }
+        self.triangle([vertices[0], vertices[1], vertices[2]]);
+        if count == 4 {
+            self.triangle([vertices[1], vertices[2], vertices[3]]);
+        }
+    }
+
+    /// Draw a triangle with its colors interpolated across it. The GPU skips triangles
+    /// spanning more than 1023x511 pixels.
+    fn triangle(&mut self, vertices: [((i32, i32), u32); 3]) {
+        let xs = vertices.map(|((x, _), _)| x);
+        let ys = vertices.map(|((_, y), _)| y);
+        let (min_x, max_x) = (*xs.iter().min().unwrap(), *xs.iter().max().unwrap());
+        let (min_y, max_y) = (*ys.iter().min().unwrap(), *ys.iter().max().unwrap());
+        if max_x - min_x >= VRAM_WIDTH as i32 || max_y - min_y >= VRAM_HEIGHT as i32 {
+            return;
+        }
+        let edge = |(ax, ay): (i32, i32), (bx, by): (i32, i32), (px, py): (i32, i32)| {
+            (bx - ax) as i64 * (py - ay) as i64 - (by - ay) as i64 * (px - ax) as i64
+        };
+        let [(p0, c0), (p1, c1), (p2, c2)] = vertices;
+        let area = edge(p0, p1, p2);
+        if area == 0 {
+            return;
+        }
+
+        // Pixels on the right and bottom edges aren't drawn
+        let (min_x, max_x) = (min_x.max(0), max_x.min(VRAM_WIDTH as i32));
+        let (min_y, max_y) = (min_y.max(0), max_y.min(VRAM_HEIGHT as i32));
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let mut weights = [edge(p1, p2, (x, y)), edge(p2, p0, (x, y)), edge(p0, p1, (x, y))];
+                if area < 0 {
+                    weights = weights.map(|weight| -weight);
+                }
+                if weights.iter().any(|&weight| weight < 0) {
+                    continue;
+                }
+                let channel = |shift: u32| {
+                    let sum = weights[0] * (c0 >> shift & 0xff) as i64
+                        + weights[1] * (c1 >> shift & 0xff) as i64
+                        + weights[2] * (c2 >> shift & 0xff) as i64;
+                    (sum / area.abs()) as u32
+                };
+                let color = rgb555(channel(0) | channel(8) << 8 | channel(16) << 16);
+                self.plot(x, y, color);
+            }
+        }
+    }
+
+    /// GP0(60h-7Fh)
+    fn rectangle(&mut self, words: &[u32]) {
+        let op = words[0] >> 24;
+        if op & 0x04 != 0 {
+            tracing::trace!("GP0({op:02x}h): textured rectangles aren't drawn");
+            return;
+        }
+        let (x, y) = self.vertex(words[1]);
+        let (width, height) = match op >> 3 & 3 {
+            0 => ((words[2] & 0x3ff) as i32, (words[2] >> 16 & 0x1ff) as i32),
+            1 => (1, 1),
+            2 => (8, 8),
+            _ => (16, 16),
+        };
+        let color = rgb555(words[0]);
+        for row in y..y + height {
+            for col in x..x + width {
+                self.plot(col, row, color);
+            }
+        }
+    }
+}
+
+/// Number of words in the GP0 command starting with `cmd`, `None` for polylines
+fn command_len(cmd: u32) -> Option<usize> {
+    let op = cmd >> 24;
+    let textured = (op >> 2 & 1) as usize;
+    let shaded = (op >> 4 & 1) as usize;
+    Some(match op {
+        0x02 => 3,
+        0x20..=0x3f => {
+            let vertices = if op & 0x08 != 0 { 4 } else { 3 };
+            1 + vertices * (1 + textured + shaded) - shaded
+        },
+        0x40..=0x5f if op & 0x08 != 0 => return None,
+        0x40..=0x5f => 3 + shaded,
+        0x60..=0x7f => 2 + textured + (op >> 3 & 3 == 0) as usize,
+        0x80..=0x9f => 4,
+        0xa0..=0xdf => 3,
+        _ => 1,
+    })
+}
+
+fn sign_extend_11(val: u32) -> i32 {
+    ((val << 21) as i32) >> 21
+}
+
+/// 24-bit command color to a 15-bit VRAM pixel
+fn rgb555(color: u32) -> u16 {
+    let (r, g, b) = (color >> 3 & 0x1f, color >> 11 & 0x1f, color >> 19 & 0x1f);
+    (r | g << 5 | b << 10) as u16
+}
+
+/// 15-bit VRAM pixel to RGB888
+fn rgb888(pixel: u16) -> [u8; 3] {
+    let expand = |c: u16| ((c & 0x1f) << 3 | (c & 0x1f) >> 2) as u8;
+    [expand(pixel), expand(pixel >> 5), expand(pixel >> 10)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_and_fills() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0x02_0000ff);
+        gpu.gp0(0x0010_0010);
+        gpu.gp0(0x0002_0001);
+        // Fill widths are rounded up to 16 pixels
+        assert_eq!(gpu.vram()[16 * VRAM_WIDTH + 16..][..16], [0x1f; 16]);
+        assert_eq!(gpu.vram()[16 * VRAM_WIDTH + 32], 0);
+
+        // CPU to VRAM, 3x1 pixels wrapping around the right edge, with the last halfword
+        // of the data ignored
+        for word in [0xa000_0000, 0x0000_03ff, 0x0001_0003, 0x2222_1111, 0x4444_3333] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.vram()[VRAM_WIDTH - 1], 0x1111);
+        assert_eq!(gpu.vram()[..3], [0x2222, 0x3333, 0]);
+
+        // VRAM to CPU reads back through GPUREAD
+        assert_eq!(gpu.status() & 1 << 27, 0);
+        for word in [0xc000_0000, 0x0000_03ff, 0x0001_0002] {
+            gpu.gp0(word);
+        }
+        assert_ne!(gpu.status() & 1 << 27, 0);
+        assert_eq!(gpu.read(), 0x2222_1111);
+        assert_eq!(gpu.status() & 1 << 27, 0);
+
+        gpu.gp1(0x10_000007);
+        assert_eq!(gpu.read(), GPU_VERSION);
+    }
+
+    #[test]
+    fn draws_untextured_primitives() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0xe3_000000);
+        gpu.gp0(0xe4_000000 | 511 << 10 | 1023);
+        gpu.gp0(0xe5_000000 | 10);
+        // 16x16 white rectangle at (10, 0) after the offset
+        gpu.gp0(0x78_ffffff);
+        gpu.gp0(0x0000_0000);
+        assert_eq!(gpu.vram()[10..26], [0x7fff; 16]);
+        assert_eq!(gpu.vram()[26], 0);
+        assert_eq!(gpu.vram()[15 * VRAM_WIDTH + 25], 0x7fff);
+
+        // Flat red quad covering (100, 100) to (110, 110)
+        gpu.gp0(0xe5_000000);
+        for word in [0x28_0000ff, 0x0064_0064, 0x0064_006e, 0x006e_0064, 0x006e_006e] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.vram()[100 * VRAM_WIDTH + 100], 0x1f);
+        assert_eq!(gpu.vram()[109 * VRAM_WIDTH + 109], 0x1f);
+        assert_eq!(gpu.vram()[110 * VRAM_WIDTH + 110], 0);
+
+        // Textured commands are consumed without drawing
+        for _ in 0..command_len(0x2c00_0000).unwrap() {
+            gpu.gp0(0x2c_ffffff);
+        }
+        gpu.gp0(0x02_000000);
+        gpu.gp0(0);
+        gpu.gp0(0x0001_0001);
+        assert_eq!(gpu.vram()[0], 0);
+    }
+
+    #[test]
+    fn converts_display_area() {
+        let mut gpu = Gpu::new();
+        for (idx, pixel) in [0x001f, 0x03e0, 0x7c00, 0x7fff].into_iter().enumerate() {
+            gpu.vram[VRAM_WIDTH + 8 + idx] = pixel;
+        }
+        gpu.gp1(0x05_000000 | 1 << 10 | 8);
+        gpu.gp1(0x08_000001);
+        assert_eq!(gpu.frame().pixels[..3], [0, 0, 0]);
+        gpu.gp1(0x03_000000);
+
+        let area = gpu.display_area();
+        assert_eq!((area.width, area.height, area.depth), (320, 240, ColorDepth::Bits15));
+        let frame = gpu.frame();
+        assert_eq!((frame.width, frame.height), (320, 240));
+        assert_eq!(frame.pixels[..12], [0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+
+        // 24-bit mode packs the bytes across halfwords: 1f 00 e0 03 00 7c
+        gpu.gp1(0x08_000011);
+        assert_eq!(gpu.frame().pixels[..6], [0x1f, 0x00, 0xe0, 0x03, 0x00, 0x7c]);
+    }
+
+    #[test]
+    fn counts_frames() {
+        let mut gpu = Gpu::new();
+        let frame = frame_instructions(VideoStandard::Ntsc);
+        assert!(!gpu.tick(frame - 1));
+        assert!(gpu.tick(1));
+        assert_eq!(gpu.frames(), 1);
+
+        gpu.gp1(0x08_000008);
+        assert_eq!(gpu.video_standard(), VideoStandard::Pal);
+        assert!(!gpu.tick(frame));
+        assert!(gpu.tick(frame_instructions(VideoStandard::Pal) - frame));
+        assert_eq!(gpu.frames(), 2);
+    }
+}
@@ -0,0 +1,1506 @@
+//! High-level emulation of the BIOS kernel, so the core can run without a BIOS dump
+//!
+//! A small stub ROM does what the real BIOS does on reset: it installs the exception
+//! vector and the A0h/B0h/C0h function dispatchers in RAM, and fills the function tables.
+//! Every table entry points at a trap address in ROM, and when the CPU reaches one of them
+//! [`Hle::dispatch`] runs the kernel function in Rust and returns to the caller. The
+//! dispatchers go through the tables in RAM, so programs patching them keep working.
+
+pub mod printf;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::{
+    bios::BIOS_SIZE,
+    bus::Bus,
+    cpu::{asm::assemble, instruction::RegisterIndex, Cpu},
+    disc::Disc,
+    exe::{self, Exe, Image},
+    ktrace,
+    map,
+    pad::PadState,
+    ram::RAM_SIZE,
+    state::{self, StateReader, StateWriter},
+};
+
+/// Reset vector, where the stub ROM code starts
+const ROM_BASE: u32 = 0xbfc0_0000;
+
+/// Start of the trap addresses the function tables point at
+pub const TRAP_BASE: u32 = 0xbfc1_0000;
+const A0_TRAPS: u32 = TRAP_BASE;
+const B0_TRAPS: u32 = TRAP_BASE + 0x400;
+const C0_TRAPS: u32 = TRAP_BASE + 0x800;
+const EXCEPTION_TRAP: u32 = TRAP_BASE + 0xc00;
+/// Reached at the end of the reset code, in place of the BIOS shell
+const SHELL_TRAP: u32 = TRAP_BASE + 0xc04;
+/// Return address of programs started with `Exec`
+const EXEC_RETURN_TRAP: u32 = TRAP_BASE + 0xc08;
+const TRAP_END: u32 = TRAP_BASE + 0xc0c;
+
+/// Endless loop for programs that called `exit`
+const HALT: u32 = TRAP_BASE + 0xc10;
+
+/// Function tables in RAM, at the same addresses as with the real BIOS
+const A0_TABLE: u32 = 0x8000_0200;
+const A0_ENTRIES: u32 = 0x100;
+const B0_TABLE: u32 = 0x8000_0874;
+const B0_ENTRIES: u32 = 0x80;
+const C0_TABLE: u32 = 0x8000_0674;
+const C0_ENTRIES: u32 = 0x20;
+
+const KERNEL_HEAP: u32 = 0x8000_e000;
+const KERNEL_HEAP_SIZE: u32 = 0x2000;
+
+const DEFAULT_STACK: u32 = 0x801f_ff00;
+
+/// Returned by most kernel functions on failure
+const ERROR: u32 = u32::MAX;
+
+/// Most bytes the memory functions touch: past that they would only go round RAM again
+const MAX_BLOCK_SIZE: u32 = RAM_SIZE as u32;
+
+const MAX_EVENTS: usize = 16;
+const MAX_FILES: usize = 16;
+
+/// Event classes
+const CLASS_SW_CARD: u32 = 0xf400_0001;
+const CLASS_HW_CARD: u32 = 0xf000_0011;
+const CLASS_SYSCALL: u32 = 0xf000_0010;
+
+/// Event specs
+const SPEC_TIMEOUT: u32 = 0x0100;
+const SPEC_UNKNOWN_SYSCALL: u32 = 0x4000;
+
+/// Event modes
+const MODE_CALLBACK: u32 = 0x1000;
+
+/// Event states
+const EVENT_FREE: u32 = 0;
+const EVENT_DISABLED: u32 = 0x1000;
+const EVENT_ENABLED: u32 = 0x2000;
+const EVENT_READY: u32 = 0x4000;
+
+/// Registers saved by `SaveState` and `SetCustomExitFromException`: ra, sp, fp, s0-s7, gp
+const JMP_BUF_REGS: [u32; 12] = [31, 29, 30, 16, 17, 18, 19, 20, 21, 22, 23, 28];
+
+const A0: RegisterIndex = RegisterIndex(4);
+const V0: RegisterIndex = RegisterIndex(2);
+const SP: RegisterIndex = RegisterIndex(29);
+const FP: RegisterIndex = RegisterIndex(30);
+const GP: RegisterIndex = RegisterIndex(28);
+const RA: RegisterIndex = RegisterIndex(31);
+
+/// Build the stub ROM, to be used in place of a BIOS dump
+pub fn stub_rom() -> Box<[u8; BIOS_SIZE]> {
+    let source = format!("
+            mtc0  zero, sr
+            mtc0  zero, cause
+            li    sp, {DEFAULT_STACK:#x}
+
+            # Exception vector and function dispatchers
+            la    a0, vectors
+            li    a1, 0x80000080
+            li    a2, 20
+        copy:
+            lw    t0, 0(a0)
+            addiu a0, a0, 4
+            sw    t0, 0(a1)
+            addiu a2, a2, -1
+            bnez  a2, copy
+            addiu a1, a1, 4
+
+            li    a0, {A0_TABLE:#x}
+            li    a1, {A0_TRAPS:#x}
+            jal   fill
+            li    a2, {A0_ENTRIES:#x}
+            li    a0, {B0_TABLE:#x}
+            li    a1, {B0_TRAPS:#x}
+            jal   fill
+            li    a2, {B0_ENTRIES:#x}
+            li    a0, {C0_TABLE:#x}
+            li    a1, {C0_TRAPS:#x}
+            jal   fill
+            li    a2, {C0_ENTRIES:#x}
+
+            li    t0, {SHELL_TRAP:#x}
+            jr    t0
+            nop
+
+        # Fill a2 table entries at a0 with consecutive trap addresses starting at a1
+        fill:
+            sw    a1, 0(a0)
+            addiu a0, a0, 4
+            addiu a2, a2, -1
+            bnez  a2, fill
+            addiu a1, a1, 4
+            jr    ra
+            nop
+
+        a0_dispatch:
+            andi  t1, t1, {a0_mask:#x}
+            sll   t0, t1, 2
+            lw    t0, {a0_table:#x}(t0)
+            nop
+            jr    t0
+            nop
+        b0_dispatch:
+            andi  t1, t1, {b0_mask:#x}
+            sll   t0, t1, 2
+            lw    t0, {b0_table:#x}(t0)
+            nop
+            jr    t0
+            nop
+        c0_dispatch:
+            andi  t1, t1, {c0_mask:#x}
+            sll   t0, t1, 2
+            lw    t0, {c0_table:#x}(t0)
+            nop
+            jr    t0
+            nop
+
+        # Copied to 0x80000080, 20 words
+        vectors:
+            la    k0, {EXCEPTION_TRAP:#x}
+            jr    k0
+            nop
+            .word 0, 0, 0, 0
+            la    t0, a0_dispatch
+            jr    t0
+            nop
+            la    t0, b0_dispatch
+            jr    t0
+            nop
+            la    t0, c0_dispatch
+            jr    t0
+            nop
+        ",
+        a0_mask = A0_ENTRIES - 1, a0_table = A0_TABLE & 0xffff,
+        b0_mask = B0_ENTRIES - 1, b0_table = B0_TABLE & 0xffff,
+        c0_mask = C0_ENTRIES - 1, c0_table = C0_TABLE & 0xffff,
+    );
+
+    let mut rom = Box::new([0u8; BIOS_SIZE]);
+    let mut place = |addr: u32, words: Vec<u32>| {
+        let offset = addr as usize & (BIOS_SIZE - 1);
+        for (idx, word) in words.into_iter().enumerate() {
+            rom[offset + 4 * idx..offset + 4 * idx + 4].copy_from_slice(&word.to_le_bytes());
+        }
+    };
+    place(ROM_BASE, assemble(&source, ROM_BASE).expect("invalid HLE stub ROM"));
+    place(HALT, assemble("halt: b halt\nnop", HALT).unwrap());
+    rom
+}
+
+/// Whether `pc` is one of the HLE trap addresses
+pub fn is_trap(pc: u32) -> bool {
+    (map::mask_region(TRAP_BASE)..map::mask_region(TRAP_END)).contains(&map::mask_region(pc))
+}
+
+/// First-fit allocator for `malloc` and friends. Only the bookkeeping lives here, guest
+/// memory isn't touched.
+#[derive(Debug, Default)]
+struct Heap {
+    start: u32,
+    end: u32,
+    /// Allocated blocks as (address, size), sorted by address
+    blocks: Vec<(u32, u32)>,
+}
+
+impl Heap {
+    fn init(&mut self, addr: u32, size: u32) {
+        self.start = addr.wrapping_add(3) & !3;
+        self.end = addr.wrapping_add(size);
+        self.blocks.clear();
+    }
+
+    fn alloc(&mut self, size: u32) -> Option<u32> {
+        let size = (size.max(1).checked_add(3)?) & !3;
+        let mut addr = self.start;
+        let mut idx = 0;
+        while idx < self.blocks.len() {
+            let (block, len) = self.blocks[idx];
+            if block - addr >= size {
+                break;
+            }
+            addr = block + len;
+            idx += 1;
+        }
+        if idx == self.blocks.len() && self.end.checked_sub(addr)? < size {
+            return None;
+        }
+        self.blocks.insert(idx, (addr, size));
+        Some(addr)
+    }
+
+    /// Release the block at `addr`, returning its size
+    fn free(&mut self, addr: u32) -> Option<u32> {
+        let idx = self.blocks.iter().position(|&(block, _)| block == addr)?;
+        Some(self.blocks.remove(idx).1)
+    }
+}
+
+impl state::Snapshot for Heap {
+    fn save(&self, w: &mut StateWriter) {
+        w.u32(self.start);
+        w.u32(self.end);
+        w.u32(self.blocks.len() as u32);
+        for &(addr, size) in &self.blocks {
+            w.u32(addr);
+            w.u32(size);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        self.start = r.u32()?;
+        self.end = r.u32()?;
+        let len = r.u32()?;
+        self.blocks = (0..len).map(|_| Ok((r.u32()?, r.u32()?))).collect::<Result<_>>()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Event {
+    class: u32,
+    spec: u32,
+    mode: u32,
+    func: u32,
+    status: u32,
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+/// Pad buffers registered with `InitPad`
+#[derive(Debug, Copy, Clone)]
+struct PadBuffers {
+    bufs: [(u32, u32); 2],
+}
+
+/// Kernel state of the HLE BIOS
+#[derive(Debug)]
+pub struct Hle {
+    disc: Option<Disc>,
+    heap: Heap,
+    kernel_heap: Heap,
+    events: [Event; MAX_EVENTS],
+    /// Open files by descriptor. 0 and 1 are the TTY.
+    files: [Option<OpenFile>; MAX_FILES],
+    pads: Option<PadBuffers>,
+    /// Buffer registered with `SetCustomExitFromException`
+    custom_exit: Option<u32>,
+    /// Header buffers of the programs started with `Exec`, innermost last
+    exec_stack: Vec<u32>,
+    rand_seed: u32,
+    /// TTY output not taken yet, see [`Hle::take_tty`]
+    tty: Vec<u8>,
+    exit_code: Option<u32>,
+}
+
+/// The disc is configuration and the TTY output the host's, they aren't part of the state
+impl state::Snapshot for Hle {
+    fn save(&self, w: &mut StateWriter) {
+        w.section(b"HLE ");
+        state::Snapshot::save(&self.heap, w);
+        state::Snapshot::save(&self.kernel_heap, w);
+        for event in &self.events {
+            w.words(&[event.class, event.spec, event.mode, event.func, event.status]);
+        }
+        for file in &self.files {
+            w.bool(file.is_some());
+            if let Some(file) = file {
+                w.bytes(&file.data);
+                w.u32(file.pos as u32);
+            }
+        }
+        w.bool(self.pads.is_some());
+        let [(buf1, size1), (buf2, size2)] = self.pads.map_or([(0, 0); 2], |pads| pads.bufs);
+        w.words(&[buf1, size1, buf2, size2]);
+        w.option_u32(self.custom_exit);
+        w.words(&self.exec_stack);
+        w.u32(self.rand_seed);
+        w.option_u32(self.exit_code);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        r.section(b"HLE ")?;
+        state::Snapshot::load(&mut self.heap, r)?;
+        state::Snapshot::load(&mut self.kernel_heap, r)?;
+        for event in &mut self.events {
+            let mut words = [0; 5];
+            r.words_into(&mut words)?;
+            let [class, spec, mode, func, status] = words;
+            *event = Event { class, spec, mode, func, status };
+        }
+        for file in &mut self.files {
+            *file = match r.bool()? {
+                true => Some(OpenFile { data: r.bytes()?, pos: r.u32()? as usize }),
+                false => None,
+            };
+        }
+        let has_pads = r.bool()?;
+        let mut words = [0; 4];
+        r.words_into(&mut words)?;
+        let [buf1, size1, buf2, size2] = words;
+        self.pads = has_pads.then_some(PadBuffers { bufs: [(buf1, size1), (buf2, size2)] });
+        self.custom_exit = r.option_u32()?;
+        let len = r.u32()?;
+        self.exec_stack = (0..len).map(|_| r.u32()).collect::<Result<_>>()?;
+        self.rand_seed = r.u32()?;
+        self.exit_code = r.option_u32()?;
+        Ok(())
+    }
+}
+
+impl Hle {
+    pub fn new(disc: Option<Disc>) -> Self {
+        let mut kernel_heap = Heap::default();
+        kernel_heap.init(KERNEL_HEAP, KERNEL_HEAP_SIZE);
+        Hle {
+            disc,
+            heap: Heap::default(),
+            kernel_heap,
+            events: [Event::default(); MAX_EVENTS],
+            files: Default::default(),
+            pads: None,
+            custom_exit: None,
+            exec_stack: Vec::new(),
+            rand_seed: 0,
+            tty: Vec::new(),
+            exit_code: None,
+        }
+    }
+
+    pub fn disc(&self) -> Option<&Disc> {
+        self.disc.as_ref()
+    }
+
+    pub fn insert_disc(&mut self, disc: Disc) {
+        self.disc = Some(disc);
+    }
+
+    /// Take what the program wrote to the TTY since the last call
+    pub fn take_tty(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.tty)
+    }
+
+    /// Exit code passed to `exit`, once the program called it
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    /// Write the state of the controllers into the buffers registered with `InitPad`,
+    /// `None` if nothing is plugged in. The kernel does this at every VBlank.
+    pub fn update_pads(&self, bus: &mut Bus, pads: &[Option<PadState>; 2]) {
+        let Some(bufs) = self.pads else { return };
+        for (&(buf, size), pad) in bufs.bufs.iter().zip(pads) {
+            if buf == 0 || size < 2 {
+                continue;
+            }
+            match pad {
+                // Status ok, then the pad's poll reply
+                Some(pad) => {
+                    let mut reply = vec![0x00];
+                    reply.extend(pad.report());
+                    reply.truncate(size as usize);
+                    write_bytes(bus, buf, &reply);
+                },
+                None => write_bytes(bus, buf, &[0xff, 0x00]),
+            }
+        }
+    }
+
+    /// Run the kernel function (or exception handler) whose trap the CPU just reached
+    pub fn dispatch(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
+        cpu.settle();
+        let offset = map::mask_region(cpu.pc()) - map::mask_region(TRAP_BASE);
+        let ret = match offset {
+            0x000..=0x3ff => self.call_a0(cpu, bus, offset / 4),
+            0x400..=0x7ff => self.call_b0(cpu, bus, (offset - 0x400) / 4),
+            0x800..=0xbff => self.call_c0(cpu, bus, (offset - 0x800) / 4),
+            _ => {
+                match offset + TRAP_BASE {
+                    EXCEPTION_TRAP => self.exception(cpu, bus),
+                    SHELL_TRAP => self.shell(cpu, bus),
+                    EXEC_RETURN_TRAP => self.exec_return(cpu, bus),
+                    _ => unreachable!("no HLE trap @ 0x{:08x}", cpu.pc()),
+                }
+                None
+            },
+        };
+        if let Some(val) = ret {
+            cpu.write_reg(V0, val);
+            cpu.set_pc(cpu.reg(RA));
+        }
+    }
+
+    /// A0h functions. Returns the value for v0, or `None` if the function already set up
+    /// where the CPU continues.
+    fn call_a0(&mut self, cpu: &mut Cpu, bus: &mut Bus, func: u32) -> Option<u32> {
+        let [a0, a1, a2, a3] = args(cpu);
+        Some(match func {
+            0x00 => self.file_open(bus, a0),
+            0x01 => self.file_seek(a0, a1, a2),
+            0x02 => self.file_read(bus, a0, a1, a2),
+            0x03 => self.file_write(bus, a0, a1, a2),
+            0x04 => self.file_close(a0),
+            0x05 | 0x07 => 0, // FileIoctl, FileGetDeviceFlag
+            0x06 | 0x3a => return self.exit(cpu, a0),
+            0x08 => self.file_getc(a0),
+            0x09 => self.file_putc(a0, a1),
+            0x0a => (a0 as u8 as char).to_digit(36).unwrap_or(9_999_999),
+            0x0c => strtol(bus, a0, a1, a2, false),
+            0x0d => strtol(bus, a0, a1, a2, true),
+            0x0e | 0x0f => (a0 as i32).unsigned_abs(),
+            0x10 | 0x11 => strtol(bus, a0, 0, 10, true),
+            0x13 => {
+                save_regs(cpu, bus, a0);
+                0
+            },
+            0x14 => {
+                restore_regs(cpu, bus, a0);
+                cpu.write_reg(V0, a1);
+                return None;
+            },
+            0x15 => {
+                let end = a0.wrapping_add(read_cstr(bus, a0).len() as u32);
+                let src = read_cstr(bus, a1);
+                write_cstr(bus, end, &src);
+                a0
+            },
+            0x16 => {
+                let end = a0.wrapping_add(read_cstr(bus, a0).len() as u32);
+                let mut src = read_cstr(bus, a1);
+                src.truncate(a2 as usize);
+                write_cstr(bus, end, &src);
+                a0
+            },
+            0x17 => strcmp(&read_cstr(bus, a0), &read_cstr(bus, a1)),
+            0x18 => strcmp(&truncated(read_cstr(bus, a0), a2), &truncated(read_cstr(bus, a1), a2)),
+            0x19 => {
+                write_cstr(bus, a0, &read_cstr(bus, a1));
+                a0
+            },
+            0x1a => {
+                let mut src = read_cstr(bus, a1);
+                src.truncate(a2 as usize);
+                write_bytes(bus, a0, &src);
+                fill_bytes(bus, a0.wrapping_add(src.len() as u32), 0, a2 - src.len() as u32);
+                a0
+            },
+            0x1b => read_cstr(bus, a0).len() as u32,
+            0x1c | 0x1e => {
+                let s = read_cstr(bus, a0);
+                match a1 as u8 {
+                    0 => a0.wrapping_add(s.len() as u32),
+                    c => s.iter().position(|&b| b == c).map_or(0, |idx| a0.wrapping_add(idx as u32)),
+                }
+            },
+            0x1d | 0x1f => {
+                let s = read_cstr(bus, a0);
+                match a1 as u8 {
+                    0 => a0.wrapping_add(s.len() as u32),
+                    c => s.iter().rposition(|&b| b == c).map_or(0, |idx| a0.wrapping_add(idx as u32)),
+                }
+            },
+            0x20 => {
+                let set = read_cstr(bus, a1);
+                read_cstr(bus, a0).iter().position(|b| set.contains(b)).map_or(0, |idx| a0.wrapping_add(idx as u32))
+            },
+            0x21 => {
+                let set = read_cstr(bus, a1);
+                read_cstr(bus, a0).iter().take_while(|b| set.contains(b)).count() as u32
+            },
+            0x22 => {
+                let set = read_cstr(bus, a1);
+                read_cstr(bus, a0).iter().take_while(|b| !set.contains(b)).count() as u32
+            },
+            0x24 => {
+                let (s, needle) = (read_cstr(bus, a0), read_cstr(bus, a1));
+                match needle.is_empty() {
+                    true => a0,
+                    false => s.windows(needle.len())
+                        .position(|window| window == needle)
+                        .map_or(0, |idx| a0.wrapping_add(idx as u32)),
+                }
+            },
+            0x25 => (a0 as u8).to_ascii_uppercase() as u32,
+            0x26 => (a0 as u8).to_ascii_lowercase() as u32,
+            0x27 => {
+                let data = read_bytes(bus, a0, a2);
+                write_bytes(bus, a1, &data);
+                a1
+            },
+            0x28 => {
+                fill_bytes(bus, a0, 0, a1);
+                a0
+            },
+            0x29 | 0x2d => memcmp(&read_bytes(bus, a0, a2), &read_bytes(bus, a1, a2)),
+            0x2a | 0x2c => {
+                let data = read_bytes(bus, a1, a2);
+                write_bytes(bus, a0, &data);
+                a0
+            },
+            0x2b => {
+                fill_bytes(bus, a0, a1 as u8, a2);
+                a0
+            },
+            0x2e => read_bytes(bus, a0, a2).iter()
+                .position(|&b| b == a1 as u8)
+                .map_or(0, |idx| a0.wrapping_add(idx as u32)),
+            0x2f => {
+                self.rand_seed = self.rand_seed.wrapping_mul(0x41c6_4e6d).wrapping_add(0x3039);
+                (self.rand_seed >> 16) & 0x7fff
+            },
+            0x30 => {
+                self.rand_seed = a0;
+                0
+            },
+            0x33 => self.heap.alloc(a0).unwrap_or(0),
+            0x34 => {
+                self.heap.free(a0);
+                0
+            },
+            0x37 => {
+                let size = a0.wrapping_mul(a1);
+                let addr = self.heap.alloc(size).unwrap_or(0);
+                if addr != 0 {
+                    fill_bytes(bus, addr, 0, size);
+                }
+                addr
+            },
+            0x38 => self.realloc(bus, a0, a1),
+            0x39 => {
+                self.heap.init(a0, a1);
+                0
+            },
+            0x3c => {
+                self.tty_write(&[a0 as u8]);
+                a0
+            },
+            0x3e => {
+                let mut line = read_cstr(bus, a0);
+                line.push(b'\n');
+                self.tty_write(&line);
+                1
+            },
+            0x3f => {
+                let fmt = read_cstr(bus, a0);
+                let sp = cpu.reg(SP);
+                let mut next = 1;
+                let out = printf::format(
+                    &fmt,
+                    || {
+                        // The fifth argument onwards is on the stack, after the a0-a3 home area
+                        let val = match next {
+                            1..=3 => [a1, a2, a3][next - 1],
+                            _ => bus.load::<u32>(sp.wrapping_add(4 * next as u32)),
+                        };
+                        next += 1;
+                        val
+                    },
+                    |addr| read_cstr(bus, addr),
+                );
+                self.tty_write(&out);
+                out.len() as u32
+            },
+            0x40 => return system_error(cpu, "SystemErrorUnresolvedException"),
+            0x41 | 0x42 => match self.load(bus, a0, a1) {
+                Ok(()) => 1,
+                Err(err) => {
+                    tracing::warn!("HLE BIOS: Load failed: {err}");
+                    0
+                },
+            },
+            0x43 => return self.exec(cpu, bus, a0, a1, a2),
+            0x44 => {
+                bus.invalidate_code_pages();
+                0
+            },
+            0x51 => return self.load_exec(cpu, bus, a0, a1, a2),
+            // CD, memory card and TTY device setup, SetConf, SetMemSize, CD IRQ handlers
+            0x45 | 0x54..=0x56 | 0x71 | 0x72 | 0x95..=0x99 | 0x9c | 0x9f | 0xa2 | 0xa3 => 0,
+            0xa1 => return system_error(cpu, "SystemErrorBootOrDiskFailure"),
+            0xa4 => match self.find_file(&format!("cdrom:{}", lossy(&read_cstr(bus, a0)))) {
+                Some(entry) => entry.lba,
+                None => ERROR,
+            },
+            0xa5 => self.cd_read_sectors(bus, a0, a1, a2),
+            0xa6 => 0x02, // Motor on, shell closed
+            0xab..=0xad => {
+                self.card_absent();
+                1
+            },
+            _ => {
+                tracing::warn!("HLE BIOS: unimplemented A0h:{func:02x} {} @ {}",
+                    ktrace::function_name(0xa0, func).unwrap_or("?"), cpu.location(cpu.reg(RA)));
+                0
+            },
+        })
+    }
+
+    /// B0h functions, see [`Hle::call_a0`]
+    fn call_b0(&mut self, cpu: &mut Cpu, bus: &mut Bus, func: u32) -> Option<u32> {
+        let [a0, a1, a2, a3] = args(cpu);
+        Some(match func {
+            0x00 => self.kernel_heap.alloc(a0).unwrap_or(0),
+            0x01 => {
+                self.kernel_heap.free(a0);
+                0
+            },
+            // Root counters aren't emulated yet
+            0x02 | 0x04..=0x06 => 1,
+            0x03 => 0,
+            0x07 => {
+                self.deliver_event(a0, a1);
+                0
+            },
+            0x08 => self.open_event(a0, a1, a2, a3),
+            0x09 => self.with_event(a0, |event| {
+                *event = Event::default();
+                1
+            }),
+            0x0a | 0x0b => self.with_event(a0, |event| {
+                let ready = event.status == EVENT_READY;
+                if ready {
+                    event.status = EVENT_ENABLED;
+                }
+                ready as u32
+            }),
+            0x0c => self.with_event(a0, |event| {
+                if event.status != EVENT_FREE {
+                    event.status = EVENT_ENABLED;
+                }
+                1
+            }),
+            0x0d => self.with_event(a0, |event| {
+                if event.status != EVENT_FREE {
+                    event.status = EVENT_DISABLED;
+                }
+                1
+            }),
+            0x12 => {
+                self.pads = Some(PadBuffers { bufs: [(a0, a1), (a2, a3)] });
+                self.update_pads(bus, &[None, None]);
+                1
+            },
+            0x13 | 0x14 | 0x5b => 1, // StartPad, StopPad, ChangeClearPad
+            0x17 => {
+                cpu.return_from_exception(cpu.epc());
+                return None;
+            },
+            0x18 => {
+                self.custom_exit = None;
+                0
+            },
+            0x19 => {
+                self.custom_exit = Some(a0);
+                0
+            },
+            0x20 => {
+                for event in self.events.iter_mut().filter(|event| event.class == a0 && event.spec == a1) {
+                    if event.status == EVENT_READY {
+                        event.status = EVENT_ENABLED;
+                    }
+                }
+                0
+            },
+            0x32 => self.file_open(bus, a0),
+            0x33 => self.file_seek(a0, a1, a2),
+            0x34 => self.file_read(bus, a0, a1, a2),
+            0x35 => self.file_write(bus, a0, a1, a2),
+            0x36 => self.file_close(a0),
+            0x37 | 0x39 => 0,
+            0x38 => return self.exit(cpu, a0),
+            0x3a => self.file_getc(a0),
+            0x3b => self.file_putc(a0, a1),
+            0x3d => {
+                self.tty_write(&[a0 as u8]);
+                a0
+            },
+            0x3f => {
+                let mut line = read_cstr(bus, a0);
+                line.push(b'\n');
+                self.tty_write(&line);
+                1
+            },
+            // Directory functions: the only writable device, the memory card, isn't there
+            0x40..=0x46 => 0,
+            0x4a..=0x4c | 0x50 => 1, // InitCard, StartCard, StopCard, allow_new_card
+            0x4d..=0x4f => {
+                self.card_absent();
+                1
+            },
+            0x56 => C0_TABLE,
+            0x57 => B0_TABLE,
+            _ => {
+                tracing::warn!("HLE BIOS: unimplemented B0h:{func:02x} {} @ {}",
+                    ktrace::function_name(0xb0, func).unwrap_or("?"), cpu.location(cpu.reg(RA)));
+                0
+            },
+        })
+    }
+
+    /// C0h functions, see [`Hle::call_a0`]. These set up the kernel internals, which
+    /// mostly don't exist here.
+    fn call_c0(&mut self, cpu: &mut Cpu, _bus: &mut Bus, func: u32) -> Option<u32> {
+        let [a0, a1, ..] = args(cpu);
+        Some(match func {
+            0x08 => {
+                self.kernel_heap.init(a0, a1);
+                0
+            },
+            0x00..=0x07 | 0x09..=0x0d | 0x12 | 0x13 | 0x1c => 0,
+            _ => {
+                tracing::warn!("HLE BIOS: unimplemented C0h:{func:02x} {} @ {}",
+                    ktrace::function_name(0xc0, func).unwrap_or("?"), cpu.location(cpu.reg(RA)));
+                0
+            },
+        })
+    }
+
+    fn exception(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
+        let cause = cpu.cause();
+        let epc = cpu.epc();
+        match (cause >> 2) & 0x1f {
+            // Interrupt: no interrupt sources are emulated, leave through the custom exit
+            // if the program registered one
+            0x00 => match self.custom_exit {
+                Some(buf) => {
+                    restore_regs(cpu, bus, buf);
+                    cpu.write_reg(V0, 1);
+                    cpu.return_from_exception(cpu.reg(RA));
+                },
+                None => cpu.return_from_exception(epc),
+            },
+            // Syscall, with the function number in a0
+            0x08 => {
+                match cpu.reg(A0) {
+                    0x00 => {},
+                    // EnterCriticalSection: disable interrupts once back from the exception
+                    0x01 => {
+                        let sr = cpu.status();
+                        cpu.write_reg(V0, (sr & 0x404 == 0x404) as u32);
+                        cpu.set_status(sr & !0x404);
+                    },
+                    // ExitCriticalSection
+                    0x02 => cpu.set_status(cpu.status() | 0x404),
+                    0x03 => tracing::warn!("HLE BIOS: ChangeThreadSubFunction: threads are unsupported"),
+                    _ => self.deliver_event(CLASS_SYSCALL, SPEC_UNKNOWN_SYSCALL),
+                }
+                cpu.return_from_exception(epc.wrapping_add(4));
+            },
+            code => {
+                tracing::error!("HLE BIOS: unhandled exception {code} (cause 0x{cause:08x}) @ {}, halting",
+                    cpu.location(epc));
+                cpu.set_pc(HALT);
+            },
+        }
+    }
+
+    /// Boot the disc like the BIOS shell would, or wait at the shell entry point for a
+    /// sideloaded executable
+    fn shell(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
+        if self.disc.is_some() {
+            if let Err(err) = self.boot_disc(cpu, bus) {
+                // The BIOS locks up on a disc it can't boot
+                tracing::error!("HLE BIOS: failed to boot disc, halting: {err}");
+                cpu.set_pc(HALT);
+            }
+            return;
+        }
+        // b . ; nop
+        bus.store::<u32>(exe::SHELL_ENTRY, 0x1000_ffff);
+        bus.store::<u32>(exe::SHELL_ENTRY + 4, 0);
+        cpu.set_pc(exe::SHELL_ENTRY);
+    }
+
+    fn boot_disc(&mut self, cpu: &mut Cpu, bus: &mut Bus) -> Result<()> {
+        let (path, stack) = match self.read_file("cdrom:\\SYSTEM.CNF;1") {
+            Ok(cnf) => parse_system_cnf(&cnf)?,
+            Err(_) => ("cdrom:\\PSX.EXE;1".to_string(), None),
+        };
+        tracing::info!("HLE BIOS: booting {path}");
+        let exe = Exe::parse(&self.read_file(&path)?)?;
+        let image = Image { sp: Some(stack.unwrap_or(DEFAULT_STACK)), ..exe.image() };
+        image.load(bus);
+        image.start(cpu);
+        Ok(())
+    }
+
+    /// Load a PS-X EXE from `filename` and write its header (from offset 0x10) to
+    /// `header_buf`, for `Exec`
+    fn load(&mut self, bus: &mut Bus, filename: u32, header_buf: u32) -> Result<()> {
+        let path = lossy(&read_cstr(bus, filename));
+        let buf = self.read_file(&path)?;
+        let exe = Exe::parse(&buf)?;
+        Image { bss: vec![], ..exe.image() }.load(bus);
+        write_bytes(bus, header_buf, &buf[0x10..0x4c]);
+        Ok(())
+    }
+
+    /// Start a program loaded with `Load`. Returns 1 to the caller once the program
+    /// returns.
+    fn exec(&mut self, cpu: &mut Cpu, bus: &mut Bus, header_buf: u32, argc: u32, argv: u32) -> Option<u32> {
+        let field = |offset: u32| bus.load::<u32>(header_buf.wrapping_add(offset));
+        let (pc, gp, bss_addr, bss_size, stack, stack_size) =
+            (field(0x00), field(0x04), field(0x18), field(0x1c), field(0x20), field(0x24));
+
+        // The caller's registers are kept in the header buffer for the return
+        save_regs(cpu, bus, header_buf.wrapping_add(0x28));
+        self.exec_stack.push(header_buf);
+
+        fill_bytes(bus, bss_addr, 0, bss_size);
+        if stack != 0 {
+            cpu.write_reg(SP, stack.wrapping_add(stack_size));
+            cpu.write_reg(FP, stack.wrapping_add(stack_size));
+        }
+        cpu.write_reg(GP, gp);
+        cpu.write_reg(RegisterIndex(4), argc);
+        cpu.write_reg(RegisterIndex(5), argv);
+        cpu.write_reg(RA, EXEC_RETURN_TRAP);
+        cpu.set_pc(pc);
+        None
+    }
+
+    fn exec_return(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
+        let Some(header_buf) = self.exec_stack.pop() else {
+            tracing::error!("HLE BIOS: returned from a program that wasn't started with Exec, halting");
+            cpu.set_pc(HALT);
+            return;
+        };
+        restore_regs(cpu, bus, header_buf.wrapping_add(0x28));
+        cpu.write_reg(V0, 1);
+        cpu.set_pc(cpu.reg(RA));
+    }
+
+    /// Start the executable at `filename`. Returns 0 to the caller if it can't be loaded.
+    fn load_exec(&mut self, cpu: &mut Cpu, bus: &mut Bus, filename: u32, stack: u32, stack_offset: u32) -> Option<u32> {
+        let path = lossy(&read_cstr(bus, filename));
+        let exe = match self.read_file(&path).and_then(|buf| Exe::parse(&buf)) {
+            Ok(exe) => exe,
+            Err(err) => {
+                tracing::warn!("HLE BIOS: LoadExec {path} failed: {err}");
+                return Some(0);
+            },
+        };
+        let sp = match stack {
+            0 => exe.sp.unwrap_or(DEFAULT_STACK),
+            stack => stack.wrapping_add(stack_offset),
+        };
+        let image = Image { sp: Some(sp), ..exe.image() };
+        image.load(bus);
+        image.start(cpu);
+        None
+    }
+
+    fn exit(&mut self, cpu: &mut Cpu, code: u32) -> Option<u32> {
+        tracing::info!("HLE BIOS: program exited with code {code}");
+        self.exit_code = Some(code);
+        cpu.set_pc(HALT);
+        None
+    }
+
+    fn realloc(&mut self, bus: &mut Bus, old: u32, size: u32) -> u32 {
+        if old == 0 {
+            return self.heap.alloc(size).unwrap_or(0);
+        }
+        let Some(old_size) = self.heap.free(old) else { return 0 };
+        let data = read_bytes(bus, old, old_size.min(size));
+        match self.heap.alloc(size) {
+            Some(addr) => {
+                write_bytes(bus, addr, &data);
+                addr
+            },
+            None => {
+                // Put the old block back, realloc failures leave it untouched
+                self.heap.blocks.push((old, old_size));
+                self.heap.blocks.sort_unstable();
+                0
+            },
+        }
+    }
+
+    fn tty_write(&mut self, data: &[u8]) {
+        self.tty.extend_from_slice(data);
+        let excess = self.tty.len().saturating_sub(ktrace::TTY_CAPACITY);
+        self.tty.drain(..excess);
+    }
+
+    fn open_event(&mut self, class: u32, spec: u32, mode: u32, func: u32) -> u32 {
+        let Some(idx) = self.events.iter().position(|event| event.status == EVENT_FREE) else {
+            return ERROR;
+        };
+        self.events[idx] = Event { class, spec, mode, func, status: EVENT_DISABLED };
+        0xf100_0000 | idx as u32
+    }
+
+    /// Run `f` on the event behind `handle`, returning its result or 0 for bad handles
+    fn with_event(&mut self, handle: u32, f: impl FnOnce(&mut Event) -> u32) -> u32 {
+        match self.events.get_mut((handle & 0xffff) as usize) {
+            Some(event) if handle & 0xffff_0000 == 0xf100_0000 => f(event),
+            _ => 0,
+        }
+    }
+
+    fn deliver_event(&mut self, class: u32, spec: u32) {
+        for event in self.events.iter_mut().filter(|event| event.class == class && event.spec == spec) {
+            if event.status != EVENT_ENABLED {
+                continue;
+            }
+            if event.mode == MODE_CALLBACK {
+                tracing::warn!("HLE BIOS: event callback 0x{:08x} not called, callbacks are unsupported", event.func);
+            } else {
+                event.status = EVENT_READY;
+            }
+        }
+    }
+
+    /// Memory card accesses fail with a timeout, as if no card was inserted
+    fn card_absent(&mut self) {
+        self.deliver_event(CLASS_SW_CARD, SPEC_TIMEOUT);
+        self.deliver_event(CLASS_HW_CARD, SPEC_TIMEOUT);
+    }
+
+    /// Look up `path`, e.g. `cdrom:\SYSTEM.CNF;1`, on the disc
+    fn find_file(&mut self, path: &str) -> Option<crate::emu::disc::DirEntry> {
+        let name = path.strip_prefix("cdrom:")?;
+        self.disc.as_mut()?.find(name).ok()?
+    }
+
+    /// Whole contents of the file at `path`. Only `cdrom:` is supported, no memory cards
+    /// are inserted.
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let Some((device, name)) = path.split_once(':') else {
+            bail!("no device in file name {path}");
+        };
+        match device {
+            "cdrom" | "cdrom0" => {
+                let disc = self.disc.as_mut().ok_or_else(|| anyhow!("no disc inserted"))?;
+                let entry = disc.find(name)?.ok_or_else(|| anyhow!("{path} not found"))?;
+                disc.read_file(entry)
+            },
+            device => bail!("device {device}: not available"),
+        }
+    }
+
+    fn file_open(&mut self, bus: &mut Bus, filename: u32) -> u32 {
+        let path = lossy(&read_cstr(bus, filename));
+        let Some(fd) = (2..MAX_FILES).find(|&fd| self.files[fd].is_none()) else {
+            return ERROR;
+        };
+        match self.read_file(&path) {
+            Ok(data) => {
+                self.files[fd] = Some(OpenFile { data, pos: 0 });
+                fd as u32
+            },
+            Err(err) => {
+                tracing::debug!("HLE BIOS: FileOpen {path} failed: {err}");
+                ERROR
+            },
+        }
+    }
+
+    fn file(&mut self, fd: u32) -> Option<&mut OpenFile> {
+        self.files.get_mut(fd as usize)?.as_mut()
+    }
+
+    fn file_seek(&mut self, fd: u32, offset: u32, whence: u32) -> u32 {
+        let Some(file) = self.file(fd) else { return ERROR };
+        let pos = match whence {
+            0 => offset as i64,
+            1 => file.pos as i64 + offset as i32 as i64,
+            _ => return ERROR,
+        };
+        match usize::try_from(pos) {
+            Ok(pos) => {
+                file.pos = pos;
+                pos as u32
+            },
+            Err(_) => ERROR,
+        }
+    }
+
+    fn file_read(&mut self, bus: &mut Bus, fd: u32, dst: u32, len: u32) -> u32 {
+        let Some(file) = self.file(fd) else {
+            // The TTY has no input
+            return if fd < 2 { 0 } else { ERROR };
+        };
+        let start = file.pos.min(file.data.len());
+        let end = start.saturating_add(len as usize).min(file.data.len());
+        file.pos = end;
+        let data = file.data[start..end].to_vec();
+        write_bytes(bus, dst, &data);
+        data.len() as u32
+    }
+
+    fn file_write(&mut self, bus: &mut Bus, fd: u32, src: u32, len: u32) -> u32 {
+        if fd >= 2 {
+            // Nothing on the disc is writable
+            return ERROR;
+        }
+        let data = read_bytes(bus, src, len);
+        self.tty_write(&data);
+        len
+    }
+
+    fn file_close(&mut self, fd: u32) -> u32 {
+        match self.files.get_mut(fd as usize).and_then(Option::take) {
+            Some(_) => fd,
+            None => ERROR,
+        }
+    }
+
+    fn file_getc(&mut self, fd: u32) -> u32 {
+        let Some(file) = self.file(fd) else { return ERROR };
+        let Some(&byte) = file.data.get(file.pos) else { return ERROR };
+        file.pos += 1;
+        byte as u32
+    }
+
+    fn file_putc(&mut self, c: u32, fd: u32) -> u32 {
+        if fd >= 2 {
+            return ERROR;
+        }
+        self.tty_write(&[c as u8]);
+        c
+    }
+
+    fn cd_read_sectors(&mut self, bus: &mut Bus, count: u32, lba: u32, dst: u32) -> u32 {
+        let Some(disc) = self.disc.as_mut() else { return ERROR };
+        let Some(end) = lba.checked_add(count) else { return ERROR };
+        let mut dst = dst;
+        for lba in lba..end {
+            match disc.read_sector(lba) {
+                Ok(sector) => {
+                    write_bytes(bus, dst, &sector);
+                    dst = dst.wrapping_add(sector.len() as u32);
+                },
+                Err(err) => {
+                    tracing::warn!("HLE BIOS: CdReadSector failed: {err}");
+                    return ERROR;
+                },
+            }
+        }
+        count
+    }
+}
+
+fn args(cpu: &Cpu) -> [u32; 4] {
+    [4, 5, 6, 7].map(|idx| cpu.reg(RegisterIndex(idx)))
+}
+
+/// Save the callee-saved registers to `buf`, like `setjmp`
+fn save_regs(cpu: &Cpu, bus: &mut Bus, buf: u32) {
+    for (idx, reg) in JMP_BUF_REGS.into_iter().enumerate() {
+        bus.store::<u32>(buf.wrapping_add(4 * idx as u32), cpu.reg(RegisterIndex(reg)));
+    }
+}
+
+/// Restore the registers saved by [`save_regs`] and continue at the saved ra
+fn restore_regs(cpu: &mut Cpu, bus: &Bus, buf: u32) {
+    for (idx, reg) in JMP_BUF_REGS.into_iter().enumerate() {
+        cpu.write_reg(RegisterIndex(reg), bus.load::<u32>(buf.wrapping_add(4 * idx as u32)));
+    }
+    cpu.set_pc(cpu.reg(RA));
+}
+
+fn read_bytes(bus: &Bus, addr: u32, len: u32) -> Vec<u8> {
+    (0..len.min(MAX_BLOCK_SIZE)).map(|offset| bus.load::<u8>(addr.wrapping_add(offset))).collect()
+}
+
+fn write_bytes(bus: &mut Bus, addr: u32, data: &[u8]) {
+    for (offset, &byte) in data.iter().enumerate() {
+        bus.store::<u8>(addr.wrapping_add(offset as u32), byte);
+    }
+}
+
+/// Set `len` bytes at `addr` to `val`
+fn fill_bytes(bus: &mut Bus, addr: u32, val: u8, len: u32) {
+    for offset in 0..len.min(MAX_BLOCK_SIZE) {
+        bus.store::<u8>(addr.wrapping_add(offset), val);
+    }
+}
+
+/// Log a fatal kernel error and lock up, as the BIOS does
+fn system_error(cpu: &mut Cpu, name: &str) -> Option<u32> {
+    tracing::error!("HLE BIOS: {name} called @ {}, halting", cpu.location(cpu.reg(RA)));
+    cpu.set_pc(HALT);
+    None
+}
+
+/// NUL terminated string at `addr`, without the terminator
+fn read_cstr(bus: &Bus, addr: u32) -> Vec<u8> {
+    if addr == 0 {
+        return b"(null)".to_vec();
+    }
+    (0..0x1_0000)
+        .map(|offset| bus.load::<u8>(addr.wrapping_add(offset)))
+        .take_while(|&b| b != 0)
+        .collect()
+}
+
+fn write_cstr(bus: &mut Bus, addr: u32, s: &[u8]) {
+    write_bytes(bus, addr, s);
+    bus.store::<u8>(addr.wrapping_add(s.len() as u32), 0);
+}
+
+fn lossy(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).into_owned()
+}
+
+fn truncated(mut s: Vec<u8>, len: u32) -> Vec<u8> {
+    s.truncate(len as usize);
+    s
+}
+
+/// Difference of the first mismatching bytes, like the C library
+fn memcmp(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b)
+        .find(|(a, b)| a != b)
+        .map_or(0, |(&a, &b)| (a as i32 - b as i32) as u32)
+}
+
+fn strcmp(a: &[u8], b: &[u8]) -> u32 {
+    // Compare the terminators too, so that a prefix sorts first
+    memcmp(&[a, &[0]].concat(), &[b, &[0]].concat())
+}
+
+/// `strtol`/`strtoul`, storing the end of the parsed number to `end_ptr` if not null
+fn strtol(bus: &mut Bus, src: u32, end_ptr: u32, base: u32, signed: bool) -> u32 {
+    let s = read_cstr(bus, src);
+    let mut idx = s.iter().take_while(|b| b.is_ascii_whitespace()).count();
+    let negative = match s.get(idx) {
+        Some(b'-') => {
+            idx += 1;
+            true
+        },
+        Some(b'+') => {
+            idx += 1;
+            false
+        },
+        _ => false,
+    };
+    let has_hex_prefix = s.get(idx) == Some(&b'0') && matches!(s.get(idx + 1), Some(b'x' | b'X'));
+    let base = match base {
+        0 if has_hex_prefix => 16,
+        0 if s.get(idx) == Some(&b'0') => 8,
+        0 => 10,
+        base => base,
+    };
+    if base == 16 && has_hex_prefix {
+        idx += 2;
+    }
+    let mut val = 0u32;
+    while let Some(digit) = s.get(idx).and_then(|&b| (b as char).to_digit(base.min(36))) {
+        val = val.wrapping_mul(base).wrapping_add(digit);
+        idx += 1;
+    }
+    if end_ptr != 0 {
+        bus.store::<u32>(end_ptr, src.wrapping_add(idx as u32));
+    }
+    match negative && (signed || val != 0) {
+        true => val.wrapping_neg(),
+        false => val,
+    }
+}
+
+/// Boot file and stack top from SYSTEM.CNF, e.g. `BOOT = cdrom:\SLUS_000.01;1`
+fn parse_system_cnf(cnf: &[u8]) -> Result<(String, Option<u32>)> {
+    let mut boot = None;
+    let mut stack = None;
+    for line in lossy(cnf).lines() {
+        let Some((key, val)) = line.split_once('=') else { continue };
+        let val = val.trim();
+        match key.trim().to_ascii_uppercase().as_str() {
+            // Anything after the file name, e.g. arguments, is ignored
+            "BOOT" => boot = val.split_whitespace().next().map(str::to_string),
+            "STACK" => stack = u32::from_str_radix(val.trim_start_matches("0x"), 16).ok(),
+            _ => {},
+        }
+    }
+    let boot = boot.ok_or_else(|| anyhow!("SYSTEM.CNF has no BOOT entry"))?;
+    Ok((boot, stack))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{disc::build_iso, exe::HEADER_SIZE, pad::{Button, PadKind}, Psx};
+
+    const ORIGIN: u32 = 0x8001_0000;
+
+    /// PS-X EXE image of `source`, assembled at `ORIGIN`
+    fn build_exe(source: &str) -> Vec<u8> {
+        let program = assemble(source, ORIGIN).unwrap();
+        let text: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let text_size = (text.len() + 0x7ff) & !0x7ff;
+        let mut buf = vec![0; HEADER_SIZE];
+        buf[..8].copy_from_slice(b"PS-X EXE");
+        for (offset, val) in [(0x10, ORIGIN), (0x18, ORIGIN), (0x1c, text_size as u32)] {
+            buf[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(val));
+        }
+        buf.extend(text);
+        buf.resize(HEADER_SIZE + text_size, 0);
+        buf
+    }
+
+    /// Run `psx` until the program calls `exit`, returning the exit code
+    fn run_to_exit(psx: &mut Psx) -> u32 {
+        while psx.hle().unwrap().exit_code().is_none() {
+            assert!(psx.instructions_retired < 100_000, "program never exited");
+            psx.step();
+        }
+        psx.hle().unwrap().exit_code().unwrap()
+    }
+
+    /// Program calling the kernel functions through the A0h/B0h vectors
+    const KERNEL_CALLS: &str = "
+            # InitHeap(heap, 0x1000)
+            la    a0, heap
+            li    a1, 0x1000
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x39
+            # s0 = malloc(16), s1 = malloc(16)
+            li    a0, 16
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x33
+            move  s0, v0
+            li    a0, 16
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x33
+            move  s1, v0
+            # strcpy(s0, hello); s2 = strlen(s0)
+            move  a0, s0
+            la    a1, hello
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x19
+            move  a0, s0
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x1b
+            move  s2, v0
+            # s3 = OpenEvent(0xf0000010, 0x4000, 0x2000, 0); EnableEvent(s3)
+            li    a0, 0xf0000010
+            li    a1, 0x4000
+            li    a2, 0x2000
+            li    a3, 0
+            li    t2, 0xb0
+            jalr  t2
+            li    t1, 0x08
+            move  s3, v0
+            move  a0, s3
+            li    t2, 0xb0
+            jalr  t2
+            li    t1, 0x0c
+            # An unknown syscall delivers the event, s4 = TestEvent(s3)
+            li    a0, 0x10
+            syscall
+            move  a0, s3
+            li    t2, 0xb0
+            jalr  t2
+            li    t1, 0x0b
+            move  s4, v0
+            # ExitCriticalSection, then s5 = EnterCriticalSection
+            li    a0, 2
+            syscall
+            li    a0, 1
+            syscall
+            move  s5, v0
+            # exit(42)
+            li    a0, 42
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x06
+        hello:
+            .word 0x6c6c6568, 0x0000006f
+        heap:
+    ";
+
+    #[test]
+    fn kernel_calls() {
+        let mut psx = Psx::new_hle(None);
+        psx.sideload(Exe::parse(&build_exe(KERNEL_CALLS)).unwrap().image(), exe::ExeBoot::Intercept);
+        assert_eq!(run_to_exit(&mut psx), 42);
+
+        let reg = |idx| psx.cpu().reg(RegisterIndex(idx));
+        let (heap, s0, s1) = (ORIGIN + assemble(KERNEL_CALLS, ORIGIN).unwrap().len() as u32 * 4, reg(16), reg(17));
+        assert_eq!((s0, s1), (heap, heap + 16));
+        assert_eq!(read_cstr(psx.bus(), s0), b"hello");
+        assert_eq!(reg(18), 5);
+        assert_eq!(reg(19), 0xf100_0000);
+        assert_eq!(reg(20), 1);
+        assert_eq!(reg(21), 1);
+        assert_eq!(psx.cpu().status() & 0x404, 0);
+    }
+
+    #[test]
+    fn boots_disc() {
+        let exe = build_exe("
+                # printf(fmt, 0x7f, str) then spin
+                la    a0, fmt
+                li    a1, 0x7f
+                la    a2, str
+                li    t2, 0xa0
+                jalr  t2
+                li    t1, 0x3f
+                move  s0, v0
+            spin:
+                b     spin
+                nop
+            fmt:
+                .word 0x20782520, 0x000a7325  # ' %x %s\\n'
+            str:
+                .word 0x00006b6f  # 'ok'
+        ");
+        let iso = build_iso(&[("SYSTEM.CNF", b"BOOT = cdrom:\\MAIN.EXE;1\r\nSTACK = 801FFF00\r\n"), ("MAIN.EXE", &exe)]);
+        let mut psx = Psx::new_hle(Some(Disc::from_bytes(iso).unwrap()));
+        let spin = ORIGIN + 9 * 4;
+        while psx.cpu().pc() != spin {
+            assert!(psx.instructions_retired < 100_000, "disc never booted");
+            psx.step();
+        }
+        assert_eq!(psx.cpu().reg(RegisterIndex(16)), " 7f ok\n".len() as u32);
+        assert_eq!(psx.cpu().reg(SP), 0x801f_ff00);
+        assert_eq!(psx.take_tty(), b" 7f ok\n");
+    }
+
+    #[test]
+    fn rejects_bad_executables() {
+        let mut bad = build_exe("nop");
+        bad[0x18..0x1c].copy_from_slice(&0xbfc0_0000u32.to_le_bytes());
+        let exe = build_exe("
+                # s0 = Load(bad, header)
+                la    a0, bad
+                la    a1, header
+                li    t2, 0xa0
+                jalr  t2
+                li    t1, 0x42
+                move  s0, v0
+                # s1 = LoadExec(bad, 0, 0)
+                la    a0, bad
+                li    a1, 0
+                li    a2, 0
+                li    t2, 0xa0
+                jalr  t2
+                li    t1, 0x51
+                move  s1, v0
+            spin:
+                b     spin
+                nop
+            bad:
+                .word 0x6f726463, 0x5c3a6d, 0x2e444142, 0x3b455845, 0x31  # 'cdrom:\\BAD.EXE;1'
+            header:
+        ");
+        let iso = build_iso(&[("PSX.EXE", &exe), ("BAD.EXE", &bad)]);
+        let mut psx = Psx::new_hle(Some(Disc::from_bytes(iso.clone()).unwrap()));
+        let spin = ORIGIN + 16 * 4;
+        psx.cpu_mut().write_reg(RegisterIndex(17), 0xdead);
+        while psx.cpu().pc() != spin {
+            assert!(psx.instructions_retired < 100_000, "never reached the spin loop");
+            psx.step();
+        }
+        assert_eq!(psx.cpu().reg(RegisterIndex(16)), 0);
+        assert_eq!(psx.cpu().reg(RegisterIndex(17)), 0);
+
+        // A disc booting it halts
+        let iso = build_iso(&[("PSX.EXE", &bad)]);
+        let mut psx = Psx::new_hle(Some(Disc::from_bytes(iso).unwrap()));
+        while psx.cpu().pc() != HALT {
+            assert!(psx.instructions_retired < 100_000, "never halted");
+            psx.step();
+        }
+    }
+
+    #[test]
+    fn system_errors_halt() {
+        let exe = build_exe("
+                # s0 = CdReadSector(1, 0xffffffff, buf)
+                li    a0, 1
+                li    a1, 0xffffffff
+                la    a2, buf
+                li    t2, 0xa0
+                jalr  t2
+                li    t1, 0xa5
+                move  s0, v0
+                # SystemErrorUnresolvedException()
+                li    t2, 0xa0
+                jalr  t2
+                li    t1, 0x40
+            buf:
+        ");
+        let iso = build_iso(&[("PSX.EXE", &exe)]);
+        let mut psx = Psx::new_hle(Some(Disc::from_bytes(iso).unwrap()));
+        while psx.cpu().pc() != HALT {
+            assert!(psx.instructions_retired < 100_000, "never halted");
+            psx.step();
+        }
+        assert_eq!(psx.cpu().reg(RegisterIndex(16)), ERROR);
+        for _ in 0..10 {
+            psx.step();
+        }
+        assert!(matches!(psx.cpu().pc(), HALT | 0xbfc1_0c14), "{:08x}", psx.cpu().pc());
+    }
+
+    #[test]
+    fn updates_pads_at_vblank() {
+        let exe = build_exe("
+                # InitPad(pad1, 34, pad2, 34) then spin
+                la    a0, pad1
+                li    a1, 34
+                la    a2, pad2
+                li    a3, 34
+                li    t2, 0xb0
+                jalr  t2
+                li    t1, 0x12
+            spin:
+                b     spin
+                nop
+            pad1:
+                .word 0, 0
+            pad2:
+                .word 0, 0
+        ");
+        let mut psx = Psx::new_hle(None);
+        psx.sideload(Exe::parse(&exe).unwrap().image(), exe::ExeBoot::Intercept);
+        let mut pad = PadState::new(PadKind::DualShock);
+        pad.set(Button::Cross, true);
+        psx.set_pads([Some(pad), None]);
+        while psx.frames() < 2 {
+            psx.step();
+        }
+
+        let (pad1, pad2) = (ORIGIN + 11 * 4, ORIGIN + 13 * 4);
+        let read = |addr: u32, len: u32| -> Vec<u8> { (0..len).map(|offset| psx.bus().load::<u8>(addr + offset)).collect() };
+        assert_eq!(read(pad1, 8), [0x00, 0x73, 0xff, 0xbf, 0x80, 0x80, 0x80, 0x80]);
+        assert_eq!(read(pad2, 2), [0xff, 0x00]);
+    }
+
+    #[test]
+    fn heap_allocation() {
+        let mut heap = Heap::default();
+        heap.init(0x1000, 0x40);
+        assert_eq!(heap.alloc(10), Some(0x1000));
+        assert_eq!(heap.alloc(0x20), Some(0x100c));
+        assert_eq!(heap.alloc(0x20), None);
+        assert_eq!(heap.free(0x1000), Some(12));
+        assert_eq!(heap.alloc(8), Some(0x1000));
+        assert_eq!(heap.alloc(4), Some(0x1008));
+        assert_eq!(heap.alloc(4), Some(0x102c));
+    }
+
+    #[test]
+    fn system_cnf() {
+        let (boot, stack) = parse_system_cnf(b"BOOT = cdrom:\\SLUS_000.01;1 arg\r\nTCB = 4\r\nSTACK=801FFFF0\r\n").unwrap();
+        assert_eq!((boot.as_str(), stack), ("cdrom:\\SLUS_000.01;1", Some(0x801f_fff0)));
+        assert!(parse_system_cnf(b"TCB = 4").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! `printf` style formatting for the BIOS TTY functions
+
+use std::iter::Peekable;
+
+/// Format `fmt` like the BIOS `printf`, pulling the arguments from `next_arg` and reading
+/// `%s` strings through `read_str`.
+///
+/// Supports the `d i u o x X p c s %` conversions with the `- + space 0 #` flags, width,
+/// precision (including `*`) and the `h`/`l` length modifiers, which are ignored since
+/// every argument is 32 bits wide.
+pub fn format(fmt: &[u8], mut next_arg: impl FnMut() -> u32, read_str: impl Fn(u32) -> Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut chars = fmt.iter().copied().peekable();
+    while let Some(c) = chars.next() {
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut spec = Spec::default();
+        while let Some(flag) = chars.next_if(|c| b"-+ 0#".contains(c)) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'0' => spec.zero = true,
+                _ => spec.alt = true,
+            }
+        }
+        spec.width = match chars.next_if_eq(&b'*') {
+            Some(_) => {
+                let width = next_arg() as i32;
+                spec.left |= width < 0;
+                width.unsigned_abs() as usize
+            },
+            None => number(&mut chars).unwrap_or(0),
+        };
+        if chars.next_if_eq(&b'.').is_some() {
+            spec.precision = match chars.next_if_eq(&b'*') {
+                Some(_) => usize::try_from(next_arg() as i32).ok(),
+                None => Some(number(&mut chars).unwrap_or(0)),
+            };
+        }
+        while chars.next_if(|c| matches!(c, b'h' | b'l' | b'L')).is_some() {}
+
+        let Some(conv) = chars.next() else {
+            out.push(b'%');
+            break;
+        };
+        match conv {
+            b'd' | b'i' => {
+                let val = next_arg() as i32;
+                let sign: &[u8] = match () {
+                    _ if val < 0 => b"-",
+                    _ if spec.plus => b"+",
+                    _ if spec.space => b" ",
+                    _ => b"",
+                };
+                spec.number(&mut out, sign, val.unsigned_abs().to_string());
+            },
+            b'u' => spec.number(&mut out, b"", next_arg().to_string()),
+            b'o' => {
+                let prefix: &[u8] = if spec.alt { b"0" } else { b"" };
+                spec.number(&mut out, prefix, format!("{:o}", next_arg()));
+            },
+            b'x' | b'p' => {
+                let val = next_arg();
+                let prefix: &[u8] = if spec.alt && val != 0 { b"0x" } else { b"" };
+                spec.number(&mut out, prefix, format!("{val:x}"));
+            },
+            b'X' => {
+                let val = next_arg();
+                let prefix: &[u8] = if spec.alt && val != 0 { b"0X" } else { b"" };
+                spec.number(&mut out, prefix, format!("{val:X}"));
+            },
+            b'c' => spec.pad(&mut out, b"", &[next_arg() as u8]),
+            b's' => {
+                let mut s = read_str(next_arg());
+                if let Some(precision) = spec.precision {
+                    s.truncate(precision);
+                }
+                spec.zero = false;
+                spec.pad(&mut out, b"", &s);
+            },
+            b'%' => out.push(b'%'),
+            other => out.extend([b'%', other]),
+        }
+    }
+    out
+}
+
+fn number(chars: &mut Peekable<impl Iterator<Item = u8>>) -> Option<usize> {
+    let mut val = None;
+    while let Some(digit) = chars.next_if(u8::is_ascii_digit) {
+        val = Some(val.unwrap_or(0) * 10 + (digit - b'0') as usize);
+    }
+    val
+}
+
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    zero: bool,
+    alt: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Emit the digits of a number, applying the precision as a minimum digit count
+    fn number(&self, out: &mut Vec<u8>, prefix: &[u8], digits: String) {
+        let digits = match self.precision {
+            Some(0) if digits == "0" => String::new(),
+            Some(precision) => format!("{digits:0>precision$}"),
+            None => digits,
+        };
+        self.pad(out, prefix, digits.as_bytes());
+    }
+
+    /// Emit `prefix` and `body` padded to the field width
+    fn pad(&self, out: &mut Vec<u8>, prefix: &[u8], body: &[u8]) {
+        let fill = self.width.saturating_sub(prefix.len() + body.len());
+        let zero_fill = self.zero && !self.left && self.precision.is_none();
+        if !self.left && !zero_fill {
+            out.extend(std::iter::repeat(b' ').take(fill));
+        }
+        out.extend_from_slice(prefix);
+        if zero_fill {
+            out.extend(std::iter::repeat(b'0').take(fill));
+        }
+        out.extend_from_slice(body);
+        if self.left {
+            out.extend(std::iter::repeat(b' ').take(fill));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sprintf(fmt: &str, args: &[u32]) -> String {
+        let mut args = args.iter().copied();
+        let out = format(fmt.as_bytes(), || args.next().unwrap(), |addr| match addr {
+            1 => b"hello".to_vec(),
+            _ => b"(null)".to_vec(),
+        });
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(sprintf("%d %i %u", &[-5i32 as u32, 42, -1i32 as u32]), "-5 42 4294967295");
+        assert_eq!(sprintf("%x %X %#x %o %c%%", &[0xbeef, 0xbeef, 0x10, 8, b'!' as u32]), "beef BEEF 0x10 10 !%");
+        assert_eq!(sprintf("[%s] [%.3s]", &[1, 1]), "[hello] [hel]");
+        assert_eq!(sprintf("%ld %hx %q", &[7, 0x12]), "7 12 %q");
+    }
+
+    #[test]
+    fn width_and_flags() {
+        assert_eq!(sprintf("[%5d] [%-5d] [%05d] [%+d] [% d]", &[42, 42, -42i32 as u32, 42, 42]), "[   42] [42   ] [-0042] [+42] [ 42]");
+        assert_eq!(sprintf("[%08x] [%.4x] [%*d] [%-*d]", &[0xabc, 0xab, 4, 7, 3, 7]), "[00000abc] [00ab] [   7] [7  ]");
+        assert_eq!(sprintf("[%8s] [%-8s]", &[1, 1]), "[   hello] [hello   ]");
+    }
+}
@@ -0,0 +1,273 @@
+//! Controller serial port (SIO0, the JOY registers)
+//!
+//! Bytes are exchanged as soon as they are written, and the controllers in both ports
+//! answer the poll command (`01 42 00 ...`) with their [`PadState::report`]. Memory cards
+//! and the configuration commands aren't emulated, they get no reply. There is no interrupt
+//! controller yet, so an acknowledge only raises the request bit of `JOY_STAT`: the port
+//! works for programs polling it, not for the BIOS's interrupt-driven pad driver.
+
+use std::cell::Cell;
+
+use crate::emu::{
+    pad::PadState,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+/// `JOY_TX_DATA` (write) and `JOY_RX_DATA` (read)
+const DATA: u32 = 0x0;
+const STAT: u32 = 0x4;
+const MODE: u32 = 0x8;
+const CTRL: u32 = 0xa;
+const BAUD: u32 = 0xe;
+
+/// `JOY_STAT` bits
+const STAT_TX_READY: u32 = 1 << 0;
+const STAT_RX_NOT_EMPTY: u32 = 1 << 1;
+const STAT_TX_FINISHED: u32 = 1 << 2;
+/// The device pulled its /ACK line low after the last byte
+const STAT_ACK: u32 = 1 << 7;
+const STAT_IRQ: u32 = 1 << 9;
+
+/// `JOY_CTRL` bits
+const CTRL_SELECT: u16 = 1 << 1;
+/// Writing it clears the interrupt request
+const CTRL_IRQ_ACK: u16 = 1 << 4;
+const CTRL_RESET: u16 = 1 << 6;
+const CTRL_ACK_IRQ_ENABLE: u16 = 1 << 12;
+/// Port 2 instead of port 1
+const CTRL_PORT2: u16 = 1 << 13;
+
+/// First byte of a transfer addressing a controller (memory cards are 0x81)
+const ADDRESS_PAD: u8 = 0x01;
+const COMMAND_POLL: u8 = 0x42;
+/// Reply to the byte after the command
+const REPLY_READY: u8 = 0x5a;
+/// Nothing driving the line
+const HIGH_Z: u8 = 0xff;
+
+/// Where a transfer is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Transfer {
+    /// Waiting for the address byte
+    #[default]
+    Idle,
+    /// Talking to the pad, this many bytes after the address
+    Pad(u8),
+    /// Nobody answers until the port is deselected
+    Ignored,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Joy {
+    ctrl: u16,
+    mode: u16,
+    baud: u16,
+    /// Received byte, which reading `JOY_RX_DATA` takes
+    rx: Cell<Option<u8>>,
+    ack: bool,
+    irq: bool,
+    transfer: Transfer,
+    /// Host input, not part of the state
+    pads: [Option<PadState>; 2],
+}
+
+impl Snapshot for Joy {
+    fn save(&self, w: &mut StateWriter) {
+        w.u32(self.ctrl as u32);
+        w.u32(self.mode as u32);
+        w.u32(self.baud as u32);
+        w.option_u32(self.rx.get().map(u32::from));
+        w.bool(self.ack);
+        w.bool(self.irq);
+        let (state, step) = match self.transfer {
+            Transfer::Idle => (0, 0),
+            Transfer::Pad(step) => (1, step),
+            Transfer::Ignored => (2, 0),
+        };
+        w.u8(state);
+        w.u8(step);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.ctrl = r.u32()? as u16;
+        self.mode = r.u32()? as u16;
+        self.baud = r.u32()? as u16;
+        self.rx.set(r.option_u32()?.map(|byte| byte as u8));
+        self.ack = r.bool()?;
+        self.irq = r.bool()?;
+        let (state, step) = (r.u8()?, r.u8()?);
+        self.transfer = match state {
+            0 => Transfer::Idle,
+            1 => Transfer::Pad(step),
+            2 => Transfer::Ignored,
+            _ => anyhow::bail!("bad controller port transfer state {state}"),
+        };
+        Ok(())
+    }
+}
+
+impl Joy {
+    pub fn new() -> Self {
+        Joy::default()
+    }
+
+    /// Plug in (or update) the controllers of ports 1 and 2
+    pub fn set_pads(&mut self, pads: [Option<PadState>; 2]) {
+        self.pads = pads;
+    }
+
+    /// Read the register at `offset`. Reading `JOY_RX_DATA` takes the received byte.
+    pub fn load(&self, offset: u32) -> u32 {
+        match offset & !1 {
+            DATA => self.rx.take().unwrap_or(HIGH_Z) as u32,
+            STAT => {
+                let mut stat = STAT_TX_READY | STAT_TX_FINISHED;
+                if self.rx.get().is_some() {
+                    stat |= STAT_RX_NOT_EMPTY;
+                }
+                if self.ack {
+                    stat |= STAT_ACK;
+                }
+                if self.irq {
+                    stat |= STAT_IRQ;
+                }
+                stat
+            },
+            MODE => self.mode as u32,
+            CTRL => self.ctrl as u32,
+            BAUD => self.baud as u32,
+            _ => {
+                tracing::warn!("read from controller port (offset 0x{offset:x}), but this is unimplemented");
+                0
+            },
+        }
+    }
+
+    /// Write the register at `offset`
+    pub fn store(&mut self, offset: u32, val: u32) {
+        match offset & !1 {
+            DATA => self.transmit(val as u8),
+            MODE => self.mode = val as u16,
+            CTRL => {
+                let ctrl = val as u16;
+                if ctrl & CTRL_RESET != 0 {
+                    *self = Joy { pads: self.pads, ..Joy::default() };
+                    return;
+                }
+                if ctrl & CTRL_IRQ_ACK != 0 {
+                    self.irq = false;
+                }
+                // Deselecting (or switching ports) ends the transfer
+                if ctrl & (CTRL_SELECT | CTRL_PORT2) != self.ctrl & (CTRL_SELECT | CTRL_PORT2) {
+                    self.transfer = Transfer::Idle;
+                    self.ack = false;
+                }
+                self.ctrl = ctrl & !CTRL_IRQ_ACK;
+            },
+            BAUD => self.baud = val as u16,
+            _ => tracing::warn!("wrote to controller port (offset 0x{offset:x}), but this is unimplemented"),
+        }
+    }
+
+    /// Send `byte` to the selected port and receive the device's reply
+    fn transmit(&mut self, byte: u8) {
+        let pad = match self.ctrl & CTRL_SELECT {
+            0 => None,
+            _ => self.pads[(self.ctrl & CTRL_PORT2 != 0) as usize],
+        };
+        let (reply, ack, next) = match (self.transfer, pad) {
+            (Transfer::Idle, Some(_)) if byte == ADDRESS_PAD => (HIGH_Z, true, Transfer::Pad(0)),
+            (Transfer::Pad(step), Some(pad)) => {
+                // The ID, then the ready marker, then the rest of the report
+                let report = pad.report();
+                let reply = match step {
+                    0 => {
+                        if byte != COMMAND_POLL {
+                            tracing::debug!("pad command 0x{byte:02x} isn't emulated, polling instead");
+                        }
+                        report[0]
+                    },
+                    1 => REPLY_READY,
+                    _ => report.get(step as usize - 1).copied().unwrap_or(HIGH_Z),
+                };
+                (reply, (step as usize) < report.len(), Transfer::Pad(step + 1))
+            },
+            _ => (HIGH_Z, false, Transfer::Ignored),
+        };
+        self.rx.set(Some(reply));
+        self.ack = ack;
+        self.transfer = if ack { next } else { Transfer::Ignored };
+        if ack && self.ctrl & CTRL_ACK_IRQ_ENABLE != 0 {
+            self.irq = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::pad::{Button, PadKind};
+
+    /// Select `port` and exchange `bytes`, returning the replies and whether each was
+    /// acknowledged
+    fn exchange(joy: &mut Joy, port: u16, bytes: &[u8]) -> Vec<(u8, bool)> {
+        joy.store(CTRL, (CTRL_SELECT | CTRL_ACK_IRQ_ENABLE | (port * CTRL_PORT2)) as u32);
+        let replies = bytes.iter()
+            .map(|&byte| {
+                joy.store(DATA, byte as u32);
+                assert_ne!(Joy::load(joy, STAT) & STAT_RX_NOT_EMPTY, 0);
+                let ack = Joy::load(joy, STAT) & STAT_ACK != 0;
+                (Joy::load(joy, DATA) as u8, ack)
+            })
+            .collect();
+        assert_eq!(Joy::load(joy, STAT) & STAT_RX_NOT_EMPTY, 0);
+        joy.store(CTRL, 0);
+        replies
+    }
+
+    #[test]
+    fn polls_pads() {
+        let mut joy = Joy::new();
+        let mut pad = PadState::new(PadKind::Digital);
+        pad.set(Button::Start, true);
+        joy.set_pads([Some(pad), None]);
+
+        assert_eq!(exchange(&mut joy, 0, &[0x01, 0x42, 0x00, 0x00, 0x00]), [
+            (0xff, true), (0x41, true), (0x5a, true), (0xf7, true), (0xff, false),
+        ]);
+        assert_ne!(joy.load(STAT) & STAT_IRQ, 0);
+        joy.store(CTRL, CTRL_IRQ_ACK as u32);
+        assert_eq!(joy.load(STAT) & STAT_IRQ, 0);
+
+        // Empty port, and memory cards
+        assert_eq!(exchange(&mut joy, 1, &[0x01, 0x42]), [(0xff, false), (0xff, false)]);
+        assert_eq!(exchange(&mut joy, 0, &[0x81, 0x52]), [(0xff, false), (0xff, false)]);
+
+        pad.kind = PadKind::DualShock;
+        pad.left_stick = [0x00, 0xff];
+        joy.set_pads([None, Some(pad)]);
+        let replies: Vec<_> = exchange(&mut joy, 1, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0])
+            .into_iter()
+            .map(|(reply, _)| reply)
+            .collect();
+        assert_eq!(replies, [0xff, 0x73, 0x5a, 0xf7, 0xff, 0x80, 0x80, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn saves_transfers() {
+        let mut joy = Joy::new();
+        joy.set_pads([Some(PadState::new(PadKind::Digital)), None]);
+        joy.store(CTRL, CTRL_SELECT as u32);
+        joy.store(DATA, ADDRESS_PAD as u32);
+
+        let mut w = StateWriter::new();
+        joy.save(&mut w);
+        let buf = w.finish();
+        let mut loaded = Joy::new();
+        loaded.set_pads(joy.pads);
+        Snapshot::load(&mut loaded, &mut StateReader::new(&buf)).unwrap();
+        assert_eq!(loaded.load(DATA), 0xff);
+        loaded.store(DATA, COMMAND_POLL as u32);
+        assert_eq!(loaded.load(DATA), 0x41);
+    }
+}
@@ -0,0 +1,514 @@
+//! Kernel call tracer: decodes the calls to the A0h/B0h/C0h BIOS function vectors
+//!
+//! Calls are logged under the `kernel` target, e.g. `B0:3D putchar('H')`. The output of
+//! putchar, puts and printf is captured as a TTY stream the host can read.
+
+use std::collections::VecDeque;
+
+use crate::emu::{
+    bus::Bus,
+    cpu::{instruction::RegisterIndex, Cpu},
+    hle::printf,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+/// Function tables as (number, name, signature). Each signature character describes an
+/// argument: `i` signed integer, `x` hex value, `p` pointer, `c` character, `s` string,
+/// and `f` a printf format followed by its arguments.
+const A0_FUNCTIONS: &[(u32, &str, &str)] = &[
+    (0x00, "FileOpen", "sx"),
+    (0x01, "FileSeek", "iii"),
+    (0x02, "FileRead", "ipi"),
+    (0x03, "FileWrite", "ipi"),
+    (0x04, "FileClose", "i"),
+    (0x05, "FileIoctl", "ixx"),
+    (0x06, "exit", "i"),
+    (0x07, "FileGetDeviceFlag", "i"),
+    (0x08, "FileGetc", "i"),
+    (0x09, "FilePutc", "ci"),
+    (0x0a, "todigit", "c"),
+    (0x0b, "atof", "s"),
+    (0x0c, "strtoul", "spi"),
+    (0x0d, "strtol", "spi"),
+    (0x0e, "abs", "i"),
+    (0x0f, "labs", "i"),
+    (0x10, "atoi", "s"),
+    (0x11, "atol", "s"),
+    (0x12, "atob", "sp"),
+    (0x13, "SaveState", "p"),
+    (0x14, "RestoreState", "px"),
+    (0x15, "strcat", "ps"),
+    (0x16, "strncat", "psi"),
+    (0x17, "strcmp", "ss"),
+    (0x18, "strncmp", "ssi"),
+    (0x19, "strcpy", "ps"),
+    (0x1a, "strncpy", "psi"),
+    (0x1b, "strlen", "s"),
+    (0x1c, "index", "sc"),
+    (0x1d, "rindex", "sc"),
+    (0x1e, "strchr", "sc"),
+    (0x1f, "strrchr", "sc"),
+    (0x20, "strpbrk", "ss"),
+    (0x21, "strspn", "ss"),
+    (0x22, "strcspn", "ss"),
+    (0x23, "strtok", "ps"),
+    (0x24, "strstr", "ss"),
+    (0x25, "toupper", "c"),
+    (0x26, "tolower", "c"),
+    (0x27, "bcopy", "ppi"),
+    (0x28, "bzero", "pi"),
+    (0x29, "bcmp", "ppi"),
+    (0x2a, "memcpy", "ppi"),
+    (0x2b, "memset", "pxi"),
+    (0x2c, "memmove", "ppi"),
+    (0x2d, "memcmp", "ppi"),
+    (0x2e, "memchr", "pxi"),
+    (0x2f, "rand", ""),
+    (0x30, "srand", "x"),
+    (0x31, "qsort", "piip"),
+    (0x32, "strtod", "sp"),
+    (0x33, "malloc", "i"),
+    (0x34, "free", "p"),
+    (0x35, "lsearch", "ppii"),
+    (0x36, "bsearch", "ppii"),
+    (0x37, "calloc", "ii"),
+    (0x38, "realloc", "pi"),
+    (0x39, "InitHeap", "px"),
+    (0x3a, "_exit", "i"),
+    (0x3b, "getchar", ""),
+    (0x3c, "putchar", "c"),
+    (0x3d, "gets", "p"),
+    (0x3e, "puts", "s"),
+    (0x3f, "printf", "f"),
+    (0x40, "SystemErrorUnresolvedException", ""),
+    (0x41, "LoadTest", "sp"),
+    (0x42, "Load", "sp"),
+    (0x43, "Exec", "pxx"),
+    (0x44, "FlushCache", ""),
+    (0x45, "init_a0_b0_c0_vectors", ""),
+    (0x46, "GPU_dw", "iiii"),
+    (0x47, "gpu_send_dma", "iiii"),
+    (0x48, "SendGP1Command", "x"),
+    (0x49, "GPU_cw", "x"),
+    (0x4a, "GPU_cwp", "pi"),
+    (0x4b, "send_gpu_linked_list", "p"),
+    (0x4c, "gpu_abort_dma", ""),
+    (0x4d, "GetGPUStatus", ""),
+    (0x4e, "gpu_sync", ""),
+    (0x51, "LoadExec", "sxx"),
+    (0x52, "GetSysSp", ""),
+    (0x54, "CdInit", ""),
+    (0x55, "_bu_init", ""),
+    (0x56, "CdRemove", ""),
+    (0x5b, "dev_tty_init", ""),
+    (0x5c, "dev_tty_open", "psx"),
+    (0x5d, "dev_tty_in_out", "px"),
+    (0x5e, "dev_tty_ioctl", "pxx"),
+    (0x5f, "dev_cd_open", "psx"),
+    (0x60, "dev_cd_read", "ppi"),
+    (0x61, "dev_cd_close", "p"),
+    (0x62, "dev_cd_firstfile", "psp"),
+    (0x63, "dev_cd_nextfile", "pp"),
+    (0x64, "dev_cd_chdir", "ps"),
+    (0x65, "dev_card_open", "psx"),
+    (0x66, "dev_card_read", "ppi"),
+    (0x67, "dev_card_write", "ppi"),
+    (0x68, "dev_card_close", "p"),
+    (0x69, "dev_card_firstfile", "psp"),
+    (0x6a, "dev_card_nextfile", "pp"),
+    (0x6b, "dev_card_erase", "ps"),
+    (0x6c, "dev_card_undelete", "ps"),
+    (0x6d, "dev_card_format", "p"),
+    (0x6e, "dev_card_rename", "psps"),
+    (0x6f, "card_clear_error", "p"),
+    (0x70, "_bu_init", ""),
+    (0x71, "CdInit", ""),
+    (0x72, "CdRemove", ""),
+    (0x78, "CdAsyncSeekL", "p"),
+    (0x7c, "CdAsyncGetStatus", "p"),
+    (0x7e, "CdAsyncReadSector", "ipx"),
+    (0x81, "CdAsyncSetMode", "x"),
+    (0x90, "CdromIoIrqFunc1", ""),
+    (0x91, "CdromDmaIrqFunc1", ""),
+    (0x92, "CdromIoIrqFunc2", ""),
+    (0x93, "CdromDmaIrqFunc2", ""),
+    (0x94, "CdromGetInt5errCode", "pp"),
+    (0x95, "CdInitSubFunc", ""),
+    (0x96, "AddCDROMDevice", ""),
+    (0x97, "AddMemCardDevice", ""),
+    (0x98, "AddDuartTtyDevice", ""),
+    (0x99, "AddDummyTtyDevice", ""),
+    (0x9c, "SetConf", "iix"),
+    (0x9d, "GetConf", "ppp"),
+    (0x9e, "SetCdromIrqAutoAbort", "ii"),
+    (0x9f, "SetMemSize", "i"),
+    (0xa0, "WarmBoot", ""),
+    (0xa1, "SystemErrorBootOrDiskFailure", "cx"),
+    (0xa2, "EnqueueCdIntr", ""),
+    (0xa3, "DequeueCdIntr", ""),
+    (0xa4, "CdGetLbn", "s"),
+    (0xa5, "CdReadSector", "iip"),
+    (0xa6, "CdGetStatus", ""),
+    (0xab, "_card_info", "x"),
+    (0xac, "_card_load", "x"),
+    (0xad, "_card_auto", "i"),
+    (0xaf, "card_write_test", "x"),
+    (0xb2, "ioabort_raw", "x"),
+    (0xb4, "GetSystemInfo", "x"),
+];
+
+const B0_FUNCTIONS: &[(u32, &str, &str)] = &[
+    (0x00, "alloc_kernel_memory", "i"),
+    (0x01, "free_kernel_memory", "p"),
+    (0x02, "init_timer", "ixx"),
+    (0x03, "get_timer", "i"),
+    (0x04, "enable_timer_irq", "i"),
+    (0x05, "disable_timer_irq", "i"),
+    (0x06, "restart_timer", "i"),
+    (0x07, "DeliverEvent", "xx"),
+    (0x08, "OpenEvent", "xxxp"),
+    (0x09, "CloseEvent", "x"),
+    (0x0a, "WaitEvent", "x"),
+    (0x0b, "TestEvent", "x"),
+    (0x0c, "EnableEvent", "x"),
+    (0x0d, "DisableEvent", "x"),
+    (0x0e, "OpenThread", "pxx"),
+    (0x0f, "CloseThread", "x"),
+    (0x10, "ChangeThread", "x"),
+    (0x11, "jump_to_00000000", ""),
+    (0x12, "InitPad", "pipi"),
+    (0x13, "StartPad", ""),
+    (0x14, "StopPad", ""),
+    (0x15, "OutdatedPadInitAndStart", "xpxx"),
+    (0x16, "OutdatedPadGetButtons", ""),
+    (0x17, "ReturnFromException", ""),
+    (0x18, "SetDefaultExitFromException", ""),
+    (0x19, "SetCustomExitFromException", "p"),
+    (0x20, "UnDeliverEvent", "xx"),
+    (0x32, "FileOpen", "sx"),
+    (0x33, "FileSeek", "iii"),
+    (0x34, "FileRead", "ipi"),
+    (0x35, "FileWrite", "ipi"),
+    (0x36, "FileClose", "i"),
+    (0x37, "FileIoctl", "ixx"),
+    (0x38, "exit", "i"),
+    (0x39, "FileGetDeviceFlag", "i"),
+    (0x3a, "FileGetc", "i"),
+    (0x3b, "FilePutc", "ci"),
+    (0x3c, "getchar", ""),
+    (0x3d, "putchar", "c"),
+    (0x3e, "gets", "p"),
+    (0x3f, "puts", "s"),
+    (0x40, "chdir", "s"),
+    (0x41, "FormatDevice", "s"),
+    (0x42, "firstfile", "sp"),
+    (0x43, "nextfile", "p"),
+    (0x44, "FileRename", "ss"),
+    (0x45, "FileDelete", "s"),
+    (0x46, "FileUndelete", "s"),
+    (0x47, "AddDevice", "p"),
+    (0x48, "RemoveDevice", "s"),
+    (0x49, "PrintInstalledDevices", ""),
+    (0x4a, "InitCard", "i"),
+    (0x4b, "StartCard", ""),
+    (0x4c, "StopCard", ""),
+    (0x4d, "_card_info_subfunc", "x"),
+    (0x4e, "write_card_sector", "xip"),
+    (0x4f, "read_card_sector", "xip"),
+    (0x50, "allow_new_card", ""),
+    (0x51, "Krom2RawAdd", "x"),
+    (0x53, "Krom2Offset", "x"),
+    (0x54, "GetLastError", ""),
+    (0x55, "GetLastFileError", "i"),
+    (0x56, "GetC0Table", ""),
+    (0x57, "GetB0Table", ""),
+    (0x58, "get_bu_callback_port", ""),
+    (0x59, "testdevice", "s"),
+    (0x5b, "ChangeClearPad", "i"),
+    (0x5c, "get_card_status", "i"),
+    (0x5d, "wait_card_status", "i"),
+];
+
+const C0_FUNCTIONS: &[(u32, &str, &str)] = &[
+    (0x00, "EnqueueTimerAndVblankIrqs", "i"),
+    (0x01, "EnqueueSyscallHandler", "i"),
+    (0x02, "SysEnqIntRP", "ip"),
+    (0x03, "SysDeqIntRP", "ip"),
+    (0x04, "get_free_EvCB_slot", ""),
+    (0x05, "get_free_TCB_slot", ""),
+    (0x06, "ExceptionHandler", ""),
+    (0x07, "InstallExceptionHandlers", ""),
+    (0x08, "SysInitMemory", "px"),
+    (0x09, "SysInitKernelVariables", ""),
+    (0x0a, "ChangeClearRCnt", "ii"),
+    (0x0c, "InitDefInt", "i"),
+    (0x0d, "SetIrqAutoAck", "ii"),
+    (0x0e, "dev_sio_init", ""),
+    (0x0f, "dev_sio_open", "psx"),
+    (0x10, "dev_sio_in_out", "px"),
+    (0x11, "dev_sio_ioctl", "pxx"),
+    (0x12, "InstallDevices", "i"),
+    (0x13, "FlushStdInOutPut", ""),
+    (0x15, "tty_cdevinput", "pc"),
+    (0x16, "tty_cdevscan", ""),
+    (0x17, "tty_circgetc", "p"),
+    (0x18, "tty_circputc", "cp"),
+    (0x19, "ioabort", "ss"),
+    (0x1a, "set_card_find_mode", "i"),
+    (0x1b, "KernelRedirect", "i"),
+    (0x1c, "AdjustA0Table", ""),
+    (0x1d, "get_card_find_mode", ""),
+];
+
+/// Longest string shown in a trace
+const MAX_STRING: usize = 80;
+
+/// Captured TTY output is kept up to this size, dropping the oldest bytes
+pub const TTY_CAPACITY: usize = 64 * 1024;
+
+const T1: RegisterIndex = RegisterIndex(9);
+const SP: RegisterIndex = RegisterIndex(29);
+const RA: RegisterIndex = RegisterIndex(31);
+
+/// Function table (0xa0, 0xb0 or 0xc0) entered at `pc`, if any
+pub fn vector(pc: u32) -> Option<u32> {
+    match pc & 0x1fff_ffff {
+        vector @ (0xa0 | 0xb0 | 0xc0) => Some(vector),
+        _ => None,
+    }
+}
+
+/// Name of kernel function `func` of the A0h/B0h/C0h table `vector`
+pub fn function_name(vector: u32, func: u32) -> Option<&'static str> {
+    lookup(vector, func).map(|(_, name, _)| *name)
+}
+
+fn lookup(vector: u32, func: u32) -> Option<&'static (u32, &'static str, &'static str)> {
+    let table = match vector {
+        0xa0 => A0_FUNCTIONS,
+        0xb0 => B0_FUNCTIONS,
+        0xc0 => C0_FUNCTIONS,
+        _ => return None,
+    };
+    table.iter().find(|(num, ..)| *num == func)
+}
+
+/// Decode a call to `func` of table `vector`, with the CPU at the vector, e.g.
+/// `A0:3F printf("%s", "hello")`
+pub fn describe(cpu: &Cpu, bus: &Bus, vector: u32, func: u32) -> String {
+    let table = vector >> 4;
+    let Some(&(_, name, signature)) = lookup(vector, func) else {
+        let args: Vec<_> = (0..4).map(|idx| format!("0x{:x}", arg(cpu, bus, idx))).collect();
+        return format!("{table:X}0:{func:02X} unknown({})", args.join(", "));
+    };
+
+    let mut args = Vec::new();
+    for (idx, kind) in signature.chars().enumerate() {
+        let val = arg(cpu, bus, idx);
+        match kind {
+            'f' => {
+                let fmt = read_str(bus, val);
+                args.push(format!("{:?}", String::from_utf8_lossy(&fmt)));
+                let mut next = idx + 1;
+                for conv in conversions(&fmt) {
+                    args.push(format_arg(bus, conv, arg(cpu, bus, next)));
+                    next += 1;
+                }
+            },
+            kind => args.push(format_arg(bus, kind, val)),
+        }
+    }
+    format!("{table:X}0:{func:02X} {name}({})", args.join(", "))
+}
+
+/// Argument `idx` of the call, from a0-a3 then the stack
+fn arg(cpu: &Cpu, bus: &Bus, idx: usize) -> u32 {
+    match idx {
+        0..=3 => cpu.reg(RegisterIndex(4 + idx as u32)),
+        _ => bus.peek::<u32>(cpu.reg(SP).wrapping_add(4 * idx as u32)),
+    }
+}
+
+fn format_arg(bus: &Bus, kind: char, val: u32) -> String {
+    match kind {
+        'i' | 'd' => (val as i32).to_string(),
+        'u' => val.to_string(),
+        'c' => format!("{:?}", val as u8 as char),
+        's' => {
+            let s = read_str(bus, val);
+            let ellipsis = if s.len() > MAX_STRING { "..." } else { "" };
+            format!("{:?}{ellipsis}", String::from_utf8_lossy(&s[..s.len().min(MAX_STRING)]))
+        },
+        _ => format!("0x{val:x}"),
+    }
+}
+
+/// Argument kinds consumed by a printf format, using the signature characters
+fn conversions(fmt: &[u8]) -> Vec<char> {
+    let mut kinds = Vec::new();
+    let mut chars = fmt.iter().copied();
+    while let Some(c) = chars.next() {
+        if c != b'%' {
+            continue;
+        }
+        for c in chars.by_ref() {
+            match c {
+                b'*' => kinds.push('i'),
+                b'-' | b'+' | b' ' | b'#' | b'.' | b'0'..=b'9' | b'h' | b'l' | b'L' => continue,
+                b'd' | b'i' => kinds.push('i'),
+                b'u' => kinds.push('u'),
+                b'c' => kinds.push('c'),
+                b's' => kinds.push('s'),
+                b'%' => {},
+                _ => kinds.push('x'),
+            }
+            if c != b'*' {
+                break;
+            }
+        }
+    }
+    kinds
+}
+
+fn read_str(bus: &Bus, addr: u32) -> Vec<u8> {
+    (0..0x1000)
+        .map(|offset| bus.peek::<u8>(addr.wrapping_add(offset)))
+        .take_while(|&b| b != 0)
+        .collect()
+}
+
+/// Watches for kernel calls and captures the TTY output
+#[derive(Debug, Default)]
+pub struct KernelTracer {
+    tty: VecDeque<u8>,
+    /// Return address of the TTY call being captured. Kernels implement printf and puts
+    /// on top of putchar, which mustn't be captured twice.
+    tty_call_return: Option<u32>,
+}
+
+/// Only the call being captured, the TTY output is the host's
+impl Snapshot for KernelTracer {
+    fn save(&self, w: &mut StateWriter) {
+        w.option_u32(self.tty_call_return);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.tty_call_return = r.option_u32()?;
+        Ok(())
+    }
+}
+
+impl KernelTracer {
+    pub fn new() -> Self {
+        KernelTracer::default()
+    }
+
+    /// Look at the CPU before it executes the instruction at its pc
+    pub fn observe(&mut self, cpu: &Cpu, bus: &Bus) {
+        let pc = cpu.pc();
+        if self.tty_call_return == Some(pc) {
+            self.tty_call_return = None;
+        }
+        let Some(vector) = vector(pc) else { return };
+        let func = cpu.reg(T1);
+
+        if tracing::enabled!(target: "kernel", tracing::Level::DEBUG) {
+            tracing::debug!(target: "kernel", "{} from {}", describe(cpu, bus, vector, func), cpu.location(cpu.reg(RA)));
+        }
+
+        if self.tty_call_return.is_some() {
+            return;
+        }
+        let output = match (vector, func) {
+            (0xa0, 0x3c) | (0xb0, 0x3d) => vec![arg(cpu, bus, 0) as u8],
+            (0xa0, 0x3e) | (0xb0, 0x3f) => {
+                let mut line = read_str(bus, arg(cpu, bus, 0));
+                line.push(b'\n');
+                line
+            },
+            (0xa0, 0x3f) => {
+                let mut next = 1;
+                printf::format(
+                    &read_str(bus, arg(cpu, bus, 0)),
+                    || {
+                        next += 1;
+                        arg(cpu, bus, next - 1)
+                    },
+                    |addr| read_str(bus, addr),
+                )
+            },
+            _ => return,
+        };
+        self.tty_call_return = Some(cpu.reg(RA));
+        self.tty.extend(output);
+        let excess = self.tty.len().saturating_sub(TTY_CAPACITY);
+        self.tty.drain(..excess);
+    }
+
+    /// Take the TTY output captured since the last call
+    pub fn take_tty(&mut self) -> Vec<u8> {
+        self.tty.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{
+        bios::{Bios, BIOS_SIZE},
+        ram::Ram,
+    };
+
+    fn call(args: [u32; 4], vector: u32, func: u32) -> Cpu {
+        let mut cpu = Cpu::new();
+        for (idx, val) in args.into_iter().enumerate() {
+            cpu.write_reg(RegisterIndex(4 + idx as u32), val);
+        }
+        cpu.write_reg(T1, func);
+        cpu.write_reg(SP, 0x801f_ff00);
+        cpu.write_reg(RA, 0x8001_0000);
+        cpu.set_pc(vector);
+        cpu
+    }
+
+    fn store_str(bus: &mut Bus, addr: u32, s: &str) {
+        for (offset, byte) in s.bytes().chain([0]).enumerate() {
+            bus.store::<u8>(addr + offset as u32, byte);
+        }
+    }
+
+    #[test]
+    fn describes_calls() {
+        let mut bus = Bus::new(Ram::new(), Bios::new(&[0; BIOS_SIZE]));
+        store_str(&mut bus, 0x8010_0000, "%s=%d %c%c\n");
+        store_str(&mut bus, 0x8010_0100, "x");
+        bus.store::<u32>(0x801f_ff10, b'!' as u32);
+
+        let cpu = call([b'H' as u32, 0, 0, 0], 0xb0, 0x3d);
+        assert_eq!(describe(&cpu, &bus, 0xb0, 0x3d), "B0:3D putchar('H')");
+        // The fifth argument is on the stack
+        let cpu = call([0x8010_0000, 0x8010_0100, -3i32 as u32, b'?' as u32], 0xa0, 0x3f);
+        assert_eq!(describe(&cpu, &bus, 0xa0, 0x3f), r#"A0:3F printf("%s=%d %c%c\n", "x", -3, '?', '!')"#);
+        let cpu = call([1, 2, 3, 4], 0xc0, 0x7f);
+        assert_eq!(describe(&cpu, &bus, 0xc0, 0x7f), "C0:7F unknown(0x1, 0x2, 0x3, 0x4)");
+        assert_eq!(function_name(0xb0, 0x17), Some("ReturnFromException"));
+    }
+
+    #[test]
+    fn captures_tty() {
+        let mut bus = Bus::new(Ram::new(), Bios::new(&[0; BIOS_SIZE]));
+        store_str(&mut bus, 0x8010_0000, "n=%d\n");
+        let mut tracer = KernelTracer::new();
+
+        tracer.observe(&call([0x8010_0000, 42, 0, 0], 0xa0, 0x3f), &bus);
+        // printf calling putchar internally
+        tracer.observe(&call([b'n' as u32, 0, 0, 0], 0xb0, 0x3d), &bus);
+        let mut returned = Cpu::new();
+        returned.set_pc(0x8001_0000);
+        tracer.observe(&returned, &bus);
+        tracer.observe(&call([b'!' as u32, 0, 0, 0], 0xa0, 0x3c), &bus);
+
+        assert_eq!(tracer.take_tty(), b"n=42\n!");
+        assert!(tracer.take_tty().is_empty());
+    }
+}
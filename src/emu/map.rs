@@ -1,6 +1,6 @@
 //!  Memory ranges for each memory map region
 
-use std::ops::Range;
+use std::{collections::HashMap, ops::Range};
 use lazy_static::lazy_static;
 
 const REGION_MASK: [u32; 8] = [
@@ -31,22 +31,25 @@ const REGION_MASK: [u32; 8] = [
 const RAM      : Mapping = Mapping::new(0x0000_0000, n_mib_bytes!(2) as u32);
 const BIOS     : Mapping = Mapping::new(0x1fc0_0000, n_kib_bytes!(512) as u32);
 const MEM_CTL  : Mapping = Mapping::new(0x1f80_1000, 36);
+const JOY      : Mapping = Mapping::new(0x1f80_1040, 16);
 const RAM_CTL  : Mapping = Mapping::new(0x1f80_1060, 4);
 const IRQ_CTL  : Mapping = Mapping::new(0x1f80_1070, 8);
 const TIMER    : Mapping = Mapping::new(0x1f80_1100, 48);
 const CACHE_CTL: Mapping = Mapping::new(0xfffe_0130, 4);
+const SCRATCHPAD: Mapping = Mapping::new(0x1f80_0000, n_kib_bytes!(1) as u32);
 const SPU      : Mapping = Mapping::new(0x1f80_1c00, 640);
-const EXP1     : Mapping = Mapping::new(0x1f00_0000, n_kib_bytes!(8) as u32);
+const EXP1     : Mapping = Mapping::new(0x1f00_0000, n_mib_bytes!(8) as u32);
 const EXP2     : Mapping = Mapping::new(0x1f80_2000, n_kib_bytes!(8) as u32);
 const DMA      : Mapping = Mapping::new(0x1f80_1080, 128);
 const GPU      : Mapping = Mapping::new(0x1f80_1810, 8);
 
 /// Contains the base address of the associated region
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub enum Region {
     Bios(Mapping),
     Ram(Mapping),
     MemCtl(Mapping),
+    Joy(Mapping),
     RamCtl(Mapping),
     IrqCtl(Mapping),
     Timer(Mapping),
@@ -56,21 +59,22 @@ pub enum Region {
     Exp2(Mapping),
     Dma(Mapping),
     Gpu(Mapping),
+    Scratchpad(Mapping),
 }
 
-// TODO: organize these based on profiling data?
-// TODO: TODO: organize these dynamically based on profiling data?
-const MEMORY_MAP: [(Mapping, Region); 12] = [
+const MEMORY_MAP: [(Mapping, Region); 14] = [
     (RAM,       Region::Ram(RAM)),
     (BIOS,      Region::Bios(BIOS)),
+    (SCRATCHPAD, Region::Scratchpad(SCRATCHPAD)),
     (MEM_CTL,   Region::MemCtl(MEM_CTL)),
+    (JOY,       Region::Joy(JOY)),
     (RAM_CTL,   Region::RamCtl(RAM_CTL)),
     (IRQ_CTL,   Region::IrqCtl(IRQ_CTL)),
     (TIMER,     Region::Timer(TIMER)),
     (CACHE_CTL, Region::CacheCtl(CACHE_CTL)),
     (SPU,       Region::Spu(SPU)),
     (EXP1,      Region::Exp1(EXP1)),
-    (EXP2,      Region::Exp1(EXP2)),
+    (EXP2,      Region::Exp2(EXP2)),
     (DMA,       Region::Dma(DMA)),
     (GPU,       Region::Gpu(GPU)),
 ];
@@ -99,16 +103,173 @@ impl Mapping {
     }
 }
 
-pub fn get_region(addr: u32) -> Region {
-    for (mapping, region) in MEMORY_MAP {
-        if mapping.contains(addr) {
-            return region;
+/// Size of the physical address space covered by the page table
+const PHYS_SIZE: u32 = 0x2000_0000;
+
+/// Granularity of the page table
+pub const PAGE_SHIFT: u32 = 12;
+
+/// The only page containing registers that don't fill whole pages. It is split further
+/// at word granularity.
+const IO_PAGE: u32 = 0x1f80_1000 >> PAGE_SHIFT;
+
+/// How a page of the physical address space is dispatched
+#[derive(Debug, Copy, Clone)]
+pub enum Page {
+    Unmapped,
+    /// Main RAM, accessed directly at `paddr - base`
+    Ram(u32),
+    /// BIOS ROM, accessed directly at `paddr - base`
+    Bios(u32),
+    /// Scratchpad, accessed directly at `paddr - base`. It only fills the first
+    /// `SCRATCHPAD_SIZE` bytes of the page, the rest is unmapped.
+    Scratchpad(u32),
+    /// Page belonging to the device at this index of `MEMORY_MAP`
+    Io(u8),
+    /// Page shared by several devices (or outside of the table), resolved by [`PageTable::region`]
+    FineIo,
+    /// Page with memory watches on it, always taking the slow path
+    Watched,
+}
+
+/// `PageTable::io` entry for words without any device
+const UNMAPPED: u8 = u8::MAX;
+
+/// Page table over the physical address space, at `PAGE_SHIFT` granularity
+#[derive(Clone)]
+pub struct PageTable {
+    pages: Vec<Page>,
+    /// Index in `MEMORY_MAP` of the device owning each word of `IO_PAGE`
+    io: Vec<u8>,
+    /// What the `Page::Watched` entries were before
+    watched: HashMap<u32, Page>,
+}
+
+impl PageTable {
+    pub fn new() -> Self {
+        let mut pages = vec![Page::Unmapped; (PHYS_SIZE >> PAGE_SHIFT) as usize];
+        for (idx, (mapping, region)) in MEMORY_MAP.iter().enumerate() {
+            if mapping.base >= PHYS_SIZE {
+                continue;
+            }
+            let first = mapping.base >> PAGE_SHIFT;
+            let last = (mapping.base + mapping.size - 1) >> PAGE_SHIFT;
+            for page in first..=last {
+                pages[page as usize] = match (page, region) {
+                    (IO_PAGE, _) => Page::FineIo,
+                    (_, Region::Ram(m)) => Page::Ram(m.base),
+                    (_, Region::Bios(m)) => Page::Bios(m.base),
+                    (_, Region::Scratchpad(m)) => Page::Scratchpad(m.base),
+                    _ => Page::Io(idx as u8),
+                };
+            }
+        }
+
+        let io_base = IO_PAGE << PAGE_SHIFT;
+        let io = (0..(1 << PAGE_SHIFT) / 4)
+            .map(|word| {
+                MEMORY_MAP.iter()
+                    .position(|(mapping, _)| mapping.contains(io_base + word * 4))
+                    .map_or(UNMAPPED, |idx| idx as u8)
+            })
+            .collect();
+
+        PageTable { pages, io, watched: HashMap::new() }
+    }
+
+    /// Return the entry for physical address `addr`. Addresses outside of the table
+    /// (KSEG2) are reported as `Page::FineIo`.
+    #[inline]
+    pub fn get(&self, addr: u32) -> Page {
+        self.pages.get((addr >> PAGE_SHIFT) as usize)
+            .copied()
+            .unwrap_or(Page::FineIo)
+    }
+
+    /// Send the accesses to `page` through the slow path, or not anymore. Pages outside of
+    /// the table (KSEG2) can't be watched.
+    pub fn set_watched(&mut self, page: u32, watched: bool) {
+        let Some(entry) = self.pages.get_mut(page as usize) else {
+            return;
+        };
+        match (watched, *entry) {
+            (true, Page::Watched) => {},
+            (true, original) => {
+                self.watched.insert(page, original);
+                *entry = Page::Watched;
+            },
+            (false, _) => {
+                if let Some(original) = self.watched.remove(&page) {
+                    *entry = original;
+                }
+            },
+        }
+    }
+
+    /// Whether `set_watched` flagged the page of physical address `addr`
+    pub fn is_watched(&self, addr: u32) -> bool {
+        matches!(self.get(addr), Page::Watched)
+    }
+
+    /// Resolve the region containing physical address `addr`, equivalent to
+    /// [`get_region`] but without walking the memory map for table-covered addresses
+    pub fn region(&self, addr: u32) -> Region {
+        let page = match self.get(addr) {
+            Page::Watched => self.watched[&(addr >> PAGE_SHIFT)],
+            page => page,
+        };
+        let idx = match page {
+            Page::Io(idx) => idx,
+            Page::FineIo if addr >> PAGE_SHIFT == IO_PAGE => {
+                self.io[((addr >> 2) & 0x3ff) as usize]
+            },
+            Page::Ram(_) => return Region::Ram(RAM),
+            Page::Bios(_) => return Region::Bios(BIOS),
+            Page::Scratchpad(_) if SCRATCHPAD.contains(addr) => return Region::Scratchpad(SCRATCHPAD),
+            Page::Unmapped | Page::Scratchpad(_) | Page::FineIo | Page::Watched => UNMAPPED,
+        };
+        match MEMORY_MAP.get(idx as usize) {
+            Some((_, region)) => *region,
+            None => get_region(addr),
         }
     }
-    panic!("Unknown region @ {addr:08x}");
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Find the region containing physical address `addr` by walking the whole memory map.
+/// Used for addresses outside of the page table (KSEG2).
+pub fn get_region(addr: u32) -> Region {
+    find_region(addr).unwrap_or_else(|| panic!("Unknown region @ {addr:08x}"))
+}
+
+/// Like [`get_region`], but `None` for unmapped addresses
+pub fn find_region(addr: u32) -> Option<Region> {
+    MEMORY_MAP.into_iter()
+        .find(|(mapping, _)| mapping.contains(addr))
+        .map(|(_, region)| region)
 }
 
 pub fn mask_region(addr: u32) -> u32 {
     let idx = (addr >> 29) as usize;
     addr & REGION_MASK[idx]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scratchpad_page_is_partly_unmapped() {
+        let pages = PageTable::new();
+        assert!(matches!(pages.get(0x1f80_0400), Page::Scratchpad(0x1f80_0000)));
+        assert!(matches!(pages.region(0x1f80_03fc), Region::Scratchpad(_)));
+        assert!(find_region(0x1f80_0400).is_none());
+        let unmapped = std::panic::catch_unwind(|| pages.region(0x1f80_0400)).unwrap_err();
+        assert_eq!(unmapped.downcast_ref::<String>().unwrap(), "Unknown region @ 1f800400");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+//! Memory control registers, which configure the expansion and BIOS address windows
+//!
+//! The registers are only stored, the access timings they set up aren't emulated. The
+//! size of the expansion region 1 window is used to mirror the cartridge ROM.
+
+use crate::emu::state::{Snapshot, StateReader, StateWriter};
+
+/// Word index of the expansion 1 base address register
+const EXP1_BASE: usize = 0;
+/// Word index of the expansion 1 delay/size register
+const EXP1_DELAY_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct MemCtl {
+    regs: [u32; 9],
+}
+
+impl Snapshot for MemCtl {
+    fn save(&self, w: &mut StateWriter) {
+        w.words(&self.regs);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.words_into(&mut self.regs)
+    }
+}
+
+impl MemCtl {
+    pub fn new() -> Self {
+        MemCtl::default()
+    }
+
+    pub fn load(&self, offset: u32) -> u32 {
+        self.regs[offset as usize / 4]
+    }
+
+    pub fn store(&mut self, offset: u32, val: u32) {
+        let idx = offset as usize / 4;
+        if idx == EXP1_BASE && val & 0x00ff_ffff != 0 {
+            tracing::warn!("expansion 1 moved to 0x{val:08x}, but it's only emulated at 0x1f000000");
+        }
+        self.regs[idx] = val;
+    }
+
+    /// Size in bytes of the expansion 1 window (bits 16-20 of its delay/size register,
+    /// as a power of two)
+    pub fn exp1_size(&self) -> u32 {
+        1 << ((self.regs[EXP1_DELAY_SIZE] >> 16) & 0x1f).min(23)
+    }
+}
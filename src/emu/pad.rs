@@ -0,0 +1,169 @@
+//! Controller state as seen by the console
+//!
+//! The pads reach programs through the controller port (see [`crate::emu::joy`]), and the
+//! buffers of the high-level BIOS's `InitPad`, which are refreshed at every VBlank.
+
+use std::fmt;
+
+/// Digital buttons, in the bit order of the pad's poll reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Button {
+    Select,
+    L3,
+    R3,
+    Start,
+    Up,
+    Right,
+    Down,
+    Left,
+    L2,
+    R2,
+    L1,
+    R1,
+    Triangle,
+    Circle,
+    Cross,
+    Square,
+}
+
+impl Button {
+    pub const ALL: [Button; 16] = [
+        Button::Select, Button::L3, Button::R3, Button::Start,
+        Button::Up, Button::Right, Button::Down, Button::Left,
+        Button::L2, Button::R2, Button::L1, Button::R1,
+        Button::Triangle, Button::Circle, Button::Cross, Button::Square,
+    ];
+
+    /// Bit of the button in [`PadState::buttons`]
+    pub fn mask(self) -> u16 {
+        1 << self as u16
+    }
+
+    /// Lowercase name, as used in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Button::Select => "select",
+            Button::L3 => "l3",
+            Button::R3 => "r3",
+            Button::Start => "start",
+            Button::Up => "up",
+            Button::Right => "right",
+            Button::Down => "down",
+            Button::Left => "left",
+            Button::L2 => "l2",
+            Button::R2 => "r2",
+            Button::L1 => "l1",
+            Button::R1 => "r1",
+            Button::Triangle => "triangle",
+            Button::Circle => "circle",
+            Button::Cross => "cross",
+            Button::Square => "square",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        Button::ALL.into_iter().find(|button| button.name() == name)
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Controller model plugged into a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PadKind {
+    /// SCPH-1080 digital pad
+    #[default]
+    Digital,
+    /// DualShock with its analog mode on
+    DualShock,
+}
+
+impl PadKind {
+    pub fn from_name(name: &str) -> Option<PadKind> {
+        match name {
+            "digital" => Some(PadKind::Digital),
+            "dualshock" => Some(PadKind::DualShock),
+            _ => None,
+        }
+    }
+
+    /// ID byte of the poll reply
+    fn id(self) -> u8 {
+        match self {
+            PadKind::Digital => 0x41,
+            PadKind::DualShock => 0x73,
+        }
+    }
+}
+
+/// Stick position, 0x00 is left (up) and 0xff right (down)
+pub type Stick = [u8; 2];
+
+/// Centered stick
+pub const STICK_CENTER: Stick = [0x80; 2];
+
+/// Inputs of one controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PadState {
+    pub kind: PadKind,
+    /// Pressed buttons, see [`Button::mask`]
+    pub buttons: u16,
+    /// Only reported in analog mode
+    pub left_stick: Stick,
+    pub right_stick: Stick,
+}
+
+impl PadState {
+    pub fn new(kind: PadKind) -> Self {
+        PadState { kind, buttons: 0, left_stick: STICK_CENTER, right_stick: STICK_CENTER }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.buttons & button.mask() != 0
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match pressed {
+            true => self.buttons |= button.mask(),
+            false => self.buttons &= !button.mask(),
+        }
+    }
+
+    /// Poll reply after the status byte, as the kernel stores it in the `InitPad`
+    /// buffers: ID, buttons active low, then the right and left sticks in analog mode
+    pub fn report(&self) -> Vec<u8> {
+        let [lo, hi] = (!self.buttons).to_le_bytes();
+        let mut report = vec![self.kind.id(), lo, hi];
+        if self.kind == PadKind::DualShock {
+            report.extend(self.right_stick);
+            report.extend(self.left_stick);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_buttons_and_sticks() {
+        assert!(Button::ALL.iter().all(|&button| Button::from_name(button.name()) == Some(button)));
+        assert_eq!(Button::Square.mask(), 0x8000);
+
+        let mut pad = PadState::new(PadKind::Digital);
+        pad.set(Button::Start, true);
+        pad.set(Button::Cross, true);
+        pad.set(Button::Cross, false);
+        assert!(pad.is_pressed(Button::Start));
+        assert_eq!(pad.report(), [0x41, 0xf7, 0xff]);
+
+        pad.kind = PadKind::DualShock;
+        pad.left_stick = [0x00, 0xff];
+        assert_eq!(pad.report(), [0x73, 0xf7, 0xff, 0x80, 0x80, 0x00, 0xff]);
+    }
+}
@@ -1,18 +1,45 @@
 //! Access executable RAM (read and write) 
 
-use crate::emu::access::{Access, AccessWidth};
+use crate::emu::{
+    access::{Access, AccessWidth},
+    state::{Snapshot, StateReader, StateWriter},
+};
 
 pub const RAM_SIZE : usize = 2 * 1024 * 1024;
 pub const RAM_START: u32   = 0xa000_0000;
 pub const RAM_END: u32     = RAM_START + RAM_SIZE as u32;
 
+pub const SCRATCHPAD_SIZE: usize = 1024;
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct Ram {
     mem: Vec<u32>,
 }
 
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Ram {
+    fn save(&self, w: &mut StateWriter) {
+        w.words(&self.mem);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.words_into(&mut self.mem)
+    }
+}
+
 impl Ram {
     pub fn new() -> Self {
-        let mem = vec![0xB0BA_CAFE; RAM_SIZE / 4];
+        Self::with_size(RAM_SIZE)
+    }
+
+    /// Create a memory of `size` bytes, e.g. for the scratchpad
+    pub fn with_size(size: usize) -> Self {
+        let mem = vec![0xB0BA_CAFE; size / 4];
         Ram { mem }
     }
 
@@ -0,0 +1,264 @@
+//! Rewind: a ring buffer of save states taken at a fixed interval
+//!
+//! Only the newest snapshot is kept whole. Every older one is stored as the delta
+//! against its successor (XOR, then run-length encoding of the zero runs), which is
+//! small since most of RAM doesn't change between snapshots. Dropping the oldest
+//! snapshot never breaks a chain, and going back one snapshot only decodes one delta.
+
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::emu::Psx;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RewindConfig {
+    /// Instructions between snapshots
+    pub interval: u64,
+    /// Most snapshots kept
+    pub depth: usize,
+    /// Most bytes used by the snapshots, the oldest are dropped beyond it
+    pub memory_cap: usize,
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    instructions: u64,
+    /// Delta against the next snapshot, or the whole state for the newest one
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Rewind {
+    config: RewindConfig,
+    /// Oldest first
+    snapshots: VecDeque<Snapshot>,
+    memory_used: usize,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Self {
+        Rewind { config, snapshots: VecDeque::new(), memory_used: 0 }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    /// Number of snapshots held
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Bytes used by the snapshots
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Instruction counts of the snapshots, oldest first
+    pub fn instructions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.snapshots.iter().map(|snapshot| snapshot.instructions)
+    }
+
+    /// Take a snapshot of `psx` if the interval has passed since the last one. Meant to
+    /// be called after every step.
+    pub fn record(&mut self, psx: &Psx) {
+        let due = match self.snapshots.back() {
+            Some(newest) => psx.instructions_retired >= newest.instructions + self.config.interval,
+            None => true,
+        };
+        if due {
+            self.push(psx);
+        }
+    }
+
+    /// Take a snapshot of `psx` now
+    pub fn push(&mut self, psx: &Psx) {
+        let state = psx.save_state();
+        if let Some(newest) = self.snapshots.back_mut() {
+            let delta = encode_delta(&newest.data, &state);
+            self.memory_used = self.memory_used - newest.data.len() + delta.len();
+            newest.data = delta;
+        }
+        self.memory_used += state.len();
+        self.snapshots.push_back(Snapshot { instructions: psx.instructions_retired, data: state });
+
+        while self.snapshots.len() > self.config.depth.max(1)
+            || (self.memory_used > self.config.memory_cap && self.snapshots.len() > 1)
+        {
+            let oldest = self.snapshots.pop_front().unwrap();
+            self.memory_used -= oldest.data.len();
+        }
+    }
+
+    /// Go back to instruction count `target`: restore the newest snapshot taken at or
+    /// before it and run up to it. Snapshots after the restored one are dropped. Returns
+    /// the instruction count reached, which can overshoot `target` by part of a block
+    /// with the block based engines.
+    pub fn rewind_to(&mut self, psx: &mut Psx, target: u64) -> Result<u64> {
+        let Some(idx) = self.snapshots.iter().rposition(|snapshot| snapshot.instructions <= target) else {
+            bail!("instruction {target} is older than the rewind buffer");
+        };
+
+        // Undo the deltas from the newest snapshot back to the chosen one
+        while self.snapshots.len() > idx + 1 {
+            let newest = self.snapshots.pop_back().unwrap();
+            let previous = self.snapshots.back_mut().unwrap();
+            let state = decode_delta(&previous.data, &newest.data)
+                .ok_or_else(|| anyhow!("corrupt rewind snapshot"))?;
+            self.memory_used = self.memory_used - newest.data.len() - previous.data.len() + state.len();
+            previous.data = state;
+        }
+
+        psx.load_state(&self.snapshots[idx].data)?;
+        while psx.instructions_retired < target {
+            psx.step();
+        }
+        Ok(psx.instructions_retired)
+    }
+
+    /// Go back `instructions` from the current instruction count, see [`Rewind::rewind_to`]
+    pub fn step_back(&mut self, psx: &mut Psx, instructions: u64) -> Result<u64> {
+        let target = psx.instructions_retired.saturating_sub(instructions);
+        self.rewind_to(psx, target)
+    }
+
+    /// Drop every snapshot, e.g. after loading a save state
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.memory_used = 0;
+    }
+}
+
+/// Encode `state` against `base` as its length, then a sequence of (zero run, literal
+/// length, literal bytes) over `state XOR base`, with LEB128 lengths
+fn encode_delta(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = state.iter()
+        .enumerate()
+        .map(|(idx, byte)| byte ^ base.get(idx).copied().unwrap_or(0))
+        .collect();
+
+    let mut out = Vec::new();
+    write_len(&mut out, state.len());
+    let mut pos = 0;
+    while pos < xor.len() {
+        let zeros = xor[pos..].iter().take_while(|&&byte| byte == 0).count();
+        pos += zeros;
+        // Short zero runs are cheaper as part of the literal
+        let mut end = pos;
+        while end < xor.len() {
+            let run = xor[end..].iter().take(8).take_while(|&&byte| byte == 0).count();
+            if run == 8 || end + run == xor.len() {
+                break;
+            }
+            end += run.max(1);
+        }
+        write_len(&mut out, zeros);
+        write_len(&mut out, end - pos);
+        out.extend_from_slice(&xor[pos..end]);
+        pos = end;
+    }
+    out
+}
+
+/// Inverse of [`encode_delta`], `None` if `delta` is malformed
+fn decode_delta(delta: &[u8], base: &[u8]) -> Option<Vec<u8>> {
+    let mut input = delta.iter().copied();
+    let len = read_len(&mut input)?;
+    let mut xor = Vec::with_capacity(len);
+    while xor.len() < len {
+        let zeros = read_len(&mut input)?;
+        let literal = read_len(&mut input)?;
+        xor.resize(xor.len() + zeros, 0);
+        for _ in 0..literal {
+            xor.push(input.next()?);
+        }
+    }
+    if xor.len() != len {
+        return None;
+    }
+    Some(xor.iter()
+        .enumerate()
+        .map(|(idx, byte)| byte ^ base.get(idx).copied().unwrap_or(0))
+        .collect())
+}
+
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_len(input: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut len = 0usize;
+    for shift in (0..64).step_by(7) {
+        let byte = input.next()?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(len);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let base: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        let mut state = base.clone();
+        state[10] ^= 1;
+        state[11] ^= 0xff;
+        state[4000..4020].fill(0xaa);
+        state.extend([1, 2, 3]);
+
+        let delta = encode_delta(&state, &base);
+        assert!(delta.len() < 64, "delta is {} bytes", delta.len());
+        assert_eq!(decode_delta(&delta, &base).unwrap(), state);
+        assert_eq!(decode_delta(&encode_delta(&base[..100], &state), &state).unwrap(), &base[..100]);
+        assert_eq!(decode_delta(&encode_delta(&[], &base), &base).unwrap(), []);
+        assert!(decode_delta(&delta[..delta.len() - 1], &base).is_none());
+    }
+
+    #[test]
+    fn rewinds_bios() {
+        let bios = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let mut psx = Psx::new_from_bios(bios.as_slice().try_into().unwrap());
+        let mut rewind = Rewind::new(RewindConfig { interval: 50_000, depth: 8, memory_cap: usize::MAX });
+
+        let mut pcs = Vec::new();
+        for _ in 0..600_000 {
+            rewind.record(&psx);
+            psx.step();
+            pcs.push(psx.cpu().pc());
+        }
+        assert_eq!(rewind.len(), 8);
+        assert_eq!(rewind.instructions().next(), Some(200_000));
+        // Deltas are much smaller than the 3 MiB of RAM and VRAM
+        assert!(rewind.memory_used() < 4 * 1024 * 1024, "{} bytes", rewind.memory_used());
+
+        assert_eq!(rewind.rewind_to(&mut psx, 420_123).unwrap(), 420_123);
+        assert_eq!(psx.cpu().pc(), pcs[420_122]);
+        assert_eq!(rewind.len(), 5);
+        assert_eq!(rewind.step_back(&mut psx, 100_000).unwrap(), 320_123);
+        assert_eq!(psx.cpu().pc(), pcs[320_122]);
+        assert!(rewind.rewind_to(&mut psx, 1000).is_err());
+
+        let mut capped = Rewind::new(RewindConfig { interval: 1, depth: 100, memory_cap: 1 });
+        capped.push(&psx);
+        capped.push(&psx);
+        assert_eq!(capped.len(), 1);
+    }
+}
@@ -0,0 +1,548 @@
+//! SPU: sound RAM, the 24 ADPCM voices with their ADSR envelopes, and the stereo mix
+//!
+//! Voices are resampled with linear interpolation rather than the hardware's gaussian
+//! filter, and volume sweeps, reverb, noise, pitch modulation, CD audio and the SPU
+//! interrupt aren't emulated. Sound RAM is only written through the manual transfer
+//! FIFO, since DMA isn't emulated yet. Samples are produced at 44.1 kHz on the
+//! instruction clock.
+
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+
+use crate::emu::state::{Snapshot, StateReader, StateWriter};
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+pub const SPU_RAM_SIZE: usize = 512 * 1024;
+
+/// The SPU makes a sample every 768 CPU cycles, at 2 cycles per instruction
+const INSTRUCTIONS_PER_SAMPLE: u64 = 768 / 2;
+
+const VOICES: usize = 24;
+
+/// Output is kept up to a second when nobody reads it, dropping the oldest samples
+const OUTPUT_CAPACITY: usize = SAMPLE_RATE as usize;
+
+/// Register offsets in the region
+const MAIN_VOLUME_LEFT: u32 = 0x180;
+const MAIN_VOLUME_RIGHT: u32 = 0x182;
+const KEY_ON: u32 = 0x188;
+const KEY_OFF: u32 = 0x18c;
+const ENDX: u32 = 0x19c;
+const TRANSFER_ADDRESS: u32 = 0x1a6;
+const TRANSFER_FIFO: u32 = 0x1a8;
+const SPUCNT: u32 = 0x1aa;
+const SPUSTAT: u32 = 0x1ae;
+
+/// Per voice registers
+const VOICE_VOLUME_LEFT: u32 = 0x0;
+const VOICE_VOLUME_RIGHT: u32 = 0x2;
+const VOICE_PITCH: u32 = 0x4;
+const VOICE_START: u32 = 0x6;
+const VOICE_ADSR_LOW: u32 = 0x8;
+const VOICE_ADSR_HIGH: u32 = 0xa;
+const VOICE_ADSR_LEVEL: u32 = 0xc;
+const VOICE_REPEAT: u32 = 0xe;
+
+/// ADPCM prediction filters
+const FILTER_POSITIVE: [i32; 5] = [0, 60, 115, 98, 122];
+const FILTER_NEGATIVE: [i32; 5] = [0, 0, -52, -55, -60];
+
+/// ADPCM block flags
+const FLAG_LOOP_END: u8 = 1 << 0;
+const FLAG_LOOP_REPEAT: u8 = 1 << 1;
+const FLAG_LOOP_START: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Phase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+impl Phase {
+    fn from_u8(val: u8) -> Result<Self> {
+        Ok(match val {
+            0 => Phase::Attack,
+            1 => Phase::Decay,
+            2 => Phase::Sustain,
+            3 => Phase::Release,
+            4 => Phase::Off,
+            _ => bail!("corrupt save state: invalid ADSR phase {val}"),
+        })
+    }
+}
+
+/// One step of an envelope: how much the level changes, and how often
+#[derive(Debug, Clone, Copy)]
+struct EnvelopeStep {
+    exponential: bool,
+    decrease: bool,
+    shift: u16,
+    step: i32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Voice {
+    /// Sound RAM address of the ADPCM block being played
+    address: u32,
+    repeat_address: u32,
+    /// Fixed point (12 fractional bits) position in the decoded block
+    counter: u32,
+    /// Decoded samples of the current block
+    samples: [i16; 28],
+    /// The last two samples of the previous block, for the prediction filter
+    history: [i16; 2],
+    /// The sample before the current block, for interpolation across blocks
+    previous: i16,
+    flags: u8,
+    phase: Phase,
+    level: i16,
+    /// Samples left before the next envelope step
+    envelope_wait: u32,
+}
+
+#[derive(Clone)]
+pub struct Spu {
+    ram: Vec<u8>,
+    /// Registers as last written
+    regs: Vec<u16>,
+    voices: [Voice; VOICES],
+    /// Voices that reached a block with the loop end flag since their key on
+    endx: u32,
+    transfer_address: u32,
+    /// Instructions towards the next sample
+    clock: u64,
+    output: VecDeque<[i16; 2]>,
+}
+
+/// The output samples are the host's, they aren't part of the state
+impl Snapshot for Spu {
+    fn save(&self, w: &mut StateWriter) {
+        w.section(b"SPU ");
+        w.bytes(&self.ram);
+        let regs: Vec<u32> = self.regs.chunks(2)
+            .map(|pair| pair[0] as u32 | (pair[1] as u32) << 16)
+            .collect();
+        w.words(&regs);
+        for voice in &self.voices {
+            w.words(&[voice.address, voice.repeat_address, voice.counter, voice.envelope_wait]);
+            for sample in voice.samples.iter().chain(&voice.history).chain([&voice.previous, &voice.level]) {
+                w.raw(&sample.to_le_bytes());
+            }
+            w.u8(voice.flags);
+            w.u8(voice.phase as u8);
+        }
+        w.u32(self.endx);
+        w.u32(self.transfer_address);
+        w.u64(self.clock);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<()> {
+        r.section(b"SPU ")?;
+        let ram = r.bytes()?;
+        if ram.len() != SPU_RAM_SIZE {
+            bail!("corrupt save state: SPU RAM is {} bytes", ram.len());
+        }
+        self.ram = ram;
+        let mut regs = vec![0; self.regs.len() / 2];
+        r.words_into(&mut regs)?;
+        for (idx, word) in regs.into_iter().enumerate() {
+            self.regs[idx * 2] = word as u16;
+            self.regs[idx * 2 + 1] = (word >> 16) as u16;
+        }
+        for voice in &mut self.voices {
+            let mut words = [0; 4];
+            r.words_into(&mut words)?;
+            let [address, repeat_address, counter, envelope_wait] = words;
+            voice.address = (address % SPU_RAM_SIZE as u32) & !0xf;
+            voice.repeat_address = (repeat_address % SPU_RAM_SIZE as u32) & !0xf;
+            voice.counter = counter.min((28 << 12) - 1);
+            voice.envelope_wait = envelope_wait;
+            let mut halfwords = [0; 32];
+            for halfword in &mut halfwords {
+                *halfword = i16::from_le_bytes(r.raw(2)?.try_into().unwrap());
+            }
+            voice.samples.copy_from_slice(&halfwords[..28]);
+            voice.history = [halfwords[28], halfwords[29]];
+            voice.previous = halfwords[30];
+            voice.level = halfwords[31];
+            voice.flags = r.u8()?;
+            voice.phase = Phase::from_u8(r.u8()?)?;
+        }
+        self.endx = r.u32()? & 0xff_ffff;
+        self.transfer_address = r.u32()? % SPU_RAM_SIZE as u32;
+        self.clock = r.u64()?;
+        Ok(())
+    }
+}
+
+impl Default for Spu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Spu {
+    pub fn new() -> Self {
+        Spu {
+            ram: vec![0; SPU_RAM_SIZE],
+            regs: vec![0; 0x140],
+            voices: [Voice::default(); VOICES],
+            endx: 0,
+            transfer_address: 0,
+            clock: 0,
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn reg(&self, offset: u32) -> u16 {
+        self.regs[offset as usize / 2]
+    }
+
+    fn voice_reg(&self, voice: usize, offset: u32) -> u16 {
+        self.reg(voice as u32 * 0x10 + offset)
+    }
+
+    /// Read the halfword register at `offset` in the region
+    pub fn load(&self, offset: u32) -> u16 {
+        match offset {
+            0x000..=0x17f if offset & 0xf == VOICE_ADSR_LEVEL => {
+                self.voices[offset as usize / 0x10].level as u16
+            },
+            ENDX => self.endx as u16,
+            0x19e => (self.endx >> 16) as u16,
+            // Transfers complete immediately, the current mode is all there is to see
+            SPUSTAT => self.reg(SPUCNT) & 0x3f,
+            _ => self.reg(offset),
+        }
+    }
+
+    /// Write the halfword register at `offset` in the region
+    pub fn store(&mut self, offset: u32, val: u16) {
+        let offset = offset & !1;
+        match offset {
+            KEY_ON => self.key_on(val as u32),
+            0x18a => self.key_on((val as u32) << 16),
+            KEY_OFF => self.key_off(val as u32),
+            0x18e => self.key_off((val as u32) << 16),
+            0x000..=0x17f if offset & 0xf == VOICE_REPEAT => {
+                self.voices[offset as usize / 0x10].repeat_address = block_address(val);
+            },
+            TRANSFER_ADDRESS => self.transfer_address = (val as u32 * 8) % SPU_RAM_SIZE as u32,
+            TRANSFER_FIFO => {
+                let address = self.transfer_address as usize;
+                self.ram[address..address + 2].copy_from_slice(&val.to_le_bytes());
+                self.transfer_address = (self.transfer_address + 2) % SPU_RAM_SIZE as u32;
+            },
+            _ => {},
+        }
+        if offset < 0x280 {
+            self.regs[offset as usize / 2] = val;
+        }
+    }
+
+    fn key_on(&mut self, mask: u32) {
+        for idx in (0..VOICES).filter(|idx| mask & 1 << idx != 0) {
+            let start = block_address(self.voice_reg(idx, VOICE_START));
+            let voice = &mut self.voices[idx];
+            voice.address = start;
+            voice.counter = 0;
+            voice.history = [0; 2];
+            voice.previous = 0;
+            voice.phase = Phase::Attack;
+            voice.level = 0;
+            voice.envelope_wait = 0;
+            self.endx &= !(1 << idx);
+            self.decode_block(idx);
+        }
+    }
+
+    fn key_off(&mut self, mask: u32) {
+        for (idx, voice) in self.voices.iter_mut().enumerate() {
+            if mask & 1 << idx != 0 && voice.phase != Phase::Off {
+                voice.phase = Phase::Release;
+                voice.envelope_wait = 0;
+            }
+        }
+    }
+
+    /// Decode the ADPCM block at the voice's address
+    fn decode_block(&mut self, idx: usize) {
+        let voice = &mut self.voices[idx];
+        let block = &self.ram[voice.address as usize..][..16];
+        let shift = match block[0] & 0xf {
+            shift @ 0..=12 => shift,
+            _ => 9,
+        };
+        let filter = ((block[0] >> 4) & 7).min(4) as usize;
+        voice.flags = block[1];
+        if voice.flags & FLAG_LOOP_START != 0 {
+            voice.repeat_address = voice.address;
+        }
+        let [mut older, mut old] = voice.history.map(|sample| sample as i32);
+        for (idx, sample) in voice.samples.iter_mut().enumerate() {
+            let nibble = (block[2 + idx / 2] >> (idx % 2 * 4)) & 0xf;
+            let raw = (((nibble as i16) << 12) >> shift) as i32;
+            let predicted = (old * FILTER_POSITIVE[filter] + older * FILTER_NEGATIVE[filter] + 32) >> 6;
+            let decoded = (raw + predicted).clamp(i16::MIN as i32, i16::MAX as i32);
+            *sample = decoded as i16;
+            older = old;
+            old = decoded;
+        }
+        voice.history = [older as i16, old as i16];
+    }
+
+    /// Play the voice for one sample, returning its output before the volume
+    fn voice_sample(&mut self, idx: usize) -> i32 {
+        let pitch = (self.voice_reg(idx, VOICE_PITCH) as u32).min(0x4000);
+        let adsr = self.voice_reg(idx, VOICE_ADSR_LOW) as u32 | (self.voice_reg(idx, VOICE_ADSR_HIGH) as u32) << 16;
+        let voice = &mut self.voices[idx];
+        if voice.phase == Phase::Off {
+            return 0;
+        }
+
+        let pos = (voice.counter >> 12) as usize;
+        let current = voice.samples[pos] as i32;
+        let before = match pos {
+            0 => voice.previous,
+            _ => voice.samples[pos - 1],
+        } as i32;
+        let frac = (voice.counter & 0xfff) as i32;
+        // Interpolate between the sample before and the current one, as the hardware
+        // lags a sample behind
+        let sample = before + (((current - before) * frac) >> 12);
+        let output = (sample * voice.level as i32) >> 15;
+
+        step_envelope(voice, adsr);
+
+        voice.counter += pitch;
+        if voice.counter >> 12 >= 28 {
+            voice.counter -= 28 << 12;
+            voice.previous = voice.samples[27];
+            if voice.flags & FLAG_LOOP_END != 0 {
+                self.endx |= 1 << idx;
+                voice.address = voice.repeat_address;
+                if voice.flags & FLAG_LOOP_REPEAT == 0 {
+                    voice.phase = Phase::Off;
+                    voice.level = 0;
+                }
+            } else {
+                voice.address = (voice.address + 16) % SPU_RAM_SIZE as u32;
+            }
+            self.decode_block(idx);
+        }
+        output
+    }
+
+    /// Advance the SPU by `instructions`, mixing the samples that are due
+    pub fn tick(&mut self, instructions: u64) {
+        self.clock += instructions;
+        while self.clock >= INSTRUCTIONS_PER_SAMPLE {
+            self.clock -= INSTRUCTIONS_PER_SAMPLE;
+            let sample = self.mix();
+            self.output.push_back(sample);
+        }
+        let excess = self.output.len().saturating_sub(OUTPUT_CAPACITY);
+        self.output.drain(..excess);
+    }
+
+    fn mix(&mut self) -> [i16; 2] {
+        let (mut left, mut right) = (0, 0);
+        for idx in 0..VOICES {
+            let sample = self.voice_sample(idx);
+            left += (sample * volume(self.voice_reg(idx, VOICE_VOLUME_LEFT))) >> 15;
+            right += (sample * volume(self.voice_reg(idx, VOICE_VOLUME_RIGHT))) >> 15;
+        }
+        // SPUCNT bit 15 enables the SPU, bit 14 unmutes it
+        if self.reg(SPUCNT) & 0xc000 != 0xc000 {
+            return [0; 2];
+        }
+        let left = (left * volume(self.reg(MAIN_VOLUME_LEFT))) >> 15;
+        let right = (right * volume(self.reg(MAIN_VOLUME_RIGHT))) >> 15;
+        [left, right].map(|sample| sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+    }
+
+    /// Take the stereo samples mixed since the last call
+    pub fn take_output(&mut self) -> Vec<[i16; 2]> {
+        self.output.drain(..).collect()
+    }
+}
+
+/// Address register (in units of 8 bytes) to the address of an ADPCM block
+fn block_address(reg: u16) -> u32 {
+    (reg as u32 * 8) & !0xf
+}
+
+/// Volume register to a factor with 15 fractional bits. Sweeps are played at full volume.
+fn volume(reg: u16) -> i32 {
+    match reg & 0x8000 != 0 {
+        true => 0x7fff,
+        false => ((reg << 1) as i16) as i32,
+    }
+}
+
+/// Advance the ADSR envelope of `voice` by a sample
+fn step_envelope(voice: &mut Voice, adsr: u32) {
+    let sustain_level = ((adsr & 0xf) + 1) * 0x800;
+    let step = match voice.phase {
+        Phase::Attack => EnvelopeStep {
+            exponential: adsr & 1 << 15 != 0,
+            decrease: false,
+            shift: (adsr >> 10 & 0x1f) as u16,
+            step: 7 - (adsr >> 8 & 3) as i32,
+        },
+        Phase::Decay => EnvelopeStep {
+            exponential: true,
+            decrease: true,
+            shift: (adsr >> 4 & 0xf) as u16,
+            step: -8,
+        },
+        Phase::Sustain => {
+            let decrease = adsr & 1 << 30 != 0;
+            let step = (adsr >> 22 & 3) as i32;
+            EnvelopeStep {
+                exponential: adsr & 1 << 31 != 0,
+                decrease,
+                shift: (adsr >> 24 & 0x1f) as u16,
+                step: if decrease { -8 + step } else { 7 - step },
+            }
+        },
+        Phase::Release => EnvelopeStep {
+            exponential: adsr & 1 << 21 != 0,
+            decrease: true,
+            shift: (adsr >> 16 & 0x1f) as u16,
+            step: -8,
+        },
+        Phase::Off => return,
+    };
+
+    if voice.envelope_wait > 0 {
+        voice.envelope_wait -= 1;
+    } else {
+        let level = voice.level as i32;
+        let mut wait = 1 << step.shift.saturating_sub(11);
+        let mut delta = step.step << 11u16.saturating_sub(step.shift);
+        if step.exponential && !step.decrease && level > 0x6000 {
+            wait *= 4;
+        }
+        if step.exponential && step.decrease {
+            delta = (delta * level) >> 15;
+        }
+        voice.level = (level + delta).clamp(0, 0x7fff) as i16;
+        voice.envelope_wait = wait - 1;
+    }
+
+    let level = voice.level as u32;
+    match voice.phase {
+        Phase::Attack if level == 0x7fff => voice.phase = Phase::Decay,
+        Phase::Decay if level <= sustain_level => voice.phase = Phase::Sustain,
+        Phase::Release if level == 0 => voice.phase = Phase::Off,
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Upload `data` to sound RAM at `address` through the transfer FIFO
+    fn upload(spu: &mut Spu, address: u32, data: &[u8]) {
+        spu.store(TRANSFER_ADDRESS, (address / 8) as u16);
+        for pair in data.chunks(2) {
+            spu.store(TRANSFER_FIFO, u16::from_le_bytes([pair[0], pair[1]]));
+        }
+    }
+
+    #[test]
+    fn decodes_adpcm() {
+        let mut spu = Spu::new();
+        // Shift 12, no filter: 0x1 and 0xf nibbles are 1 and -1
+        let mut block = [0u8; 16];
+        block[0] = 12;
+        block[1] = FLAG_LOOP_END;
+        block[2] = 0xf1;
+        upload(&mut spu, 0x1000, &block);
+        assert_eq!(spu.ram()[0x1002], 0xf1);
+
+        spu.store(VOICE_START, 0x1000 / 8);
+        spu.store(KEY_ON, 1);
+        assert_eq!(spu.voices[0].samples[..3], [1, -1, 0]);
+
+        // Filter 1 predicts old * 60 / 64, rounded
+        block[0] = 0x10;
+        block[2] = 0x07;
+        upload(&mut spu, 0x1000, &block);
+        spu.store(KEY_ON, 1);
+        assert_eq!(spu.voices[0].samples[..3], [0x7000, 26880, 25200]);
+    }
+
+    #[test]
+    fn plays_voice() {
+        let mut spu = Spu::new();
+        // A square wave: looping blocks of full scale samples
+        let mut block = [0x77u8; 16];
+        block[0] = 0;
+        block[1] = FLAG_LOOP_START | FLAG_LOOP_END | FLAG_LOOP_REPEAT;
+        upload(&mut spu, 0x1000, &block);
+
+        spu.store(SPUCNT, 0xc000);
+        spu.store(MAIN_VOLUME_LEFT, 0x3fff);
+        spu.store(MAIN_VOLUME_RIGHT, 0x3fff);
+        spu.store(VOICE_VOLUME_LEFT, 0x3fff);
+        spu.store(VOICE_VOLUME_RIGHT, 0x2000);
+        spu.store(VOICE_PITCH, 0x1000);
+        spu.store(VOICE_START, 0x1000 / 8);
+        // Fastest linear attack, sustain at the top
+        spu.store(VOICE_ADSR_LOW, 0x000f);
+        spu.store(VOICE_ADSR_HIGH, 0x0000);
+        spu.store(KEY_ON, 1);
+
+        spu.tick(INSTRUCTIONS_PER_SAMPLE * 100);
+        let output = spu.take_output();
+        assert_eq!(output.len(), 100);
+        assert!(spu.take_output().is_empty());
+        let [left, right] = output[99];
+        assert!(left > 0x6000, "{left:x}");
+        assert!((right - left / 2).abs() < 0x100, "{left:x} {right:x}");
+        assert_ne!(spu.load(0x00c), 0);
+        assert_ne!(spu.load(ENDX) & 1, 0);
+
+        // Released to silence
+        spu.store(KEY_OFF, 1);
+        spu.tick(INSTRUCTIONS_PER_SAMPLE * 0x1000);
+        assert_eq!(spu.take_output().last(), Some(&[0, 0]));
+        assert_eq!(spu.voices[0].phase, Phase::Off);
+
+        // SPUSTAT mirrors the mode bits of SPUCNT
+        spu.store(SPUCNT, 0xc020);
+        assert_eq!(spu.load(SPUSTAT), 0x20);
+    }
+
+    #[test]
+    fn loads_out_of_range_counter() {
+        let mut spu = Spu::new();
+        spu.voices[0].counter = u32::MAX;
+        spu.voices[0].phase = Phase::Sustain;
+        let mut w = StateWriter::new();
+        Snapshot::save(&spu, &mut w);
+        let state = w.finish();
+
+        let mut loaded = Spu::new();
+        let mut r = StateReader::new(&state);
+        Snapshot::load(&mut loaded, &mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(loaded.voices[0].counter, (28 << 12) - 1);
+        loaded.store(SPUCNT, 0xc000);
+        loaded.tick(INSTRUCTIONS_PER_SAMPLE);
+        assert_eq!(loaded.take_output().len(), 1);
+    }
+}
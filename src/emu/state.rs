@@ -0,0 +1,280 @@
+//! Save states: snapshots of the whole emulated system in a versioned binary format
+//!
+//! A state starts with a [`Header`], followed by the sections written by the
+//! [`Snapshot`] implementations of the devices. Everything is little-endian, and each
+//! section starts with a tag so that a layout mismatch is caught where it happens.
+//! Configuration (BIOS, disc, expansion ROM, CPU engine) isn't part of the state, the
+//! header only records what the state was saved with.
+
+use anyhow::{anyhow, bail, Result};
+
+pub const MAGIC: [u8; 8] = *b"PSXSTATE";
+
+/// Bumped on every change to the layout, older states are rejected
+pub const VERSION: u32 = 6;
+
+/// Longest disc ID stored in the header
+const MAX_DISC_ID: usize = 32;
+
+/// State of a device that can be written to and restored from a save state
+pub trait Snapshot {
+    fn save(&self, w: &mut StateWriter);
+    /// Restore the state written by [`Snapshot::save`]. On error, the device may be left
+    /// partially restored.
+    fn load(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+/// What a state was saved with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    /// MD5 of the BIOS image, as patched
+    pub bios_md5: [u8; 16],
+    /// ID of the disc (e.g. `SLUS_006.74`), empty without one
+    pub disc_id: String,
+    pub instructions_retired: u64,
+}
+
+impl Header {
+    pub fn save(&self, w: &mut StateWriter) {
+        w.raw(&MAGIC);
+        w.u32(self.version);
+        w.raw(&self.bios_md5);
+        w.bytes(self.disc_id.as_bytes());
+        w.u64(self.instructions_retired);
+    }
+
+    /// Parse the header at the start of `buf`, returning it and a reader for the rest
+    pub fn parse(buf: &[u8]) -> Result<(Header, StateReader<'_>)> {
+        let mut r = StateReader::new(buf);
+        if r.raw(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            bail!("not a save state");
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            bail!("save state version {version} is not supported (expected {VERSION})");
+        }
+        let bios_md5 = r.raw(16)?.try_into().unwrap();
+        let disc_id = r.bytes()?;
+        if disc_id.len() > MAX_DISC_ID {
+            bail!("corrupt save state header");
+        }
+        let header = Header {
+            version,
+            bios_md5,
+            disc_id: String::from_utf8_lossy(&disc_id).into_owned(),
+            instructions_retired: r.u64()?,
+        };
+        Ok((header, r))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Start the section of a device
+    pub fn section(&mut self, tag: &[u8; 4]) {
+        self.raw(tag);
+    }
+
+    pub fn raw(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.raw(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.raw(&val.to_le_bytes());
+    }
+
+    /// Length-prefixed bytes
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.raw(data);
+    }
+
+    /// Length-prefixed words
+    pub fn words(&mut self, words: &[u32]) {
+        self.u32(words.len() as u32);
+        for &word in words {
+            self.u32(word);
+        }
+    }
+
+    pub fn option_u32(&mut self, val: Option<u32>) {
+        self.bool(val.is_some());
+        self.u32(val.unwrap_or(0));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        StateReader { buf, pos: 0 }
+    }
+
+    /// Check that the whole state was consumed
+    pub fn finish(self) -> Result<()> {
+        match self.buf.len() - self.pos {
+            0 => Ok(()),
+            extra => bail!("{extra} unexpected bytes at the end of the save state"),
+        }
+    }
+
+    /// Enter the section of a device, which must be next
+    pub fn section(&mut self, tag: &[u8; 4]) -> Result<()> {
+        let found = self.raw(4)?;
+        if found != tag {
+            bail!("corrupt save state: expected section {:?} at offset {}, found {:?}",
+                String::from_utf8_lossy(tag), self.pos - 4, String::from_utf8_lossy(found));
+        }
+        Ok(())
+    }
+
+    pub fn raw(&mut self, len: usize) -> Result<&'a [u8]> {
+        let data = self.buf.get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("save state truncated at offset {}", self.pos))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            val => bail!("corrupt save state: invalid bool {val} at offset {}", self.pos - 1),
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.raw(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.raw(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.raw(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.raw(len)?.to_vec())
+    }
+
+    /// Words written with [`StateWriter::words`]
+    pub fn words(&mut self) -> Result<Vec<u32>> {
+        let len = self.u32()? as usize;
+        let data = self.raw(len.checked_mul(4).ok_or_else(|| anyhow!("corrupt save state"))?)?;
+        Ok(data.array_chunks().map(|word| u32::from_le_bytes(*word)).collect())
+    }
+
+    /// Words written with [`StateWriter::words`], which must be exactly `out.len()`
+    pub fn words_into(&mut self, out: &mut [u32]) -> Result<()> {
+        let len = self.u32()? as usize;
+        if len != out.len() {
+            bail!("corrupt save state: expected {} words, found {len}", out.len());
+        }
+        for word in out {
+            *word = self.u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn option_u32(&mut self) -> Result<Option<u32>> {
+        let is_some = self.bool()?;
+        let val = self.u32()?;
+        Ok(is_some.then_some(val))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{bios::BIOS_SIZE, cpu::Engine, Psx};
+
+    fn run_trace(psx: &mut Psx, steps: usize) -> Vec<(u32, u64)> {
+        (0..steps)
+            .map(|_| {
+                psx.step();
+                (psx.cpu().pc(), psx.instructions_retired)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_exactly() {
+        let bios = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/scph1001.bin")).unwrap();
+        let mut psx = Psx::new_from_bios(bios.as_slice().try_into().unwrap());
+        psx.set_engine(Engine::CachedInterpreter).unwrap();
+        run_trace(&mut psx, 300_000);
+
+        let state = psx.save_state();
+        let trace = run_trace(&mut psx, 200_000);
+        let end_state = psx.save_state();
+
+        psx.load_state(&state).unwrap();
+        assert_eq!(psx.save_state(), state);
+        assert_eq!(run_trace(&mut psx, 200_000), trace);
+        assert_eq!(psx.save_state(), end_state);
+
+        // Rejected states leave everything untouched
+        assert!(psx.load_state(&state[..state.len() - 1]).is_err());
+        assert!(psx.load_state(&[state.as_slice(), &[0]].concat()).is_err());
+        assert_eq!(psx.save_state(), end_state);
+        assert!(Psx::new_from_bios(&[0; BIOS_SIZE]).load_state(&state).is_err());
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            version: VERSION,
+            bios_md5: [7; 16],
+            disc_id: "SLUS_006.74".to_string(),
+            instructions_retired: 1234,
+        };
+        let mut w = StateWriter::new();
+        header.save(&mut w);
+        w.section(b"TEST");
+        w.option_u32(Some(5));
+        let buf = w.finish();
+
+        let (parsed, mut r) = Header::parse(&buf).unwrap();
+        assert_eq!(parsed, header);
+        r.section(b"TEST").unwrap();
+        assert_eq!(r.option_u32().unwrap(), Some(5));
+        r.finish().unwrap();
+
+        assert!(Header::parse(&buf[..20]).is_err());
+        assert!(Header::parse(b"PSXSTATE\x63\0\0\0").is_err());
+        let (_, mut r) = Header::parse(&buf).unwrap();
+        assert!(r.section(b"CPU ").is_err());
+    }
+}
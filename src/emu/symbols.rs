@@ -0,0 +1,104 @@
+//! Symbol table used to show addresses as `function+offset`
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub addr: u32,
+    /// Size in bytes, 0 if unknown (e.g. assembler labels)
+    pub size: u32,
+    pub name: String,
+}
+
+/// Symbols sorted by address
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|sym| sym.addr);
+        SymbolTable { symbols }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Find the symbol `addr` belongs to, and the offset of `addr` inside of it. Symbols of
+    /// unknown size only cover their own address, past it the closest sized symbol before
+    /// `addr` is used.
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let end = self.symbols.partition_point(|sym| sym.addr <= addr);
+        let sym = self.symbols[..end].last()?;
+        if sym.addr == addr {
+            return Some((sym, 0));
+        }
+        let sym = self.symbols[..end].iter().rev().find(|sym| sym.size != 0)?;
+        let offset = addr - sym.addr;
+        (offset < sym.size).then_some((sym, offset))
+    }
+
+    /// Address of the symbol called `name`
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.symbols.iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| sym.addr)
+    }
+}
+
+/// An address, displayed as `0x80010010 <main+0x10>` when a symbol covers it
+#[derive(Debug, Copy, Clone)]
+pub struct Location<'a> {
+    pub addr: u32,
+    pub symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> Location<'a> {
+    pub fn new(addr: u32, symbols: Option<&'a SymbolTable>) -> Self {
+        Location { addr, symbols }
+    }
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08x}", self.addr)?;
+        match self.symbols.and_then(|symbols| symbols.lookup(self.addr)) {
+            Some((sym, 0)) => write!(f, " <{}>", sym.name),
+            Some((sym, offset)) => write!(f, " <{}+0x{offset:x}>", sym.name),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_only_cover_their_address() {
+        let symbol = |addr, size, name: &str| Symbol { addr, size, name: name.into() };
+        let symbols = SymbolTable::new(vec![
+            symbol(0x8001_0000, 0x20, "main"),
+            symbol(0x8001_0010, 0, "loop"),
+            symbol(0x8001_0040, 0, "end"),
+        ]);
+        let lookup = |addr| symbols.lookup(addr).map(|(sym, offset)| (sym.name.as_str(), offset));
+        assert_eq!(lookup(0x8001_0004), Some(("main", 4)));
+        assert_eq!(lookup(0x8001_0010), Some(("loop", 0)));
+        assert_eq!(lookup(0x8001_0014), Some(("main", 0x14)));
+        assert_eq!(lookup(0x8001_0020), None);
+        assert_eq!(lookup(0x8001_0040), Some(("end", 0)));
+        assert_eq!(lookup(0x8001_0044), None);
+        assert_eq!(lookup(0x8000_fffc), None);
+    }
+}
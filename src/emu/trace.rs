@@ -0,0 +1,235 @@
+//! Execution trace: one line per retired instruction, for diffing against other emulators
+//!
+//! ```text
+//! 0000000508 8001000c ad280000 sw      t0, 0x0(t1)              w[80010020]=00000005
+//! 0000000510 80010010 852a0000 lh      t2, 0x0(t1)              r[80010020]=0005
+//! 0000000512 80010014 00000000 nop                              t2=00000005
+//! ```
+//!
+//! The columns are the cycle the instruction issued at, its address, its word and its
+//! disassembly, followed by the registers it changed and the memory it accessed, all in
+//! hex. As on the hardware, a loaded register only changes after the load delay slot.
+//! Accesses are seen through a watch on the whole physical address space (see
+//! [`crate::emu::watch`]), and need the interpreter, which retires one instruction per step.
+
+use std::{cell::RefCell, fmt::Write as _, io::{self, Write}, ops::Range, rc::Rc};
+
+use crate::emu::{
+    bus::Bus,
+    cpu::{disasm::{disassemble, REGISTER_NAMES}, instruction::{Instruction, RegisterIndex}, Cpu},
+    gpu::CYCLES_PER_INSTRUCTION,
+    watch::{AccessKind, Triggers, WatchAction, WatchHit, WatchId},
+};
+
+/// Width of the disassembly column
+const DISASM_WIDTH: usize = 32;
+
+/// Register file, as compared between instructions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub gpr: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub sr: u32,
+    pub cause: u32,
+    pub epc: u32,
+}
+
+impl Registers {
+    pub fn of(cpu: &Cpu) -> Registers {
+        Registers {
+            gpr: std::array::from_fn(|idx| cpu.reg(RegisterIndex(idx as u32))),
+            hi: cpu.hi(),
+            lo: cpu.lo(),
+            sr: cpu.status(),
+            cause: cpu.cause(),
+            epc: cpu.epc(),
+        }
+    }
+
+    /// Name and value of each register, GPRs first
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u32)> + '_ {
+        REGISTER_NAMES.into_iter()
+            .zip(self.gpr)
+            .chain([("hi", self.hi), ("lo", self.lo), ("sr", self.sr), ("cause", self.cause), ("epc", self.epc)])
+    }
+
+    /// Value of the register called `name` in the trace
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.iter().find(|&(other, _)| other == name).map(|(_, value)| value)
+    }
+
+    /// Registers with another value in `other`, and that value
+    pub fn changes<'a>(&'a self, other: &'a Registers) -> impl Iterator<Item = (&'static str, u32)> + 'a {
+        self.iter()
+            .zip(other.iter())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|(_, change)| change)
+    }
+}
+
+/// Instructions to trace, all of them by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Addresses of the instructions, as the CPU sees them
+    pub pcs: Option<Range<u32>>,
+    /// Instruction counts since power on
+    pub instructions: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    fn accepts(&self, pc: u32, instruction: u64) -> bool {
+        self.pcs.as_ref().map_or(true, |pcs| pcs.contains(&pc))
+            && self.instructions.as_ref().map_or(true, |window| window.contains(&instruction))
+    }
+}
+
+/// The instruction about to be traced
+struct Pending {
+    instruction: u64,
+    pc: u32,
+    inst: Instruction,
+    registers: Registers,
+}
+
+/// Writes the trace, fed by [`crate::emu::Psx::step`]
+pub struct Tracer {
+    out: Box<dyn Write>,
+    filter: TraceFilter,
+    /// Reporting to `accesses`
+    watch: WatchId,
+    accesses: Rc<RefCell<Vec<WatchHit>>>,
+    pending: Option<Pending>,
+    line: String,
+}
+
+impl Tracer {
+    pub fn new(bus: &mut Bus, out: Box<dyn Write>, filter: TraceFilter) -> Tracer {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let sink = accesses.clone();
+        let watch = bus.add_watch(0, 0x2000_0000, Triggers::ACCESS, WatchAction::Callback(Box::new(move |hit| {
+            sink.borrow_mut().push(*hit);
+        })));
+        Tracer { out, filter, watch, accesses, pending: None, line: String::new() }
+    }
+
+    /// Remove the watch and flush the trace
+    pub fn finish(mut self, bus: &mut Bus) -> io::Result<()> {
+        bus.remove_watch(self.watch);
+        self.out.flush()
+    }
+
+    /// Look at the CPU before it executes the instruction at its pc, the `instruction`th
+    /// since power on
+    pub fn begin(&mut self, cpu: &Cpu, bus: &Bus, instruction: u64) {
+        self.accesses.borrow_mut().clear();
+        let pc = cpu.pc();
+        self.pending = self.filter.accepts(pc, instruction).then(|| Pending {
+            instruction,
+            pc,
+            inst: Instruction(bus.peek(pc)),
+            registers: Registers::of(cpu),
+        });
+    }
+
+    /// Write the line of the instruction seen by `begin`, now that it retired
+    pub fn end(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        let line = &mut self.line;
+        line.clear();
+        let disasm = disassemble(pending.inst, pending.pc);
+        let _ = write!(line, "{:010} {:08x} {:08x} {disasm:DISASM_WIDTH$}",
+            pending.instruction * CYCLES_PER_INSTRUCTION, pending.pc, pending.inst.0);
+        for (name, value) in pending.registers.changes(&Registers::of(cpu)) {
+            let _ = write!(line, " {name}={value:08x}");
+        }
+        for access in self.accesses.borrow().iter() {
+            let kind = match access.kind {
+                AccessKind::Read => 'r',
+                AccessKind::Write => 'w',
+                AccessKind::Execute => continue,
+            };
+            let _ = write!(line, " {kind}[{:08x}]={:0width$x}", access.addr, access.value, width = access.len as usize * 2);
+        }
+        writeln!(self.out, "{}", line.trim_end())
+    }
+
+    /// Whether the instruction window is over after `instructions` retired
+    pub fn is_done(&self, instructions: u64) -> bool {
+        self.filter.instructions.as_ref().map_or(false, |window| instructions >= window.end)
+    }
+}
+
+/// Trace output that tests can read back
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct TestBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for TestBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{map, Psx, TEST_ORIGIN as ORIGIN};
+
+    #[test]
+    fn traces_instructions() {
+        let mut psx = Psx::boot_test_program("
+                li    t0, 5
+                la    t1, data
+                sw    t0, 0(t1)
+                lh    t2, 0(t1)
+                nop
+            spin:
+                b     spin
+                nop
+            data:
+                .word 0
+        ");
+
+        let buffer = TestBuffer::default();
+        let filter = TraceFilter { pcs: Some(ORIGIN + 12..ORIGIN + 32), instructions: None };
+        psx.start_trace(Box::new(buffer.clone()), filter).unwrap();
+        for _ in 0..100_000 {
+            psx.step();
+        }
+        psx.stop_trace().unwrap();
+
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        let lines: Vec<_> = trace.lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .take(5)
+            .collect();
+        assert_eq!(lines, [
+            "8001000c ad280000 sw      t0, 0x0(t1)              w[80010020]=00000005",
+            "80010010 852a0000 lh      t2, 0x0(t1)              r[80010020]=0005",
+            "80010014 00000000 nop                              t2=00000005",
+            "80010018 1000ffff b       0x80010018",
+            "8001001c 00000000 nop",
+        ]);
+        let cycles: Vec<u64> = trace.lines().take(2).map(|line| line[..10].parse().unwrap()).collect();
+        assert_eq!(cycles[1] - cycles[0], CYCLES_PER_INSTRUCTION);
+
+        // The window ends the trace by itself
+        let buffer = TestBuffer::default();
+        let start = psx.instructions_retired;
+        let filter = TraceFilter { pcs: None, instructions: Some(start..start + 3) };
+        psx.start_trace(Box::new(buffer.clone()), filter).unwrap();
+        for _ in 0..10 {
+            psx.step();
+        }
+        assert_eq!(String::from_utf8(buffer.0.take()).unwrap().lines().count(), 3);
+        assert!(matches!(psx.bus().page(ORIGIN & 0x1fff_ffff), map::Page::Ram(_)));
+    }
+}
@@ -0,0 +1,251 @@
+//! Memory watches, for debuggers, cheat engines and test harnesses
+//!
+//! A watch covers an address range and triggers on reads, writes and/or instruction
+//! fetches there. A hit either calls the watch's callback, or is queued as a break for the
+//! tool to collect after the step. Only the pages holding a watch take the bus slow path,
+//! so memory accesses cost nothing extra while no watch is set. Addresses are compared
+//! physically, so a watch on `0x80010000` also sees accesses through `0x00010000`.
+
+use std::{cell::RefCell, fmt, ops::BitOr};
+
+use crate::emu::map::{self, PAGE_SHIFT};
+
+/// Accesses a watch triggers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Triggers(u8);
+
+impl Triggers {
+    pub const READ: Triggers = Triggers(1);
+    pub const WRITE: Triggers = Triggers(2);
+    /// Instruction fetches
+    pub const EXECUTE: Triggers = Triggers(4);
+    pub const ACCESS: Triggers = Triggers(1 | 2);
+
+    pub fn contains(self, kind: AccessKind) -> bool {
+        let bit = match kind {
+            AccessKind::Read => Triggers::READ,
+            AccessKind::Write => Triggers::WRITE,
+            AccessKind::Execute => Triggers::EXECUTE,
+        };
+        self.0 & bit.0 != 0
+    }
+}
+
+impl BitOr for Triggers {
+    type Output = Triggers;
+
+    fn bitor(self, other: Triggers) -> Triggers {
+        Triggers(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccessKind::Read => "read",
+            AccessKind::Write => "write",
+            AccessKind::Execute => "execute",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WatchId(u32);
+
+/// An access that triggered a watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: WatchId,
+    pub kind: AccessKind,
+    /// Address as accessed by the CPU
+    pub addr: u32,
+    /// Size in bytes
+    pub len: u32,
+    /// Value read, written or fetched
+    pub value: u32,
+}
+
+/// What a hit does
+pub enum WatchAction {
+    /// Queue the hit, see [`Watches::take_breaks`]
+    Break,
+    Callback(Box<dyn FnMut(&WatchHit)>),
+}
+
+struct Watch {
+    id: WatchId,
+    /// Physical range, `end` excluded
+    start: u32,
+    end: u32,
+    triggers: Triggers,
+    action: WatchAction,
+}
+
+impl Watch {
+    /// Pages of the physical address space the watch covers
+    fn pages(&self) -> std::ops::RangeInclusive<u32> {
+        (self.start >> PAGE_SHIFT)..=((self.end - 1) >> PAGE_SHIFT)
+    }
+}
+
+/// The watches set on the bus. Callbacks can't be copied, so clones (e.g. the lock-step
+/// shadow bus) start without any.
+#[derive(Default)]
+pub struct Watches {
+    watches: RefCell<Vec<Watch>>,
+    breaks: RefCell<Vec<WatchHit>>,
+    next_id: u32,
+    /// Whether any watch triggers on fetches, which don't go through the page table
+    execute: bool,
+}
+
+impl Clone for Watches {
+    fn clone(&self) -> Self {
+        Watches::default()
+    }
+}
+
+impl Watches {
+    pub fn is_empty(&self) -> bool {
+        self.watches.borrow().is_empty()
+    }
+
+    /// Whether any watch triggers on instruction fetches
+    pub fn has_execute(&self) -> bool {
+        self.execute
+    }
+
+    /// Watch `len` bytes at `addr`. Returns the watch and the pages it covers.
+    pub fn add(&mut self, addr: u32, len: u32, triggers: Triggers, action: WatchAction) -> (WatchId, Vec<u32>) {
+        let start = map::mask_region(addr);
+        let id = WatchId(self.next_id);
+        self.next_id += 1;
+        let watch = Watch { id, start, end: start.saturating_add(len.max(1)), triggers, action };
+        let pages = watch.pages().collect();
+        self.execute |= triggers.contains(AccessKind::Execute);
+        self.watches.get_mut().push(watch);
+        (id, pages)
+    }
+
+    /// Remove a watch. Returns the pages no watch covers anymore, `None` if there was no
+    /// such watch.
+    pub fn remove(&mut self, id: WatchId) -> Option<Vec<u32>> {
+        let watches = self.watches.get_mut();
+        let idx = watches.iter().position(|watch| watch.id == id)?;
+        let removed = watches.remove(idx);
+        self.execute = watches.iter().any(|watch| watch.triggers.contains(AccessKind::Execute));
+        let pages = removed.pages()
+            .filter(|page| !watches.iter().any(|watch| watch.pages().contains(page)))
+            .collect();
+        Some(pages)
+    }
+
+    /// Report an access of `len` bytes at `addr` to the watches covering it
+    pub fn access(&self, kind: AccessKind, addr: u32, len: u32, value: u32) {
+        let paddr = map::mask_region(addr);
+        for watch in self.watches.borrow_mut().iter_mut() {
+            if !watch.triggers.contains(kind) || paddr >= watch.end || paddr + len <= watch.start {
+                continue;
+            }
+            let hit = WatchHit { id: watch.id, kind, addr, len, value };
+            match &mut watch.action {
+                WatchAction::Break => self.breaks.borrow_mut().push(hit),
+                WatchAction::Callback(callback) => callback(&hit),
+            }
+        }
+    }
+
+    /// Take the hits of the `Break` watches since the last call
+    pub fn take_breaks(&self) -> Vec<WatchHit> {
+        self.breaks.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::emu::{Psx, TEST_ORIGIN as ORIGIN};
+
+    const SPIN: u32 = ORIGIN + 7 * 4;
+    const DATA: u32 = ORIGIN + 9 * 4;
+
+    /// Stopped at the first access to `DATA`
+    fn psx() -> Psx {
+        let mut psx = Psx::boot_test_program("
+                la    s0, data
+                lw    t0, 0(s0)
+                nop
+                addiu t0, t0, 1
+                sw    t0, 0(s0)
+                sw    t0, 4(s0)
+            spin:
+                b     spin
+                nop
+            data:
+                .word 5
+                .word 0
+        ");
+        while psx.cpu().pc() != ORIGIN + 8 {
+            psx.step();
+        }
+        psx
+    }
+
+    #[test]
+    fn reports_accesses() {
+        let mut psx = psx();
+        let writes = Rc::new(Cell::new(0));
+        let counter = writes.clone();
+        let write = psx.add_watch(DATA, 4, Triggers::WRITE, WatchAction::Callback(Box::new(move |hit| {
+            assert_eq!((hit.addr, hit.value), (DATA, 6));
+            counter.set(counter.get() + 1);
+        })));
+        let read = psx.add_watch(DATA & 0x1fff_ffff, 4, Triggers::READ, WatchAction::Break);
+        let spin = psx.add_watch(SPIN, 4, Triggers::EXECUTE, WatchAction::Break);
+
+        let mut hits = Vec::new();
+        for _ in 0..1_000_000 {
+            psx.step();
+            hits.extend(psx.take_watch_breaks());
+            if hits.iter().any(|hit| hit.id == spin) {
+                break;
+            }
+        }
+        assert_eq!(writes.get(), 1);
+        assert_eq!(hits, [
+            WatchHit { id: read, kind: AccessKind::Read, addr: DATA, len: 4, value: 5 },
+            WatchHit { id: spin, kind: AccessKind::Execute, addr: SPIN, len: 4, value: psx.bus().peek(SPIN) },
+        ]);
+
+        // The code page stays watched until its last watch goes
+        assert!(psx.remove_watch(spin));
+        assert!(!psx.remove_watch(spin));
+        assert!(matches!(psx.bus().page(SPIN & 0x1fff_ffff), map::Page::Watched));
+        assert!(psx.remove_watch(read));
+        assert!(matches!(psx.bus().page(SPIN & 0x1fff_ffff), map::Page::Watched));
+        assert!(psx.remove_watch(write));
+        assert!(matches!(psx.bus().page(SPIN & 0x1fff_ffff), map::Page::Ram(0)));
+    }
+
+    #[test]
+    fn fetches_are_not_reads() {
+        // Read watch over the code as well as the data, without any execute watch
+        let mut psx = psx();
+        let read = psx.add_watch(ORIGIN, DATA + 8 - ORIGIN, Triggers::READ, WatchAction::Break);
+        let mut hits = Vec::new();
+        while psx.cpu().pc() != SPIN {
+            psx.step();
+            hits.extend(psx.take_watch_breaks());
+        }
+        assert_eq!(hits, [WatchHit { id: read, kind: AccessKind::Read, addr: DATA, len: 4, value: 5 }]);
+    }
+}
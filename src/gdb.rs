@@ -0,0 +1,524 @@
+//! GDB remote serial protocol stub
+//!
+//! `--gdb <PORT>` waits for a debugger on localhost before starting emulation:
+//!
+//! ```text
+//! gdb-multiarch -ex 'set architecture mips:3000' -ex 'target remote :<PORT>'
+//! ```
+//!
+//! Registers follow GDB's MIPS numbering (GPRs, SR, LO, HI, BadVaddr, Cause, PC, then
+//! the FPU, which the PSX doesn't have and reads as zero), with EPC appended after the
+//! FPU. Software and hardware breakpoints are both checked against the PC before every
+//! instruction, so the recompiler is swapped for the interpreter while a debugger is
+//! attached. Watchpoints are memory watches on the bus, see [`crate::emu::watch`].
+
+mod packet;
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    emu::{
+        cpu::{instruction::RegisterIndex, Cpu, Engine},
+        watch::{Triggers, WatchAction, WatchId},
+    },
+    gdb::packet::{decode_hex, encode_hex, parse_hex, Connection, Incoming},
+    Context,
+};
+
+/// Steps between checks for an interrupt from GDB while running
+const POLL_STEPS: u32 = 0x1000;
+
+/// Packet size advertised to GDB in `qSupported`
+const PACKET_SIZE: usize = 0x4000;
+/// Largest `m` read, whose hex reply has to fit in a packet too
+const MAX_READ: u32 = PACKET_SIZE as u32 / 2;
+
+/// Registers in a `g` packet: 32 GPRs, SR, LO, HI, BadVaddr, Cause, PC, 32 FPRs, FCSR,
+/// FIR and EPC
+const REGISTER_COUNT: usize = 73;
+const REG_SR: usize = 32;
+const REG_LO: usize = 33;
+const REG_HI: usize = 34;
+const REG_BAD_VADDR: usize = 35;
+const REG_CAUSE: usize = 36;
+const REG_PC: usize = 37;
+const REG_EPC: usize = 72;
+
+/// GDB's signal numbers, which stop replies are made of
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+
+/// How a debugging session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// GDB detached or hung up, emulation can go on without it
+    Detached,
+    /// GDB killed the target
+    Killed,
+    /// The window was closed while running
+    WindowClosed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    /// Name of the stop reason reporting a hit
+    fn reason(self) -> &'static str {
+        match self {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    kind: WatchKind,
+    addr: u32,
+    len: u32,
+}
+
+/// Why the target stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Signal(u8),
+    Watch(Watchpoint),
+    WindowClosed,
+}
+
+/// Listen for GDB on localhost. Port 0 picks a free one.
+pub fn bind(port: u16) -> Result<TcpListener> {
+    TcpListener::bind(("127.0.0.1", port)).map_err(|e| anyhow!("failed to listen for GDB on port {port}: {e}"))
+}
+
+/// Wait for GDB to connect to `listener`, then debug `ctx` until it leaves
+pub fn serve(ctx: &mut Context, listener: &TcpListener) -> Result<SessionEnd> {
+    tracing::info!("waiting for GDB on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    tracing::info!("GDB connected from {peer}");
+    let engine = ctx.psx.cpu().engine();
+    if engine != Engine::Interpreter {
+        tracing::info!("switching to the interpreter while debugging");
+        ctx.psx.set_engine(Engine::Interpreter)?;
+    }
+    let mut stub = GdbStub::new(stream)?;
+    let end = stub.serve(ctx);
+    // The emulation goes on without the debugger
+    for (_, watch) in stub.watchpoints.drain(..) {
+        ctx.psx.remove_watch(watch);
+    }
+    if engine != Engine::Interpreter {
+        tracing::info!("switching back to the {engine:?} engine");
+        ctx.psx.set_engine(engine)?;
+    }
+    end
+}
+
+struct GdbStub {
+    conn: Connection,
+    /// Software and hardware breakpoints alike
+    breakpoints: BTreeSet<u32>,
+    /// With the watch set on the bus for each
+    watchpoints: Vec<(Watchpoint, WatchId)>,
+}
+
+impl GdbStub {
+    fn new(stream: TcpStream) -> Result<Self> {
+        Ok(GdbStub { conn: Connection::new(stream)?, breakpoints: BTreeSet::new(), watchpoints: Vec::new() })
+    }
+
+    fn serve(&mut self, ctx: &mut Context) -> Result<SessionEnd> {
+        loop {
+            let packet = match self.conn.read()? {
+                None => return Ok(SessionEnd::Detached),
+                // Already stopped
+                Some(Incoming::Interrupt) => continue,
+                Some(Incoming::Packet(packet)) => packet,
+            };
+            tracing::trace!("GDB: {}", String::from_utf8_lossy(&packet));
+            let reply = match packet.first() {
+                Some(b'c') | Some(b's') => {
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        ctx.psx.cpu_mut().set_pc(addr);
+                    }
+                    let stop = match packet[0] {
+                        b'c' => self.resume(ctx)?,
+                        _ => self.step(ctx)?.unwrap_or(Stop::Signal(SIGTRAP)),
+                    };
+                    ctx.flush_tty()?;
+                    match stop {
+                        Stop::WindowClosed => return Ok(SessionEnd::WindowClosed),
+                        stop => stop_reply(stop),
+                    }
+                },
+                Some(b'D') => {
+                    self.conn.send(b"OK")?;
+                    return Ok(SessionEnd::Detached);
+                },
+                Some(b'k') => return Ok(SessionEnd::Killed),
+                _ => self.handle(ctx, &packet).unwrap_or_else(|| "E01".to_string()),
+            };
+            self.conn.send(reply.as_bytes())?;
+        }
+    }
+
+    /// Reply to a packet that doesn't resume the target, `None` for an error
+    fn handle(&mut self, ctx: &mut Context, packet: &[u8]) -> Option<String> {
+        let (&command, args) = packet.split_first()?;
+        let reply = match command {
+            b'?' => stop_reply(Stop::Signal(SIGTRAP)),
+            b'g' => (0..REGISTER_COUNT).map(|idx| encode_hex(&read_register(ctx.psx.cpu(), idx).to_le_bytes())).collect(),
+            b'G' => {
+                let values = decode_hex(args)?;
+                for (idx, value) in values.array_chunks::<4>().enumerate().take(REGISTER_COUNT) {
+                    write_register(ctx, idx, u32::from_le_bytes(*value));
+                }
+                "OK".to_string()
+            },
+            b'p' => encode_hex(&read_register(ctx.psx.cpu(), parse_hex(args)? as usize).to_le_bytes()),
+            b'P' => {
+                let (idx, value) = split(args, b'=')?;
+                let value: [u8; 4] = decode_hex(value)?.try_into().ok()?;
+                write_register(ctx, parse_hex(idx)? as usize, u32::from_le_bytes(value));
+                "OK".to_string()
+            },
+            b'm' => {
+                let (addr, len) = split(args, b',')?;
+                let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+                if len > MAX_READ {
+                    return None;
+                }
+                let bytes = (0..len).map(|offset| ctx.psx.peek(addr.wrapping_add(offset))).collect::<Option<Vec<u8>>>()?;
+                encode_hex(&bytes)
+            },
+            b'M' => {
+                let (range, data) = split(args, b':')?;
+                let (addr, _) = split(range, b',')?;
+                self.write_memory(ctx, parse_hex(addr)?, &decode_hex(data)?)?
+            },
+            b'X' => {
+                let (range, data) = split(args, b':')?;
+                let (addr, _) = split(range, b',')?;
+                self.write_memory(ctx, parse_hex(addr)?, data)?
+            },
+            b'Z' | b'z' => self.set_breakpoint(ctx, command == b'Z', args)?,
+            b'H' | b'T' => "OK".to_string(),
+            b'q' | b'Q' => self.query(packet)?,
+            // vCont and everything else isn't supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &[u8]) -> Option<String> {
+        let query = std::str::from_utf8(packet).ok()?;
+        let reply = if query.starts_with("qSupported") {
+            format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+;QStartNoAckMode+")
+        } else if query == "QStartNoAckMode" {
+            self.conn.set_no_ack();
+            "OK".to_string()
+        } else if let Some(range) = query.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = range.split_once(',')?;
+            let (offset, len) = (usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(len, 16).ok()?);
+            let xml = target_xml();
+            let end = offset.saturating_add(len);
+            let chunk = xml.get(offset.min(xml.len())..end.min(xml.len()))?;
+            let more = end < xml.len();
+            format!("{}{chunk}", if more { 'm' } else { 'l' })
+        } else {
+            match query {
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            }
+        };
+        Some(reply)
+    }
+
+    fn write_memory(&mut self, ctx: &mut Context, addr: u32, data: &[u8]) -> Option<String> {
+        for (offset, &byte) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(offset as u32);
+            ctx.psx.poke(addr, byte).ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    /// `Z`/`z` packets: `<type>,<addr>,<kind or length>`
+    fn set_breakpoint(&mut self, ctx: &mut Context, insert: bool, args: &[u8]) -> Option<String> {
+        let mut fields = args.split(|&byte| byte == b',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let len = parse_hex(fields.next()?)?;
+        let watch = |kind| Watchpoint { kind, addr, len: len.max(1) };
+        let watchpoint = match kind {
+            b"0" | b"1" => {
+                match insert {
+                    true => self.breakpoints.insert(addr),
+                    false => self.breakpoints.remove(&addr),
+                };
+                return Some("OK".to_string());
+            },
+            b"2" => watch(WatchKind::Write),
+            b"3" => watch(WatchKind::Read),
+            b"4" => watch(WatchKind::Access),
+            _ => return Some(String::new()),
+        };
+        if insert {
+            let triggers = match watchpoint.kind {
+                WatchKind::Write => Triggers::WRITE,
+                WatchKind::Read => Triggers::READ,
+                WatchKind::Access => Triggers::ACCESS,
+            };
+            let id = ctx.psx.add_watch(addr, watchpoint.len, triggers, WatchAction::Break);
+            self.watchpoints.push((watchpoint, id));
+        } else if let Some(idx) = self.watchpoints.iter().position(|&(other, _)| other == watchpoint) {
+            ctx.psx.remove_watch(self.watchpoints.remove(idx).1);
+        }
+        Some("OK".to_string())
+    }
+
+    /// Run until a breakpoint, watchpoint, exception or interrupt
+    fn resume(&mut self, ctx: &mut Context) -> Result<Stop> {
+        // The breakpoint we may be sitting on doesn't count
+        if let Some(stop) = self.step(ctx)? {
+            return Ok(stop);
+        }
+        loop {
+            for _ in 0..POLL_STEPS {
+                if self.breakpoints.contains(&ctx.psx.cpu().pc()) {
+                    return Ok(Stop::Signal(SIGTRAP));
+                }
+                if let Some(stop) = self.step(ctx)? {
+                    return Ok(stop);
+                }
+            }
+            ctx.flush_tty()?;
+            if self.conn.poll_interrupt()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    /// Execute one instruction, returning why to stop if anything happened
+    fn step(&mut self, ctx: &mut Context) -> Result<Option<Stop>> {
+        let pc = ctx.psx.cpu().pc();
+        if !ctx.step()? {
+            return Ok(Some(Stop::WindowClosed));
+        }
+        for hit in ctx.psx.take_watch_breaks() {
+            if let Some(&(watchpoint, _)) = self.watchpoints.iter().find(|(_, id)| *id == hit.id) {
+                return Ok(Some(Stop::Watch(watchpoint)));
+            }
+        }
+        Ok(exception_signal(pc, ctx.psx.cpu()).map(Stop::Signal))
+    }
+}
+
+/// Signal reporting the exception the CPU just entered when executing `pc`, if any.
+/// Interrupts and system calls are business as usual.
+fn exception_signal(pc: u32, cpu: &Cpu) -> Option<u8> {
+    match cpu.entered_exception(pc)? {
+        4 | 5 => Some(SIGBUS),
+        9 => Some(SIGTRAP),
+        10 | 11 => Some(SIGILL),
+        12 => Some(SIGFPE),
+        _ => None,
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Watch(watch) => format!("T{SIGTRAP:02x}{}:{:08x};", watch.kind.reason(), watch.addr),
+        Stop::Signal(signal) => format!("S{signal:02x}"),
+        Stop::WindowClosed => unreachable!("the session ends instead"),
+    }
+}
+
+fn read_register(cpu: &Cpu, idx: usize) -> u32 {
+    match idx {
+        0..=31 => cpu.reg(RegisterIndex(idx as u32)),
+        REG_SR => cpu.status(),
+        REG_LO => cpu.lo(),
+        REG_HI => cpu.hi(),
+        REG_BAD_VADDR => cpu.bad_vaddr(),
+        REG_CAUSE => cpu.cause(),
+        REG_PC => cpu.pc(),
+        REG_EPC => cpu.epc(),
+        // No FPU
+        _ => 0,
+    }
+}
+
+fn write_register(ctx: &mut Context, idx: usize, value: u32) {
+    let cpu = ctx.psx.cpu_mut();
+    match idx {
+        0..=31 => cpu.write_reg(RegisterIndex(idx as u32), value),
+        REG_SR => cpu.set_status(value),
+        REG_LO => cpu.set_lo(value),
+        REG_HI => cpu.set_hi(value),
+        REG_BAD_VADDR => cpu.set_bad_vaddr(value),
+        REG_CAUSE => cpu.set_cause(value),
+        // Rewriting the same PC mustn't drop a pending branch
+        REG_PC if value != cpu.pc() => ctx.psx.cpu_mut().set_pc(value),
+        REG_EPC => cpu.set_epc(value),
+        _ => {},
+    }
+}
+
+/// `args` split at the first `separator`
+fn split(args: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let pos = args.iter().position(|&byte| byte == separator)?;
+    Some((&args[..pos], &args[pos + 1..]))
+}
+
+/// Target description, numbering the registers like GDB does for MIPS
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+        r#"<target version="1.0"><architecture>mips:3000</architecture>"#,
+        r#"<feature name="org.gnu.gdb.mips.cpu">"#,
+    ));
+    for idx in 0..32 {
+        reg(&mut xml, &format!("r{idx}"), idx, "int");
+    }
+    reg(&mut xml, "lo", REG_LO, "int");
+    reg(&mut xml, "hi", REG_HI, "int");
+    reg(&mut xml, "pc", REG_PC, "code_ptr");
+    xml.push_str(r#"</feature><feature name="org.gnu.gdb.mips.cp0">"#);
+    reg(&mut xml, "status", REG_SR, "int");
+    reg(&mut xml, "badvaddr", REG_BAD_VADDR, "data_ptr");
+    reg(&mut xml, "cause", REG_CAUSE, "int");
+    reg(&mut xml, "epc", REG_EPC, "code_ptr");
+    xml.push_str(r#"</feature><feature name="org.gnu.gdb.mips.fpu">"#);
+    for idx in 0..32 {
+        reg(&mut xml, &format!("f{idx}"), 38 + idx, "ieee_single");
+    }
+    reg(&mut xml, "fcsr", 70, "int");
+    reg(&mut xml, "fir", 71, "int");
+    xml.push_str("</feature></target>");
+    xml
+}
+
+/// Describe a 32 bit register in the target description
+fn reg(xml: &mut String, name: &str, regnum: usize, kind: &str) {
+    write!(xml, r#"<reg name="{name}" bitsize="32" regnum="{regnum}" type="{kind}"/>"#).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{Read, Write}, thread};
+
+    use super::*;
+    use crate::emu::{Psx, TEST_ORIGIN as ORIGIN};
+
+    /// Scripted GDB
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        /// Send `packet` and wait for the reply
+        fn request(&mut self, packet: &str) -> String {
+            let sum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${packet}#{sum:02x}").unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if reply.is_empty() => {},
+                    b'#' => break,
+                    b'$' => reply.clear(),
+                    byte => reply.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn debugs_over_rsp() {
+        let mut ctx = Context::new_hle().unwrap();
+        ctx.set_tty_output(Box::new(std::io::sink()));
+        *ctx.psx = Psx::boot_test_program("
+                li    t0, 5
+                la    t1, data
+                sw    t0, 0(t1)
+                lw    t2, 0(t1)
+            spin:
+                b     spin
+                nop
+            data:
+                .word 0
+        ");
+        ctx.psx.set_engine(Engine::CachedInterpreter).unwrap();
+
+        let listener = bind(0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut gdb = Client { stream: TcpStream::connect(addr).unwrap() };
+            assert!(gdb.request("qSupported:multiprocess+").contains("qXfer:features:read+"));
+            assert!(gdb.request("qXfer:features:read:target.xml:0,20").starts_with('m'));
+            assert_eq!(gdb.request("qXfer:features:read:target.xml:10,ffffffffffffffff").chars().next(), Some('l'));
+            assert_eq!(gdb.request("?"), "S05");
+
+            // Breakpoint on the store
+            assert_eq!(gdb.request("Z0,8001000c,4"), "OK");
+            assert_eq!(gdb.request("c"), "S05");
+            assert_eq!(gdb.request("p25"), "0c000180");
+            assert_eq!(gdb.request("p8"), "05000000");
+            assert_eq!(gdb.request("z0,8001000c,4"), "OK");
+
+            // Stops after the store to the watched word
+            assert_eq!(gdb.request("Z2,8001001c,4"), "OK");
+            assert_eq!(gdb.request("c"), "T05watch:8001001c;");
+            assert_eq!(gdb.request("m8001001c,4"), "05000000");
+            assert_eq!(gdb.request("M8001001c,4:78563412"), "OK");
+            // The load lands after its delay slot
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("p0a"), "78563412");
+            assert_eq!(gdb.request("g").len(), REGISTER_COUNT * 8);
+            assert_eq!(gdb.request("m10000000,4"), "E01");
+            assert_eq!(gdb.request("m80010000,ffffffff"), "E01");
+            assert_eq!(gdb.request("m80010000,2000").len(), 0x4000);
+            assert_eq!(gdb.request("Mbfc00000,1:00"), "E01");
+
+            // Interrupt the spin loop
+            assert_eq!(gdb.request("z2,8001001c,4"), "OK");
+            gdb.stream.write_all(b"$c#63").unwrap();
+            gdb.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(gdb.reply(), "S02");
+            assert_eq!(gdb.request("D"), "OK");
+        });
+        let end = serve(&mut ctx, &listener).unwrap();
+        client.join().unwrap();
+        assert_eq!(end, SessionEnd::Detached);
+        assert_eq!(ctx.psx.cpu().engine(), Engine::CachedInterpreter);
+        assert_eq!(ctx.psx.cpu().reg(RegisterIndex(10)), 0x1234_5678);
+    }
+}
@@ -0,0 +1,209 @@
+//! Framing of the remote serial protocol: `$<data>#<checksum>` packets, acknowledged
+//! with `+` (or `-` to ask for a resend) until no-ack mode is negotiated, and a bare
+//! 0x03 byte to interrupt the target
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+/// Interrupt request, sent by GDB on Ctrl-C
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    Packet(Vec<u8>),
+    Interrupt,
+}
+
+pub struct Connection {
+    stream: TcpStream,
+    /// Received bytes not consumed yet
+    buf: Vec<u8>,
+    /// Set once GDB agreed to stop acknowledging packets
+    no_ack: bool,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Connection { stream, buf: Vec::new(), no_ack: false })
+    }
+
+    pub fn set_no_ack(&mut self) {
+        self.no_ack = true;
+    }
+
+    /// Wait for the next packet or interrupt. `None` once GDB hung up.
+    pub fn read(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            if let Some(incoming) = self.next_incoming()? {
+                return Ok(Some(incoming));
+            }
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk)? {
+                0 => return Ok(None),
+                len => self.buf.extend(&chunk[..len]),
+            }
+        }
+    }
+
+    /// Whether GDB asked to interrupt the target, without waiting. Packets received in
+    /// the meantime are kept for [`Connection::read`].
+    pub fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut chunk = [0; 4096];
+        let result = self.stream.read(&mut chunk);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            // Hung up, stop so that the next read notices
+            Ok(0) => return Ok(true),
+            Ok(len) => self.buf.extend(&chunk[..len]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err),
+        }
+        if let Some(pos) = self.buf.iter().position(|&byte| byte == INTERRUPT) {
+            // Only count interrupts outside of packets
+            if !self.buf[..pos].contains(&b'$') {
+                self.buf.remove(pos);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&frame(data))
+    }
+
+    /// Take the next complete packet or interrupt out of the buffer, acknowledging it
+    fn next_incoming(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            let (consumed, parsed) = parse(&self.buf);
+            self.buf.drain(..consumed);
+            match parsed {
+                None => return Ok(None),
+                Some(Parsed::Interrupt) => return Ok(Some(Incoming::Interrupt)),
+                Some(Parsed::Packet(data)) => {
+                    if !self.no_ack {
+                        self.stream.write_all(b"+")?;
+                    }
+                    return Ok(Some(Incoming::Packet(data)));
+                },
+                Some(Parsed::Corrupt) => {
+                    tracing::warn!("GDB packet with a bad checksum");
+                    if !self.no_ack {
+                        self.stream.write_all(b"-")?;
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    Packet(Vec<u8>),
+    Interrupt,
+    /// Packet whose checksum doesn't match
+    Corrupt,
+}
+
+/// Parse the start of `buf`, returning how many bytes were used. Acknowledgements and
+/// noise before a packet are skipped.
+fn parse(buf: &[u8]) -> (usize, Option<Parsed>) {
+    let Some(start) = buf.iter().position(|&byte| byte == b'$' || byte == INTERRUPT) else {
+        return (buf.len(), None);
+    };
+    if buf[start] == INTERRUPT {
+        return (start + 1, Some(Parsed::Interrupt));
+    }
+    let Some(end) = buf[start..].iter().position(|&byte| byte == b'#').map(|end| start + end) else {
+        return (start, None);
+    };
+    if buf.len() < end + 3 {
+        return (start, None);
+    }
+    let body = &buf[start + 1..end];
+    let expected = std::str::from_utf8(&buf[end + 1..end + 3]).ok()
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    if expected != Some(checksum(body)) {
+        return (end + 3, Some(Parsed::Corrupt));
+    }
+    // `}` escapes the next byte, XORed with 0x20
+    let mut data = Vec::with_capacity(body.len());
+    let mut bytes = body.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => data.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            byte => data.push(byte),
+        }
+    }
+    (end + 3, Some(Parsed::Packet(data)))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+/// `data` as a packet, escaping the bytes with a meaning in the framing
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len());
+    for &byte in data {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => body.extend([b'}', byte ^ 0x20]),
+            byte => body.push(byte),
+        }
+    }
+    let mut packet = Vec::with_capacity(body.len() + 4);
+    packet.push(b'$');
+    packet.extend(&body);
+    packet.extend(format!("#{:02x}", checksum(&body)).bytes());
+    packet
+}
+
+/// Lowercase hex digits of `bytes`
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Big-endian hex number, as used for addresses and lengths
+pub fn parse_hex(hex: &[u8]) -> Option<u32> {
+    u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_frames_packets() {
+        let buf = b"++$m80010000,4#56\x03$bad#00$X0,1:}]#";
+        let (used, parsed) = parse(buf);
+        assert_eq!(parsed, Some(Parsed::Packet(b"m80010000,4".to_vec())));
+        let (used2, parsed) = parse(&buf[used..]);
+        assert_eq!(parsed, Some(Parsed::Interrupt));
+        let (used3, parsed) = parse(&buf[used + used2..]);
+        assert_eq!(parsed, Some(Parsed::Corrupt));
+        // Incomplete, waits for the checksum
+        let rest = &buf[used + used2 + used3..];
+        assert_eq!(parse(rest), (0, None));
+
+        let packet = frame(b"X0,1:}");
+        assert_eq!(packet, b"$X0,1:}]#f9");
+        assert_eq!(parse(&packet).1, Some(Parsed::Packet(b"X0,1:}".to_vec())));
+
+        assert_eq!(decode_hex(b"00ff10"), Some(vec![0, 0xff, 0x10]));
+        assert_eq!(encode_hex(&[0xde, 0xad]), "dead");
+        assert_eq!(parse_hex(b"bfc00180"), Some(0xbfc0_0180));
+    }
+}
@@ -0,0 +1,387 @@
+//! Differential testing against a golden trace
+//!
+//! `--golden <PATH>` runs the core in lock-step with a trace recorded on another emulator,
+//! and stops at the first instruction they disagree on. Each line of the trace is an
+//! instruction:
+//!
+//! ```text
+//! # PC, then registers and memory after the instruction
+//! 80010004 t1=80010000
+//! 80010008 t1=80010020
+//! 8001000c [80010020]=00000005
+//! ```
+//!
+//! The PC comes first, then any of the registers (`zero`..`ra`, `hi`, `lo`, `sr`, `cause`,
+//! `epc`) and memory (`[ADDR]=VALUE`, the number of digits giving the size) as they are
+//! once the instruction retired, load delay included. Only what a line lists is checked,
+//! so the whole register file catches the most. Comparing starts once the CPU reaches the
+//! PC of the first line.
+//!
+//! The output of `--trace` (see [`crate::emu::trace`]) is a golden trace too: the cycle,
+//! word and disassembly columns are skipped, and the memory accesses (`r[ADDR]=VALUE`,
+//! `w[ADDR]=VALUE`) are checked like `[ADDR]=VALUE`.
+//!
+//! Memory is only checked in RAM, the scratchpad and the BIOS: reading back a device
+//! register doesn't give what was stored to it, and may have side effects.
+
+use std::{fmt, io::BufRead};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    emu::{
+        cpu::{disasm::disassemble, instruction::Instruction, Engine},
+        trace::Registers,
+        map::{self, Region},
+        Psx,
+    },
+    runner::Outcome,
+};
+
+/// Instructions to run at most before the CPU reaches the first PC of the trace
+const SYNC_INSTRUCTIONS: u64 = 500_000_000;
+
+/// One line of a golden trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoldenStep {
+    /// 1-based, for reports
+    pub line: usize,
+    pub pc: u32,
+    pub registers: Vec<(String, u32)>,
+    /// Address, value and size in bytes
+    pub memory: Vec<(u32, u32, u32)>,
+}
+
+impl GoldenStep {
+    /// Parse line number `line`, `None` for blank lines and comments
+    pub fn parse(line: usize, text: &str) -> Result<Option<GoldenStep>> {
+        let text = text.split('#').next().unwrap_or("").trim();
+        let mut tokens = text.split_whitespace();
+        let Some(first) = tokens.next() else {
+            return Ok(None);
+        };
+        // Lines of `--trace` start with the cycle in decimal
+        let is_trace = first.len() == 10 && first.bytes().all(|byte| byte.is_ascii_digit());
+        let pc = match is_trace {
+            true => tokens.next().ok_or_else(|| anyhow!("line {line}: expected the PC after the cycle"))?,
+            false => first,
+        };
+        let mut step = GoldenStep { line, pc: parse_hex(line, pc)?, registers: Vec::new(), memory: Vec::new() };
+        for token in tokens {
+            // The word and the disassembly
+            if is_trace && !token.contains('=') {
+                continue;
+            }
+            let (name, value) = token.split_once('=')
+                .ok_or_else(|| anyhow!("line {line}: expected NAME=VALUE or [ADDR]=VALUE, got {token:?}"))?;
+            if value.is_empty() || value.len() > 8 {
+                bail!("line {line}: bad value in {token:?}");
+            }
+            let parsed = parse_hex(line, value)?;
+            let access = ["[", "r[", "w["].iter().find_map(|prefix| name.strip_prefix(prefix));
+            match access.and_then(|addr| addr.strip_suffix(']')) {
+                Some(addr) => {
+                    let len = match value.len() {
+                        2 => 1,
+                        4 => 2,
+                        8 => 4,
+                        _ => bail!("line {line}: memory values are 2, 4 or 8 digits, got {token:?}"),
+                    };
+                    step.memory.push((parse_hex(line, addr)?, parsed, len));
+                },
+                None => {
+                    if Registers::default().get(name).is_none() {
+                        bail!("line {line}: unknown register {name:?}");
+                    }
+                    step.registers.push((name.to_string(), parsed));
+                },
+            }
+        }
+        Ok(Some(step))
+    }
+}
+
+fn parse_hex(line: usize, text: &str) -> Result<u32> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u32::from_str_radix(digits, 16).map_err(|e| anyhow!("line {line}: bad hex number {text:?}: {e}"))
+}
+
+/// An instruction the core ran against a line of the trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retired {
+    pub line: usize,
+    /// Instructions retired before it since power on
+    pub instruction: u64,
+    pub pc: u32,
+    pub inst: Instruction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The CPU went elsewhere after the instruction
+    Pc { expected: u32, actual: u32 },
+    Register { name: String, expected: u32, actual: u32 },
+    /// `actual` is `None` if nothing is mapped there
+    Memory { addr: u32, len: u32, expected: u32, actual: Option<u32> },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Pc { expected, actual } => write!(f, "next pc is {actual:08x}, expected {expected:08x}"),
+            Mismatch::Register { name, expected, actual } => write!(f, "{name} is {actual:08x}, expected {expected:08x}"),
+            Mismatch::Memory { addr, len, expected, actual } => {
+                let width = *len as usize * 2;
+                match actual {
+                    Some(actual) => write!(f, "[{addr:08x}] is {actual:0width$x}, expected {expected:0width$x}"),
+                    None => write!(f, "[{addr:08x}] is unmapped, expected {expected:0width$x}"),
+                }
+            },
+        }
+    }
+}
+
+/// The first instruction the core and the trace disagree on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub retired: Retired,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Retired { line, instruction, pc, inst } = self.retired;
+        write!(f, "diverged at line {line} (instruction {instruction}): {pc:08x} {:08x} {}", inst.0, disassemble(inst, pc))?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n  {mismatch}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    /// The whole trace matched, this many instructions
+    Matched(u64),
+    Diverged(Divergence),
+    /// The CPU never reached the first PC of the trace
+    NotReached(u32),
+}
+
+impl Comparison {
+    pub fn outcome(&self) -> Outcome {
+        match self {
+            Comparison::Matched(count) => Outcome::Success(format!("{count} instructions matched the golden trace")),
+            Comparison::Diverged(divergence) => Outcome::Failure(divergence.to_string()),
+            Comparison::NotReached(pc) => Outcome::Timeout(format!(
+                "the CPU didn't reach {pc:08x}, where the golden trace starts, within {SYNC_INSTRUCTIONS} instructions")),
+        }
+    }
+}
+
+/// Run `psx` in lock-step with the golden trace read from `trace`, on the interpreter
+pub fn compare(psx: &mut Psx, trace: impl BufRead) -> Result<Comparison> {
+    if psx.cpu().engine() != Engine::Interpreter {
+        tracing::info!("switching to the interpreter to compare with the golden trace");
+        psx.set_engine(Engine::Interpreter)?;
+    }
+    let mut steps = trace.lines().enumerate().filter_map(|(idx, text)| match text {
+        Ok(text) => GoldenStep::parse(idx + 1, &text).transpose(),
+        Err(err) => Some(Err(err.into())),
+    });
+    let Some(first) = steps.next().transpose()? else {
+        bail!("the golden trace is empty");
+    };
+
+    let mut synced = 0;
+    while psx.cpu().pc() != first.pc {
+        if synced == SYNC_INSTRUCTIONS {
+            return Ok(Comparison::NotReached(first.pc));
+        }
+        psx.step();
+        synced += 1;
+    }
+
+    let mut previous: Option<Retired> = None;
+    let mut matched = 0;
+    for step in std::iter::once(Ok(first)).chain(steps) {
+        let step = step?;
+        let pc = psx.cpu().pc();
+        if pc != step.pc {
+            // Synced on the first line, so there is one
+            let retired = previous.unwrap();
+            return Ok(Comparison::Diverged(Divergence {
+                retired,
+                mismatches: vec![Mismatch::Pc { expected: step.pc, actual: pc }],
+            }));
+        }
+        let retired = Retired {
+            line: step.line,
+            instruction: psx.instructions_retired,
+            pc,
+            inst: Instruction(psx.bus().peek(pc)),
+        };
+        psx.step();
+        let mismatches = check(psx, &step);
+        if !mismatches.is_empty() {
+            return Ok(Comparison::Diverged(Divergence { retired, mismatches }));
+        }
+        previous = Some(retired);
+        matched += 1;
+    }
+    Ok(Comparison::Matched(matched))
+}
+
+/// What differs from `step` after running its instruction
+fn check(psx: &Psx, step: &GoldenStep) -> Vec<Mismatch> {
+    let registers = Registers::of(psx.cpu());
+    let mut mismatches = Vec::new();
+    for (name, expected) in &step.registers {
+        let actual = registers.get(name).unwrap();
+        if actual != *expected {
+            mismatches.push(Mismatch::Register { name: name.clone(), expected: *expected, actual });
+        }
+    }
+    for &(addr, expected, len) in &step.memory {
+        if let Some(region) = map::find_region(map::mask_region(addr))
+            && !matches!(region, Region::Ram(_) | Region::Scratchpad(_) | Region::Bios(_))
+        {
+            continue;
+        }
+        let actual = (0..len)
+            .map(|offset| psx.peek(addr.wrapping_add(offset)))
+            .try_rfold(0, |value, byte| Some(value << 8 | byte? as u32));
+        if actual != Some(expected) {
+            mismatches.push(Mismatch::Memory { addr, len, expected, actual });
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{trace::{TestBuffer, TraceFilter}, TEST_ORIGIN as ORIGIN};
+
+    const GOLDEN: &str = "
+        # Stores 5 and loads it back
+        80010000 t0=00000005
+        80010004 t1=80010000
+        80010008 t1=80010020
+        8001000c [80010020]=00000005 [80010022]=0000
+        80010010
+        80010014 t2=00000005   # after the load delay slot
+        80010018
+        8001001c
+        80010018
+    ";
+
+    const PROGRAM: &str = "
+            li    t0, 5
+            la    t1, data
+            sw    t0, 0(t1)
+            lw    t2, 0(t1)
+            nop
+        spin:
+            b     spin
+            nop
+        data:
+            .word 0
+    ";
+
+    fn compare_with(golden: &str) -> Comparison {
+        let mut psx = Psx::boot_test_program(PROGRAM);
+        compare(&mut psx, golden.as_bytes()).unwrap()
+    }
+
+    /// `--trace` output of the first `count` instructions of `program`
+    fn trace_of(program: &str, count: u64) -> String {
+        let mut psx = Psx::boot_test_program(program);
+        while psx.cpu().pc() != ORIGIN {
+            psx.step();
+        }
+        let buffer = TestBuffer::default();
+        let start = psx.instructions_retired;
+        let filter = TraceFilter { pcs: None, instructions: Some(start..start + count) };
+        psx.start_trace(Box::new(buffer.clone()), filter).unwrap();
+        for _ in 0..count {
+            psx.step();
+        }
+        psx.stop_trace().unwrap();
+        String::from_utf8(buffer.0.take()).unwrap()
+    }
+
+    #[test]
+    fn finds_divergences() {
+        assert_eq!(compare_with(GOLDEN), Comparison::Matched(9));
+
+        let Comparison::Diverged(divergence) = compare_with(&GOLDEN.replace("t2=00000005", "t2=00000006 t0=00000005 hi=00000001")) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.retired.line, 8);
+        assert_eq!(divergence.to_string().split_once("): ").unwrap().1, "\
+80010014 00000000 nop
+  t2 is 00000005, expected 00000006
+  hi is 00000000, expected 00000001");
+
+        let Comparison::Diverged(divergence) = compare_with(&format!("{GOLDEN}80010020\n")) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.mismatches, [Mismatch::Pc { expected: 0x8001_0020, actual: 0x8001_001c }]);
+        assert_eq!(divergence.retired.pc, 0x8001_0018);
+
+        let Comparison::Diverged(divergence) = compare_with(&GOLDEN.replace("[80010022]=0000", "[80010021]=05 [10000000]=00")) else {
+            panic!("expected a divergence");
+        };
+        let mismatches: Vec<_> = divergence.mismatches.iter().map(Mismatch::to_string).collect();
+        assert_eq!(mismatches, ["[80010021] is 00, expected 05", "[10000000] is unmapped, expected 00"]);
+    }
+
+    #[test]
+    fn compares_with_execution_traces() {
+        let trace = trace_of(PROGRAM, 12);
+        assert!(trace.contains(" w[80010020]=00000005") && trace.contains(" r[80010020]=00000005"), "{trace}");
+        assert_eq!(compare_with(&trace), Comparison::Matched(12));
+
+        let Comparison::Diverged(divergence) = compare_with(&trace.replace("t2=00000005", "t2=00000006")) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.mismatches, [Mismatch::Register { name: "t2".into(), expected: 6, actual: 5 }]);
+        assert_eq!(GoldenStep::parse(1, "0000000508 8001000c ad280000 sw      t0, 0x0(t1)  w[80010020]=00000005").unwrap(),
+            Some(GoldenStep { line: 1, pc: 0x8001_000c, registers: vec![], memory: vec![(0x8001_0020, 5, 4)] }));
+    }
+
+    #[test]
+    fn skips_device_accesses() {
+        // GPUSTAT reads back instead of the GP1 command
+        let program = "
+                li    t0, 0x1f801814
+                li    t1, 0x08000001
+                sw    t1, 0(t0)
+                lw    t2, 0(t0)
+                nop
+            spin:
+                b     spin
+                nop
+        ";
+        let trace = trace_of(program, 10);
+        assert!(trace.contains(" w[1f801814]=08000001"), "{trace}");
+        let mut psx = Psx::boot_test_program(program);
+        assert_eq!(compare(&mut psx, trace.as_bytes()).unwrap(), Comparison::Matched(10));
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(GoldenStep::parse(1, "  # comment").unwrap(), None);
+        assert_eq!(GoldenStep::parse(2, "0x80010000 ra=80010008 [80010020]=0005").unwrap(), Some(GoldenStep {
+            line: 2,
+            pc: 0x8001_0000,
+            registers: vec![("ra".into(), 0x8001_0008)],
+            memory: vec![(0x8001_0020, 5, 2)],
+        }));
+        assert!(GoldenStep::parse(3, "80010000 r99=0").unwrap_err().to_string().contains("unknown register"));
+        assert!(GoldenStep::parse(4, "80010000 [80010020]=005").is_err());
+        assert!(GoldenStep::parse(5, "80010000 t0").is_err());
+        assert!(GoldenStep::parse(6, "pc").is_err());
+    }
+}
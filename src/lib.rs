@@ -4,56 +4,323 @@
 
 #[macro_use] extern crate byte_unit;
 
-use std::{path::Path, fs::File, io::Read};
+use std::{path::{Path, PathBuf}, fs::{self, File}, io::{self, Read, Write}};
 
 use tracing_subscriber::{reload, filter, Registry, prelude::*};
 pub static mut TRACING_RELOAD_HANDLE: Option<reload::Handle<filter::LevelFilter, Registry>> = None;
 
 pub fn set_log_level(filter_level: filter::LevelFilter) {
-    unsafe { 
-        let _ = TRACING_RELOAD_HANDLE.as_ref().unwrap().modify(|filter| *filter = filter_level);
+    // Not set up in tests
+    if let Some(handle) = unsafe { TRACING_RELOAD_HANDLE.as_ref() } {
+        let _ = handle.modify(|filter| *filter = filter_level);
     }
 }
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 
+pub mod audio;
+pub mod bench;
 pub mod config;
+pub mod debugger;
 pub mod emu;
-//pub mod sdl;
+pub mod gdb;
+pub mod golden;
+pub mod runner;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+
+/// Steps between writing out the TTY output
+const TTY_FLUSH_STEPS: u32 = 0x1_0000;
+
+/// Number of save state slots
+pub const STATE_SLOTS: u8 = 10;
+
+/// Video frames the rewind hotkey goes back
+#[cfg(feature = "sdl")]
+const REWIND_HOTKEY_FRAMES: u64 = 60;
 
 /// The interactive context of the PSX-RS emulator. Contains the emulation core and the frontend context
 pub struct Context {
     pub psx: Box<emu::Psx>,
-    //pub sdl: Option<sdl::SdlFrontend>,
+    /// Where the TTY output of the expansion port DUART goes
+    tty: Box<dyn Write>,
+    /// Directory of the save state slots
+    state_dir: PathBuf,
+    /// Name of the slot files, e.g. the disc ID
+    state_name: String,
+    rewind: Option<emu::rewind::Rewind>,
+    /// Slot the save and load hotkeys use
+    slot: u8,
+    /// Where the screenshot hotkey writes to
+    screenshot_dir: PathBuf,
+    paused: bool,
+    /// Run unthrottled
+    fast_forward: bool,
+    /// Pause again at the end of the frame
+    frame_advance: bool,
+    /// Frame presented last
+    last_frame: u64,
+    /// Window showing the frames, `None` when running headless
+    #[cfg(feature = "sdl")]
+    pub sdl: Option<sdl::SdlFrontend>,
 }
 
 impl Context {
     pub fn new(bios_path: &Path) -> Result<Context> {
         let bios = read_bios_file(bios_path)?;
         let psx = emu::Psx::new_from_bios(&bios);
-        
-        //let sdl = sdl::SdlFrontend::new()?;
+        match psx.bus().bios().identification() {
+            emu::bios::Identification::Known(info) => tracing::info!(
+                "BIOS {}: {} v{} ({}, {})", bios_path.display(), info.models, info.version, info.date, info.region),
+            emu::bios::Identification::Unknown { date } => tracing::warn!(
+                "BIOS {}: unknown version (kernel dated {date:08x}), it may be modified or a bad dump", bios_path.display()),
+            emu::bios::Identification::Corrupt => tracing::warn!(
+                "BIOS {}: not a PlayStation BIOS, or a corrupt dump", bios_path.display()),
+        }
 
         Ok( Context {
             psx: Box::new(psx),
-            //sdl: None, //Some(sdl)
+            tty: Box::new(io::stdout()),
+            state_dir: PathBuf::from("states"),
+            state_name: "bios".to_string(),
+            rewind: None,
+            slot: 0,
+            screenshot_dir: PathBuf::from("screenshots"),
+            paused: false,
+            fast_forward: false,
+            frame_advance: false,
+            last_frame: 0,
+            #[cfg(feature = "sdl")]
+            sdl: None,
+        })
+    }
+
+    /// Run on the high-level BIOS, no BIOS dump needed
+    pub fn new_hle() -> Result<Context> {
+        let psx = emu::Psx::new_hle(None);
+
+        Ok(Context {
+            psx: Box::new(psx),
+            tty: Box::new(io::stdout()),
+            state_dir: PathBuf::from("states"),
+            state_name: "hle".to_string(),
+            rewind: None,
+            slot: 0,
+            screenshot_dir: PathBuf::from("screenshots"),
+            paused: false,
+            fast_forward: false,
+            frame_advance: false,
+            last_frame: 0,
+            #[cfg(feature = "sdl")]
+            sdl: None,
         })
+    }
+
+    /// Show the frames in a window instead of running headless, set up as in `config`
+    #[cfg(feature = "sdl")]
+    pub fn open_window(&mut self, config: &config::Config) -> Result<()> {
+        self.sdl = Some(sdl::SdlFrontend::new(config)?);
+        Ok(())
+    }
 
-        // add another comment
+    /// Send the TTY output to `tty` instead of stdout
+    pub fn set_tty_output(&mut self, tty: Box<dyn Write>) {
+        self.tty = tty;
+    }
+
+    /// Keep the save state slots in `dir`, as `<name>.ss<slot>`
+    pub fn set_state_location(&mut self, dir: PathBuf, name: String) {
+        self.state_dir = dir;
+        self.state_name = name;
+    }
+
+    pub fn slot_path(&self, slot: u8) -> PathBuf {
+        self.state_dir.join(format!("{}.ss{slot}", self.state_name))
+    }
+
+    pub fn save_slot(&self, slot: u8) -> Result<()> {
+        if slot >= STATE_SLOTS {
+            bail!("no save state slot {slot}");
+        }
+        let path = self.slot_path(slot);
+        fs::create_dir_all(&self.state_dir)?;
+        fs::write(&path, self.psx.save_state())
+            .map_err(|e| anyhow!("failed to write save state {}: {e}", path.display()))?;
+        tracing::info!("saved state to slot {slot} ({})", path.display());
+        Ok(())
+    }
+
+    /// Load the state in `slot`, which the save and load hotkeys then use
+    pub fn load_slot(&mut self, slot: u8) -> Result<()> {
+        if slot >= STATE_SLOTS {
+            bail!("no save state slot {slot}");
+        }
+        let path = self.slot_path(slot);
+        let state = fs::read(&path)
+            .map_err(|e| anyhow!("failed to read save state {}: {e}", path.display()))?;
+        self.psx.load_state(&state)
+            .map_err(|e| anyhow!("failed to load save state {}: {e}", path.display()))?;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        tracing::info!("loaded state from slot {slot} ({})", path.display());
+        self.slot = slot;
+        Ok(())
     }
 
+    /// Keep snapshots to rewind to while running
+    pub fn enable_rewind(&mut self, config: emu::rewind::RewindConfig) {
+        self.rewind = Some(emu::rewind::Rewind::new(config));
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&emu::rewind::Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Go back about `frames` video frames
+    pub fn rewind(&mut self, frames: u64) -> Result<()> {
+        let Some(rewind) = &mut self.rewind else {
+            bail!("rewind is not enabled");
+        };
+        let instructions = frames * emu::gpu::frame_instructions(self.psx.video_standard());
+        let reached = rewind.step_back(&mut self.psx, instructions)?;
+        tracing::info!("rewound to instruction {reached}");
+        Ok(())
+    }
+
+    /// Run until the window is closed, or forever when headless
     pub fn run(&mut self) -> Result<()> {
         loop {
-            tracing::trace!("=== Instruction {:2} issued ===", self.psx.instructions_retired + 1);
-            self.psx.step();
+            if self.paused {
+                // Keep the window alive until resumed
+                if !self.present_frame()? {
+                    return self.flush_tty();
+                }
+                continue;
+            }
+            for _ in 0..TTY_FLUSH_STEPS {
+                if !self.step()? {
+                    return self.flush_tty();
+                }
+                if self.paused {
+                    break;
+                }
+            }
+            self.flush_tty()?;
+        }
+    }
+
+    /// Execute the next instruction (or block), and present the frame if one just ended.
+    /// Returns false once the window was closed.
+    pub fn step(&mut self) -> Result<bool> {
+        tracing::trace!("=== Instruction {:2} issued ===", self.psx.instructions_retired + 1);
+        self.psx.step();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.record(&self.psx);
+        }
+        if self.psx.frames() != self.last_frame {
+            self.last_frame = self.psx.frames();
+            return self.present_frame();
+        }
+        Ok(true)
+    }
+
+    /// Show the frame that just ended (or the last one, while paused), queue its audio
+    /// and handle the window events. Returns false once the window was closed.
+    fn present_frame(&mut self) -> Result<bool> {
+        if self.frame_advance {
+            self.frame_advance = false;
+            self.paused = true;
+        }
+        #[cfg(feature = "sdl")]
+        if let Some(sdl) = &mut self.sdl {
+            sdl.present(&self.psx.frame())?;
+            sdl.set_paused(self.paused);
+            sdl.queue_audio(&self.psx.take_audio());
+            if !self.fast_forward {
+                let fps = emu::gpu::frame_rate(self.psx.bus().gpu().video_standard());
+                sdl.throttle(std::time::Duration::from_secs(1) / fps);
+            }
+            let actions = sdl.poll();
+            self.psx.set_pads(sdl.pads());
+            return Ok(self.handle_actions(actions));
+        }
+        // Nobody listens when headless
+        self.psx.take_audio();
+        Ok(true)
+    }
+
+    /// Act on the hotkeys. Returns false if the user asked to quit.
+    #[cfg(feature = "sdl")]
+    fn handle_actions(&mut self, actions: Vec<sdl::Action>) -> bool {
+        use config::input::Hotkey;
+        for action in actions {
+            let result = match action {
+                sdl::Action::Quit => return false,
+                sdl::Action::Press(Hotkey::SaveState) => self.save_slot(self.slot),
+                sdl::Action::Press(Hotkey::LoadState) => self.load_slot(self.slot),
+                sdl::Action::Press(Hotkey::Pause) => {
+                    self.paused = !self.paused;
+                    tracing::info!("{}", if self.paused { "paused" } else { "resumed" });
+                    Ok(())
+                },
+                sdl::Action::Press(Hotkey::FastForward) => {
+                    self.fast_forward = true;
+                    Ok(())
+                },
+                sdl::Action::Release(Hotkey::FastForward) => {
+                    self.fast_forward = false;
+                    Ok(())
+                },
+                sdl::Action::Press(Hotkey::FrameAdvance) => {
+                    // Run to the end of the next frame when paused, stop right away if not
+                    self.frame_advance = self.paused;
+                    self.paused = !self.paused;
+                    Ok(())
+                },
+                sdl::Action::Press(Hotkey::Screenshot) => self.screenshot(),
+                sdl::Action::Press(Hotkey::Rewind) => self.rewind(REWIND_HOTKEY_FRAMES),
+                sdl::Action::Release(_) => Ok(()),
+            };
+            // A missing slot file and the like shouldn't end the session
+            if let Err(err) = result {
+                tracing::warn!("{err}");
+            }
+        }
+        true
+    }
+
+    /// Write the current frame to the screenshot directory, as `<name>-<n>.bmp`
+    #[cfg(feature = "sdl")]
+    fn screenshot(&self) -> Result<()> {
+        let Some(sdl) = &self.sdl else { return Ok(()) };
+        fs::create_dir_all(&self.screenshot_dir)?;
+        let path = (0..)
+            .map(|idx| self.screenshot_dir.join(format!("{}-{idx:03}.bmp", self.state_name)))
+            .find(|path| !path.exists())
+            .unwrap();
+        sdl.screenshot(&self.psx.frame(), &path)?;
+        tracing::info!("saved screenshot {}", path.display());
+        Ok(())
+    }
+
+    /// Write out the TTY output produced so far
+    pub fn flush_tty(&mut self) -> Result<()> {
+        let output = self.psx.take_tty();
+        if !output.is_empty() {
+            self.tty.write_all(&output)?;
+            self.tty.flush()?;
         }
+        Ok(())
     }
 }
 
 fn read_bios_file(path: &Path) -> Result<[u8; emu::bios::BIOS_SIZE]>{
-    let mut file = File::open(path)?;
-    let mut buf = [0u8; emu::bios::BIOS_SIZE as usize];
-    file.read_exact(&mut buf)?;
+    let mut file = File::open(path)
+        .map_err(|e| anyhow!("failed to open BIOS {}: {e} (set bios in the config file, use --bios, or --hle)", path.display()))?;
+    let mut buf = [0u8; emu::bios::BIOS_SIZE];
+    file.read_exact(&mut buf)
+        .map_err(|e| anyhow!("failed to read BIOS {}: {e}, dumps are 512 KiB", path.display()))?;
 
     Ok(buf)
 }
@@ -1,5 +1,3 @@
-use std::path::Path;
-
 use tracing_subscriber::{fmt::format, filter, reload, prelude::*};
 use anyhow::Result;
 
@@ -8,19 +6,139 @@ use psx_rs::{
     TRACING_RELOAD_HANDLE,
     Context,
     emu::{
-        bios::Bios,
+        bios::{patch::PatchKind, Bios},
+        disc::Disc,
         cpu::{Cpu, self},
+        exe::{self, ExeBoot},
+        gpu,
+        rewind::RewindConfig,
         Psx,
     },
     config::{self, Config},
+    runner::HeadlessRun,
+    gdb::{self, SessionEnd},
+    golden,
+    debugger::Debugger,
 };
 
 fn main() -> Result<()> {
-    let config = config::Config::parse_args();
+    let config = config::Config::parse_args()?;
     setup_trace(&config);
 
     let start = std::time::SystemTime::now();
-    let mut ctx = Context::new(Path::new("./scph1001.bin"))?;
+    let mut ctx = match config.hle {
+        true => Context::new_hle()?,
+        false => Context::new(&config.bios)?,
+    };
+    for patch in &config.patches {
+        let kind = match patch {
+            config::BiosPatch::Tty => PatchKind::Tty,
+            config::BiosPatch::SkipIntro => PatchKind::SkipIntro,
+        };
+        if let Err(err) = ctx.psx.patch_bios(kind) {
+            tracing::warn!("{err}");
+        }
+    }
+    if let Some(path) = &config.exp_rom {
+        let rom = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("failed to read expansion ROM {}: {e}", path.display()))?;
+        ctx.psx.insert_expansion_rom(rom)?;
+    }
+    if let Some(path) = &config.tty {
+        let file = std::fs::File::create(path)
+            .map_err(|e| anyhow::anyhow!("failed to create TTY output {}: {e}", path.display()))?;
+        ctx.set_tty_output(Box::new(file));
+    }
+    let mut state_name = if config.hle { "hle" } else { "bios" }.to_string();
+    if let Some(path) = &config.disc {
+        let disc = Disc::open(path)?;
+        if let Some(id) = disc.id() {
+            state_name = id.to_string();
+        }
+        ctx.psx.insert_disc(disc);
+    }
+    if let Some(region) = config.region {
+        ctx.psx.set_region(region);
+    }
+    tracing::info!("video standard: {:?}", ctx.psx.video_standard());
+    for card in config.memory_cards.iter().flatten() {
+        tracing::warn!("memory card {} ignored, memory cards aren't emulated yet", card.display());
+    }
+    ctx.psx.set_engine(match config.engine {
+        config::Engine::Interpreter => cpu::Engine::Interpreter,
+        config::Engine::Cached => cpu::Engine::CachedInterpreter,
+        #[cfg(feature = "recompiler")]
+        config::Engine::Recompiler => cpu::Engine::Recompiler,
+    })?;
+    #[cfg(feature = "recompiler")]
+    if config.lockstep {
+        ctx.psx.enable_lockstep();
+    }
+    if let Some(path) = &config.exe {
+        let (image, symbols) = exe::load_executable(path)?;
+        let boot = if config.fast_boot { ExeBoot::Fast } else { ExeBoot::Intercept };
+        ctx.psx.set_symbols(symbols);
+        ctx.psx.sideload(image, boot);
+        if let Some(stem) = path.file_stem() {
+            state_name = stem.to_string_lossy().into_owned();
+        }
+    }
+    ctx.set_state_location(config.state_dir.clone(), state_name);
+    if let Some(slot) = config.load_slot {
+        ctx.load_slot(slot)?;
+    }
+    if let Some(depth) = config.rewind_depth {
+        ctx.enable_rewind(RewindConfig {
+            interval: config.rewind_interval * gpu::frame_instructions(ctx.psx.video_standard()),
+            depth,
+            memory_cap: config.rewind_memory * 1024 * 1024,
+        });
+    }
+    if let Some(path) = &config.trace {
+        let file = std::fs::File::create(path)
+            .map_err(|e| anyhow::anyhow!("failed to create trace {}: {e}", path.display()))?;
+        ctx.psx.start_trace(Box::new(std::io::BufWriter::new(file)), config.trace_filter.clone())?;
+    }
+    if let Some(path) = &config.golden {
+        let file = std::fs::File::open(path)
+            .map_err(|e| anyhow::anyhow!("failed to open golden trace {}: {e}", path.display()))?;
+        let outcome = golden::compare(&mut ctx.psx, std::io::BufReader::new(file))?.outcome();
+        println!("{outcome}");
+        ctx.flush_tty()?;
+        ctx.psx.stop_trace()?;
+        std::process::exit(outcome.exit_code());
+    }
+    if let Some(instructions) = config.bench {
+        psx_rs::bench::run(&mut ctx.psx, instructions);
+        return Ok(());
+    }
+    if !config.exit.is_empty() {
+        let mut run = HeadlessRun::new(config.exit.clone());
+        let outcome = run.run(&mut ctx);
+        println!("{outcome}");
+        ctx.flush_tty()?;
+        run.write_artifacts(&ctx, &config.artifacts)?;
+        ctx.psx.stop_trace()?;
+        std::process::exit(outcome.exit_code());
+    }
+    #[cfg(feature = "sdl")]
+    if !config.headless {
+        ctx.open_window(&config)?;
+    }
+    if config.debug {
+        let mut debugger = Debugger::new(level_filter(config.log_level));
+        debugger.repl(&mut ctx, std::io::stdin().lock(), &mut std::io::stdout())?;
+        return ctx.psx.stop_trace();
+    }
+    if let Some(port) = config.gdb {
+        let listener = gdb::bind(port)?;
+        println!("Waiting for GDB on {}", listener.local_addr()?);
+        match gdb::serve(&mut ctx, &listener)? {
+            SessionEnd::Detached => tracing::info!("GDB detached, running on"),
+            SessionEnd::Killed | SessionEnd::WindowClosed => return ctx.psx.stop_trace(),
+        }
+    }
+
     let any_error = ctx.run();
     let done = start.elapsed()?.as_millis();
     println!("Elapsed time: {done}");
@@ -30,25 +148,30 @@ fn main() -> Result<()> {
     }
 
     println!("Total instructions processed: {}", ctx.psx.instructions_retired);
+    ctx.psx.stop_trace()?;
 
     Ok(())
 }
 
-fn setup_trace(config: &Config) {
-    let filter = match config.log_level {
+fn level_filter(level: config::LogLevel) -> filter::LevelFilter {
+    match level {
         config::LogLevel::Trace => filter::LevelFilter::TRACE,
         config::LogLevel::Debug => filter::LevelFilter::DEBUG,
         config::LogLevel::Info  => filter::LevelFilter::INFO,
         config::LogLevel::Warn  => filter::LevelFilter::WARN,
         config::LogLevel::Error => filter::LevelFilter::ERROR,
-    };
+    }
+}
+
+fn setup_trace(config: &Config) {
+    let filter = level_filter(config.log_level);
     let (filter, reload_handle) = reload::Layer::new(filter);
     let time_format = time::macros::format_description!(
         "[hour]:[minute]:[second].[subsecond digits:5]"
     );
     let time_offset = time::UtcOffset::current_local_offset()
-        .unwrap_or_else(|_| time::UtcOffset::UTC);
-    let timer = tracing_subscriber::fmt::time::OffsetTime::new(time_offset, time_format);
+        .unwrap_or(time::UtcOffset::UTC);
+    let _timer = tracing_subscriber::fmt::time::OffsetTime::new(time_offset, time_format);
     let formatted_layer = tracing_subscriber::fmt::layer()
         .event_format(format().compact())
         //.with_timer(timer);
@@ -0,0 +1,278 @@
+//! Headless runs for CI
+//!
+//! Runs the core with no window and no audio until an exit condition is met, then writes
+//! the requested artifacts. The process exit code tells how the run ended.
+
+use std::{fmt, fs, io::Write, panic::{self, AssertUnwindSafe}, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    emu::{gpu::{Frame, INSTRUCTIONS_PER_SECOND}, hle::Hle},
+    Context,
+};
+
+pub const EXIT_SUCCESS: i32 = 0;
+/// The emulator failed, or the program exited with a non-zero code
+pub const EXIT_FAILURE: i32 = 1;
+/// A limit ran out before the awaited PC or TTY output
+pub const EXIT_TIMEOUT: i32 = 2;
+
+/// Steps between looks at the TTY output
+const TTY_CHECK_STEPS: u32 = 0x1000;
+
+/// When to stop. The limits count from the start of the run, e.g. after loading a state.
+#[derive(Debug, Clone, Default)]
+pub struct ExitConditions {
+    pub frames: Option<u64>,
+    pub instructions: Option<u64>,
+    /// Emulated time
+    pub seconds: Option<f64>,
+    /// Stop when the CPU is about to execute this address. It's checked between steps,
+    /// so the recompiler only stops on block entries.
+    pub pc: Option<u32>,
+    /// Stop once the kernel TTY output (putchar, puts, printf) contains this
+    pub tty: Option<String>,
+}
+
+impl ExitConditions {
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_none() && self.instructions.is_none() && self.seconds.is_none()
+            && self.pc.is_none() && self.tty.is_none()
+    }
+
+    /// Whether the run waits for something, so that running out of time is a failure
+    fn awaits(&self) -> bool {
+        self.pc.is_some() || self.tty.is_some()
+    }
+
+    /// Instructions the run may take at most
+    fn instruction_budget(&self) -> Option<u64> {
+        let seconds = self.seconds.map(|seconds| (seconds * INSTRUCTIONS_PER_SECOND as f64) as u64);
+        match (self.instructions, seconds) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Files written when the run stops
+#[derive(Debug, Clone, Default)]
+pub struct Artifacts {
+    /// Last frame, as a binary PPM
+    pub screenshot: Option<PathBuf>,
+    /// Whole VRAM, as 1024x512 little endian 16-bit pixels
+    pub vram: Option<PathBuf>,
+    /// Kernel TTY output of the run
+    pub tty_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Success(String),
+    Timeout(String),
+    Failure(String),
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Success(_) => EXIT_SUCCESS,
+            Outcome::Timeout(_) => EXIT_TIMEOUT,
+            Outcome::Failure(_) => EXIT_FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success(reason) => write!(f, "success: {reason}"),
+            Outcome::Timeout(reason) => write!(f, "timeout: {reason}"),
+            Outcome::Failure(reason) => write!(f, "failure: {reason}"),
+        }
+    }
+}
+
+pub struct HeadlessRun {
+    conditions: ExitConditions,
+    /// Kernel TTY output so far
+    tty_log: Vec<u8>,
+}
+
+impl HeadlessRun {
+    pub fn new(conditions: ExitConditions) -> Self {
+        HeadlessRun { conditions, tty_log: Vec::new() }
+    }
+
+    pub fn tty_log(&self) -> &[u8] {
+        &self.tty_log
+    }
+
+    /// Run until an exit condition is met. Emulator errors and panics end the run as a
+    /// failure, leaving `ctx` as it was at that point.
+    pub fn run(&mut self, ctx: &mut Context) -> Outcome {
+        let outcome = match panic::catch_unwind(AssertUnwindSafe(|| self.run_until_exit(ctx))) {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(err)) => Outcome::Failure(err.to_string()),
+            Err(panic) => {
+                let message = panic.downcast_ref::<String>().map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("unknown panic");
+                Outcome::Failure(format!("emulator panicked: {message}"))
+            },
+        };
+        self.collect_tty(ctx);
+        outcome
+    }
+
+    fn run_until_exit(&mut self, ctx: &mut Context) -> Result<Outcome> {
+        let (start_frames, start_instructions) = (ctx.psx.frames(), ctx.psx.instructions_retired);
+        let budget = self.conditions.instruction_budget();
+        loop {
+            for _ in 0..TTY_CHECK_STEPS {
+                ctx.psx.step();
+                if let Some(pc) = self.conditions.pc && ctx.psx.cpu().pc() == pc {
+                    return Ok(Outcome::Success(format!("reached PC 0x{pc:08x}")));
+                }
+                let instructions = ctx.psx.instructions_retired - start_instructions;
+                if let Some(budget) = budget && instructions >= budget {
+                    return Ok(self.out_of_time(format!("ran {instructions} instructions")));
+                }
+                let frames = ctx.psx.frames() - start_frames;
+                if let Some(limit) = self.conditions.frames && frames >= limit {
+                    return Ok(self.out_of_time(format!("ran {frames} frames")));
+                }
+            }
+            // Nobody listens when headless
+            ctx.psx.take_audio();
+            ctx.flush_tty()?;
+            if self.collect_tty(ctx) {
+                let text = self.conditions.tty.as_ref().unwrap();
+                return Ok(Outcome::Success(format!("TTY printed {text:?}")));
+            }
+            if let Some(code) = ctx.psx.hle().and_then(Hle::exit_code) {
+                return Ok(match code {
+                    0 => Outcome::Success("program exited with code 0".to_string()),
+                    code => Outcome::Failure(format!("program exited with code {code}")),
+                });
+            }
+        }
+    }
+
+    fn out_of_time(&self, reason: String) -> Outcome {
+        match self.conditions.awaits() {
+            true => Outcome::Timeout(reason),
+            false => Outcome::Success(reason),
+        }
+    }
+
+    /// Append the new kernel TTY output to the log. Returns whether the awaited text
+    /// showed up in it.
+    fn collect_tty(&mut self, ctx: &mut Context) -> bool {
+        let output = ctx.psx.take_kernel_tty();
+        if output.is_empty() {
+            return false;
+        }
+        let Some(text) = &self.conditions.tty else {
+            self.tty_log.extend(output);
+            return false;
+        };
+        // Only look where the text could end in the new output
+        let start = self.tty_log.len().saturating_sub(text.len().saturating_sub(1));
+        self.tty_log.extend(output);
+        self.tty_log[start..].windows(text.len().max(1)).any(|window| window == text.as_bytes())
+    }
+
+    /// Write the artifacts of the run
+    pub fn write_artifacts(&self, ctx: &Context, artifacts: &Artifacts) -> Result<()> {
+        if let Some(path) = &artifacts.screenshot {
+            write_artifact(path, &ppm(&ctx.psx.frame()))?;
+        }
+        if let Some(path) = &artifacts.vram {
+            let vram: Vec<u8> = ctx.psx.bus().gpu().vram().iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+            write_artifact(path, &vram)?;
+        }
+        if let Some(path) = &artifacts.tty_log {
+            write_artifact(path, &self.tty_log)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_artifact(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() && !dir.as_os_str().is_empty() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, data).map_err(|e| anyhow!("failed to write {}: {e}", path.display()))?;
+    tracing::info!("wrote {}", path.display());
+    Ok(())
+}
+
+/// `frame` as a binary PPM image
+fn ppm(frame: &Frame) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write!(buf, "P6\n{} {}\n255\n", frame.width, frame.height).unwrap();
+    buf.extend(&frame.pixels);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{Psx, TEST_ORIGIN as ORIGIN};
+
+    /// Context on the high-level BIOS, booting `source`
+    fn boot(source: &str) -> Context {
+        let mut ctx = Context::new_hle().unwrap();
+        ctx.set_tty_output(Box::new(std::io::sink()));
+        *ctx.psx = Psx::boot_test_program(source);
+        ctx
+    }
+
+    /// puts("ready") then spin
+    const READY: &str = "
+            la    a0, ready
+            li    t2, 0xa0
+            jalr  t2
+            li    t1, 0x3e
+        spin:
+            b     spin
+            nop
+        ready:
+            .word 0x64616572, 0x00000079
+    ";
+
+    #[test]
+    fn stops_on_conditions() {
+        let mut ctx = boot(READY);
+        let mut run = HeadlessRun::new(ExitConditions { tty: Some("ady".into()), frames: Some(60), ..Default::default() });
+        assert_eq!(run.run(&mut ctx), Outcome::Success("TTY printed \"ady\"".into()));
+        assert!(run.tty_log().starts_with(b"ready"));
+
+        let spin = ORIGIN + 5 * 4;
+        let mut run = HeadlessRun::new(ExitConditions { pc: Some(spin), ..Default::default() });
+        assert_eq!(run.run(&mut ctx).exit_code(), EXIT_SUCCESS);
+
+        let mut run = HeadlessRun::new(ExitConditions { tty: Some("never".into()), frames: Some(2), ..Default::default() });
+        assert_eq!(run.run(&mut ctx), Outcome::Timeout("ran 2 frames".into()));
+        let mut run = HeadlessRun::new(ExitConditions { seconds: Some(0.001), ..Default::default() });
+        let outcome = run.run(&mut ctx);
+        assert_eq!(outcome.exit_code(), EXIT_SUCCESS, "{outcome}");
+    }
+
+    #[test]
+    fn reports_program_exit() {
+        // exit(3)
+        let mut ctx = boot("
+                li    a0, 3
+                li    t2, 0xa0
+                jalr  t2
+                li    t1, 0x06
+        ");
+        let mut run = HeadlessRun::new(ExitConditions { frames: Some(60), ..Default::default() });
+        let outcome = run.run(&mut ctx);
+        assert_eq!(outcome, Outcome::Failure("program exited with code 3".into()));
+        assert_eq!(outcome.exit_code(), EXIT_FAILURE);
+    }
+}
@@ -2,29 +2,91 @@ mod video;
 mod audio;
 mod input;
 
+pub use input::Action;
+
+use std::{path::Path, thread, time::{Duration, Instant}};
+
 use anyhow::{anyhow, Result};
-use crate::sdl::{
-    video::VideoDriver,
-    audio::AudioDriver,
-    input::InputDriver,
+use crate::{
+    config::Config,
+    emu::{gpu::Frame, pad::PadState},
+    sdl::{
+        video::VideoDriver,
+        audio::AudioDriver,
+        input::InputDriver,
+    },
 };
 
 pub struct SdlFrontend {
     video: VideoDriver,
-    audio: AudioDriver,
+    /// `None` when audio is disabled
+    audio: Option<AudioDriver>,
     input: InputDriver,
+    /// When the next frame is due
+    next_frame: Instant,
 }
 
 impl SdlFrontend {
-    pub fn new() -> Result<SdlFrontend> {
+    pub fn new(config: &Config) -> Result<SdlFrontend> {
         let sdl_context = sdl2::init().map_err(|e| anyhow!(e))?;
-        let video = VideoDriver::new(&sdl_context)?;
-        let audio = AudioDriver::new(&sdl_context)?;
-        let input = InputDriver::new(&sdl_context)?;
+        let video = VideoDriver::new(&sdl_context, &config.video, &config.enhancements)?;
+        let audio = match config.audio.enabled {
+            true => Some(AudioDriver::new(&sdl_context, &config.audio)?),
+            false => None,
+        };
+        let input = InputDriver::new(&sdl_context, &config.input)?;
         Ok(SdlFrontend { 
             video, 
             audio,
             input,
+            next_frame: Instant::now(),
         })
     }
-}
\ No newline at end of file
+
+    /// Show `frame` in the window
+    pub fn present(&mut self, frame: &Frame) -> Result<()> {
+        self.video.present(frame)
+    }
+
+    /// Write `frame` to `path` as a BMP
+    pub fn screenshot(&self, frame: &Frame, path: &Path) -> Result<()> {
+        self.video.screenshot(frame, path)
+    }
+
+    /// Queue the samples mixed by the SPU
+    pub fn queue_audio(&mut self, samples: &[[i16; 2]]) {
+        if let Some(audio) = &mut self.audio {
+            audio.queue(samples);
+        }
+    }
+
+    /// Play silence while emulation is paused
+    pub fn set_paused(&mut self, paused: bool) {
+        if let Some(audio) = &mut self.audio {
+            audio.set_paused(paused);
+        }
+    }
+
+    /// Wait until the next frame is due, so that emulation runs at the console's speed.
+    /// The audio rate control absorbs the drift between this and the audio clock.
+    pub fn throttle(&mut self, frame_time: Duration) {
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+            self.next_frame += frame_time;
+        } else {
+            // Running late, don't try to catch up
+            self.next_frame = now + frame_time;
+        }
+    }
+
+    /// Handle the pending window and input events
+    pub fn poll(&mut self) -> Vec<Action> {
+        self.input.poll()
+    }
+
+    /// State of the controllers in ports 1 and 2
+    pub fn pads(&self) -> [Option<PadState>; 2] {
+        self.input.pads()
+    }
+}
@@ -1,55 +1,83 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Result};
 use sdl2::{audio::{AudioDevice, AudioCallback, AudioSpecDesired}, Sdl};
 
-struct BasicWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+use crate::{
+    audio::{Resampler, SampleRing},
+    config::file::AudioConfig,
+    emu::spu,
+};
+
+/// Samples the ring holds (about 186 ms at 44.1 kHz)
+const RING_CAPACITY: usize = 8192;
+
+struct RingPlayback {
+    ring: Arc<SampleRing>,
+    /// Last sample played, faded out when the ring runs dry
+    last: [i16; 2],
 }
 
-impl AudioCallback for BasicWave {
-    type Channel = f32;
+impl AudioCallback for RingPlayback {
+    type Channel = i16;
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            if self.phase <= 0.5 {
-                *x = self.volume;
-            } else {
-                *x = -self.volume;
-            }
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        }
+    fn callback(&mut self, out: &mut [i16]) {
+        self.ring.play(out, &mut self.last);
     }
 }
 
 pub struct AudioDriver {
-    device: AudioDevice<BasicWave>
+    device: AudioDevice<RingPlayback>,
+    ring: Arc<SampleRing>,
+    resampler: Resampler,
+    volume: f32,
 }
 
 impl AudioDriver {
-    pub fn new(sdl_context: &Sdl) -> Result<Self> {
+    pub fn new(sdl_context: &Sdl, config: &AudioConfig) -> Result<Self> {
         let audio_subsystem = sdl_context.audio().map_err(|e| anyhow!(e))?;
 
         let desired_spec = AudioSpecDesired {
-            freq: Some(44_100),
-            channels: Some(1),
-            samples: None,
+            freq: Some(spu::SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(config.buffer),
         };
 
+        let ring = Arc::new(SampleRing::new(RING_CAPACITY));
         let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-            log::debug!("Audio driver spec obtained: {:?}", spec);
+            tracing::debug!("Audio driver spec obtained: {:?}", spec);
 
-            BasicWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.03,
+            RingPlayback {
+                ring: ring.clone(),
+                last: [0; 2],
             }
         }).map_err(|e| anyhow!(e))?;
+        let resampler = Resampler::new(spu::SAMPLE_RATE, device.spec().freq as u32);
+        device.resume();
 
-        log::info!("SDL audio subsystem initialized");
+        tracing::info!("SDL audio subsystem initialized");
         Ok( AudioDriver {
             device,
+            ring,
+            resampler,
+            volume: config.volume,
         })
     }
-}
\ No newline at end of file
+
+    /// Queue the samples mixed by the SPU
+    pub fn queue(&mut self, samples: &[[i16; 2]]) {
+        if self.volume == 1.0 {
+            self.resampler.push(samples, &self.ring);
+            return;
+        }
+        let scaled: Vec<[i16; 2]> = samples.iter()
+            .map(|sample| sample.map(|channel| (channel as f32 * self.volume) as i16))
+            .collect();
+        self.resampler.push(&scaled, &self.ring);
+    }
+
+    /// Play silence, e.g. while emulation is paused
+    pub fn set_paused(&mut self, paused: bool) {
+        self.ring.set_paused(paused);
+    }
+}
@@ -1,19 +1,185 @@
-use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
 use sdl2::{
-    event::Event,
-    keyboard::Keycode,
+    controller::{self, Axis, GameController},
+    event::{Event, WindowEvent},
+    keyboard::{Keycode, Scancode},
     EventPump,
+    GameControllerSubsystem,
     Sdl,
 };
 
+use crate::{
+    config::input::{Hotkey, InputBindings, PortBindings},
+    emu::pad::{Button, PadKind, PadState},
+};
+
+/// Axis value past which a trigger counts as pressed, or a stick as a d-pad direction
+/// on a digital pad
+const AXIS_THRESHOLD: i16 = 0x4000;
+
+/// What the user asked for, besides playing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Press(Hotkey),
+    Release(Hotkey),
+}
+
+/// Game controller input a pad button is bound to
+#[derive(Debug, Clone, Copy)]
+enum ControllerInput {
+    Button(controller::Button),
+    /// Analog trigger
+    Axis(Axis),
+}
+
+/// Resolved bindings of a port
+struct Port {
+    kind: PadKind,
+    controller: usize,
+    keys: Vec<(Button, Scancode)>,
+    buttons: Vec<(Button, ControllerInput)>,
+}
+
+impl Port {
+    fn new(table: &str, bindings: &PortBindings) -> Result<Port> {
+        let keys = bindings.keys.iter()
+            .map(|(&button, name)| Ok((button, scancode(&format!("{table}.keys.{button}"), name)?)))
+            .collect::<Result<_>>()?;
+        let buttons = bindings.buttons.iter()
+            .map(|(&button, name)| {
+                let input = match (controller::Button::from_string(name), Axis::from_string(name)) {
+                    (Some(input), _) => ControllerInput::Button(input),
+                    (None, Some(axis @ (Axis::TriggerLeft | Axis::TriggerRight))) => ControllerInput::Axis(axis),
+                    _ => bail!("{table}.buttons.{button}: unknown game controller button {name:?}"),
+                };
+                Ok((button, input))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Port { kind: bindings.kind, controller: bindings.controller, keys, buttons })
+    }
+}
+
 pub struct InputDriver {
     events: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    /// Open game controllers, in connection order
+    controllers: Vec<GameController>,
+    ports: [Port; 2],
+    hotkeys: HashMap<Keycode, Hotkey>,
 }
 
 impl InputDriver {
-    pub fn new(sdl_context: &Sdl) -> Result<Self> {
+    pub fn new(sdl_context: &Sdl, bindings: &InputBindings) -> Result<Self> {
         let events = sdl_context.event_pump().map_err(|e| anyhow!(e))?;
-        log::info!("SDL input handler initialized");
-        Ok(InputDriver{events})
+        // Already connected controllers show up as added in the first poll
+        let controller_subsystem = sdl_context.game_controller().map_err(|e| anyhow!(e))?;
+        let [port1, port2] = &bindings.ports;
+        let ports = [Port::new("input.port1", port1)?, Port::new("input.port2", port2)?];
+        let mut hotkeys = HashMap::new();
+        for (&hotkey, name) in &bindings.hotkeys {
+            let Some(keycode) = Keycode::from_name(name) else {
+                bail!("input.hotkeys.{}: unknown key {name:?}", hotkey.name());
+            };
+            if let Some(other) = hotkeys.insert(keycode, hotkey) {
+                bail!("input.hotkeys.{}: key {name:?} is already bound to input.hotkeys.{}", hotkey.name(), other.name());
+            }
+        }
+        tracing::info!("SDL input handler initialized");
+        Ok(InputDriver { events, controller_subsystem, controllers: Vec::new(), ports, hotkeys })
+    }
+
+    /// Drain the pending events, returning the hotkeys pressed and released
+    pub fn poll(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for event in self.events.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::Window { win_event: WindowEvent::Close, .. } => actions.push(Action::Quit),
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    if let Some(&hotkey) = self.hotkeys.get(&keycode) {
+                        actions.push(Action::Press(hotkey));
+                    }
+                },
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(&hotkey) = self.hotkeys.get(&keycode) {
+                        actions.push(Action::Release(hotkey));
+                    }
+                },
+                Event::ControllerDeviceAdded { which, .. } => match self.controller_subsystem.open(which) {
+                    Ok(controller) => {
+                        tracing::info!("game controller {} connected: {}", self.controllers.len() + 1, controller.name());
+                        self.controllers.push(controller);
+                    },
+                    Err(err) => tracing::warn!("failed to open game controller {which}: {err}"),
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.retain(|controller| controller.instance_id() != which);
+                    tracing::info!("game controller disconnected");
+                },
+                _ => {},
+            }
+        }
+        actions
     }
-}
\ No newline at end of file
+
+    /// State of the pads, from the keys and controllers held right now. A port is
+    /// empty when it has no keys bound and its controller isn't connected.
+    pub fn pads(&self) -> [Option<PadState>; 2] {
+        let keyboard = self.events.keyboard_state();
+        std::array::from_fn(|idx| {
+            let port = &self.ports[idx];
+            let controller = self.controllers.get(port.controller);
+            if port.keys.is_empty() && controller.is_none() {
+                return None;
+            }
+            let mut pad = PadState::new(port.kind);
+            for &(button, scancode) in &port.keys {
+                if keyboard.is_scancode_pressed(scancode) {
+                    pad.set(button, true);
+                }
+            }
+            if let Some(controller) = controller {
+                for &(button, input) in &port.buttons {
+                    let pressed = match input {
+                        ControllerInput::Button(input) => controller.button(input),
+                        ControllerInput::Axis(axis) => controller.axis(axis) > AXIS_THRESHOLD,
+                    };
+                    if pressed {
+                        pad.set(button, true);
+                    }
+                }
+                let stick = |x, y| [x, y].map(|axis| (controller.axis(axis) as u16 >> 8) as u8 ^ 0x80);
+                match port.kind {
+                    PadKind::DualShock => {
+                        pad.left_stick = stick(Axis::LeftX, Axis::LeftY);
+                        pad.right_stick = stick(Axis::RightX, Axis::RightY);
+                    },
+                    // The left stick doubles as the d-pad
+                    PadKind::Digital => {
+                        let (x, y) = (controller.axis(Axis::LeftX), controller.axis(Axis::LeftY));
+                        for (button, pressed) in [
+                            (Button::Left, x < -AXIS_THRESHOLD),
+                            (Button::Right, x > AXIS_THRESHOLD),
+                            (Button::Up, y < -AXIS_THRESHOLD),
+                            (Button::Down, y > AXIS_THRESHOLD),
+                        ] {
+                            if pressed {
+                                pad.set(button, true);
+                            }
+                        }
+                    },
+                }
+            }
+            Some(pad)
+        })
+    }
+}
+
+/// Scancode of the key called `name` in the current layout, for polling the keyboard
+fn scancode(path: &str, name: &str) -> Result<Scancode> {
+    Keycode::from_name(name)
+        .and_then(Scancode::from_keycode)
+        .ok_or_else(|| anyhow!("{path}: unknown key {name:?}"))
+}
@@ -1,43 +1,90 @@
+use std::path::Path;
+
 use anyhow::{anyhow, Result};
 use sdl2::{
     video::Window,
-    render::Canvas,
-    rect::Rect,
-    pixels::Color,
+    render::{Canvas, Texture},
+    surface::Surface,
+    pixels::{Color, PixelFormatEnum},
+};
+
+use crate::{
+    config::file::{Enhancements, VideoConfig},
+    emu::gpu::Frame,
 };
 
-macro_rules! rect(
-    ($x:expr, $y:expr, $w:expr, $h:expr $(,)?) => (
-        Rect::new($x as i32, $y as i32, $w as u32, $h as u32)
-    )
-);
+/// Window size at scale 1
+const BASE_SIZE: (u32, u32) = (320, 240);
 
 pub struct VideoDriver {
     canvas: Canvas<Window>,
+    /// Streaming texture the frames are uploaded to, sized like the last frame
+    texture: Option<(Texture, u32, u32)>,
 }
 
 impl VideoDriver {
-    pub fn new(sdl_context: &sdl2::Sdl) -> Result<Self> {
+    pub fn new(sdl_context: &sdl2::Sdl, config: &VideoConfig, enhancements: &Enhancements) -> Result<Self> {
         let video_subsystem = sdl_context.video().map_err(|e| anyhow!(e))?;
 
-        let window = video_subsystem
-            .window("PSX-RS", 640, 480)
-            .opengl()
-            .build()?;
-
-        let mut canvas = window.into_canvas()
-            .index(find_sdl_gl_driver().ok_or("No opengl driver").map_err(|e| anyhow!(e))?)
-            .build()
-            .map_err(|e| anyhow!(e))?;
+        let (width, height) = (BASE_SIZE.0 * config.scale, BASE_SIZE.1 * config.scale);
+        let mut builder = video_subsystem.window("PSX-RS", width, height);
+        builder.opengl().resizable();
+        if config.fullscreen {
+            builder.fullscreen_desktop();
+        }
+        let window = builder.build()?;
 
-        log::info!("SDL video subsystem initialized");
+        let mut builder = window.into_canvas();
+        if let Some(index) = find_sdl_gl_driver() {
+            builder = builder.index(index);
+        }
+        let mut canvas = builder.build().map_err(|e| anyhow!(e))?;
+        if enhancements.keep_aspect_ratio {
+            canvas.set_logical_size(width, height)?;
+        }
+        // Read when the textures are created
+        let quality = if enhancements.linear_filtering { "linear" } else { "nearest" };
+        sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", quality);
 
-        canvas.set_draw_color(Color::RGB(145, 145, 135));
-        canvas.fill_rect(rect!(0, 0, 640, 480)).map_err(|e| anyhow!(e))?;
+        tracing::info!("SDL video subsystem initialized");
 
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
         canvas.present();
 
-        Ok( VideoDriver{ canvas })
+        Ok( VideoDriver{ canvas, texture: None })
+    }
+
+    /// Stretch `frame` over the window
+    pub fn present(&mut self, frame: &Frame) -> Result<()> {
+        self.canvas.clear();
+        if frame.width == 0 || frame.height == 0 {
+            self.canvas.present();
+            return Ok(());
+        }
+
+        if !matches!(self.texture, Some((_, width, height)) if (width, height) == (frame.width, frame.height)) {
+            if let Some((texture, _, _)) = self.texture.take() {
+                // Safety: the canvas, and so the renderer, is still alive
+                unsafe { texture.destroy() };
+            }
+            let texture = self.canvas.create_texture_streaming(PixelFormatEnum::RGB24, frame.width, frame.height)?;
+            tracing::debug!("display resolution changed to {}x{}", frame.width, frame.height);
+            self.texture = Some((texture, frame.width, frame.height));
+        }
+        let (texture, _, _) = self.texture.as_mut().unwrap();
+        texture.update(None, &frame.pixels, frame.width as usize * 3)?;
+        self.canvas.copy(texture, None, None).map_err(|e| anyhow!(e))?;
+        self.canvas.present();
+        Ok(())
+    }
+
+    /// Write `frame` to `path` as a BMP
+    pub fn screenshot(&self, frame: &Frame, path: &Path) -> Result<()> {
+        let mut pixels = frame.pixels.clone();
+        let surface = Surface::from_data(&mut pixels, frame.width, frame.height, frame.width * 3, PixelFormatEnum::RGB24)
+            .map_err(|e| anyhow!(e))?;
+        surface.save_bmp(path).map_err(|e| anyhow!("failed to write screenshot {}: {e}", path.display()))
     }
 }
 
@@ -45,9 +92,9 @@ impl VideoDriver {
 fn find_sdl_gl_driver() -> Option<u32> {
     for (index, item) in sdl2::render::drivers().enumerate() {
         if item.name == "opengl" {
-            log::info!("opengl driver identified");
+            tracing::info!("opengl driver identified");
             return Some(index as u32);
         }
     }
     None
-}
\ No newline at end of file
+}